@@ -0,0 +1,70 @@
+//! Benchmarks TCP connect, TLS handshake and throughput against a configurable endpoint.
+//!
+//! Configure at build time through the environment, e.g.
+//!
+//! ```text
+//! WIFI_SSID=... WIFI_PASS=... BENCH_HOST=speed.example.com BENCH_PATH=/10MB.bin \
+//!     cargo run --release --example bench
+//! ```
+//!
+//! and collect the results with `grep '^BENCH '`.
+
+use esp_idf_hal::prelude::Peripherals;
+use esp_idf_svc::{eventloop::EspSystemEventLoop, tls};
+use esp_idf_sys as _;
+use log::*;
+use repro_async_tls::{
+    bench::{self, BenchConfig},
+    wifi, TlsConnector,
+};
+
+fn env_or<T: std::str::FromStr>(value: Option<&str>, default: T) -> T {
+    value.and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+fn main() -> anyhow::Result<()> {
+    esp_idf_sys::link_patches();
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    let peripherals = Peripherals::take().unwrap();
+    let sysloop = EspSystemEventLoop::take().unwrap();
+
+    let _wifi = wifi::connect(
+        peripherals.modem,
+        sysloop,
+        option_env!("WIFI_SSID").unwrap_or("ssid"),
+        option_env!("WIFI_PASS").unwrap_or("pass"),
+    )?;
+
+    let host = option_env!("BENCH_HOST").unwrap_or("example.com");
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n",
+        option_env!("BENCH_PATH").unwrap_or("/")
+    );
+
+    let bench = BenchConfig {
+        host,
+        port: env_or(option_env!("BENCH_PORT"), 443),
+        request: Some(request.as_bytes()),
+        upload_len: env_or(option_env!("BENCH_UPLOAD"), 0),
+        download_len: env_or(option_env!("BENCH_DOWNLOAD"), 1024 * 1024),
+        chunk_len: env_or(option_env!("BENCH_CHUNK"), 4096),
+        rounds: env_or(option_env!("BENCH_ROUNDS"), 5),
+    };
+    let cfg = tls::Config {
+        common_name: Some(host),
+        use_crt_bundle_attach: true,
+        ..Default::default()
+    };
+
+    info!(
+        "Benchmarking {host}:{} in {} rounds",
+        bench.port, bench.rounds
+    );
+    async_io::block_on(bench::run(&TlsConnector::new(), &bench, &cfg))?;
+    info!("Benchmark done");
+
+    loop {
+        std::thread::sleep(std::time::Duration::from_secs(1));
+    }
+}
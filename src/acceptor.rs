@@ -0,0 +1,120 @@
+use std::sync::Arc;
+
+use futures_lite::{AsyncRead, AsyncWrite};
+
+#[cfg(feature = "debug-keylog")]
+use crate::keylog::KeyLogFn;
+#[cfg(feature = "debug-tap")]
+use crate::tap::Tap;
+use crate::{error::Result, stream::TlsStream};
+
+/// Accepts TLS connections as a server, e.g. on connections from an
+/// [`AsyncTcpListener`](crate::AsyncTcpListener).
+///
+/// Clients are not asked for a certificate.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    cert: Arc<[u8]>,
+    key: Arc<[u8]>,
+    server_names: Arc<[ServerName]>,
+    #[cfg(feature = "debug-keylog")]
+    keylog: Option<Arc<KeyLogFn>>,
+    #[cfg(feature = "debug-tap")]
+    tap: Option<Tap>,
+}
+
+impl TlsAcceptor {
+    /// Present `cert` (the chain, leaf first) to clients and authenticate with `key`.
+    ///
+    /// Both are PEM or DER encoded, PEM needs to be nul terminated.
+    pub fn new(cert: &[u8], key: &[u8]) -> Self {
+        Self {
+            cert: cert.into(),
+            key: key.into(),
+            server_names: Arc::new([]),
+            #[cfg(feature = "debug-keylog")]
+            keylog: None,
+            #[cfg(feature = "debug-tap")]
+            tap: None,
+        }
+    }
+
+    /// Present `cert` and authenticate with `key` to clients asking for `name` with SNI, so that
+    /// one listener serves several hostnames.
+    ///
+    /// `name` matches case-insensitively, and a leading `*.` matches one label, e.g.
+    /// `*.example.com` matches `a.example.com` but not `example.com`. The first match wins.
+    /// Clients without SNI or with a name that matches nothing get the certificate of
+    /// [`new`](Self::new). Requires `CONFIG_MBEDTLS_SERVER_NAME_INDICATION`.
+    pub fn server_name(mut self, name: &str, cert: &[u8], key: &[u8]) -> Self {
+        let mut server_names = self.server_names.to_vec();
+        server_names.push(ServerName {
+            name: name.to_ascii_lowercase(),
+            cert: cert.into(),
+            key: key.into(),
+        });
+        self.server_names = server_names.into();
+        self
+    }
+
+    /// Hand the secrets of every session to `callback`, to decrypt captures in Wireshark. See
+    /// [`keylog`](crate::keylog), never enable this in production.
+    #[cfg(feature = "debug-keylog")]
+    pub fn keylog<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.keylog = Some(Arc::new(callback));
+        self
+    }
+
+    /// Mirror the decrypted data of every session to `tap`, see [`tap`](crate::tap). Never
+    /// enable this in production.
+    #[cfg(feature = "debug-tap")]
+    pub fn tap(mut self, tap: Tap) -> Self {
+        self.tap = Some(tap);
+        self
+    }
+
+    /// Perform the server side of the handshake on a freshly accepted connection.
+    pub async fn accept<T>(&self, transport: T) -> Result<TlsStream<T>>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut tls = TlsStream::new(transport)?;
+        #[cfg(feature = "debug-keylog")]
+        if let Some(keylog) = &self.keylog {
+            tls.set_keylog_callback(keylog.clone());
+        }
+        #[cfg(feature = "debug-tap")]
+        if let Some(tap) = &self.tap {
+            tls.set_tap(tap.clone());
+        }
+        tls.accept(&self.cert, &self.key, self.server_names.clone())
+            .await?;
+
+        Ok(tls)
+    }
+}
+
+/// A certificate to present for a name, see [`TlsAcceptor::server_name`].
+#[derive(Clone)]
+pub(crate) struct ServerName {
+    name: String,
+    pub(crate) cert: Arc<[u8]>,
+    pub(crate) key: Arc<[u8]>,
+}
+
+impl ServerName {
+    /// Whether this is the certificate for `requested`, the name a client sent with SNI.
+    pub(crate) fn matches(&self, requested: &str) -> bool {
+        let requested = requested.to_ascii_lowercase();
+
+        match self.name.strip_prefix("*.") {
+            Some(domain) => requested
+                .split_once('.')
+                .map_or(false, |(label, rest)| !label.is_empty() && rest == domain),
+            None => requested == self.name,
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Connections to AWS IoT Core, authenticated with the device certificate of a thing.
+//!
+//! ```ignore
+//! let aws = AwsIot::new("a1b2c3d4e5-ats.iot.eu-west-1.amazonaws.com", "sensor-42", CERT, KEY)
+//!     .port(AwsPort::Https);
+//! let tls = aws.connect(&TlsConnector::new()).await?;
+//! // MQTT CONNECT over `tls` with `aws.client_id()` and `aws_iot::KEEP_ALIVE`
+//! ```
+//!
+//! The crate has no MQTT client of its own, the connection is handed to one. AWS IoT closes the
+//! connection on a CONNECT with another client ID than the thing name if the policy uses
+//! `iot:Connection.Thing.ThingName`, and when the keep alive runs out.
+
+use std::time::Duration;
+
+use esp_idf_svc::tls::Config;
+
+use crate::{
+    connector::TlsConnector,
+    credstore::x509,
+    error::{Error, Result},
+    tls::AsyncTls,
+};
+
+/// The ALPN protocol that selects MQTT with certificate authentication on port 443.
+pub const ALPN_MQTT: &str = "x-amzn-mqtt-ca";
+
+/// MQTT keep alive as the AWS IoT device SDKs use it. AWS IoT accepts 30 s to 1200 s.
+pub const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+/// The port to connect to AWS IoT on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AwsPort {
+    /// 8883, the MQTT port
+    #[default]
+    Mqtt,
+    /// 443 with the [`ALPN_MQTT`] protocol, for networks that block everything but HTTPS
+    Https,
+}
+
+impl AwsPort {
+    pub fn number(self) -> u16 {
+        match self {
+            Self::Mqtt => 8883,
+            Self::Https => 443,
+        }
+    }
+}
+
+/// Connects a thing to AWS IoT Core, see the [module docs](self).
+pub struct AwsIot {
+    endpoint: String,
+    thing_name: String,
+    client_cert: Vec<u8>,
+    client_key: Vec<u8>,
+    ca_cert: Option<Vec<u8>>,
+    port: AwsPort,
+}
+
+impl AwsIot {
+    /// Connect to the device data `endpoint` of the account, as `aws iot describe-endpoint
+    /// --endpoint-type iot:Data-ATS` shows it, as `thing_name` with its certificate and key
+    /// (PEM, nul terminated like `X509::pem`, or DER).
+    ///
+    /// The server is verified with the certificate bundle, which includes the Amazon roots,
+    /// unless a CA certificate is set.
+    pub fn new(endpoint: &str, thing_name: &str, client_cert: &[u8], client_key: &[u8]) -> Self {
+        if !endpoint.contains("-ats.") {
+            log::warn!(
+                "{endpoint} is a legacy endpoint with a Symantec certificate, use the ATS one"
+            );
+        }
+
+        Self {
+            endpoint: endpoint.to_owned(),
+            thing_name: thing_name.to_owned(),
+            client_cert: client_cert.to_vec(),
+            client_key: client_key.to_vec(),
+            ca_cert: None,
+            port: AwsPort::default(),
+        }
+    }
+
+    /// Verify the server with `ca_cert`, e.g. Amazon Root CA 1, instead of the certificate
+    /// bundle. Required without `CONFIG_MBEDTLS_CERTIFICATE_BUNDLE`.
+    pub fn ca_cert(mut self, ca_cert: &[u8]) -> Self {
+        self.ca_cert = Some(ca_cert.to_vec());
+        self
+    }
+
+    /// Connect on `port` instead of 8883.
+    pub fn port(mut self, port: AwsPort) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// The MQTT client ID to connect with, the thing name.
+    pub fn client_id(&self) -> &str {
+        &self.thing_name
+    }
+
+    /// Open the TLS connection for MQTT with `connector`.
+    pub async fn connect(&self, connector: &TlsConnector) -> Result<AsyncTls> {
+        if self.ca_cert.is_none() && !cfg!(esp_idf_mbedtls_certificate_bundle) {
+            return Err(Error::Credentials(
+                "no CA certificate for AWS IoT and no certificate bundle".to_owned(),
+            ));
+        }
+
+        let alpn_protos = [ALPN_MQTT];
+        let cfg = Config {
+            ca_cert: self.ca_cert.as_deref().map(x509),
+            #[cfg(esp_idf_mbedtls_certificate_bundle)]
+            use_crt_bundle_attach: self.ca_cert.is_none(),
+            client_cert: Some(x509(&self.client_cert)),
+            client_key: Some(x509(&self.client_key)),
+            alpn_protos: (self.port == AwsPort::Https).then_some(&alpn_protos[..]),
+            ..Default::default()
+        };
+
+        connector
+            .connect(&self.endpoint, self.port.number(), &cfg)
+            .await
+    }
+}
@@ -0,0 +1,198 @@
+//! Connections to Azure IoT Hub, authenticated with a shared access signature (SAS) token
+//! derived from the device key.
+//!
+//! ```ignore
+//! let mut azure = AzureIot::new("my-hub.azure-devices.net", "sensor-42", DEVICE_KEY)?;
+//! let tls = azure.connect(&TlsConnector::new()).await?;
+//! let creds = azure.mqtt_credentials()?;
+//! // MQTT CONNECT over `tls` with `creds.client_id`, `creds.username` and `creds.password`
+//!
+//! // Later, before the hub drops the connection when the token expires
+//! if azure.needs_renewal() {
+//!     // Reconnect with fresh credentials
+//! }
+//! ```
+//!
+//! The crate has no MQTT client of its own, the connection is handed to one. Tokens are signed
+//! with the current time, so the system time has to be set, e.g. by SNTP.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use esp_idf_svc::tls::Config;
+use esp_idf_sys as sys;
+
+use crate::{
+    cert::clock_is_set,
+    connector::TlsConnector,
+    error::{Error, Result},
+    tls::AsyncTls,
+    util::{base64, base64_decode, url_encode},
+};
+
+/// The IoT Hub API version the MQTT username asks for.
+pub const API_VERSION: &str = "2021-04-12";
+
+/// A SAS token and when it expires.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SasToken {
+    /// `SharedAccessSignature sr=...&sig=...&se=...`
+    pub token: String,
+    pub expires: SystemTime,
+}
+
+/// What to send in the MQTT CONNECT packet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MqttCredentials {
+    pub client_id: String,
+    pub username: String,
+    pub password: String,
+}
+
+/// Connects a device to Azure IoT Hub, see the [module docs](self).
+pub struct AzureIot {
+    hub: String,
+    device_id: String,
+    key: Vec<u8>,
+    validity: Duration,
+    renew_before: Duration,
+    token: Option<SasToken>,
+}
+
+impl AzureIot {
+    /// Connect to the hub `hub`, e.g. `my-hub.azure-devices.net`, as `device_id` with its
+    /// primary or secondary symmetric key `key`, base64 encoded as the portal shows it.
+    ///
+    /// Tokens are valid for an hour and renewed 5 minutes before they expire.
+    pub fn new(hub: &str, device_id: &str, key: &str) -> Result<Self> {
+        let key = base64_decode(key.trim())
+            .ok_or_else(|| Error::Credentials("the device key is not base64".to_owned()))?;
+
+        Ok(Self {
+            hub: hub.to_owned(),
+            device_id: device_id.to_owned(),
+            key,
+            validity: Duration::from_secs(3600),
+            renew_before: Duration::from_secs(300),
+            token: None,
+        })
+    }
+
+    /// Sign tokens valid for `validity`.
+    pub fn validity(mut self, validity: Duration) -> Self {
+        self.validity = validity;
+        self
+    }
+
+    /// Consider the token due for renewal `margin` before it expires.
+    pub fn renew_before(mut self, margin: Duration) -> Self {
+        self.renew_before = margin;
+        self
+    }
+
+    /// The resource the tokens grant access to, `<hub>/devices/<device_id>`.
+    pub fn resource_uri(&self) -> String {
+        format!("{}/devices/{}", self.hub, self.device_id)
+    }
+
+    /// Sign a token for [`resource_uri`](Self::resource_uri) that expires `validity` after
+    /// `now`.
+    pub fn sas_token(&self, now: SystemTime) -> Result<SasToken> {
+        if !clock_is_set(now) {
+            return Err(Error::Credentials(
+                "the system time is not set, can't sign a SAS token".to_owned(),
+            ));
+        }
+
+        let expiry = (now + self.validity)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let resource = url_encode(&self.resource_uri());
+        let signature = hmac_sha256(&self.key, format!("{resource}\n{expiry}").as_bytes())?;
+
+        Ok(SasToken {
+            token: format!(
+                "SharedAccessSignature sr={resource}&sig={}&se={expiry}",
+                url_encode(&base64(&signature))
+            ),
+            expires: UNIX_EPOCH + Duration::from_secs(expiry),
+        })
+    }
+
+    /// The credentials for the MQTT CONNECT packet, with the current token or a new one if it
+    /// is due for renewal.
+    pub fn mqtt_credentials(&mut self) -> Result<MqttCredentials> {
+        if self.needs_renewal() {
+            self.token = Some(self.sas_token(SystemTime::now())?);
+        }
+        let token = self.token.as_ref().unwrap();
+
+        Ok(MqttCredentials {
+            client_id: self.device_id.clone(),
+            username: format!("{}/{}/?api-version={API_VERSION}", self.hub, self.device_id),
+            password: token.token.clone(),
+        })
+    }
+
+    /// Whether there is no token yet or it expires within the renewal margin. The hub closes
+    /// the connection once the token it was opened with expires, so reconnect with new
+    /// [`mqtt_credentials`](Self::mqtt_credentials) before.
+    pub fn needs_renewal(&self) -> bool {
+        self.token.as_ref().map_or(true, |token| {
+            SystemTime::now() + self.renew_before >= token.expires
+        })
+    }
+
+    /// When the current token is due for renewal, if there is one.
+    pub fn renew_at(&self) -> Option<SystemTime> {
+        let token = self.token.as_ref()?;
+
+        token.expires.checked_sub(self.renew_before)
+    }
+
+    /// Open the TLS connection for MQTT on port 8883 with `connector`, verifying the hub with
+    /// the certificate bundle. Requires `CONFIG_MBEDTLS_CERTIFICATE_BUNDLE`, use
+    /// [`TlsConnector::connect`] with the DigiCert roots otherwise.
+    pub async fn connect(&self, connector: &TlsConnector) -> Result<AsyncTls> {
+        if !cfg!(esp_idf_mbedtls_certificate_bundle) {
+            return Err(Error::Credentials(
+                "no certificate bundle to verify Azure IoT Hub with".to_owned(),
+            ));
+        }
+
+        let cfg = Config {
+            #[cfg(esp_idf_mbedtls_certificate_bundle)]
+            use_crt_bundle_attach: true,
+            ..Default::default()
+        };
+
+        connector.connect(&self.hub, 8883, &cfg).await
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<[u8; 32]> {
+    let mut mac = [0; 32];
+    let ret = unsafe {
+        let md = sys::mbedtls_md_info_from_type(sys::mbedtls_md_type_t_MBEDTLS_MD_SHA256);
+        if md.is_null() {
+            return Err(Error::Credentials("SHA-256 is not available".to_owned()));
+        }
+
+        sys::mbedtls_md_hmac(
+            md,
+            key.as_ptr(),
+            key.len(),
+            data.as_ptr(),
+            data.len(),
+            mac.as_mut_ptr(),
+        )
+    };
+
+    match ret {
+        0 => Ok(mac),
+        ret => Err(Error::Credentials(format!(
+            "signing the SAS token failed: -0x{:04x}",
+            -ret
+        ))),
+    }
+}
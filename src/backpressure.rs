@@ -0,0 +1,245 @@
+//! A write buffer that tells producers to slow down while the connection can't keep up, so that
+//! a slow uplink doesn't make the device buffer data until the heap runs out.
+//!
+//! ```ignore
+//! let mut writer = WatermarkWriter::new(tls).watermarks(4 * 1024, 16 * 1024);
+//! loop {
+//!     // Waits while more than 16 KiB are buffered, until no more than 4 KiB are left
+//!     writer.ready().await?;
+//!     writer.write_all(&next_sample()).await?;
+//! }
+//! ```
+//!
+//! Buffered data goes out as the writer is used: on writes, [`ready`](WatermarkWriter::ready),
+//! flushes and on close. Flush before dropping the writer, unflushed data is lost.
+
+use core::{
+    future::poll_fn,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use std::{collections::VecDeque, io};
+
+use futures_lite::AsyncWrite;
+
+/// Low watermark of [`WatermarkWriter`] unless configured otherwise.
+pub const DEFAULT_LOW_WATERMARK: usize = 4 * 1024;
+
+/// High watermark of [`WatermarkWriter`] unless configured otherwise.
+pub const DEFAULT_HIGH_WATERMARK: usize = 16 * 1024;
+
+/// Buffers writes to `W` up to a high watermark, see the [module docs](self).
+pub struct WatermarkWriter<W> {
+    inner: W,
+    buf: VecDeque<u8>,
+    low: usize,
+    high: usize,
+    /// Set once the buffer reached the high watermark, until it drained to the low one
+    throttled: bool,
+}
+
+impl<W: AsyncWrite + Unpin> WatermarkWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            buf: VecDeque::new(),
+            low: DEFAULT_LOW_WATERMARK,
+            high: DEFAULT_HIGH_WATERMARK,
+            throttled: false,
+        }
+    }
+
+    /// Stop accepting writes once `high` bytes are buffered, until no more than `low` are left.
+    /// `low` is capped to `high`.
+    pub fn watermarks(mut self, low: usize, high: usize) -> Self {
+        self.high = high.max(1);
+        self.low = low.min(self.high);
+        self
+    }
+
+    /// Wait until the writer accepts data again, sending buffered data meanwhile.
+    ///
+    /// Returns right away unless the buffer reached the high watermark. Cancellation safe.
+    pub async fn ready(&mut self) -> io::Result<()> {
+        poll_fn(|cx| self.poll_ready(cx)).await
+    }
+
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_drain(cx, self.low)?;
+
+        match self.throttled {
+            // `poll_drain` stopped at a pending write, which wakes us
+            true => Poll::Pending,
+            false => Poll::Ready(Ok(())),
+        }
+    }
+
+    /// Whether writes are accepted without waiting, i.e. the high watermark wasn't reached or
+    /// the buffer drained to the low watermark since.
+    pub fn is_ready(&self) -> bool {
+        !self.throttled
+    }
+
+    /// How many bytes are waiting to be written.
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// The writer, dropping the buffered data. Flush first to keep it.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Write buffered data to the inner writer until no more than `target` bytes are left or
+    /// the inner writer is not ready. Lifts the throttling once at or below the low watermark.
+    fn poll_drain(&mut self, cx: &mut Context<'_>, target: usize) -> io::Result<()> {
+        while self.buf.len() > target {
+            let (chunk, _) = self.buf.as_slices();
+            match Pin::new(&mut self.inner).poll_write(cx, chunk) {
+                Poll::Ready(Ok(0)) => return Err(io::ErrorKind::WriteZero.into()),
+                Poll::Ready(Ok(n)) => {
+                    self.buf.drain(..n);
+                }
+                Poll::Ready(Err(e)) => return Err(e),
+                Poll::Pending => break,
+            }
+        }
+
+        if self.buf.len() <= self.low {
+            self.throttled = false;
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for WatermarkWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_ready(cx))?;
+        // Send what we can without waiting, the rest stays buffered
+        this.poll_drain(cx, 0)?;
+
+        // Not throttled, so below the high watermark
+        let len = buf.len().min(this.high - this.buf.len());
+        this.buf.extend(&buf[..len]);
+        if this.buf.len() >= this.high {
+            this.throttled = true;
+        }
+
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        this.poll_drain(cx, 0)?;
+        if !this.buf.is_empty() {
+            return Poll::Pending;
+        }
+
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        task::{Wake, Waker},
+    };
+
+    use futures_lite::{future, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::mock::MockSocket;
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    #[test]
+    fn watermarks() {
+        let (a, mut b) = MockSocket::pair_with_capacity(4);
+        let mut writer = WatermarkWriter::new(a).watermarks(2, 8);
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let mut cx = Context::from_waker(&waker);
+
+        // Only up to the high watermark is taken
+        assert!(matches!(
+            Pin::new(&mut writer).poll_write(&mut cx, b"0123456789"),
+            Poll::Ready(Ok(8))
+        ));
+        assert!(!writer.is_ready());
+        assert_eq!(writer.buffered(), 8);
+
+        // The socket takes 4 bytes, which leaves the buffer between the watermarks
+        assert!(writer.poll_ready(&mut cx).is_pending());
+        assert!(Pin::new(&mut writer)
+            .poll_write(&mut cx, b"89")
+            .is_pending());
+        assert_eq!(writer.buffered(), 4);
+        assert_eq!(b.pending(), 4);
+
+        // Room for 3 more leaves 1 byte, below the low watermark
+        let mut buf = [0; 3];
+        future::block_on(b.read_exact(&mut buf)).unwrap();
+        assert_eq!(&buf, b"012");
+        assert!(writer.poll_ready(&mut cx).is_ready());
+        assert!(writer.is_ready());
+        assert_eq!(writer.buffered(), 1);
+
+        assert!(matches!(
+            Pin::new(&mut writer).poll_write(&mut cx, b"89"),
+            Poll::Ready(Ok(2))
+        ));
+        assert_eq!(writer.buffered(), 3);
+
+        let mut received = Vec::new();
+        let (closed, read) =
+            future::block_on(future::zip(writer.close(), b.read_to_end(&mut received)));
+        closed.unwrap();
+        read.unwrap();
+        assert_eq!(received, b"3456789");
+    }
+
+    #[test]
+    fn ready_without_backlog() {
+        let (a, mut b) = MockSocket::pair();
+        let mut writer = WatermarkWriter::new(a).watermarks(2, 8);
+
+        future::block_on(async {
+            writer.ready().await.unwrap();
+            writer.write_all(b"hello").await.unwrap();
+            // Below the high watermark, the data goes out on the next use
+            assert!(writer.is_ready());
+            writer.flush().await.unwrap();
+        });
+        assert_eq!(writer.buffered(), 0);
+
+        let mut buf = [0; 5];
+        future::block_on(b.read_exact(&mut buf)).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
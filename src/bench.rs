@@ -0,0 +1,330 @@
+//! Measuring connection setup and throughput, e.g. to compare mbedtls configurations or chips.
+//!
+//! [`run`] prints one line per round in the form
+//!
+//! ```text
+//! BENCH host=example.com port=443 connect_ms=41 handshake_ms=1183 ...
+//! ```
+//!
+//! so that the results can be picked out of the monitor output with e.g. `grep '^BENCH '`.
+//!
+//! [`throughput`] measures the data rate alone, over plain TCP or TLS, e.g. against an iperf 2
+//! server to tell radio regressions from TLS ones:
+//!
+//! ```text
+//! IPERF host=192.168.1.10 port=5001 transport=tcp direction=push bytes=1048576 ms=912 mbps=9.20
+//! ```
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use esp_idf_svc::tls::Config;
+use futures_lite::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    connector::TlsConnector,
+    error::Result,
+    mem::{self, MemSnapshot},
+    tcp::{self, AsyncTcp},
+};
+
+/// The endpoint to benchmark against and the amount of data to move.
+#[derive(Clone, Debug)]
+pub struct BenchConfig<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    /// Sent right after the handshake, e.g. an HTTP request for a large file.
+    pub request: Option<&'a [u8]>,
+    /// Bytes to send after the request, e.g. to a discard or echo server.
+    pub upload_len: usize,
+    /// Bytes to receive, reading stops earlier if the server closes the connection.
+    pub download_len: usize,
+    /// Size of the buffer used for reading and writing.
+    pub chunk_len: usize,
+    pub rounds: usize,
+}
+
+impl Default for BenchConfig<'_> {
+    fn default() -> Self {
+        Self {
+            host: "example.com",
+            port: 443,
+            request: None,
+            upload_len: 0,
+            download_len: 64 * 1024,
+            chunk_len: 1024,
+            rounds: 1,
+        }
+    }
+}
+
+/// The results of a single benchmark round.
+#[derive(Clone, Debug)]
+pub struct BenchReport {
+    pub host: String,
+    pub port: u16,
+    pub round: usize,
+    pub connect_ms: u128,
+    pub handshake_ms: u128,
+    pub upload_bytes: u64,
+    pub upload_ms: u128,
+    pub download_bytes: u64,
+    pub download_ms: u128,
+    /// Free heap after the handshake
+    pub free_heap: usize,
+    /// Lowest free heap since boot, after the round
+    pub min_free_heap: usize,
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "BENCH host={} port={} round={} connect_ms={} handshake_ms={} upload_bytes={} \
+             upload_ms={} upload_kbps={:.1} download_bytes={} download_ms={} download_kbps={:.1} \
+             free_heap={} min_free_heap={}",
+            self.host,
+            self.port,
+            self.round,
+            self.connect_ms,
+            self.handshake_ms,
+            self.upload_bytes,
+            self.upload_ms,
+            kbps(self.upload_bytes, self.upload_ms),
+            self.download_bytes,
+            self.download_ms,
+            kbps(self.download_bytes, self.download_ms),
+            self.free_heap,
+            self.min_free_heap,
+        )
+    }
+}
+
+/// Run all rounds of `bench`, printing the report of each.
+pub async fn run(
+    connector: &TlsConnector,
+    bench: &BenchConfig<'_>,
+    cfg: &Config<'_>,
+) -> Result<Vec<BenchReport>> {
+    let mut reports = Vec::with_capacity(bench.rounds);
+
+    for round in 0..bench.rounds {
+        let report = run_round(connector, bench, cfg, round).await?;
+        println!("{report}");
+
+        reports.push(report);
+    }
+
+    Ok(reports)
+}
+
+async fn run_round(
+    connector: &TlsConnector,
+    bench: &BenchConfig<'_>,
+    cfg: &Config<'_>,
+    round: usize,
+) -> Result<BenchReport> {
+    let start = Instant::now();
+    let tcp = tcp::connect(bench.host, bench.port, Default::default()).await?;
+    let connected = Instant::now();
+
+    let mut tls = connector.adopt(AsyncTcp::new(tcp), bench.host, cfg).await?;
+    let negotiated = Instant::now();
+    let free_heap = MemSnapshot::take().free_heap;
+
+    if let Some(request) = bench.request {
+        tls.write_all(request).await?;
+    }
+
+    let mut buf = mem::alloc_buffer(bench.chunk_len);
+
+    let mut upload_bytes = 0;
+    while upload_bytes < bench.upload_len {
+        let len = buf.len().min(bench.upload_len - upload_bytes);
+        tls.write_all(&buf[..len]).await?;
+        upload_bytes += len;
+    }
+    tls.flush().await?;
+    let uploaded = Instant::now();
+
+    let mut download_bytes = 0;
+    while download_bytes < bench.download_len {
+        let len = buf.len().min(bench.download_len - download_bytes);
+        match tls.read(&mut buf[..len]).await? {
+            0 => break,
+            read => download_bytes += read,
+        }
+    }
+    let downloaded = Instant::now();
+
+    Ok(BenchReport {
+        host: bench.host.to_owned(),
+        port: bench.port,
+        round,
+        connect_ms: (connected - start).as_millis(),
+        handshake_ms: (negotiated - connected).as_millis(),
+        upload_bytes: upload_bytes as u64,
+        upload_ms: (uploaded - negotiated).as_millis(),
+        download_bytes: download_bytes as u64,
+        download_ms: (downloaded - uploaded).as_millis(),
+        free_heap,
+        min_free_heap: MemSnapshot::take().min_free_heap,
+    })
+}
+
+/// Which way the data of a [`throughput`] test moves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Send only, to an iperf 2 server (`iperf -s`) or a discard server
+    Push,
+    /// Receive only, until the server closes the connection or enough was read, e.g. from
+    /// `nc -l 5001 < /dev/zero`
+    Pull,
+    /// Send each chunk and read it back from an echo server
+    Echo,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Push => "push",
+            Self::Pull => "pull",
+            Self::Echo => "echo",
+        })
+    }
+}
+
+/// A throughput test, see [`throughput`].
+#[derive(Clone, Debug)]
+pub struct ThroughputConfig<'a> {
+    pub host: &'a str,
+    /// 5001 by default, the port of iperf 2
+    pub port: u16,
+    /// Encrypt with the TLS configuration passed to [`throughput`], or send plain TCP.
+    pub tls: bool,
+    pub direction: Direction,
+    /// Bytes to move, an echoed byte counts once
+    pub len: usize,
+    /// Size of the buffer used for reading and writing.
+    pub chunk_len: usize,
+}
+
+impl Default for ThroughputConfig<'_> {
+    fn default() -> Self {
+        Self {
+            host: "example.com",
+            port: 5001,
+            tls: false,
+            direction: Direction::Push,
+            len: 1024 * 1024,
+            chunk_len: 4096,
+        }
+    }
+}
+
+/// The result of a [`throughput`] test.
+#[derive(Clone, Debug)]
+pub struct ThroughputReport {
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+    pub direction: Direction,
+    pub bytes: u64,
+    /// From the end of the handshake, or the TCP connect, to the last byte
+    pub duration: Duration,
+}
+
+impl ThroughputReport {
+    /// Megabits per second.
+    pub fn mbps(&self) -> f64 {
+        kbps(self.bytes, self.duration.as_millis()) / 1000.0
+    }
+}
+
+impl fmt::Display for ThroughputReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "IPERF host={} port={} transport={} direction={} bytes={} ms={} mbps={:.2}",
+            self.host,
+            self.port,
+            if self.tls { "tls" } else { "tcp" },
+            self.direction,
+            self.bytes,
+            self.duration.as_millis(),
+            self.mbps(),
+        )
+    }
+}
+
+/// Connect as `test` says, move its data and print the report. `cfg` applies if `test.tls` is
+/// set.
+pub async fn throughput(
+    connector: &TlsConnector,
+    test: &ThroughputConfig<'_>,
+    cfg: &Config<'_>,
+) -> Result<ThroughputReport> {
+    let cfg = test.tls.then_some(cfg);
+    let mut conn = connector
+        .connect_maybe_tls(test.host, test.port, cfg)
+        .await?;
+
+    let start = Instant::now();
+    let bytes = transfer(&mut conn, test).await?;
+    let duration = start.elapsed();
+    conn.close().await?;
+
+    let report = ThroughputReport {
+        host: test.host.to_owned(),
+        port: test.port,
+        tls: test.tls,
+        direction: test.direction,
+        bytes,
+        duration,
+    };
+    println!("{report}");
+
+    Ok(report)
+}
+
+async fn transfer<S>(stream: &mut S, test: &ThroughputConfig<'_>) -> Result<u64>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = mem::alloc_buffer(test.chunk_len.max(1));
+    let mut moved = 0;
+
+    while moved < test.len {
+        let len = buf.len().min(test.len - moved);
+
+        moved += match test.direction {
+            Direction::Push => {
+                stream.write_all(&buf[..len]).await?;
+                len
+            }
+            Direction::Pull => match stream.read(&mut buf[..len]).await? {
+                0 => break,
+                read => read,
+            },
+            Direction::Echo => {
+                stream.write_all(&buf[..len]).await?;
+                stream.flush().await?;
+                stream.read_exact(&mut buf[..len]).await?;
+                len
+            }
+        };
+    }
+    stream.flush().await?;
+
+    Ok(moved as u64)
+}
+
+fn kbps(bytes: u64, ms: u128) -> f64 {
+    if ms == 0 {
+        return 0.0;
+    }
+
+    bytes as f64 * 8.0 / ms as f64
+}
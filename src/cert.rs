@@ -0,0 +1,271 @@
+use core::{ffi::c_char, fmt, slice};
+use std::{
+    net::IpAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use esp_idf_svc::tls::Config;
+use esp_idf_sys as sys;
+
+use crate::util::days_from_civil;
+
+/// Before this the system time can't have been set, e.g. by SNTP.
+const PLAUSIBLE_YEAR: u16 = 2023;
+
+/// A point in time as encoded in an X.509 certificate (always UTC).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct X509Time {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl X509Time {
+    fn from_raw(time: &sys::mbedtls_x509_time) -> Self {
+        Self {
+            year: time.year as u16,
+            month: time.mon as u8,
+            day: time.day as u8,
+            hour: time.hour as u8,
+            minute: time.min as u8,
+            second: time.sec as u8,
+        }
+    }
+}
+
+impl X509Time {
+    /// The time as seconds since the Unix epoch.
+    pub fn to_system_time(&self) -> SystemTime {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        let secs =
+            days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+
+        UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64)
+    }
+}
+
+/// Whether `now` is late enough that the system time was set, e.g. by SNTP.
+pub(crate) fn clock_is_set(now: SystemTime) -> bool {
+    let earliest = X509Time {
+        year: PLAUSIBLE_YEAR,
+        month: 1,
+        day: 1,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+
+    now >= earliest.to_system_time()
+}
+
+impl fmt::Display for X509Time {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// A certificate presented by the peer during the TLS handshake.
+#[derive(Clone, Debug)]
+pub struct Certificate {
+    /// The DER encoding of the certificate, e.g. for pinning.
+    pub der: Vec<u8>,
+    /// The subject distinguished name, e.g. `C=US, O=DigiCert Inc, CN=...`.
+    pub subject: String,
+    /// The issuer distinguished name.
+    pub issuer: String,
+    pub not_before: X509Time,
+    pub not_after: X509Time,
+    /// The iPAddress entries of the subjectAltName extension, for servers addressed by IP.
+    pub ip_addresses: Vec<IpAddr>,
+}
+
+impl Certificate {
+    /// # Safety
+    ///
+    /// `crt` must point to a successfully parsed mbedtls certificate.
+    pub(crate) unsafe fn from_raw(crt: &sys::mbedtls_x509_crt) -> Self {
+        Self {
+            der: slice::from_raw_parts(crt.raw.p, crt.raw.len).to_vec(),
+            subject: dn_to_string(&crt.subject),
+            issuer: dn_to_string(&crt.issuer),
+            not_before: X509Time::from_raw(&crt.valid_from),
+            not_after: X509Time::from_raw(&crt.valid_to),
+            ip_addresses: ip_addresses(&crt.subject_alt_names),
+        }
+    }
+
+    /// Parse a DER encoded certificate, e.g. one received by a TLS stack other than mbedtls.
+    pub fn from_der(der: &[u8]) -> Option<Self> {
+        unsafe {
+            let mut crt = core::mem::zeroed();
+            sys::mbedtls_x509_crt_init(&mut crt);
+
+            let cert = (sys::mbedtls_x509_crt_parse_der(&mut crt, der.as_ptr(), der.len()) == 0)
+                .then(|| Self::from_raw(&crt));
+            sys::mbedtls_x509_crt_free(&mut crt);
+
+            cert
+        }
+    }
+
+    /// Parse all certificates of PEM (nul terminated) or DER `data`, e.g. a CA bundle.
+    pub fn parse_all(data: &[u8]) -> Option<Vec<Self>> {
+        unsafe {
+            let mut crt = core::mem::zeroed();
+            sys::mbedtls_x509_crt_init(&mut crt);
+
+            // Positive for PEM data of which some certificates could not be parsed
+            let certs = (sys::mbedtls_x509_crt_parse(&mut crt, data.as_ptr(), data.len()) == 0)
+                .then(|| Self::chain_from_raw(&crt));
+            sys::mbedtls_x509_crt_free(&mut crt);
+
+            certs
+        }
+    }
+
+    /// The time left until the certificate expires at `now`, zero once it expired.
+    pub fn expires_in(&self, now: SystemTime) -> Duration {
+        self.not_after
+            .to_system_time()
+            .duration_since(now)
+            .unwrap_or_default()
+    }
+
+    /// Collects all certificates of the chain starting at `crt`, leaf first.
+    ///
+    /// # Safety
+    ///
+    /// `crt` must either be null or point to a chain of successfully parsed certificates.
+    pub(crate) unsafe fn chain_from_raw(mut crt: *const sys::mbedtls_x509_crt) -> Vec<Self> {
+        let mut chain = Vec::new();
+
+        while let Some(c) = crt.as_ref() {
+            // An empty chain head has no raw data attached
+            if c.raw.p.is_null() {
+                break;
+            }
+
+            chain.push(Self::from_raw(c));
+            crt = c.next;
+        }
+
+        chain
+    }
+}
+
+/// What a certificate of the [`Config`] is used for, see [`check_expiry`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CertUsage {
+    /// Verifies the server
+    Ca,
+    /// Authenticates the device
+    Client,
+}
+
+/// A certificate of the [`Config`] that expires soon or already did, see [`check_expiry`].
+#[derive(Clone, Debug)]
+pub struct ExpiryWarning {
+    pub usage: CertUsage,
+    pub certificate: Certificate,
+    /// Zero once expired
+    pub remaining: Duration,
+}
+
+/// Check the CA and client certificates of `cfg` for expiry within `window`, before connecting
+/// with them. Logs a warning for each one found and returns them, e.g. to report them to the
+/// backend while the device can still be updated.
+///
+/// A device with an expired CA or client certificate can't connect anymore, so this is worth
+/// running at every start. Nothing is checked while the system time is not set, e.g. before
+/// SNTP synchronized it.
+pub fn check_expiry(cfg: &Config<'_>, window: Duration) -> Vec<ExpiryWarning> {
+    let now = SystemTime::now();
+    if !clock_is_set(now) {
+        log::debug!("the system time is not set, skipping the certificate expiry check");
+        return Vec::new();
+    }
+
+    let configured = [
+        (CertUsage::Ca, cfg.ca_cert),
+        (CertUsage::Client, cfg.client_cert),
+    ];
+    let mut warnings = Vec::new();
+    for (usage, x509) in configured {
+        let Some(x509) = x509 else { continue };
+        let Some(certs) = Certificate::parse_all(x509.data()) else {
+            log::warn!("failed to parse the {usage:?} certificate for the expiry check");
+            continue;
+        };
+
+        for certificate in certs {
+            let remaining = certificate.expires_in(now);
+            if remaining > window {
+                continue;
+            }
+
+            if remaining.is_zero() {
+                log::warn!(
+                    "{usage:?} certificate {} expired at {}",
+                    certificate.subject,
+                    certificate.not_after
+                );
+            } else {
+                log::warn!(
+                    "{usage:?} certificate {} expires at {}, in {} days",
+                    certificate.subject,
+                    certificate.not_after,
+                    remaining.as_secs() / 86400
+                );
+            }
+            warnings.push(ExpiryWarning {
+                usage,
+                certificate,
+                remaining,
+            });
+        }
+    }
+
+    warnings
+}
+
+/// The iPAddress entries among the `san` general names.
+unsafe fn ip_addresses(mut san: *const sys::mbedtls_x509_sequence) -> Vec<IpAddr> {
+    const IP_ADDRESS_TAG: i32 =
+        (sys::MBEDTLS_ASN1_CONTEXT_SPECIFIC | sys::MBEDTLS_X509_SAN_IP_ADDRESS) as i32;
+
+    let mut addrs = Vec::new();
+    while let Some(entry) = san.as_ref() {
+        if entry.buf.tag == IP_ADDRESS_TAG && !entry.buf.p.is_null() {
+            match slice::from_raw_parts(entry.buf.p, entry.buf.len) {
+                &[a, b, c, d] => addrs.push(IpAddr::from([a, b, c, d])),
+                octets => {
+                    if let Ok(octets) = <[u8; 16]>::try_from(octets) {
+                        addrs.push(IpAddr::from(octets));
+                    }
+                }
+            }
+        }
+
+        san = entry.next;
+    }
+
+    addrs
+}
+
+unsafe fn dn_to_string(dn: &sys::mbedtls_x509_name) -> String {
+    let mut buf = [0u8; 256];
+
+    let len = sys::mbedtls_x509_dn_gets(buf.as_mut_ptr() as *mut c_char, buf.len(), dn);
+    if len < 0 {
+        return String::new();
+    }
+
+    String::from_utf8_lossy(&buf[..len as usize]).into_owned()
+}
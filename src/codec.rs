@@ -0,0 +1,466 @@
+//! Delimited reads for text protocols such as HTTP headers, SMTP or Redis, over any
+//! [`AsyncBufRead`], e.g. an [`AsyncTls`](crate::AsyncTls) or a `BufReader`, and length-prefixed
+//! frames for binary ones, see [`FramedRead`] and [`FramedWrite`].
+//!
+//! ```ignore
+//! let mut lines = Lines::new(tls).max_line_len(512);
+//! while let Some(line) = lines.next().await {
+//!     handle_reply(&line?)?;
+//! }
+//! ```
+//!
+//! Lines are limited in length, so that a peer can't make the device buffer an endless line.
+//! A longer one fails with `InvalidData` and leaves the stream at an unknown position in the
+//! line, i.e. unusable for the protocol.
+
+use core::{
+    future::poll_fn,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use std::io;
+
+use futures_lite::{AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt, Stream};
+
+/// Longest line read by [`Lines`] unless configured otherwise.
+pub const DEFAULT_MAX_LINE_LEN: usize = 8 * 1024;
+
+/// Largest frame of [`FramedRead`] and [`FramedWrite`] unless configured otherwise.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 16 * 1024;
+
+/// Append the bytes up to and including `delim` to `buf`, or up to the end of the stream.
+///
+/// Returns the number of bytes appended, 0 at the end of the stream. Fails with `InvalidData`
+/// once more than `max_len` bytes come without `delim`. Cancellation safe, a cancelled read
+/// leaves the bytes read so far in `buf`.
+pub async fn read_until<R>(
+    reader: &mut R,
+    delim: u8,
+    buf: &mut Vec<u8>,
+    max_len: usize,
+) -> io::Result<usize>
+where
+    R: AsyncBufRead + Unpin + ?Sized,
+{
+    let start = buf.len();
+    poll_fn(|cx| poll_read_until(Pin::new(&mut *reader), cx, delim, buf, start, max_len)).await?;
+
+    Ok(buf.len() - start)
+}
+
+/// Append the next line including the `\n` to `buf`, see [`read_until`].
+///
+/// Fails with `InvalidData` if the line is not UTF-8. Not cancellation safe, the part of the
+/// line read so far is lost.
+pub async fn read_line<R>(reader: &mut R, buf: &mut String, max_len: usize) -> io::Result<usize>
+where
+    R: AsyncBufRead + Unpin + ?Sized,
+{
+    let mut line = Vec::new();
+    let len = read_until(reader, b'\n', &mut line, max_len).await?;
+
+    let line =
+        String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    buf.push_str(&line);
+
+    Ok(len)
+}
+
+fn poll_read_until<R>(
+    mut reader: Pin<&mut R>,
+    cx: &mut Context<'_>,
+    delim: u8,
+    buf: &mut Vec<u8>,
+    start: usize,
+    max_len: usize,
+) -> Poll<io::Result<()>>
+where
+    R: AsyncBufRead + ?Sized,
+{
+    loop {
+        let available = ready!(reader.as_mut().poll_fill_buf(cx))?;
+        if available.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+
+        let (found, used) = match available.iter().position(|&b| b == delim) {
+            Some(i) => (true, i + 1),
+            None => (false, available.len()),
+        };
+        if buf.len() - start + used > max_len {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line longer than {max_len} bytes"),
+            )));
+        }
+
+        buf.extend_from_slice(&available[..used]);
+        reader.as_mut().consume(used);
+
+        if found {
+            return Poll::Ready(Ok(()));
+        }
+    }
+}
+
+/// The lines of a stream without the `\n` or `\r\n`, see the [module docs](self).
+///
+/// A last line without a line ending is yielded as well. Lines that are not UTF-8 fail with
+/// `InvalidData`.
+pub struct Lines<R> {
+    reader: R,
+    buf: Vec<u8>,
+    max_len: usize,
+}
+
+impl<R: AsyncBufRead + Unpin> Lines<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: Vec::new(),
+            max_len: DEFAULT_MAX_LINE_LEN,
+        }
+    }
+
+    /// Fail lines longer than `max_len` bytes, including the line ending.
+    pub fn max_line_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// The reader, a partially read line is lost.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> Stream for Lines<R> {
+    type Item = io::Result<String>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        ready!(poll_read_until(
+            Pin::new(&mut this.reader),
+            cx,
+            b'\n',
+            &mut this.buf,
+            0,
+            this.max_len,
+        ))?;
+        if this.buf.is_empty() {
+            return Poll::Ready(None);
+        }
+
+        let mut line = core::mem::take(&mut this.buf);
+        if line.ends_with(b"\n") {
+            line.pop();
+            if line.ends_with(b"\r") {
+                line.pop();
+            }
+        }
+
+        Poll::Ready(Some(
+            String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        ))
+    }
+}
+
+/// The big-endian length that precedes each frame of [`FramedRead`] and [`FramedWrite`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LengthPrefix {
+    U16,
+    U32,
+}
+
+impl LengthPrefix {
+    fn len(self) -> usize {
+        match self {
+            Self::U16 => 2,
+            Self::U32 => 4,
+        }
+    }
+
+    fn max(self) -> usize {
+        match self {
+            Self::U16 => u16::MAX as usize,
+            Self::U32 => u32::MAX as usize,
+        }
+    }
+}
+
+/// The frames of a stream of length-prefixed frames, e.g. of a device-to-cloud protocol.
+///
+/// A frame larger than the [maximum](Self::max_frame_len) fails with `InvalidData` without
+/// being read, which leaves the stream unusable. The end of the stream within a frame fails
+/// with `UnexpectedEof`. Reading is cancellation safe.
+pub struct FramedRead<R> {
+    reader: R,
+    prefix: LengthPrefix,
+    max_len: usize,
+    header: [u8; 4],
+    header_read: usize,
+    /// The frame being read, once its header is complete
+    frame: Option<Vec<u8>>,
+    frame_read: usize,
+}
+
+impl<R: AsyncRead + Unpin> FramedRead<R> {
+    pub fn new(reader: R, prefix: LengthPrefix) -> Self {
+        Self {
+            reader,
+            prefix,
+            max_len: DEFAULT_MAX_FRAME_LEN,
+            header: [0; 4],
+            header_read: 0,
+            frame: None,
+            frame_read: 0,
+        }
+    }
+
+    /// Fail frames longer than `max_len` bytes, not counting the length prefix.
+    pub fn max_frame_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// The reader, a partially read frame is lost.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    fn poll_header(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Option<usize>>> {
+        let header_len = self.prefix.len();
+        while self.header_read < header_len {
+            let read = ready!(Pin::new(&mut self.reader)
+                .poll_read(cx, &mut self.header[self.header_read..header_len]))?;
+            if read == 0 {
+                return Poll::Ready(match self.header_read {
+                    0 => Ok(None),
+                    _ => Err(io::ErrorKind::UnexpectedEof.into()),
+                });
+            }
+            self.header_read += read;
+        }
+
+        let len = match self.prefix {
+            LengthPrefix::U16 => u16::from_be_bytes([self.header[0], self.header[1]]) as usize,
+            LengthPrefix::U32 => u32::from_be_bytes(self.header) as usize,
+        };
+
+        Poll::Ready(Ok(Some(len)))
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for FramedRead<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.frame.is_none() {
+            let Some(len) = ready!(this.poll_header(cx))? else {
+                return Poll::Ready(None);
+            };
+            if len > this.max_len {
+                return Poll::Ready(Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "frame of {len} bytes exceeds the maximum of {}",
+                        this.max_len
+                    ),
+                ))));
+            }
+
+            this.frame = Some(vec![0; len]);
+            this.frame_read = 0;
+        }
+
+        let frame = this.frame.as_mut().unwrap();
+        while this.frame_read < frame.len() {
+            let read =
+                ready!(Pin::new(&mut this.reader).poll_read(cx, &mut frame[this.frame_read..]))?;
+            if read == 0 {
+                return Poll::Ready(Some(Err(io::ErrorKind::UnexpectedEof.into())));
+            }
+            this.frame_read += read;
+        }
+
+        this.header_read = 0;
+        Poll::Ready(this.frame.take().map(Ok))
+    }
+}
+
+/// Writes length-prefixed frames, the counterpart of [`FramedRead`].
+pub struct FramedWrite<W> {
+    writer: W,
+    prefix: LengthPrefix,
+    max_len: usize,
+}
+
+impl<W: AsyncWrite + Unpin> FramedWrite<W> {
+    pub fn new(writer: W, prefix: LengthPrefix) -> Self {
+        Self {
+            writer,
+            prefix,
+            max_len: DEFAULT_MAX_FRAME_LEN,
+        }
+    }
+
+    /// Refuse frames longer than `max_len` bytes, not counting the length prefix.
+    pub fn max_frame_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// Write `frame` with its length prefix and flush.
+    ///
+    /// Fails with `InvalidInput` for frames over the maximum or the range of the prefix,
+    /// without writing anything. Not cancellation safe, a cancelled send leaves a partial frame.
+    pub async fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        let max_len = self.max_len.min(self.prefix.max());
+        if frame.len() > max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "frame of {} bytes exceeds the maximum of {max_len}",
+                    frame.len()
+                ),
+            ));
+        }
+
+        // Header and payload in one write, i.e. one TLS record for small frames
+        let mut buf = Vec::with_capacity(self.prefix.len() + frame.len());
+        match self.prefix {
+            LengthPrefix::U16 => buf.extend_from_slice(&(frame.len() as u16).to_be_bytes()),
+            LengthPrefix::U32 => buf.extend_from_slice(&(frame.len() as u32).to_be_bytes()),
+        }
+        buf.extend_from_slice(frame);
+
+        self.writer.write_all(&buf).await?;
+        self.writer.flush().await
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::{future, io::BufReader, StreamExt};
+
+    use super::*;
+    use crate::mock::MockSocket;
+
+    #[test]
+    fn frames_roundtrip() {
+        for prefix in [LengthPrefix::U16, LengthPrefix::U32] {
+            // Smaller than most frames, so that reader and writer take turns
+            let (a, b) = MockSocket::pair_with_capacity(7);
+            let frames = [b"".to_vec(), b"hello".to_vec(), vec![0xa5; 1000]];
+
+            let send = async {
+                let mut writer = FramedWrite::new(a, prefix);
+                for frame in &frames {
+                    writer.send(frame).await.unwrap();
+                }
+            };
+            let receive = async {
+                let mut reader = FramedRead::new(b, prefix);
+                let mut received = Vec::new();
+                while let Some(frame) = reader.next().await {
+                    received.push(frame.unwrap());
+                }
+                received
+            };
+
+            let ((), received) = future::block_on(future::zip(send, receive));
+            assert_eq!(received, frames, "{prefix:?}");
+        }
+    }
+
+    #[test]
+    fn frame_too_long() {
+        let (a, b) = MockSocket::pair();
+
+        let mut writer = FramedWrite::new(a, LengthPrefix::U16).max_frame_len(4);
+        let err = future::block_on(writer.send(b"hello")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(b.pending(), 0);
+
+        let mut writer = writer.into_inner();
+        future::block_on(writer.write_all(&[0, 5, b'h', b'e', b'l', b'l', b'o'])).unwrap();
+        let mut reader = FramedRead::new(b, LengthPrefix::U16).max_frame_len(4);
+        let err = future::block_on(reader.next()).unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn eof_within_frame() {
+        for partial in [&[0, 0][..], &[0, 0, 0, 3, b'a'][..]] {
+            let (mut a, b) = MockSocket::pair();
+            future::block_on(a.write_all(partial)).unwrap();
+            drop(a);
+
+            let mut reader = FramedRead::new(b, LengthPrefix::U32);
+            let err = future::block_on(reader.next()).unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "{partial:?}");
+        }
+    }
+
+    #[test]
+    fn lines() {
+        let (mut a, b) = MockSocket::pair_with_capacity(3);
+
+        let send = async {
+            a.write_all(b"first\r\nsecond\n\nlast").await.unwrap();
+            drop(a);
+        };
+        let receive = async {
+            let mut lines = Lines::new(BufReader::new(b));
+            let mut received = Vec::new();
+            while let Some(line) = lines.next().await {
+                received.push(line.unwrap());
+            }
+            received
+        };
+
+        let ((), received) = future::block_on(future::zip(send, receive));
+        assert_eq!(received, ["first", "second", "", "last"]);
+    }
+
+    #[test]
+    fn line_too_long() {
+        let (mut a, b) = MockSocket::pair();
+        future::block_on(a.write_all(b"0123456789\n")).unwrap();
+
+        let mut lines = Lines::new(BufReader::new(b)).max_line_len(10);
+        let err = future::block_on(lines.next()).unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,206 @@
+//! Adjusting the mbedtls configuration that esp-tls sets up, before the handshake starts.
+//!
+//! esp-tls has no hook for this, but it hands its `mbedtls_ssl_config` to the
+//! `crt_bundle_attach` callback right before the session is set up. [`ConfHook`] takes that
+//! callback over and, as esp-tls then skips its own CA setup, performs the server verification
+//! setup esp-tls would have done before applying the tweaks.
+
+use core::{
+    cell::Cell,
+    ffi::{c_char, c_void, CStr},
+    ptr,
+};
+use std::sync::Arc;
+
+use esp_idf_sys::{self as sys, EspError, ESP_ERR_INVALID_STATE, ESP_FAIL, ESP_OK};
+
+pub(crate) type ConfFn = dyn Fn(*mut sys::mbedtls_ssl_config) -> Result<(), EspError> + Send;
+
+thread_local! {
+    /// The hook of the handshake step currently running on this thread, see [`ConfHook::enter`].
+    static ACTIVE: Cell<*mut ConfHook> = Cell::new(ptr::null_mut());
+}
+
+/// How the server is verified, as configured in the original `esp_tls_cfg`.
+enum Verification {
+    None,
+    Bundle(unsafe extern "C" fn(*mut c_void) -> sys::esp_err_t),
+    GlobalCaStore,
+    CaCert(*const u8, usize),
+    Psk(*const sys::psk_key_hint),
+}
+
+#[derive(Default)]
+pub(crate) struct ConfHook {
+    tweaks: Vec<Box<ConfFn>>,
+    verification: Option<Verification>,
+    ca_chain: Option<Box<sys::mbedtls_x509_crt>>,
+    /// Trusted in addition to the verification of the original `esp_tls_cfg`
+    extra_cas: Vec<Arc<[u8]>>,
+    crt_bundle: bool,
+}
+
+impl ConfHook {
+    pub(crate) fn push(&mut self, tweak: Box<ConfFn>) {
+        self.tweaks.push(tweak);
+    }
+
+    /// Also trust the PEM (nul terminated) or DER certificates `cas`, and the ESP x509
+    /// certificate bundle with `crt_bundle`.
+    pub(crate) fn trust(&mut self, cas: &[Arc<[u8]>], crt_bundle: bool) {
+        self.extra_cas.extend_from_slice(cas);
+        self.crt_bundle |= crt_bundle;
+    }
+
+    /// Redirect the `crt_bundle_attach` callback of `raw` to this hook.
+    ///
+    /// The pointers in `raw` need to stay valid until the first handshake step has run.
+    pub(crate) fn take_over(&mut self, raw: &mut sys::esp_tls_cfg) -> Result<(), EspError> {
+        if !cfg!(esp_idf_mbedtls_certificate_bundle) {
+            log::error!(
+                "adjusting the mbedtls configuration requires CONFIG_MBEDTLS_CERTIFICATE_BUNDLE"
+            );
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        // Same order of precedence as in esp-tls
+        let (ca_cert, ca_cert_len) = unsafe {
+            (
+                raw.__bindgen_anon_1.cacert_buf,
+                raw.__bindgen_anon_2.cacert_bytes as usize,
+            )
+        };
+        let verification = if let Some(attach) = raw.crt_bundle_attach {
+            Verification::Bundle(attach)
+        } else if raw.use_global_ca_store {
+            Verification::GlobalCaStore
+        } else if !ca_cert.is_null() {
+            Verification::CaCert(ca_cert, ca_cert_len)
+        } else if !raw.psk_hint_key.is_null() {
+            Verification::Psk(raw.psk_hint_key)
+        } else {
+            Verification::None
+        };
+
+        self.verification = Some(verification);
+        raw.crt_bundle_attach = Some(conf_trampoline);
+
+        Ok(())
+    }
+
+    /// Run `f` (a handshake step) with this hook receiving the configuration callback.
+    pub(crate) fn enter<R>(hook: Option<&mut Self>, f: impl FnOnce() -> R) -> R {
+        let hook = hook.map_or(ptr::null_mut(), |hook| hook as *mut Self);
+
+        let prev = ACTIVE.with(|active| active.replace(hook));
+        let res = f();
+        ACTIVE.with(|active| active.set(prev));
+
+        res
+    }
+
+    unsafe fn apply(&mut self, conf: *mut sys::mbedtls_ssl_config) -> Result<(), EspError> {
+        let extra = !self.extra_cas.is_empty() || self.crt_bundle;
+        #[cfg_attr(not(esp_idf_mbedtls_certificate_bundle), allow(unused_assignments))]
+        let mut bundle_attached = false;
+
+        match self.verification.take().unwrap_or(Verification::None) {
+            Verification::None if extra => (),
+            Verification::None => {
+                log::error!("no server verification option set");
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+            }
+            Verification::Bundle(attach) => {
+                sys::esp!(attach(conf as *mut c_void))?;
+                bundle_attached = true;
+            }
+            Verification::GlobalCaStore if extra => {
+                log::error!(
+                    "additional CA certificates can't be combined with the global CA store"
+                );
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+            }
+            Verification::GlobalCaStore => {
+                let ca_chain = sys::esp_tls_get_global_ca_store();
+                if ca_chain.is_null() {
+                    log::error!("global CA store is not initialized");
+                    return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+                }
+
+                sys::mbedtls_ssl_conf_ca_chain(conf, ca_chain, ptr::null_mut());
+            }
+            Verification::CaCert(buf, len) => self.parse_ca(buf, len)?,
+            Verification::Psk(psk) => {
+                let psk = &*psk;
+                let hint = CStr::from_ptr(psk.hint as *const c_char);
+
+                let ret = sys::mbedtls_ssl_conf_psk(
+                    conf,
+                    psk.key,
+                    psk.key_size,
+                    hint.as_ptr() as *const u8,
+                    hint.to_bytes().len(),
+                );
+                if ret != 0 {
+                    return Err(EspError::from(ret).unwrap());
+                }
+            }
+        }
+
+        // The bundle verifies what the CA chain doesn't, so the chain is set up after it,
+        // replacing the placeholder chain of the bundle
+        #[cfg(esp_idf_mbedtls_certificate_bundle)]
+        if self.crt_bundle && !bundle_attached {
+            sys::esp!(sys::esp_crt_bundle_attach(conf as *mut c_void))?;
+        }
+        for ca in core::mem::take(&mut self.extra_cas) {
+            self.parse_ca(ca.as_ptr(), ca.len())?;
+        }
+        if let Some(ca_chain) = &mut self.ca_chain {
+            sys::mbedtls_ssl_conf_ca_chain(conf, ca_chain.as_mut(), ptr::null_mut());
+        }
+
+        for tweak in &self.tweaks {
+            tweak(conf)?;
+        }
+
+        Ok(())
+    }
+
+    /// Add the PEM (nul terminated) or DER certificates in `buf` to the CA chain.
+    unsafe fn parse_ca(&mut self, buf: *const u8, len: usize) -> Result<(), EspError> {
+        let ca_chain = self.ca_chain.get_or_insert_with(|| {
+            let mut ca_chain: Box<sys::mbedtls_x509_crt> = Box::new(core::mem::zeroed());
+            sys::mbedtls_x509_crt_init(ca_chain.as_mut());
+            ca_chain
+        });
+
+        let ret = sys::mbedtls_x509_crt_parse(ca_chain.as_mut(), buf, len);
+        if ret < 0 {
+            log::error!("failed to parse the CA certificate: -0x{:04x}", -ret);
+            return Err(EspError::from_infallible::<ESP_FAIL>());
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for ConfHook {
+    fn drop(&mut self) {
+        if let Some(ca_chain) = &mut self.ca_chain {
+            unsafe { sys::mbedtls_x509_crt_free(ca_chain.as_mut()) };
+        }
+    }
+}
+
+unsafe extern "C" fn conf_trampoline(conf: *mut c_void) -> sys::esp_err_t {
+    let hook = ACTIVE.with(|active| active.get());
+
+    match hook.as_mut() {
+        Some(hook) => match hook.apply(conf as *mut sys::mbedtls_ssl_config) {
+            Ok(()) => ESP_OK,
+            Err(e) => e.code(),
+        },
+        None => ESP_FAIL,
+    }
+}
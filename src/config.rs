@@ -0,0 +1,257 @@
+use core::ffi::CStr;
+use std::time::Duration;
+#[cfg(feature = "json")]
+use std::{ffi::CString, path::Path};
+
+use esp_idf_sys as sys;
+
+#[cfg(feature = "json")]
+use crate::error::{Error, Result};
+use crate::{connector::TlsConnector, tls::ProtocolVersion};
+
+/// Application settings, set through `idf.py menuconfig` or `sdkconfig.defaults` (see the
+/// `repro-async-tls` menu in `components/app_config/Kconfig`), or read from a JSON file with
+/// [`from_fs`](Self::from_fs).
+#[derive(Clone, Debug)]
+pub struct AppConfig {
+    pub wifi_ssid: &'static str,
+    pub wifi_pass: &'static str,
+    pub target_host: &'static str,
+    pub target_port: u16,
+    pub read_buffer_size: usize,
+    /// How often to repeat the request, `None` to send it once.
+    pub request_interval: Option<Duration>,
+    pub tls: TlsOptions,
+}
+
+/// The TLS settings of an [`AppConfig`], applied to a connector with [`apply`](Self::apply).
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    /// Additional CA certificates, PEM and nul terminated, see [`TlsConnector::add_ca_cert`].
+    pub ca_certs: Vec<Vec<u8>>,
+    pub crt_bundle: bool,
+    pub min_protocol_version: Option<ProtocolVersion>,
+    /// Empty for the mbedtls defaults.
+    pub cipher_suites: Vec<String>,
+}
+
+impl TlsOptions {
+    pub fn apply(&self, mut connector: TlsConnector) -> TlsConnector {
+        for cert in &self.ca_certs {
+            connector = connector.add_ca_cert(cert);
+        }
+        if let Some(version) = self.min_protocol_version {
+            connector = connector.min_protocol_version(version);
+        }
+        if !self.cipher_suites.is_empty() {
+            let suites: Vec<_> = self.cipher_suites.iter().map(String::as_str).collect();
+            connector = connector.cipher_suites(&suites);
+        }
+
+        connector.crt_bundle(self.crt_bundle)
+    }
+}
+
+impl AppConfig {
+    /// The settings of the Kconfig, with the WiFi credentials taken from the `WIFI_SSID` and
+    /// `WIFI_PASS` environment variables of the build where set, so that the same sources can be
+    /// built for different sites.
+    pub fn from_sdkconfig() -> Self {
+        Self {
+            wifi_ssid: option_env!("WIFI_SSID")
+                .unwrap_or_else(|| kconfig_str(sys::CONFIG_APP_WIFI_SSID)),
+            wifi_pass: option_env!("WIFI_PASS")
+                .unwrap_or_else(|| kconfig_str(sys::CONFIG_APP_WIFI_PASS)),
+            target_host: kconfig_str(sys::CONFIG_APP_TARGET_HOST),
+            // Kconfig enforces the ranges
+            target_port: sys::CONFIG_APP_TARGET_PORT as u16,
+            read_buffer_size: sys::CONFIG_APP_READ_BUFFER_SIZE as usize,
+            request_interval: None,
+            tls: TlsOptions::default(),
+        }
+    }
+
+    /// Read the settings from the JSON file at `path`, e.g. `/spiffs/config.json` on a mounted
+    /// SPIFFS or LittleFS partition, so that they can be shipped separately from the firmware:
+    ///
+    /// ```json
+    /// {
+    ///   "wifi": { "ssid": "fleet", "pass": "secret" },
+    ///   "target": { "host": "example.com", "port": 443 },
+    ///   "read_buffer_size": 1024,
+    ///   "intervals": { "request_secs": 60 },
+    ///   "tls": {
+    ///     "ca_certs": ["/spiffs/ca.pem"],
+    ///     "crt_bundle": false,
+    ///     "min_version": "1.3",
+    ///     "cipher_suites": ["TLS1-3-AES-128-GCM-SHA256"]
+    ///   }
+    /// }
+    /// ```
+    ///
+    /// Only `wifi.ssid` and `target.host` are required. Unknown keys and invalid values fail
+    /// with [`Error::Config`] naming the key. CA certificate paths are read right away.
+    ///
+    /// The strings are leaked to fit the `&'static str` fields, so load the configuration once.
+    #[cfg(feature = "json")]
+    pub fn from_fs(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)?;
+
+        let de = &mut serde_json::Deserializer::from_slice(&data);
+        let file: file::ConfigFile = serde_path_to_error::deserialize(de).map_err(|e| {
+            let key = e.path().to_string();
+            Error::Config(format!("{key}: {}", e.into_inner()))
+        })?;
+
+        let config = file.validate()?;
+        log::info!("loaded the configuration from {}", path.display());
+
+        Ok(config)
+    }
+}
+
+/// The layout of the file read by [`AppConfig::from_fs`].
+#[cfg(feature = "json")]
+mod file {
+    use serde::Deserialize;
+
+    use super::*;
+
+    /// Longest SSID 802.11 allows.
+    const MAX_SSID_LEN: usize = 32;
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub(super) struct ConfigFile {
+        wifi: Wifi,
+        target: Target,
+        #[serde(default = "default_read_buffer_size")]
+        read_buffer_size: usize,
+        #[serde(default)]
+        intervals: Intervals,
+        #[serde(default)]
+        tls: Tls,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Wifi {
+        ssid: String,
+        #[serde(default)]
+        pass: String,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Target {
+        host: String,
+        #[serde(default = "default_port")]
+        port: u16,
+    }
+
+    #[derive(Deserialize, Default)]
+    #[serde(deny_unknown_fields)]
+    struct Intervals {
+        request_secs: Option<u64>,
+    }
+
+    #[derive(Deserialize, Default)]
+    #[serde(deny_unknown_fields)]
+    struct Tls {
+        #[serde(default)]
+        ca_certs: Vec<String>,
+        #[serde(default)]
+        crt_bundle: bool,
+        min_version: Option<String>,
+        #[serde(default)]
+        cipher_suites: Vec<String>,
+    }
+
+    fn default_read_buffer_size() -> usize {
+        1024
+    }
+
+    fn default_port() -> u16 {
+        443
+    }
+
+    fn invalid(key: &str, message: impl std::fmt::Display) -> Error {
+        Error::Config(format!("{key}: {message}"))
+    }
+
+    impl ConfigFile {
+        /// Check what the types don't, with the same ranges as the Kconfig options.
+        pub(super) fn validate(self) -> Result<AppConfig> {
+            if self.wifi.ssid.is_empty() || self.wifi.ssid.len() > MAX_SSID_LEN {
+                return Err(invalid("wifi.ssid", "expected 1 to 32 bytes"));
+            }
+            if self.target.host.is_empty() {
+                return Err(invalid("target.host", "must not be empty"));
+            }
+            if self.target.port == 0 {
+                return Err(invalid("target.port", "expected 1 to 65535"));
+            }
+            if !(64..=65536).contains(&self.read_buffer_size) {
+                return Err(invalid("read_buffer_size", "expected 64 to 65536"));
+            }
+            if self.intervals.request_secs == Some(0) {
+                return Err(invalid("intervals.request_secs", "must not be 0"));
+            }
+
+            let min_protocol_version = match self.tls.min_version.as_deref() {
+                None => None,
+                Some("1.2") => Some(ProtocolVersion::Tls1_2),
+                Some("1.3") => Some(ProtocolVersion::Tls1_3),
+                Some(other) => {
+                    return Err(invalid(
+                        "tls.min_version",
+                        format!("expected \"1.2\" or \"1.3\", found \"{other}\""),
+                    ))
+                }
+            };
+
+            let ca_certs = self
+                .tls
+                .ca_certs
+                .iter()
+                .enumerate()
+                .map(|(i, path)| {
+                    let pem = std::fs::read(path)
+                        .map_err(|e| invalid(&format!("tls.ca_certs[{i}]"), e))?;
+                    // `add_ca_cert` takes PEM nul terminated
+                    CString::new(pem)
+                        .map(|pem| pem.into_bytes_with_nul())
+                        .map_err(|_| invalid(&format!("tls.ca_certs[{i}]"), "not a PEM file"))
+                })
+                .collect::<Result<_>>()?;
+
+            Ok(AppConfig {
+                wifi_ssid: leak(self.wifi.ssid),
+                wifi_pass: leak(self.wifi.pass),
+                target_host: leak(self.target.host),
+                target_port: self.target.port,
+                read_buffer_size: self.read_buffer_size,
+                request_interval: self.intervals.request_secs.map(Duration::from_secs),
+                tls: TlsOptions {
+                    ca_certs,
+                    crt_bundle: self.tls.crt_bundle,
+                    min_protocol_version,
+                    cipher_suites: self.tls.cipher_suites,
+                },
+            })
+        }
+    }
+
+    fn leak(s: String) -> &'static str {
+        Box::leak(s.into_boxed_str())
+    }
+}
+
+/// String options end up as nul terminated byte strings in the bindings.
+fn kconfig_str(value: &'static [u8]) -> &'static str {
+    CStr::from_bytes_with_nul(value)
+        .ok()
+        .and_then(|s| s.to_str().ok())
+        .expect("Kconfig strings are nul terminated UTF-8")
+}
@@ -0,0 +1,1269 @@
+use core::{
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use std::{
+    ffi::CString,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    panic,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use esp_idf_svc::tls::{Config, KeepAliveConfig, PollableSocket, PskHint, X509};
+use esp_idf_sys as sys;
+use futures_lite::{AsyncRead, AsyncWrite};
+
+#[cfg(esp_idf_esp_tls_use_ds_peripheral)]
+use crate::ds::DsKey;
+#[cfg(feature = "debug-keylog")]
+use crate::keylog::KeyLogFn;
+#[cfg(feature = "backend-rustls")]
+use crate::rustls_backend::RustlsTls;
+#[cfg(feature = "debug-tap")]
+use crate::tap::Tap;
+use crate::{
+    cert::Certificate,
+    conf::ConfFn,
+    dns::{self, DnsCache, IpPreference},
+    doh::DohResolver,
+    error::{Error, Result},
+    events::{self, ConnectionEvent, DisconnectReason},
+    executor::Executor,
+    limit::{self, ConnectionPermit},
+    maybe_tls::MaybeTls,
+    mem::{self, MemSnapshot},
+    proxy::Proxy,
+    reactor, runtime,
+    stream::TlsStream,
+    tcp::{self, AsyncTcp, TcpKeepAlive, TcpOptions},
+    tls::{AsyncTls, ProtocolVersion},
+    transport::{Tcp, Transport},
+    verify::{self, VerifyFn},
+};
+#[cfg(esp_idf_esp_tls_client_session_tickets)]
+use crate::{session::TlsSession, suspend::Suspended};
+
+/// Establishes [`AsyncTls`] connections with options that go beyond the esp-tls [`Config`].
+#[derive(Clone, Default)]
+pub struct TlsConnector {
+    verify: Option<Arc<VerifyFn>>,
+    danger_accept_invalid_certs: bool,
+    psk: Option<Psk>,
+    max_fragment_length: Option<MaxFragmentLength>,
+    min_protocol_version: Option<ProtocolVersion>,
+    cipher_suites: Option<Arc<[i32]>>,
+    mbedtls_debug: Option<u8>,
+    ca_certs: Vec<Arc<[u8]>>,
+    crt_bundle: bool,
+    secure_element: bool,
+    #[cfg(esp_idf_esp_tls_use_ds_peripheral)]
+    ds_key: Option<Arc<DsKey>>,
+    instrument: bool,
+    min_free_heap: Option<usize>,
+    proxy: Option<Proxy>,
+    ip_preference: IpPreference,
+    dns_cache: Option<DnsCache>,
+    doh: Option<DohResolver>,
+    handshake_stack_size: Option<usize>,
+    handshake_watchdog: bool,
+    write_chunk_size: Option<usize>,
+    executor: Executor,
+    tcp_options: TcpOptions,
+    #[cfg(esp_idf_esp_tls_client_session_tickets)]
+    saved_session: Option<TlsSession>,
+    #[cfg(feature = "debug-keylog")]
+    keylog: Option<Arc<KeyLogFn>>,
+    #[cfg(feature = "debug-tap")]
+    tap: Option<Tap>,
+}
+
+/// Maximum TLS record payload to negotiate with the server (RFC 6066).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaxFragmentLength {
+    Bytes512,
+    Bytes1024,
+    Bytes2048,
+    Bytes4096,
+}
+
+impl MaxFragmentLength {
+    fn code(self) -> u8 {
+        (match self {
+            Self::Bytes512 => sys::MBEDTLS_SSL_MAX_FRAG_LEN_512,
+            Self::Bytes1024 => sys::MBEDTLS_SSL_MAX_FRAG_LEN_1024,
+            Self::Bytes2048 => sys::MBEDTLS_SSL_MAX_FRAG_LEN_2048,
+            Self::Bytes4096 => sys::MBEDTLS_SSL_MAX_FRAG_LEN_4096,
+        }) as u8
+    }
+
+    fn tweak(self) -> Box<ConfFn> {
+        Box::new(move |conf| {
+            match unsafe { sys::mbedtls_ssl_conf_max_frag_len(conf, self.code()) } {
+                0 => Ok(()),
+                err => Err(sys::EspError::from(err).unwrap()),
+            }
+        })
+    }
+}
+
+fn min_version_tweak(version: ProtocolVersion) -> Box<ConfFn> {
+    Box::new(move |conf| {
+        let raw = match version {
+            ProtocolVersion::Tls1_2 => sys::mbedtls_ssl_protocol_version_MBEDTLS_SSL_VERSION_TLS1_2,
+            #[cfg(esp_idf_mbedtls_ssl_proto_tls1_3)]
+            ProtocolVersion::Tls1_3 => sys::mbedtls_ssl_protocol_version_MBEDTLS_SSL_VERSION_TLS1_3,
+            #[cfg(not(esp_idf_mbedtls_ssl_proto_tls1_3))]
+            ProtocolVersion::Tls1_3 => {
+                log::error!("TLS 1.3 is required, but not enabled in mbedtls");
+                return Err(sys::EspError::from_infallible::<
+                    { sys::ESP_ERR_NOT_SUPPORTED },
+                >());
+            }
+        };
+
+        // What the inline `mbedtls_ssl_conf_min_tls_version` does, which has no binding
+        unsafe { (*conf).private_min_tls_version = raw };
+        Ok(())
+    })
+}
+
+fn cipher_suites_tweak(suites: Arc<[i32]>) -> Box<ConfFn> {
+    Box::new(move |conf| {
+        if suites.len() <= 1 {
+            return Err(sys::EspError::from_infallible::<{ sys::ESP_ERR_INVALID_ARG }>());
+        }
+
+        // mbedtls keeps the pointer, the tweak and with it `suites` live as long as the session
+        unsafe { sys::mbedtls_ssl_conf_ciphersuites(conf, suites.as_ptr()) };
+        Ok(())
+    })
+}
+
+/// Log the debug output of mbedtls up to `threshold` under the target `mbedtls::hs`.
+fn debug_tweak(threshold: u8) -> Box<ConfFn> {
+    Box::new(move |conf| {
+        #[cfg(esp_idf_mbedtls_debug)]
+        unsafe {
+            // Global, the last session to set it wins
+            sys::mbedtls_debug_set_threshold(threshold as _);
+            sys::mbedtls_ssl_conf_dbg(conf, Some(log_mbedtls_debug), core::ptr::null_mut());
+        }
+        #[cfg(not(esp_idf_mbedtls_debug))]
+        {
+            let _ = (conf, threshold);
+            log::warn!("mbedtls debug output requires CONFIG_MBEDTLS_DEBUG");
+        }
+
+        Ok(())
+    })
+}
+
+#[cfg(esp_idf_mbedtls_debug)]
+unsafe extern "C" fn log_mbedtls_debug(
+    _ctx: *mut core::ffi::c_void,
+    level: core::ffi::c_int,
+    file: *const core::ffi::c_char,
+    line: core::ffi::c_int,
+    msg: *const core::ffi::c_char,
+) {
+    let level = match level {
+        1 => log::Level::Error,
+        2 => log::Level::Info,
+        3 => log::Level::Debug,
+        _ => log::Level::Trace,
+    };
+    let file = core::ffi::CStr::from_ptr(file).to_string_lossy();
+    let file = file.rsplit('/').next().unwrap_or_default();
+    let msg = core::ffi::CStr::from_ptr(msg).to_string_lossy();
+
+    log::log!(target: "mbedtls::hs", level, "{file}:{line}: {}", msg.trim_end());
+}
+
+#[derive(Clone)]
+struct Psk {
+    identity: CString,
+    key: Vec<u8>,
+}
+
+impl TlsConnector {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Register a callback that can inspect, accept or reject the server's certificate chain
+    /// during the handshake, e.g. to pin a fingerprint. See [`VerifyFn`].
+    pub fn verify_callback<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&Certificate, usize, u32) -> u32 + Send + Sync + 'static,
+    {
+        self.verify = Some(Arc::new(callback));
+        self
+    }
+
+    /// Accept any server certificate, including expired, self-signed and mismatched ones.
+    ///
+    /// This makes the connection vulnerable to man-in-the-middle attacks and must only be used
+    /// for bring-up and lab setups. Every accepted invalid certificate is logged as a warning.
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.danger_accept_invalid_certs = accept;
+        self
+    }
+
+    /// Authenticate with a pre-shared key instead of certificates, using a TLS-PSK cipher suite.
+    ///
+    /// Takes precedence over any certificate verification options of the [`Config`]. Requires
+    /// `CONFIG_ESP_TLS_PSK_VERIFICATION`.
+    ///
+    /// # Panics
+    ///
+    /// If `identity` contains a nul byte.
+    pub fn psk(mut self, identity: &str, key: &[u8]) -> Self {
+        self.psk = Some(Psk {
+            identity: CString::new(identity).expect("PSK identity must not contain nul bytes"),
+            key: key.to_vec(),
+        });
+        self
+    }
+
+    /// Hand the secrets of every session to `callback`, to decrypt captures in Wireshark. See
+    /// [`keylog`](crate::keylog), never enable this in production.
+    #[cfg(feature = "debug-keylog")]
+    pub fn keylog<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.keylog = Some(Arc::new(callback));
+        self
+    }
+
+    /// Mirror the decrypted data of every session to `tap`, see [`tap`](crate::tap). Never
+    /// enable this in production.
+    #[cfg(feature = "debug-tap")]
+    pub fn tap(mut self, tap: Tap) -> Self {
+        self.tap = Some(tap);
+        self
+    }
+
+    /// Ask the server to limit TLS records to `len` bytes.
+    ///
+    /// With `CONFIG_MBEDTLS_VARIABLE_BUFFER_LENGTH` mbedtls shrinks the I/O buffers of the session
+    /// accordingly once the handshake is done. Not every server honors the extension.
+    ///
+    /// The size the buffers start out with is not a per-connection setting: it is
+    /// `CONFIG_MBEDTLS_SSL_IN_CONTENT_LEN` and `CONFIG_MBEDTLS_SSL_OUT_CONTENT_LEN` in the
+    /// sdkconfig, see `sdkconfig.defaults`.
+    ///
+    /// Requires `CONFIG_MBEDTLS_SSL_MAX_FRAGMENT_LENGTH`, and `CONFIG_MBEDTLS_CERTIFICATE_BUNDLE`
+    /// for esp-tls connections, which otherwise fail with `ESP_ERR_INVALID_STATE`.
+    pub fn max_fragment_length(mut self, len: MaxFragmentLength) -> Self {
+        self.max_fragment_length = Some(len);
+        self
+    }
+
+    /// Refuse servers that don't support `version`, e.g. [`ProtocolVersion::Tls1_3`] to rule out
+    /// a downgrade. TLS 1.3 requires `CONFIG_MBEDTLS_SSL_PROTO_TLS1_3`, without it connections
+    /// fail during the setup.
+    ///
+    /// TLS 1.3 early data (0-RTT) is not available: the mbedtls of ESP-IDF 5.1 can't send it as a
+    /// client. A resumed [session](crate::session) still saves the certificate exchange.
+    pub fn min_protocol_version(mut self, version: ProtocolVersion) -> Self {
+        self.min_protocol_version = Some(version);
+        self
+    }
+
+    /// Trust servers with a certificate issued by `cert`, in addition to the CA certificate or
+    /// other verification of the [`Config`]. Call it once per CA, e.g. for an old and a new root
+    /// during a migration.
+    ///
+    /// `cert` is PEM, nul terminated like `X509::pem`, or DER. A PEM `cert` can also hold several
+    /// certificates. Can't be combined with `use_global_ca_store`.
+    pub fn add_ca_cert(mut self, cert: &[u8]) -> Self {
+        self.ca_certs.push(cert.into());
+        self
+    }
+
+    /// Also trust the servers the ESP x509 certificate bundle covers, i.e. the common public
+    /// CAs, whatever else the [`Config`] specifies. Same as `use_crt_bundle_attach` when the
+    /// `Config` sets no other verification. Requires `CONFIG_MBEDTLS_CERTIFICATE_BUNDLE`.
+    pub fn crt_bundle(mut self, enabled: bool) -> Self {
+        self.crt_bundle = enabled;
+        self
+    }
+
+    /// Authenticate with the private key in an ATECC608A/B secure element (e.g. of the
+    /// ESP32-WROOM-32SE), which signs the handshake, so that the key never exists in flash.
+    ///
+    /// The client certificate is read from the chip unless the [`Config`] has one, a
+    /// `client_key` of the `Config` is ignored. Requires `CONFIG_ESP_TLS_USE_SECURE_ELEMENT`
+    /// and the esp-cryptoauthlib component, configured for the chip type and its I2C pins.
+    /// Only for the esp-tls based connections, not [`connect_stream`](Self::connect_stream).
+    pub fn secure_element(mut self, enabled: bool) -> Self {
+        self.secure_element = enabled;
+        self
+    }
+
+    /// Sign the handshake with `key` in the Digital Signature peripheral instead of the
+    /// `client_key` of the [`Config`], see [`ds`](crate::ds). Only for the esp-tls based
+    /// connections, not [`connect_stream`](Self::connect_stream).
+    #[cfg(esp_idf_esp_tls_use_ds_peripheral)]
+    pub fn ds_key(mut self, key: DsKey) -> Self {
+        self.ds_key = Some(Arc::new(key));
+        self
+    }
+
+    /// Offer only `suites` to the server, by mbedtls name as [`AsyncTls::cipher_suite`] reports
+    /// them, e.g. `TLS-ECDHE-ECDSA-WITH-AES-128-GCM-SHA256` or `TLS1-3-AES-128-GCM-SHA256`.
+    ///
+    /// Besides satisfying security policies, fewer suites make a smaller ClientHello. Names
+    /// mbedtls doesn't know or wasn't built with are logged and left out, connections fail if
+    /// none remain.
+    pub fn cipher_suites(mut self, suites: &[&str]) -> Self {
+        let ids = suites
+            .iter()
+            .filter_map(|name| {
+                let id = CString::new(*name)
+                    .map(|c_name| unsafe { sys::mbedtls_ssl_get_ciphersuite_id(c_name.as_ptr()) })
+                    .unwrap_or(0);
+                if id == 0 {
+                    log::error!("unknown cipher suite {name}");
+                }
+
+                (id != 0).then_some(id)
+            })
+            // mbedtls expects the list to end with 0
+            .chain([0])
+            .collect();
+
+        self.cipher_suites = Some(ids);
+        self
+    }
+
+    /// Log what mbedtls does up to debug level `threshold`, 1 (errors) to 4 (everything), with
+    /// the target `mbedtls::hs`, to diagnose failing handshakes. Levels 1 to 3 are logged as
+    /// error, info and debug records, level 4 as trace records.
+    ///
+    /// Requires `CONFIG_MBEDTLS_DEBUG`, but not the mbedtls log level of the ESP-IDF
+    /// configuration. The threshold is global to mbedtls, the connector that set it last decides
+    /// for all sessions that log.
+    pub fn mbedtls_debug(mut self, threshold: u8) -> Self {
+        self.mbedtls_debug = Some(threshold.min(4));
+        self
+    }
+
+    /// Log free heap and stack high-water marks before and after the TCP connect and the TLS
+    /// handshake, to help sizing heap and task stacks.
+    pub fn instrument(mut self, instrument: bool) -> Self {
+        self.instrument = instrument;
+        self
+    }
+
+    /// Fail with [`Error::InsufficientMemory`] before setting up a session while less than
+    /// `bytes` of internal heap are free, instead of running out inside mbedtls. A session with
+    /// the default 16 KiB record buffers takes about 40 KiB while handshaking.
+    pub fn min_free_heap(mut self, bytes: usize) -> Self {
+        self.min_free_heap = Some(bytes);
+        self
+    }
+
+    /// Restrict or order the address families to connect with, when a name resolves to both.
+    pub fn ip_preference(mut self, pref: IpPreference) -> Self {
+        self.ip_preference = pref;
+        self
+    }
+
+    /// Look up names in `cache` first, and add the results of lookups to it.
+    pub fn dns_cache(mut self, cache: DnsCache) -> Self {
+        self.dns_cache = Some(cache);
+        self
+    }
+
+    /// Resolve names with DNS-over-HTTPS instead of the system resolver.
+    pub fn doh_resolver(mut self, resolver: DohResolver) -> Self {
+        self.doh = Some(resolver);
+        self
+    }
+
+    /// Run the TLS handshake of socket connections on a dedicated thread with a stack of
+    /// `stack_size` bytes, for callers whose task stack is too small for mbedtls. A handshake
+    /// with RSA-2048 certificates needs around 8 KiB, more with larger keys or chains.
+    ///
+    /// The calling task waits for the handshake thread like for any other future, other tasks
+    /// of its executor keep running. The thread works on a copy of the [`Config`]. Dropping the
+    /// connecting future during the handshake leaves the thread to finish it and drop the
+    /// session.
+    pub fn handshake_stack_size(mut self, stack_size: usize) -> Self {
+        self.handshake_stack_size = Some(stack_size);
+        self
+    }
+
+    /// Keep the task watchdog from resetting the device during slow handshakes, e.g. with large
+    /// RSA keys, see [`AsyncTls::set_handshake_watchdog`].
+    pub fn handshake_watchdog(mut self, enabled: bool) -> Self {
+        self.handshake_watchdog = enabled;
+        self
+    }
+
+    /// Encrypt writes in records of at most `size` bytes, see
+    /// [`AsyncTls::set_write_chunk_size`].
+    pub fn write_chunk_size(mut self, size: usize) -> Self {
+        self.write_chunk_size = Some(size);
+        self
+    }
+
+    /// Run the handshake thread of [`handshake_stack_size`](Self::handshake_stack_size) with
+    /// `executor` instead of the [default one](Executor::default).
+    pub fn executor(mut self, executor: Executor) -> Self {
+        self.executor = executor;
+        self
+    }
+
+    /// Apply `options` to the sockets of new connections, before the TLS handshake.
+    pub fn tcp_options(mut self, options: TcpOptions) -> Self {
+        self.tcp_options = options;
+        self
+    }
+
+    /// Enable TCP keepalive on the sockets of new connections, see
+    /// [`FdSocket::set_keepalive`](crate::FdSocket::set_keepalive).
+    pub fn tcp_keepalive(mut self, keepalive: TcpKeepAlive) -> Self {
+        self.tcp_options = self.tcp_options.keepalive(keepalive);
+        self
+    }
+
+    /// Resume `session` instead of a full handshake when connecting to its server, e.g. after
+    /// deep sleep. See [`session`](crate::session).
+    #[cfg(esp_idf_esp_tls_client_session_tickets)]
+    pub fn with_saved_session(mut self, session: TlsSession) -> Self {
+        self.saved_session = Some(session);
+        self
+    }
+
+    /// Tunnel connections through `proxy`. The TLS session is end-to-end with the server.
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    pub async fn connect(&self, hostname: &str, port: u16, cfg: &Config<'_>) -> Result<AsyncTls> {
+        self.connect_over(&Tcp, hostname, port, cfg).await
+    }
+
+    /// [`connect`](Self::connect) over `transport`, e.g. through a given interface, see
+    /// [`transport`](crate::transport).
+    pub async fn connect_over<T: Transport>(
+        &self,
+        transport: &T,
+        hostname: &str,
+        port: u16,
+        cfg: &Config<'_>,
+    ) -> Result<AsyncTls<T::Socket>> {
+        self.connect_with(
+            transport.connect(self, hostname, port),
+            hostname,
+            &format!("{hostname}:{port}"),
+            cfg,
+        )
+        .await
+    }
+
+    /// Re-establish a connection closed by [`AsyncTls::suspend`], resuming its session if there
+    /// is one. See [`suspend`](crate::suspend).
+    ///
+    /// Connects to the address of the suspended connection without resolving the name, or, if
+    /// that fails, e.g. because the server moved meanwhile, with a full [`connect`](Self::connect).
+    #[cfg(esp_idf_esp_tls_client_session_tickets)]
+    pub async fn resume(&self, suspended: &Suspended, cfg: &Config<'_>) -> Result<AsyncTls> {
+        let mut connector = self.clone();
+        if let Some(session) = suspended.session() {
+            connector.saved_session = Some(session.clone());
+        }
+
+        let addr = suspended.addr();
+        match connector
+            .connect_to_addr(addr, suspended.hostname(), cfg)
+            .await
+        {
+            Err(Error::TcpConnect { source, .. }) => {
+                log::warn!("reconnecting to {addr} failed ({source}), resolving the name again");
+                connector
+                    .connect(suspended.hostname(), addr.port(), cfg)
+                    .await
+            }
+            result => result,
+        }
+    }
+
+    /// Connect without TLS, but through the proxy and with the name resolution and TCP options
+    /// of the connector.
+    pub async fn connect_plain(&self, hostname: &str, port: u16) -> Result<AsyncTcp> {
+        let (tcp, _) = self.connect_plain_timed(hostname, port, None).await?;
+
+        Ok(tcp)
+    }
+
+    /// [`connect_plain`](Self::connect_plain), through the interface with the lwIP name
+    /// `interface` if given, bypassing the proxy. Also returns how long resolving `hostname` took.
+    pub(crate) async fn connect_plain_timed(
+        &self,
+        hostname: &str,
+        port: u16,
+        interface: Option<&str>,
+    ) -> Result<(AsyncTcp, Option<Duration>)> {
+        let (tcp, dns) = self.connect_tcp(hostname, port, interface).await?;
+        let tcp = AsyncTcp::new(tcp);
+        tcp.set_options(&self.tcp_options)?;
+
+        Ok((tcp, dns))
+    }
+
+    /// [`connect`](Self::connect) with `cfg`, or [`connect_plain`](Self::connect_plain) without.
+    pub async fn connect_maybe_tls(
+        &self,
+        hostname: &str,
+        port: u16,
+        cfg: Option<&Config<'_>>,
+    ) -> Result<MaybeTls> {
+        Ok(match cfg {
+            Some(cfg) => MaybeTls::Tls(self.connect(hostname, port, cfg).await?),
+            None => MaybeTls::Plain(self.connect_plain(hostname, port).await?),
+        })
+    }
+
+    /// Connect with mbedtls driven directly by [`TlsStream`] rather than through the esp-tls
+    /// wrapper, e.g. to work around an esp-tls issue or to get at the mbedtls session.
+    ///
+    /// The same mbedtls that esp-tls uses, with the same [`TlsConnector`] options. The
+    /// `esp-mbedtls` crate is not an option on ESP-IDF: it targets bare-metal `esp-hal`
+    /// applications and links a build of mbedtls of its own, whose symbols clash with the one
+    /// of ESP-IDF.
+    pub async fn connect_mbedtls(
+        &self,
+        hostname: &str,
+        port: u16,
+        cfg: &Config<'_>,
+    ) -> Result<TlsStream<AsyncTcp>> {
+        let tcp = self.connect_plain(hostname, port).await?;
+
+        self.connect_stream(tcp, hostname, cfg).await
+    }
+
+    /// Connect with rustls instead of esp-tls, see [`rustls_backend`](crate::rustls_backend).
+    #[cfg(feature = "backend-rustls")]
+    pub async fn connect_rustls(
+        &self,
+        hostname: &str,
+        port: u16,
+        cfg: &Config<'_>,
+    ) -> Result<RustlsTls> {
+        let tcp = self.connect_plain(hostname, port).await?;
+
+        RustlsTls::negotiate(tcp, hostname, cfg).await
+    }
+
+    /// The connected socket and how long resolving `hostname` took, if it was resolved here.
+    async fn connect_tcp(
+        &self,
+        hostname: &str,
+        port: u16,
+        interface: Option<&str>,
+    ) -> Result<(reactor::Tcp, Option<Duration>)> {
+        match (&self.proxy, interface) {
+            (Some(proxy), None) => Ok((proxy.connect(hostname, port).await?, None)),
+            _ => {
+                events::post(ConnectionEvent::Resolving);
+                let resolving = Instant::now();
+                let addrs = self.resolve(hostname, port).await?;
+                let dns = resolving.elapsed();
+
+                let tcp = tcp::connect_any_via(&addrs, interface).await;
+                if let (Err(_), Some(cache)) = (&tcp, &self.dns_cache) {
+                    // The addresses might be stale
+                    cache.remove(hostname);
+                }
+
+                Ok((tcp?, Some(dns)))
+            }
+        }
+    }
+
+    /// Connect to `addr` without resolving any name, while SNI and the verification of the
+    /// server certificate use `hostname`.
+    ///
+    /// As with [`connect`](Self::connect), a `common_name` in the [`Config`] takes precedence over
+    /// `hostname` for the verification.
+    pub async fn connect_to_addr(
+        &self,
+        addr: SocketAddr,
+        hostname: &str,
+        cfg: &Config<'_>,
+    ) -> Result<AsyncTls> {
+        let tcp = async {
+            let tcp = match &self.proxy {
+                Some(proxy) => proxy.connect(&addr.ip().to_string(), addr.port()).await?,
+                None => tcp::connect_addr(addr).await?,
+            };
+            let tcp = AsyncTcp::new(tcp);
+            tcp.set_options(&self.tcp_options)?;
+
+            Ok((tcp, None))
+        };
+
+        self.connect_with(tcp, hostname, &addr.to_string(), cfg)
+            .await
+    }
+
+    pub(crate) async fn resolve(&self, hostname: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let pref = self.ip_preference;
+
+        if let Some(addrs) = self
+            .dns_cache
+            .as_ref()
+            .and_then(|cache| cache.get(hostname, port, pref))
+        {
+            return Ok(addrs);
+        }
+
+        let (addrs, ttl) = match &self.doh {
+            Some(doh) => doh.resolve(hostname, port, pref).await?,
+            None => (dns::resolve(hostname, port, pref)?, None),
+        };
+
+        if let Some(cache) = &self.dns_cache {
+            cache.insert(hostname, pref, &addrs, ttl);
+        }
+
+        Ok(addrs)
+    }
+
+    async fn connect_with<S: PollableSocket + Send + 'static>(
+        &self,
+        tcp: impl Future<Output = Result<(S, Option<Duration>)>>,
+        hostname: &str,
+        target: &str,
+        cfg: &Config<'_>,
+    ) -> Result<AsyncTls<S>> {
+        let start = self.instrument.then(MemSnapshot::take);
+        let started = Instant::now();
+        let (tcp, dns) = match tcp.await {
+            Ok(connected) => connected,
+            Err(e) => {
+                events::post(ConnectionEvent::Disconnected {
+                    reason: DisconnectReason::ConnectFailed,
+                });
+                return Err(e);
+            }
+        };
+        events::post(ConnectionEvent::TcpConnected);
+        let failed = ConnectFailedGuard;
+        let tcp_connect = started.elapsed().saturating_sub(dns.unwrap_or_default());
+
+        let permit = limit::acquire().await?;
+        let connected = self.instrument.then(MemSnapshot::take);
+        let mut tls = self.negotiate(tcp, hostname, cfg, permit).await?;
+        failed.disarm();
+        tls.set_connect_timings(dns, tcp_connect);
+        if let Some(timings) = tls.connect_timings() {
+            log::debug!("connected to {target}: {timings:?}");
+        }
+
+        if let (Some(start), Some(connected)) = (start, connected) {
+            let negotiated = MemSnapshot::take();
+
+            start.log_diff(&connected, &format!("TCP connect to {target}"));
+            connected.log_diff(&negotiated, &format!("TLS handshake with {target}"));
+            start.log_diff(&negotiated, "Total");
+        }
+
+        Ok(tls)
+    }
+
+    fn check_heap(&self) -> Result<()> {
+        let Some(required) = self.min_free_heap else {
+            return Ok(());
+        };
+
+        let free = mem::free_internal();
+        if free < required {
+            log::warn!("{free} bytes of internal heap free, not setting up a TLS session");
+            return Err(Error::InsufficientMemory { free, required });
+        }
+
+        Ok(())
+    }
+
+    /// Establish TLS on a socket the caller connected, e.g. one bound to a specific interface.
+    ///
+    /// The socket is `Send + 'static` as the handshake might move to a thread of its own, see
+    /// [`handshake_stack_size`](Self::handshake_stack_size).
+    pub async fn adopt<S>(&self, socket: S, hostname: &str, cfg: &Config<'_>) -> Result<AsyncTls<S>>
+    where
+        S: PollableSocket + Send + 'static,
+    {
+        let failed = ConnectFailedGuard;
+        let permit = limit::acquire().await?;
+        let tls = self.negotiate(socket, hostname, cfg, permit).await?;
+        failed.disarm();
+
+        Ok(tls)
+    }
+
+    async fn negotiate<S>(
+        &self,
+        socket: S,
+        hostname: &str,
+        cfg: &Config<'_>,
+        permit: Option<ConnectionPermit>,
+    ) -> Result<AsyncTls<S>>
+    where
+        S: PollableSocket + Send + 'static,
+    {
+        #[cfg(not(esp_idf_esp_tls_use_secure_element))]
+        if self.secure_element {
+            log::error!("the secure element requires CONFIG_ESP_TLS_USE_SECURE_ELEMENT");
+            return Err(Error::TlsSetup(not_supported()));
+        }
+
+        self.check_heap()?;
+        let mut tls = AsyncTls::adopt(socket).map_err(Error::TlsSetup)?;
+        tls.set_permit(permit);
+        log::info!("adopted socket");
+
+        if self.danger_accept_invalid_certs {
+            log::warn!(
+                "!!! Certificate verification for {hostname} is DISABLED, the connection is NOT secure !!!"
+            );
+
+            // esp-tls refuses to connect without any verification option, so fall back to the
+            // (possibly empty) global CA store and let the callback below accept the result
+            sys::esp!(unsafe { sys::esp_tls_init_global_ca_store() }).map_err(Error::TlsSetup)?;
+
+            tls.set_verify_callback(self.insecure_verify_callback());
+        } else if let Some(verify) = self.verify_callback(hostname, cfg) {
+            tls.set_verify_callback(verify);
+        }
+
+        if let Some(mfl) = self.max_fragment_length {
+            tls.add_conf_tweak(mfl.tweak());
+        }
+
+        if let Some(version) = self.min_protocol_version {
+            tls.add_conf_tweak(min_version_tweak(version));
+        }
+
+        if let Some(suites) = &self.cipher_suites {
+            tls.add_conf_tweak(cipher_suites_tweak(suites.clone()));
+        }
+
+        if let Some(threshold) = self.mbedtls_debug {
+            tls.add_conf_tweak(debug_tweak(threshold));
+        }
+
+        if !self.ca_certs.is_empty() || self.crt_bundle {
+            tls.add_trust(&self.ca_certs, self.crt_bundle);
+        }
+
+        #[cfg(feature = "debug-keylog")]
+        if let Some(keylog) = &self.keylog {
+            tls.set_keylog_callback(keylog.clone());
+        }
+        #[cfg(feature = "debug-tap")]
+        if let Some(tap) = &self.tap {
+            tls.set_tap(tap.clone());
+        }
+
+        tls.set_handshake_watchdog(self.handshake_watchdog);
+        tls.set_write_chunk_size(self.write_chunk_size);
+
+        let tweaks = RawTweaks {
+            psk: self.psk.clone(),
+            #[cfg(esp_idf_esp_tls_use_ds_peripheral)]
+            ds_key: self.ds_key.clone(),
+            #[cfg(esp_idf_esp_tls_client_session_tickets)]
+            saved_session: self
+                .saved_session
+                .as_ref()
+                .filter(|session| session.hostname() == hostname)
+                .cloned(),
+            secure_element: self.secure_element,
+            global_ca_store: self.danger_accept_invalid_certs
+                && self.ca_certs.is_empty()
+                && !self.crt_bundle,
+        };
+        match self.handshake_stack_size {
+            Some(stack_size) => {
+                let handshake = ThreadedHandshake {
+                    tls,
+                    hostname: hostname.to_owned(),
+                    cfg: OwnedConfig::new(cfg),
+                    tweaks,
+                };
+                HandshakeThread::spawn(&self.executor, stack_size, handshake)?.await
+            }
+            None => {
+                tweaks.negotiate(&mut tls, hostname, cfg).await?;
+
+                Ok(tls)
+            }
+        }
+    }
+
+    /// Establish TLS over an arbitrary transport, e.g. an in-memory pipe or a PPP serial stream.
+    ///
+    /// See [`TlsStream::negotiate`] for the [`Config`] options that apply.
+    pub async fn connect_stream<T>(
+        &self,
+        transport: T,
+        hostname: &str,
+        cfg: &Config<'_>,
+    ) -> Result<TlsStream<T>>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        if self.secure_element {
+            log::error!("the secure element is not supported with TlsStream");
+            return Err(Error::TlsSetup(not_supported()));
+        }
+
+        #[cfg(esp_idf_esp_tls_use_ds_peripheral)]
+        if self.ds_key.is_some() {
+            log::error!("the DS peripheral is not supported with TlsStream");
+            return Err(Error::TlsSetup(not_supported()));
+        }
+
+        let permit = limit::acquire().await?;
+        self.check_heap()?;
+        let mut tls = TlsStream::new(transport)?;
+        tls.set_permit(permit);
+
+        if self.danger_accept_invalid_certs {
+            log::warn!(
+                "!!! Certificate verification for {hostname} is DISABLED, the connection is NOT secure !!!"
+            );
+
+            // Without a CA chain mbedtls refuses to verify at all, so don't insist on it
+            tls.add_conf_tweak(Box::new(|conf| {
+                unsafe {
+                    sys::mbedtls_ssl_conf_authmode(conf, sys::MBEDTLS_SSL_VERIFY_OPTIONAL as _)
+                };
+                Ok(())
+            }));
+            tls.set_verify_callback(self.insecure_verify_callback());
+        } else if let Some(verify) = self.verify_callback(hostname, cfg) {
+            tls.set_verify_callback(verify);
+        }
+
+        if let Some(mfl) = self.max_fragment_length {
+            tls.add_conf_tweak(mfl.tweak());
+        }
+
+        if let Some(version) = self.min_protocol_version {
+            tls.add_conf_tweak(min_version_tweak(version));
+        }
+
+        if let Some(suites) = &self.cipher_suites {
+            tls.add_conf_tweak(cipher_suites_tweak(suites.clone()));
+        }
+
+        if let Some(threshold) = self.mbedtls_debug {
+            tls.add_conf_tweak(debug_tweak(threshold));
+        }
+
+        if !self.ca_certs.is_empty() || self.crt_bundle {
+            tls.add_trust(&self.ca_certs, self.crt_bundle);
+        }
+
+        #[cfg(feature = "debug-keylog")]
+        if let Some(keylog) = &self.keylog {
+            tls.set_keylog_callback(keylog.clone());
+        }
+        #[cfg(feature = "debug-tap")]
+        if let Some(tap) = &self.tap {
+            tls.set_tap(tap.clone());
+        }
+
+        tls.set_handshake_watchdog(self.handshake_watchdog);
+        tls.set_write_chunk_size(self.write_chunk_size);
+
+        if let Some(psk) = self.psk.clone() {
+            tls.add_conf_tweak(Box::new(move |conf| {
+                let identity = psk.identity.as_bytes();
+                match unsafe {
+                    sys::mbedtls_ssl_conf_psk(
+                        conf,
+                        psk.key.as_ptr(),
+                        psk.key.len(),
+                        identity.as_ptr(),
+                        identity.len(),
+                    )
+                } {
+                    0 => Ok(()),
+                    err => Err(sys::EspError::from(err).unwrap()),
+                }
+            }));
+        }
+
+        tls.negotiate(hostname, cfg).await?;
+
+        Ok(tls)
+    }
+
+    /// The verification callback, which for a server addressed by IP also accepts the address
+    /// in its certificate, see [`verify::match_ip_address`].
+    fn verify_callback(&self, hostname: &str, cfg: &Config<'_>) -> Option<Arc<VerifyFn>> {
+        let name = cfg.common_name.unwrap_or(hostname);
+
+        match name.parse::<IpAddr>() {
+            Ok(ip) if !cfg.skip_common_name => {
+                Some(verify::match_ip_address(ip, self.verify.clone()))
+            }
+            _ => self.verify.clone(),
+        }
+    }
+
+    fn insecure_verify_callback(&self) -> Arc<VerifyFn> {
+        let verify = self.verify.clone();
+
+        Arc::new(move |cert, depth, flags| {
+            let flags = match &verify {
+                Some(verify) => verify(cert, depth, flags),
+                None => flags,
+            };
+
+            if flags != 0 {
+                log::warn!(
+                    "!!! Accepting invalid certificate \"{}\" (verification flags {flags:#x}) !!!",
+                    cert.subject
+                );
+            }
+
+            0
+        })
+    }
+}
+
+/// Posts [`DisconnectReason::ConnectFailed`] when dropped unless disarmed once connected, so that
+/// every way connecting fails is reported, including the connecting future being dropped.
+struct ConnectFailedGuard;
+
+impl ConnectFailedGuard {
+    fn disarm(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for ConnectFailedGuard {
+    fn drop(&mut self) {
+        events::post(ConnectionEvent::Disconnected {
+            reason: DisconnectReason::ConnectFailed,
+        });
+    }
+}
+
+/// What [`TlsConnector`] sets in the raw esp-tls configuration beyond the [`Config`], owned so
+/// that it can move to the handshake thread.
+struct RawTweaks {
+    psk: Option<Psk>,
+    #[cfg(esp_idf_esp_tls_use_ds_peripheral)]
+    ds_key: Option<Arc<DsKey>>,
+    #[cfg(esp_idf_esp_tls_client_session_tickets)]
+    saved_session: Option<TlsSession>,
+    secure_element: bool,
+    /// Fall back to the global CA store if nothing else verifies the server
+    global_ca_store: bool,
+}
+
+impl RawTweaks {
+    async fn negotiate<S: PollableSocket>(
+        self,
+        tls: &mut AsyncTls<S>,
+        hostname: &str,
+        cfg: &Config<'_>,
+    ) -> Result<()> {
+        let mut psk = self.psk.as_ref().map(|psk| sys::psk_key_hint {
+            key: psk.key.as_ptr(),
+            key_size: psk.key.len(),
+            hint: psk.identity.as_ptr(),
+        });
+
+        #[cfg(esp_idf_esp_tls_client_session_tickets)]
+        let mut client_session = self
+            .saved_session
+            .as_ref()
+            .map(TlsSession::to_client_session)
+            .transpose()
+            .map_err(Error::TlsSetup)?;
+
+        // esp-tls keeps the pointer into the key, not to the context
+        #[cfg(esp_idf_esp_tls_use_ds_peripheral)]
+        let mut ds_ctx = self.ds_key.as_ref().map(|key| key.raw_ctx());
+
+        let tweak = |raw: &mut sys::esp_tls_cfg| {
+            #[cfg(esp_idf_esp_tls_use_ds_peripheral)]
+            if let Some(ds_ctx) = &mut ds_ctx {
+                raw.ds_data = ds_ctx as *mut _ as *mut core::ffi::c_void;
+            }
+
+            #[cfg(esp_idf_esp_tls_client_session_tickets)]
+            if let Some(session) = &mut client_session {
+                raw.client_session = session.as_mut_ptr();
+            }
+
+            // esp-tls prefers it over the client key of the configuration
+            raw.use_secure_element |= self.secure_element;
+
+            if let Some(psk) = &mut psk {
+                // esp-tls only considers the PSK if no certificate verification is set up
+                raw.__bindgen_anon_1.cacert_buf = core::ptr::null();
+                raw.__bindgen_anon_2.cacert_bytes = 0;
+                raw.use_global_ca_store = false;
+                raw.crt_bundle_attach = None;
+                raw.psk_hint_key = psk as *mut _;
+            }
+
+            if self.global_ca_store && !has_verification_option(raw) {
+                raw.use_global_ca_store = true;
+            }
+        };
+
+        tls.negotiate_with(hostname, cfg, tweak)
+            .await
+            .map_err(Error::TlsHandshake)
+    }
+}
+
+/// A copy of a [`Config`] for the handshake thread, which must not borrow from the connecting
+/// task: that could be forgotten instead of dropped, leaving the thread with dangling borrows.
+struct OwnedConfig {
+    alpn_protos: Vec<String>,
+    ca_cert: Option<Vec<u8>>,
+    client_cert: Option<Vec<u8>>,
+    client_key: Option<Vec<u8>>,
+    client_key_password: Option<String>,
+    non_block: bool,
+    use_secure_element: bool,
+    timeout_ms: u32,
+    use_global_ca_store: bool,
+    common_name: Option<String>,
+    skip_common_name: bool,
+    keep_alive_cfg: Option<KeepAliveConfig>,
+    psk_hint_key: Option<(Vec<u8>, CString)>,
+    #[cfg(esp_idf_mbedtls_certificate_bundle)]
+    use_crt_bundle_attach: bool,
+    is_plain_tcp: bool,
+}
+
+impl OwnedConfig {
+    fn new(cfg: &Config<'_>) -> Self {
+        Self {
+            alpn_protos: cfg
+                .alpn_protos
+                .unwrap_or_default()
+                .iter()
+                .map(|p| p.to_string())
+                .collect(),
+            ca_cert: cfg.ca_cert.map(|cert| cert.data().to_vec()),
+            client_cert: cfg.client_cert.map(|cert| cert.data().to_vec()),
+            client_key: cfg.client_key.map(|key| key.data().to_vec()),
+            client_key_password: cfg.client_key_password.map(str::to_owned),
+            non_block: cfg.non_block,
+            use_secure_element: cfg.use_secure_element,
+            timeout_ms: cfg.timeout_ms,
+            use_global_ca_store: cfg.use_global_ca_store,
+            common_name: cfg.common_name.map(str::to_owned),
+            skip_common_name: cfg.skip_common_name,
+            keep_alive_cfg: cfg.keep_alive_cfg.as_ref().map(|kac| KeepAliveConfig {
+                enable: kac.enable,
+                idle: kac.idle,
+                interval: kac.interval,
+                count: kac.count,
+            }),
+            psk_hint_key: cfg
+                .psk_hint_key
+                .as_ref()
+                .map(|psk| (psk.key.to_vec(), psk.hint.to_owned())),
+            #[cfg(esp_idf_mbedtls_certificate_bundle)]
+            use_crt_bundle_attach: cfg.use_crt_bundle_attach,
+            is_plain_tcp: cfg.is_plain_tcp,
+        }
+    }
+
+    /// The [`Config`] borrowing from the copy, with `alpn_protos` borrowed from
+    /// [`alpn_protos`](Self::alpn_protos).
+    fn config<'a>(&'a self, alpn_protos: &'a [&'a str]) -> Config<'a> {
+        // esp-tls only looks at the data, which the copy keeps as it was, PEM or DER
+        Config {
+            alpn_protos: (!alpn_protos.is_empty()).then_some(alpn_protos),
+            ca_cert: self.ca_cert.as_deref().map(X509::der),
+            client_cert: self.client_cert.as_deref().map(X509::der),
+            client_key: self.client_key.as_deref().map(X509::der),
+            client_key_password: self.client_key_password.as_deref(),
+            non_block: self.non_block,
+            use_secure_element: self.use_secure_element,
+            timeout_ms: self.timeout_ms,
+            use_global_ca_store: self.use_global_ca_store,
+            common_name: self.common_name.as_deref(),
+            skip_common_name: self.skip_common_name,
+            keep_alive_cfg: self.keep_alive_cfg.as_ref().map(|kac| KeepAliveConfig {
+                enable: kac.enable,
+                idle: kac.idle,
+                interval: kac.interval,
+                count: kac.count,
+            }),
+            psk_hint_key: self.psk_hint_key.as_ref().map(|(key, hint)| PskHint {
+                key: key.as_slice(),
+                hint: hint.as_c_str(),
+            }),
+            #[cfg(esp_idf_mbedtls_certificate_bundle)]
+            use_crt_bundle_attach: self.use_crt_bundle_attach,
+            is_plain_tcp: self.is_plain_tcp,
+        }
+    }
+
+    fn alpn_protos(&self) -> Vec<&str> {
+        self.alpn_protos.iter().map(String::as_str).collect()
+    }
+}
+
+/// What the thread of [`TlsConnector::handshake_stack_size`] works on, moved to it and returned
+/// once the handshake is done.
+struct ThreadedHandshake<S: PollableSocket> {
+    tls: AsyncTls<S>,
+    hostname: String,
+    cfg: OwnedConfig,
+    tweaks: RawTweaks,
+}
+
+impl<S: PollableSocket> ThreadedHandshake<S> {
+    async fn run(self) -> Result<AsyncTls<S>> {
+        let Self {
+            mut tls,
+            hostname,
+            cfg,
+            tweaks,
+        } = self;
+        let alpn_protos = cfg.alpn_protos();
+        tweaks
+            .negotiate(&mut tls, &hostname, &cfg.config(&alpn_protos))
+            .await?;
+
+        Ok(tls)
+    }
+}
+
+/// A [`ThreadedHandshake`] running on a thread of its own, ready once the thread is done with it.
+/// Dropped early, it leaves the thread to finish the handshake and drop the session.
+struct HandshakeThread<S: PollableSocket> {
+    state: Arc<Mutex<ThreadState>>,
+    thread: Option<thread::JoinHandle<Result<AsyncTls<S>>>>,
+}
+
+#[derive(Default)]
+struct ThreadState {
+    done: bool,
+    waker: Option<Waker>,
+}
+
+/// Marks the thread as done when it returns or panics.
+struct DoneGuard(Arc<Mutex<ThreadState>>);
+
+impl Drop for DoneGuard {
+    fn drop(&mut self) {
+        let waker = {
+            let mut state = self.0.lock().unwrap();
+            state.done = true;
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<S: PollableSocket + Send + 'static> HandshakeThread<S> {
+    /// Run `handshake` with `executor` on a new thread with a stack of `stack_size` bytes.
+    fn spawn(
+        executor: &Executor,
+        stack_size: usize,
+        handshake: ThreadedHandshake<S>,
+    ) -> io::Result<Self> {
+        let state = Arc::new(Mutex::new(ThreadState::default()));
+
+        let executor = executor.clone();
+        let done = DoneGuard(state.clone());
+        let thread = runtime::spawn_with(runtime::current().handshake, || {
+            thread::Builder::new()
+                .name("tls-handshake".to_owned())
+                .stack_size(stack_size)
+                .spawn(move || {
+                    let _done = done;
+                    executor.block_on(handshake.run())
+                })
+        })?;
+
+        Ok(Self {
+            state,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl<S: PollableSocket> Future for HandshakeThread<S> {
+    type Output = Result<AsyncTls<S>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if !state.done {
+                state.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
+
+        // The handshake is done, this only waits for the thread to exit
+        let thread = self.thread.take().expect("polled after completion");
+        match thread.join() {
+            Ok(result) => Poll::Ready(result),
+            Err(panic) => panic::resume_unwind(panic),
+        }
+    }
+}
+
+fn not_supported() -> sys::EspError {
+    sys::EspError::from_infallible::<{ sys::ESP_ERR_NOT_SUPPORTED }>()
+}
+
+fn has_verification_option(raw: &sys::esp_tls_cfg) -> bool {
+    let has_ca_cert = unsafe { !raw.__bindgen_anon_1.cacert_buf.is_null() };
+
+    has_ca_cert
+        || raw.use_global_ca_store
+        || raw.crt_bundle_attach.is_some()
+        || !raw.psk_hint_key.is_null()
+}
+
+/// Connect over `transport`, e.g. [`Tcp`], with the default [`TlsConnector`].
+pub async fn connect_async_tls<T: Transport>(
+    transport: &T,
+    hostname: &str,
+    port: u16,
+    cfg: &Config<'_>,
+) -> Result<AsyncTls<T::Socket>> {
+    TlsConnector::new()
+        .connect_over(transport, hostname, port, cfg)
+        .await
+}
+
+/// Connect to a fixed address, using `hostname` for SNI and certificate verification.
+pub async fn connect_async_tls_to_addr(
+    addr: SocketAddr,
+    hostname: &str,
+    cfg: &Config<'_>,
+) -> Result<AsyncTls> {
+    TlsConnector::new()
+        .connect_to_addr(addr, hostname, cfg)
+        .await
+}
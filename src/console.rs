@@ -0,0 +1,420 @@
+//! An interactive console on the default UART for network diagnostics in the field.
+//!
+//! ```text
+//! > wifi status
+//! ssid MyNetwork, channel 6, rssi -61 dBm, ip 192.168.1.42
+//! > dns example.com
+//! 93.184.216.34
+//! > tls connect example.com 443
+//! connected in 812 ms
+//! ...
+//! ```
+//!
+//! With [`Console::wifi`] and [`Console::credentials`] it also replaces the WiFi credentials and
+//! the TLS certificates at runtime, e.g. to recover a device whose access point password changed.
+
+use std::{
+    io::{self, BufRead, Write},
+    net::Ipv4Addr,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use embedded_svc::wifi::Configuration;
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
+    nvs::{EspNvs, NvsDefault},
+    tls::{Config, X509},
+    wifi::EspWifi,
+};
+use esp_idf_sys::{self as sys, esp};
+use futures_lite::AsyncWriteExt;
+
+use crate::{
+    bench::{self, ThroughputConfig},
+    connector::TlsConnector,
+    credstore::{CredStore, Credentials},
+    error::{Error, Result},
+    executor::Executor,
+    mem::{self, MemSnapshot},
+    runtime,
+    wifi::{self, WifiCredentials},
+};
+
+const HELP: &str = "\
+commands:
+  wifi status                access point, signal strength and IP address
+  wifi set <ssid> [pass]     join another access point and keep its credentials
+  tls connect <host> [port]  handshake with a server and show its certificates
+  dns <name>                 resolve a name like connections do
+  iperf <host> <port> [tls]  send 1 MiB, e.g. to `iperf -s`, and show the rate
+  creds ca|cert|key          replace a certificate or the key, pasted as PEM
+  creds token <token>        replace the API token
+  creds rollback             switch back to the previous credentials
+  heap                       memory usage
+  help                       this text";
+
+/// Reads commands line by line from the console UART, see the [module docs](self).
+pub struct Console {
+    connector: TlsConnector,
+    ca_cert: Option<X509<'static>>,
+    executor: Executor,
+    wifi: Option<WifiControl>,
+    credentials: Option<CredControl>,
+}
+
+struct WifiControl {
+    wifi: Arc<Mutex<Box<EspWifi<'static>>>>,
+    sysloop: EspSystemEventLoop,
+    nvs: Mutex<EspNvs<NvsDefault>>,
+}
+
+type ReplacedFn = dyn Fn(&Credentials) + Send;
+
+struct CredControl {
+    store: Mutex<CredStore<NvsDefault>>,
+    on_replaced: Box<ReplacedFn>,
+}
+
+impl Console {
+    /// Run `tls connect` and `dns` with `connector`, i.e. with its proxy, DNS and TLS settings.
+    ///
+    /// Servers are verified with the certificate bundle, unless a CA certificate is set.
+    pub fn new(connector: TlsConnector) -> Self {
+        Self {
+            connector,
+            ca_cert: None,
+            executor: Default::default(),
+            wifi: None,
+            credentials: None,
+        }
+    }
+
+    /// Verify servers with `ca_cert` instead of the certificate bundle.
+    pub fn ca_cert(mut self, ca_cert: X509<'static>) -> Self {
+        self.ca_cert = Some(ca_cert);
+        self
+    }
+
+    /// Run commands with `executor` instead of the [default one](Executor::default).
+    pub fn executor(mut self, executor: Executor) -> Self {
+        self.executor = executor;
+        self
+    }
+
+    /// Enable `wifi set`, which reconnects `wifi` with the new credentials and stores them in
+    /// `nvs` once connected, see [`WifiCredentials`]. If the access point can't be joined the
+    /// previous one is joined again and nothing is stored.
+    pub fn wifi(
+        mut self,
+        wifi: Arc<Mutex<Box<EspWifi<'static>>>>,
+        sysloop: EspSystemEventLoop,
+        nvs: EspNvs<NvsDefault>,
+    ) -> Self {
+        self.wifi = Some(WifiControl {
+            wifi,
+            sysloop,
+            nvs: Mutex::new(nvs),
+        });
+        self
+    }
+
+    /// Enable the `creds` commands, which replace the credentials in `store` with a new version.
+    /// `on_replaced` is called with the credentials in use afterwards, to reconnect with them.
+    pub fn credentials<F>(mut self, store: CredStore<NvsDefault>, on_replaced: F) -> Self
+    where
+        F: Fn(&Credentials) + Send + 'static,
+    {
+        self.credentials = Some(CredControl {
+            store: Mutex::new(store),
+            on_replaced: Box::new(on_replaced),
+        });
+        self
+    }
+
+    /// Install the UART driver for the console and handle commands on a separate thread.
+    ///
+    /// Log output keeps going to the same UART.
+    pub fn spawn(self) -> Result<JoinHandle<()>> {
+        let port = sys::CONFIG_ESP_CONSOLE_UART_NUM as sys::uart_port_t;
+
+        // Without the driver stdin does not block but returns no data
+        unsafe {
+            esp!(sys::uart_driver_install(
+                port,
+                256,
+                0,
+                0,
+                core::ptr::null_mut(),
+                0
+            ))
+            .map_err(|e| Error::Io(io::Error::new(io::ErrorKind::Other, e)))?;
+            sys::esp_vfs_dev_uart_use_driver(port as _);
+            sys::esp_vfs_dev_uart_port_set_rx_line_endings(
+                port as _,
+                sys::esp_line_endings_t_ESP_LINE_ENDINGS_CR,
+            );
+            sys::esp_vfs_dev_uart_port_set_tx_line_endings(
+                port as _,
+                sys::esp_line_endings_t_ESP_LINE_ENDINGS_CRLF,
+            );
+        }
+
+        let handle = runtime::spawn_with(runtime::current().background, || {
+            thread::Builder::new()
+                .name("console".into())
+                .stack_size(12 * 1024)
+                .spawn(move || self.run())
+        })?;
+
+        Ok(handle)
+    }
+
+    fn run(self) {
+        let stdin = io::stdin();
+        let mut line = String::new();
+
+        loop {
+            print!("> ");
+            let _ = io::stdout().flush();
+
+            line.clear();
+            if let Err(e) = stdin.lock().read_line(&mut line) {
+                log::warn!("console read failed: {e}");
+                continue;
+            }
+
+            let args: Vec<_> = line.split_whitespace().collect();
+            if args.is_empty() {
+                continue;
+            }
+
+            if let Err(e) = self.executor.block_on(self.execute(&args)) {
+                println!("error: {e}");
+            }
+        }
+    }
+
+    async fn execute(&self, args: &[&str]) -> Result<()> {
+        match args {
+            ["wifi", "status"] => wifi_status(),
+            ["wifi", "set", ssid] => self.wifi_set(ssid, "")?,
+            ["wifi", "set", ssid, pass] => self.wifi_set(ssid, pass)?,
+            ["tls", "connect", host] => self.tls_connect(host, 443).await?,
+            ["tls", "connect", host, port] => match port.parse() {
+                Ok(port) => self.tls_connect(host, port).await?,
+                Err(_) => println!("invalid port {port}"),
+            },
+            ["iperf", host, port, rest @ ..] => match (port.parse(), rest) {
+                (Ok(port), []) => self.iperf(host, port, false).await?,
+                (Ok(port), ["tls"]) => self.iperf(host, port, true).await?,
+                _ => println!("usage: iperf <host> <port> [tls]"),
+            },
+            ["dns", name] => {
+                for addr in self.connector.resolve(name, 0).await? {
+                    println!("{}", addr.ip());
+                }
+            }
+            ["creds", entry @ ("ca" | "cert" | "key")] => {
+                let pem = read_pem()?;
+                self.replace_credentials(|creds| match *entry {
+                    "ca" => creds.ca_cert = Some(pem),
+                    "cert" => creds.client_cert = Some(pem),
+                    _ => creds.client_key = Some(pem),
+                })?
+            }
+            ["creds", "token", token] => {
+                self.replace_credentials(|creds| creds.api_token = Some(token.to_string()))?
+            }
+            ["creds", "rollback"] => self.rollback_credentials()?,
+            ["heap"] => heap(),
+            ["help"] => println!("{HELP}"),
+            _ => println!("unknown command, try `help`"),
+        }
+
+        Ok(())
+    }
+
+    fn tls_config(&self) -> Config<'static> {
+        Config {
+            ca_cert: self.ca_cert,
+            #[cfg(esp_idf_mbedtls_certificate_bundle)]
+            use_crt_bundle_attach: self.ca_cert.is_none(),
+            ..Default::default()
+        }
+    }
+
+    async fn tls_connect(&self, host: &str, port: u16) -> Result<()> {
+        let start = Instant::now();
+        let mut tls = self
+            .connector
+            .connect(host, port, &self.tls_config())
+            .await?;
+        println!("connected in {} ms", start.elapsed().as_millis());
+
+        for (depth, cert) in tls.peer_certificate_chain().iter().enumerate() {
+            println!(
+                "{depth}: subject {}\n   issuer {}\n   valid {} to {}",
+                cert.subject, cert.issuer, cert.not_before, cert.not_after
+            );
+        }
+
+        tls.close().await?;
+
+        Ok(())
+    }
+
+    async fn iperf(&self, host: &str, port: u16, tls: bool) -> Result<()> {
+        let test = ThroughputConfig {
+            host,
+            port,
+            tls,
+            ..Default::default()
+        };
+
+        // Prints the report
+        bench::throughput(&self.connector, &test, &self.tls_config()).await?;
+
+        Ok(())
+    }
+
+    fn wifi_set(&self, ssid: &str, pass: &str) -> Result<()> {
+        let Some(control) = &self.wifi else {
+            println!("not enabled, see `Console::wifi`");
+            return Ok(());
+        };
+
+        let mut wifi = control.wifi.lock().unwrap();
+        let previous = match wifi.get_configuration().map_err(Error::Wifi)? {
+            Configuration::Client(client) => {
+                Some(WifiCredentials::new(&client.ssid, &client.password))
+            }
+            _ => None,
+        };
+
+        match wifi::reconnect(&mut wifi, control.sysloop.clone(), ssid, pass) {
+            Ok(()) => {
+                WifiCredentials::new(ssid, pass).store(&mut control.nvs.lock().unwrap())?;
+                println!("connected to {ssid}, stored the credentials");
+            }
+            Err(e) => {
+                println!("joining {ssid} failed: {e}");
+                if let Some(previous) = previous {
+                    println!("rejoining {}", previous.ssid);
+                    wifi::reconnect(
+                        &mut wifi,
+                        control.sysloop.clone(),
+                        &previous.ssid,
+                        &previous.pass,
+                    )?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Store the credentials in use, changed by `update`, as the next version.
+    fn replace_credentials(&self, update: impl FnOnce(&mut Credentials)) -> Result<()> {
+        let Some(control) = &self.credentials else {
+            println!("not enabled, see `Console::credentials`");
+            return Ok(());
+        };
+
+        let creds = {
+            let mut store = control.store.lock().unwrap();
+            let mut creds = store.load()?.unwrap_or_default();
+            creds.version += 1;
+            update(&mut creds);
+            store.replace(&creds)?;
+            creds
+        };
+        println!("stored credentials version {}", creds.version);
+
+        (control.on_replaced)(&creds);
+
+        Ok(())
+    }
+
+    fn rollback_credentials(&self) -> Result<()> {
+        let Some(control) = &self.credentials else {
+            println!("not enabled, see `Console::credentials`");
+            return Ok(());
+        };
+
+        let creds = control.store.lock().unwrap().rollback()?;
+        println!("back to credentials version {}", creds.version);
+
+        (control.on_replaced)(&creds);
+
+        Ok(())
+    }
+}
+
+/// Read PEM pasted into the console up to the `-----END` line, nul terminated like `X509::pem`.
+fn read_pem() -> Result<Vec<u8>> {
+    println!("paste the PEM, up to the -----END line");
+
+    let stdin = io::stdin();
+    let mut pem = String::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let line = line.trim();
+        pem.push_str(line);
+        pem.push('\n');
+        if line.starts_with("-----END") {
+            break;
+        }
+    }
+
+    let mut pem = pem.into_bytes();
+    pem.push(0);
+
+    Ok(pem)
+}
+
+fn wifi_status() {
+    let mut info: sys::wifi_ap_record_t = Default::default();
+    if unsafe { sys::esp_wifi_sta_get_ap_info(&mut info) } != sys::ESP_OK {
+        println!("not connected");
+        return;
+    }
+
+    let ssid_len = info
+        .ssid
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(info.ssid.len());
+    let ssid = String::from_utf8_lossy(&info.ssid[..ssid_len]);
+    print!(
+        "ssid {ssid}, channel {}, rssi {} dBm",
+        info.primary, info.rssi
+    );
+
+    let mut ip_info: sys::esp_netif_ip_info_t = Default::default();
+    let netif = unsafe { sys::esp_netif_get_handle_from_ifkey(b"WIFI_STA_DEF\0".as_ptr() as _) };
+    if !netif.is_null() && unsafe { sys::esp_netif_get_ip_info(netif, &mut ip_info) } == sys::ESP_OK
+    {
+        print!(", ip {}", Ipv4Addr::from(u32::from_be(ip_info.ip.addr)));
+    }
+    println!();
+}
+
+fn heap() {
+    let mem = MemSnapshot::take();
+
+    println!(
+        "free heap {}, free internal {}, min free heap {}, largest free block {}, free psram {}",
+        mem.free_heap,
+        mem.free_internal,
+        mem.min_free_heap,
+        mem.largest_free_block,
+        mem::free_psram()
+    );
+}
@@ -0,0 +1,193 @@
+//! Moving data between two connections in both directions at once, the core of proxies and
+//! bridges, e.g. from a local plain TCP client to a server over TLS:
+//!
+//! ```ignore
+//! let (client, _) = listener.accept().await?;
+//! let server = connector.connect("example.com", 443, &cfg).await?;
+//!
+//! let (sent, received) = copy_bidirectional(&mut client, &mut server).await?;
+//! ```
+//!
+//! Once one side has nothing more to send, i.e. a read returns 0, its data is flushed and the
+//! other side is closed for writing, while data still flows the other way. For an
+//! [`AsyncTcp`](crate::AsyncTcp) that shuts down the sending half of the socket, so the peer
+//! sees the end of the data. [`AsyncTls`](crate::AsyncTls) has no half-close, closing only
+//! flushes it, so the copy goes on until the TLS peer closes as well.
+
+use core::{
+    future::poll_fn,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use std::io;
+
+use futures_lite::{AsyncRead, AsyncWrite};
+
+use crate::mem::{self, Buffer};
+
+/// Size of the buffer of each direction.
+const BUFFER_LEN: usize = 4 * 1024;
+
+/// Copy from `a` to `b` and from `b` to `a` until both reached their end, see the
+/// [module docs](self).
+///
+/// Returns the bytes copied from `a` to `b` and from `b` to `a`. Fails on the first error of
+/// either side, dropping the data read but not yet written.
+pub async fn copy_bidirectional<A, B>(a: &mut A, b: &mut B) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let mut a_to_b = OneWay::new();
+    let mut b_to_a = OneWay::new();
+
+    poll_fn(|cx| {
+        let a_to_b = a_to_b.poll_copy(cx, &mut *a, &mut *b)?;
+        let b_to_a = b_to_a.poll_copy(cx, &mut *b, &mut *a)?;
+
+        match (a_to_b, b_to_a) {
+            (Poll::Ready(a_to_b), Poll::Ready(b_to_a)) => Poll::Ready(Ok((a_to_b, b_to_a))),
+            _ => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// One direction of [`copy_bidirectional`].
+struct OneWay {
+    buf: Buffer,
+    /// The data read and not yet written, `buf[pos..filled]`
+    pos: usize,
+    filled: usize,
+    /// Total bytes written
+    copied: u64,
+    /// The reader reached its end
+    read_done: bool,
+    /// The writer was closed after `read_done`
+    done: bool,
+    /// Written since the last flush
+    need_flush: bool,
+}
+
+impl OneWay {
+    fn new() -> Self {
+        Self {
+            buf: mem::alloc_buffer(BUFFER_LEN),
+            pos: 0,
+            filled: 0,
+            copied: 0,
+            read_done: false,
+            done: false,
+            need_flush: false,
+        }
+    }
+
+    /// Copy until `reader` ended and `writer` is closed, returning the total bytes copied.
+    fn poll_copy<R, W>(
+        &mut self,
+        cx: &mut Context<'_>,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> Poll<io::Result<u64>>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        loop {
+            if self.done {
+                return Poll::Ready(Ok(self.copied));
+            }
+
+            if self.pos == self.filled && !self.read_done {
+                match Pin::new(&mut *reader).poll_read(cx, &mut self.buf) {
+                    Poll::Ready(Ok(0)) => self.read_done = true,
+                    Poll::Ready(Ok(read)) => {
+                        self.pos = 0;
+                        self.filled = read;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => {
+                        // Send what was written so far while waiting for more
+                        if self.need_flush {
+                            ready!(Pin::new(&mut *writer).poll_flush(cx))?;
+                            self.need_flush = false;
+                        }
+
+                        return Poll::Pending;
+                    }
+                }
+            }
+
+            while self.pos < self.filled {
+                let written = ready!(
+                    Pin::new(&mut *writer).poll_write(cx, &self.buf[self.pos..self.filled])
+                )?;
+                if written == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+
+                self.pos += written;
+                self.copied += written as u64;
+                self.need_flush = true;
+            }
+
+            if self.read_done && self.pos == self.filled {
+                // Flushes as well
+                ready!(Pin::new(&mut *writer).poll_close(cx))?;
+                self.done = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::{future, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::mock::MockSocket;
+
+    #[test]
+    fn half_close() {
+        // Smaller than the data, so that the copy has to wait for both sides
+        let (mut client, mut a) = MockSocket::pair_with_capacity(1000);
+        let (mut b, mut server) = MockSocket::pair_with_capacity(1000);
+        let request: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+
+        let client = async {
+            client.write_all(&request).await.unwrap();
+            client.close().await.unwrap();
+
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            response
+        };
+        let server = async {
+            // Ends once the client's close went through
+            let mut received = Vec::new();
+            server.read_to_end(&mut received).await.unwrap();
+            assert!(received == request);
+
+            // The other direction is still open
+            server.write_all(b"response").await.unwrap();
+            server.close().await.unwrap();
+        };
+
+        let (copied, (response, ())) = future::block_on(future::zip(
+            copy_bidirectional(&mut a, &mut b),
+            future::zip(client, server),
+        ));
+        assert_eq!(copied.unwrap(), (10_000, 8));
+        assert_eq!(response, b"response");
+    }
+
+    #[test]
+    fn nothing_to_copy() {
+        let (client, mut a) = MockSocket::pair();
+        let (mut b, server) = MockSocket::pair();
+        drop((client, server));
+
+        let copied = future::block_on(copy_bidirectional(&mut a, &mut b));
+        assert_eq!(copied.unwrap(), (0, 0));
+    }
+}
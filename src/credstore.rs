@@ -0,0 +1,277 @@
+//! Credentials in NVS that are replaced as a whole, so that a rotation interrupted by a reset or
+//! power loss leaves the device with either the old or the new set, never half of each.
+//!
+//! ```ignore
+//! let mut store = CredStore::new(EspNvs::new(nvs_partition, "creds", true)?);
+//! let creds = store.load()?.expect("provisioned in the factory");
+//! let cfg = Config {
+//!     ca_cert: creds.ca_cert.as_deref().map(x509),
+//!     client_cert: creds.client_cert.as_deref().map(x509),
+//!     client_key: creds.client_key.as_deref().map(x509),
+//!     ..Default::default()
+//! };
+//!
+//! // Later, with new credentials received from the backend
+//! store.replace(&new_creds)?;
+//! ```
+//!
+//! The store keeps two slots. A replace writes the inactive one and then switches over with a
+//! single NVS write, which NVS performs atomically. The previous set stays in the other slot
+//! until the next replace, for [`rollback`](CredStore::rollback).
+
+#[cfg(feature = "esp")]
+use esp_idf_svc::{
+    nvs::{EspNvs, NvsPartitionId},
+    tls::X509,
+};
+
+use crate::error::{Error, Result};
+
+/// Entries larger than this are not loaded, and not stored either.
+const MAX_ENTRY_LEN: usize = 8 * 1024;
+
+#[cfg(feature = "esp")]
+const ACTIVE_KEY: &str = "active";
+
+/// A set of credentials with a version, which increases with every replacement.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Credentials {
+    pub version: u32,
+    /// CA certificate or bundle, PEM (nul terminated) or DER
+    pub ca_cert: Option<Vec<u8>>,
+    pub client_cert: Option<Vec<u8>>,
+    pub client_key: Option<Vec<u8>>,
+    /// E.g. for an HTTP API
+    pub api_token: Option<String>,
+}
+
+impl Credentials {
+    /// Fail if an entry is too long to be loaded again, see [`MAX_ENTRY_LEN`].
+    #[cfg_attr(not(feature = "esp"), allow(dead_code))]
+    fn check_len(&self) -> Result<()> {
+        let entries = [
+            ("CA certificate", self.ca_cert.as_deref()),
+            ("client certificate", self.client_cert.as_deref()),
+            ("client key", self.client_key.as_deref()),
+            ("API token", self.api_token.as_deref().map(str::as_bytes)),
+        ];
+
+        for (name, data) in entries {
+            let len = data.map_or(0, <[u8]>::len);
+            if len > MAX_ENTRY_LEN {
+                return Err(Error::Credentials(format!(
+                    "the {name} is {len} bytes, at most {MAX_ENTRY_LEN} can be stored"
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// `data` as PEM if it is nul terminated, as DER otherwise, for the esp-tls
+/// [`Config`](esp_idf_svc::tls::Config).
+#[cfg(feature = "esp")]
+pub fn x509(data: &[u8]) -> X509<'_> {
+    match data.last() {
+        Some(0) => X509::pem_until_nul(data),
+        _ => X509::der(data),
+    }
+}
+
+#[cfg(feature = "esp")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Slot {
+    A,
+    B,
+}
+
+#[cfg(feature = "esp")]
+impl Slot {
+    fn other(self) -> Self {
+        match self {
+            Self::A => Self::B,
+            Self::B => Self::A,
+        }
+    }
+
+    /// NVS keys are limited to 15 characters.
+    fn key(self, entry: &str) -> String {
+        match self {
+            Self::A => format!("a.{entry}"),
+            Self::B => format!("b.{entry}"),
+        }
+    }
+}
+
+/// See the [module docs](self).
+#[cfg(feature = "esp")]
+pub struct CredStore<T: NvsPartitionId> {
+    nvs: EspNvs<T>,
+}
+
+#[cfg(feature = "esp")]
+impl<T: NvsPartitionId> CredStore<T> {
+    /// Use `nvs`, a namespace of its own, for the credentials.
+    pub fn new(nvs: EspNvs<T>) -> Self {
+        Self { nvs }
+    }
+
+    /// The credentials in use, `None` before the first [`replace`](Self::replace).
+    pub fn load(&self) -> Result<Option<Credentials>> {
+        match self.active()? {
+            Some(slot) => self.read(slot),
+            None => Ok(None),
+        }
+    }
+
+    /// The version of the credentials in use.
+    pub fn version(&self) -> Result<Option<u32>> {
+        match self.active()? {
+            Some(slot) => self.nvs.get_u32(&slot.key("ver")).map_err(Error::Nvs),
+            None => Ok(None),
+        }
+    }
+
+    /// Atomically replace the credentials in use with `creds`.
+    ///
+    /// Fails if `creds` is not newer than the credentials in use, so that an old set replayed
+    /// by the backend or a retried rotation doesn't overwrite a newer one.
+    pub fn replace(&mut self, creds: &Credentials) -> Result<()> {
+        let active = self.active()?;
+        if let Some(current) = self.version()? {
+            if creds.version <= current {
+                return Err(Error::Credentials(format!(
+                    "version {} is not newer than {current}",
+                    creds.version
+                )));
+            }
+        }
+        creds.check_len()?;
+
+        let slot = active.map_or(Slot::A, Slot::other);
+        // Invalidate the slot first, in case the previous set there is what remains after a
+        // reset in the middle of writing
+        self.remove(&slot.key("ver"))?;
+        self.write(&slot.key("ca"), creds.ca_cert.as_deref())?;
+        self.write(&slot.key("cert"), creds.client_cert.as_deref())?;
+        self.write(&slot.key("key"), creds.client_key.as_deref())?;
+        self.write(
+            &slot.key("token"),
+            creds.api_token.as_deref().map(str::as_bytes),
+        )?;
+        self.nvs
+            .set_u32(&slot.key("ver"), creds.version)
+            .map_err(Error::Nvs)?;
+
+        self.activate(slot)?;
+        log::info!("switched to credentials version {}", creds.version);
+
+        Ok(())
+    }
+
+    /// Switch back to the credentials in use before the last [`replace`](Self::replace), e.g.
+    /// when the new ones are rejected by the server. Fails if there are none.
+    pub fn rollback(&mut self) -> Result<Credentials> {
+        let Some(active) = self.active()? else {
+            return Err(Error::Credentials("no credentials stored".to_owned()));
+        };
+
+        let previous = active.other();
+        let creds = self
+            .read(previous)?
+            .ok_or_else(|| Error::Credentials("no previous credentials".to_owned()))?;
+
+        self.activate(previous)?;
+        log::warn!("rolled back to credentials version {}", creds.version);
+
+        Ok(creds)
+    }
+
+    fn active(&self) -> Result<Option<Slot>> {
+        Ok(match self.nvs.get_u8(ACTIVE_KEY).map_err(Error::Nvs)? {
+            Some(0) => Some(Slot::A),
+            Some(_) => Some(Slot::B),
+            None => None,
+        })
+    }
+
+    fn activate(&mut self, slot: Slot) -> Result<()> {
+        let value = match slot {
+            Slot::A => 0,
+            Slot::B => 1,
+        };
+
+        self.nvs.set_u8(ACTIVE_KEY, value).map_err(Error::Nvs)?;
+
+        Ok(())
+    }
+
+    /// The credentials in `slot`, `None` if it was never completely written.
+    fn read(&self, slot: Slot) -> Result<Option<Credentials>> {
+        let Some(version) = self.nvs.get_u32(&slot.key("ver")).map_err(Error::Nvs)? else {
+            return Ok(None);
+        };
+
+        let api_token = self
+            .read_entry(&slot.key("token"))?
+            .map(String::from_utf8)
+            .transpose()
+            .map_err(|_| Error::Credentials("the API token is not UTF-8".to_owned()))?;
+
+        Ok(Some(Credentials {
+            version,
+            ca_cert: self.read_entry(&slot.key("ca"))?,
+            client_cert: self.read_entry(&slot.key("cert"))?,
+            client_key: self.read_entry(&slot.key("key"))?,
+            api_token,
+        }))
+    }
+
+    fn read_entry(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let mut buf = vec![0; MAX_ENTRY_LEN];
+        let data = self.nvs.get_raw(key, &mut buf).map_err(Error::Nvs)?;
+
+        Ok(data.map(<[u8]>::to_vec))
+    }
+
+    fn write(&mut self, key: &str, data: Option<&[u8]>) -> Result<()> {
+        match data {
+            Some(data) => {
+                self.nvs.set_raw(key, data).map_err(Error::Nvs)?;
+            }
+            None => self.remove(key)?,
+        }
+
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        self.nvs.remove(key).map_err(Error::Nvs)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_too_long() {
+        let mut creds = Credentials {
+            version: 1,
+            ca_cert: Some(vec![0; MAX_ENTRY_LEN]),
+            client_cert: Some(vec![0; 1024]),
+            client_key: None,
+            api_token: Some("token".to_owned()),
+        };
+        creds.check_len().unwrap();
+
+        creds.client_key = Some(vec![0; MAX_ENTRY_LEN + 1]);
+        let Err(Error::Credentials(msg)) = creds.check_len() else {
+            panic!("a key of {} bytes was accepted", MAX_ENTRY_LEN + 1);
+        };
+        assert!(msg.contains("client key"), "{msg}");
+    }
+}
@@ -0,0 +1,247 @@
+use core::ptr;
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use esp_idf_sys as sys;
+
+use crate::error::{Error, Result};
+
+/// Which address families to connect with, and in which order.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum IpPreference {
+    /// Whatever the resolver returns first.
+    #[default]
+    Any,
+    V4Only,
+    V6Only,
+    /// IPv4 if the name has an A record, IPv6 otherwise.
+    PreferV4,
+    /// IPv6 if the name has an AAAA record, IPv4 otherwise.
+    PreferV6,
+}
+
+impl IpPreference {
+    fn allows(self, ip: &IpAddr) -> bool {
+        match self {
+            Self::V4Only => ip.is_ipv4(),
+            Self::V6Only => ip.is_ipv6(),
+            _ => true,
+        }
+    }
+
+    /// The address families to query, in order.
+    fn families(self) -> &'static [u32] {
+        match self {
+            Self::Any => &[sys::AF_UNSPEC],
+            Self::V4Only => &[sys::AF_INET],
+            Self::V6Only => &[sys::AF_INET6],
+            Self::PreferV4 => &[sys::AF_INET, sys::AF_INET6],
+            Self::PreferV6 => &[sys::AF_INET6, sys::AF_INET],
+        }
+    }
+}
+
+/// An in-memory cache of resolved names, so that reconnects skip the lookup.
+///
+/// Entries expire with the lowest TTL of the records, for names resolved with a
+/// [`DohResolver`](crate::DohResolver). lwIP does not expose the TTL of the records it resolved,
+/// so those entries expire after a fixed maximum age instead (60 seconds unless configured
+/// otherwise). Clones share the cache.
+#[derive(Clone, Debug)]
+pub struct DnsCache {
+    entries: Arc<Mutex<HashMap<(String, IpPreference), CacheEntry>>>,
+    max_age: Duration,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    ips: Vec<IpAddr>,
+    /// `None` if too far out to represent
+    expires: Option<Instant>,
+}
+
+impl Default for DnsCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DnsCache {
+    pub fn new() -> Self {
+        Self {
+            entries: Default::default(),
+            max_age: Duration::from_secs(60),
+        }
+    }
+
+    /// Resolve names without a known TTL again once their entry is older than `max_age`.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Drop all entries, e.g. after switching networks.
+    pub fn flush(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Drop the entries for `hostname`, e.g. because its addresses stopped working.
+    pub fn remove(&self, hostname: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|(host, _), _| host != hostname);
+    }
+
+    pub(crate) fn get(
+        &self,
+        hostname: &str,
+        port: u16,
+        pref: IpPreference,
+    ) -> Option<Vec<SocketAddr>> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(&(hostname.to_owned(), pref)).filter(|entry| {
+            entry
+                .expires
+                .map_or(true, |expires| Instant::now() < expires)
+        })?;
+
+        log::debug!("{hostname} resolved from cache");
+
+        Some(
+            entry
+                .ips
+                .iter()
+                .map(|&ip| SocketAddr::new(ip, port))
+                .collect(),
+        )
+    }
+
+    /// Cache `addrs` for `ttl`, or `max_age` if the TTL is unknown.
+    pub(crate) fn insert(
+        &self,
+        hostname: &str,
+        pref: IpPreference,
+        addrs: &[SocketAddr],
+        ttl: Option<Duration>,
+    ) {
+        self.entries.lock().unwrap().insert(
+            (hostname.to_owned(), pref),
+            CacheEntry {
+                ips: addrs.iter().map(|addr| addr.ip()).collect(),
+                expires: Instant::now().checked_add(ttl.unwrap_or(self.max_age)),
+            },
+        );
+    }
+}
+
+/// Resolve `hostname`, which may also be an IPv4 or (optionally bracketed) IPv6 literal, to the
+/// addresses to try in order.
+pub(crate) fn resolve(hostname: &str, port: u16, pref: IpPreference) -> Result<Vec<SocketAddr>> {
+    let addrs = if let Some(ip) = parse_literal(hostname) {
+        vec![SocketAddr::new(ip, port)]
+    } else if pref == IpPreference::Any {
+        (hostname, port)
+            .to_socket_addrs()
+            .map_err(|source| Error::Dns {
+                host: hostname.to_owned(),
+                source,
+            })?
+            .collect()
+    } else {
+        // lwIP only returns a single address per query, so ask for each family separately
+        let mut addrs = Vec::new();
+        let mut last_err = None;
+
+        for &family in pref.families() {
+            match getaddrinfo(hostname, port, family) {
+                Ok(found) => addrs.extend(found),
+                Err(e) => last_err = Some(e),
+            }
+        }
+
+        if addrs.is_empty() {
+            if let Some(source) = last_err {
+                return Err(Error::Dns {
+                    host: hostname.to_owned(),
+                    source,
+                });
+            }
+        }
+
+        addrs
+    };
+
+    let addrs: Vec<_> = addrs
+        .into_iter()
+        .filter(|addr| pref.allows(&addr.ip()))
+        .collect();
+    if addrs.is_empty() {
+        return Err(Error::NoAddress(hostname.to_owned()));
+    }
+
+    Ok(addrs)
+}
+
+/// Parse an IPv4 or (optionally bracketed) IPv6 literal.
+pub(crate) fn parse_literal(hostname: &str) -> Option<IpAddr> {
+    hostname
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(hostname)
+        .parse()
+        .ok()
+}
+
+fn getaddrinfo(hostname: &str, port: u16, family: u32) -> io::Result<Vec<SocketAddr>> {
+    let name =
+        CString::new(hostname).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let hints = sys::addrinfo {
+        ai_family: family as _,
+        ai_socktype: sys::SOCK_STREAM as _,
+        ..Default::default()
+    };
+
+    let mut res: *mut sys::addrinfo = ptr::null_mut();
+    let ret = unsafe { sys::lwip_getaddrinfo(name.as_ptr(), ptr::null(), &hints, &mut res) };
+    if ret != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("getaddrinfo failed: {ret}"),
+        ));
+    }
+
+    let mut addrs = Vec::new();
+    let mut ai = res;
+    while let Some(info) = unsafe { ai.as_ref() } {
+        if let Some(ip) = unsafe { ip_from_sockaddr(info.ai_addr) } {
+            addrs.push(SocketAddr::new(ip, port));
+        }
+
+        ai = info.ai_next;
+    }
+
+    unsafe { sys::lwip_freeaddrinfo(res) };
+
+    Ok(addrs)
+}
+
+unsafe fn ip_from_sockaddr(addr: *const sys::sockaddr) -> Option<IpAddr> {
+    match addr.as_ref()?.sa_family as u32 {
+        sys::AF_INET => {
+            let addr = &*(addr as *const sys::sockaddr_in);
+            Some(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).into())
+        }
+        sys::AF_INET6 => {
+            let addr = &*(addr as *const sys::sockaddr_in6);
+            Some(Ipv6Addr::from(addr.sin6_addr.un.u8_addr).into())
+        }
+        _ => None,
+    }
+}
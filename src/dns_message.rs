@@ -0,0 +1,234 @@
+//! The DNS wire format as far as [`DohResolver`](crate::DohResolver) needs it: A and AAAA
+//! queries and the addresses in their answers.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+pub(crate) const TYPE_A: u16 = 1;
+pub(crate) const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+pub(crate) fn build_query(hostname: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    // ID 0 as recommended for DoH, recursion desired, one question
+    let mut query = vec![0, 0, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+
+    for label in hostname.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid hostname",
+            ));
+        }
+
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Ok(query)
+}
+
+/// The addresses of type `qtype` in the answer `msg` and the lowest TTL of its answers, in
+/// seconds.
+pub(crate) fn parse_response(msg: &[u8], qtype: u16) -> io::Result<(Vec<IpAddr>, Option<u32>)> {
+    let u16_at = |pos: usize| -> io::Result<u16> {
+        msg.get(pos..pos + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(|| invalid("truncated DNS message"))
+    };
+
+    let flags = u16_at(2)?;
+    match flags & 0xf {
+        0 => (),
+        // NXDOMAIN, answered with an empty list rather than an error so that the other address
+        // family still gets a chance
+        3 => return Ok((Vec::new(), None)),
+        rcode => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("DNS server returned error {rcode}"),
+            ))
+        }
+    }
+
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(msg, pos)? + 4;
+    }
+
+    let mut ips = Vec::new();
+    let mut min_ttl = None;
+    for _ in 0..answers {
+        pos = skip_name(msg, pos)?;
+        let rtype = u16_at(pos)?;
+        let ttl = u32::from(u16_at(pos + 4)?) << 16 | u32::from(u16_at(pos + 6)?);
+        let rdlen = u16_at(pos + 8)? as usize;
+        pos += 10;
+
+        // CNAMEs included, the chain is only valid as long as each of its records
+        min_ttl = Some(min_ttl.map_or(ttl, |min: u32| min.min(ttl)));
+
+        let rdata = msg
+            .get(pos..pos + rdlen)
+            .ok_or_else(|| invalid("truncated DNS message"))?;
+        pos += rdlen;
+
+        // Skips CNAMEs, the resolver already followed them
+        match (rtype, rdata.len()) {
+            (TYPE_A, 4) if qtype == TYPE_A => {
+                ips.push(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]).into());
+            }
+            (TYPE_AAAA, 16) if qtype == TYPE_AAAA => {
+                let octets: [u8; 16] = rdata.try_into().unwrap();
+                ips.push(Ipv6Addr::from(octets).into());
+            }
+            _ => (),
+        }
+    }
+
+    Ok((ips, min_ttl))
+}
+
+/// Returns the position right after the (possibly compressed) name at `pos`.
+fn skip_name(msg: &[u8], mut pos: usize) -> io::Result<usize> {
+    loop {
+        let len = *msg
+            .get(pos)
+            .ok_or_else(|| invalid("truncated DNS message"))?;
+
+        match len {
+            0 => return Ok(pos + 1),
+            // A pointer ends the name
+            len if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TYPE_CNAME: u16 = 5;
+
+    fn record(rtype: u16, ttl: u32, rdata: &[u8]) -> Vec<u8> {
+        // The name is a pointer to the question
+        let mut record = vec![0xc0, 12];
+        record.extend_from_slice(&rtype.to_be_bytes());
+        record.extend_from_slice(&CLASS_IN.to_be_bytes());
+        record.extend_from_slice(&ttl.to_be_bytes());
+        record.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        record.extend_from_slice(rdata);
+        record
+    }
+
+    fn response(rcode: u8, qtype: u16, answers: &[Vec<u8>]) -> Vec<u8> {
+        let mut msg = build_query("example.com", qtype).unwrap();
+        msg[2] = 0x81;
+        msg[3] = 0x80 | rcode;
+        msg[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+        for answer in answers {
+            msg.extend_from_slice(answer);
+        }
+        msg
+    }
+
+    #[test]
+    fn query() {
+        let query = build_query("www.example.com.", TYPE_AAAA).unwrap();
+
+        assert_eq!(
+            query,
+            b"\0\0\x01\0\0\x01\0\0\0\0\0\0\x03www\x07example\x03com\0\0\x1c\0\x01"
+        );
+    }
+
+    #[test]
+    fn query_invalid_hostname() {
+        for hostname in ["", "a..b", "x".repeat(64).as_str()] {
+            let err = build_query(hostname, TYPE_A).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{hostname}");
+        }
+    }
+
+    #[test]
+    fn addresses_and_lowest_ttl() {
+        let msg = response(
+            0,
+            TYPE_A,
+            &[
+                record(TYPE_CNAME, 30, b"\x03cdn\xc0\x0c"),
+                record(TYPE_A, 300, &[93, 184, 216, 34]),
+                record(TYPE_A, 120, &[93, 184, 216, 35]),
+            ],
+        );
+
+        let (ips, ttl) = parse_response(&msg, TYPE_A).unwrap();
+        assert_eq!(
+            ips,
+            [
+                IpAddr::from([93, 184, 216, 34]),
+                IpAddr::from([93, 184, 216, 35])
+            ]
+        );
+        assert_eq!(ttl, Some(30));
+    }
+
+    #[test]
+    fn only_the_queried_type() {
+        let v6 = Ipv6Addr::new(0x2606, 0x2800, 0x220, 1, 0x248, 0x1893, 0x25c8, 0x1946);
+        let msg = response(
+            0,
+            TYPE_AAAA,
+            &[
+                record(TYPE_A, 60, &[93, 184, 216, 34]),
+                record(TYPE_AAAA, 60, &v6.octets()),
+            ],
+        );
+
+        let (ips, ttl) = parse_response(&msg, TYPE_AAAA).unwrap();
+        assert_eq!(ips, [IpAddr::from(v6)]);
+        assert_eq!(ttl, Some(60));
+    }
+
+    #[test]
+    fn no_answers() {
+        assert_eq!(
+            parse_response(&response(0, TYPE_A, &[]), TYPE_A).unwrap(),
+            (vec![], None)
+        );
+        // NXDOMAIN
+        assert_eq!(
+            parse_response(&response(3, TYPE_A, &[]), TYPE_A).unwrap(),
+            (vec![], None)
+        );
+    }
+
+    #[test]
+    fn server_error() {
+        // SERVFAIL
+        let err = parse_response(&response(2, TYPE_A, &[]), TYPE_A).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn truncated() {
+        let msg = response(0, TYPE_A, &[record(TYPE_A, 60, &[93, 184, 216, 34])]);
+
+        for len in [0, 5, 20, msg.len() - 1] {
+            let err = parse_response(&msg[..len], TYPE_A).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{len}");
+        }
+    }
+}
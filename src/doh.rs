@@ -0,0 +1,213 @@
+//! Resolving names with DNS-over-HTTPS (RFC 8484), for networks where plain DNS is filtered or
+//! not to be trusted.
+
+use std::{io, net::SocketAddr, time::Duration};
+
+use esp_idf_svc::tls::{Config, X509};
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    connector::TlsConnector,
+    dns::{self, IpPreference},
+    dns_message::{build_query, parse_response, TYPE_A, TYPE_AAAA},
+    error::{Error, Result},
+    tcp::{self, AsyncTcp},
+    tls::AsyncTls,
+};
+
+/// Upper bound for a DoH response, DNS messages over HTTPS are limited to 64 KiB but answers
+/// with just addresses are way smaller.
+const MAX_RESPONSE_LEN: usize = 8 * 1024;
+
+/// A DNS-over-HTTPS provider, see [`TlsConnector::doh_resolver`].
+#[derive(Clone, Debug)]
+pub struct DohResolver {
+    host: String,
+    path: String,
+    bootstrap: Option<SocketAddr>,
+    ca_cert: Option<X509<'static>>,
+}
+
+impl DohResolver {
+    /// Send queries to `https://{host}{path}`, e.g. `cloudflare-dns.com` and `/dns-query`.
+    ///
+    /// The server is verified with the certificate bundle, unless a CA certificate is set.
+    pub fn new(host: &str, path: &str) -> Self {
+        Self {
+            host: host.to_owned(),
+            path: path.to_owned(),
+            bootstrap: None,
+            ca_cert: None,
+        }
+    }
+
+    /// Connect to the provider at `addr` rather than resolving its name with the system resolver.
+    pub fn bootstrap_addr(mut self, addr: SocketAddr) -> Self {
+        self.bootstrap = Some(addr);
+        self
+    }
+
+    /// Verify the provider with `ca_cert` instead of the certificate bundle.
+    pub fn ca_cert(mut self, ca_cert: X509<'static>) -> Self {
+        self.ca_cert = Some(ca_cert);
+        self
+    }
+
+    /// The addresses of `hostname` and how long they may be cached, the lowest TTL of the
+    /// answers. The TTL is `None` for literals and names without answers.
+    pub(crate) async fn resolve(
+        &self,
+        hostname: &str,
+        port: u16,
+        pref: IpPreference,
+    ) -> Result<(Vec<SocketAddr>, Option<Duration>)> {
+        if let Some(ip) = dns::parse_literal(hostname) {
+            return Ok((vec![SocketAddr::new(ip, port)], None));
+        }
+
+        let qtypes: &[u16] = match pref {
+            IpPreference::V4Only => &[TYPE_A],
+            IpPreference::V6Only => &[TYPE_AAAA],
+            IpPreference::PreferV6 => &[TYPE_AAAA, TYPE_A],
+            IpPreference::Any | IpPreference::PreferV4 => &[TYPE_A, TYPE_AAAA],
+        };
+
+        let dns_error = |source| Error::Dns {
+            host: hostname.to_owned(),
+            source,
+        };
+
+        // The queries share one connection, unless the provider closes it after the first
+        let mut conn = None;
+        let mut addrs = Vec::new();
+        let mut ttl = None;
+        for &qtype in qtypes {
+            let query = build_query(hostname, qtype).map_err(dns_error)?;
+            let response = self.exchange(&mut conn, &query).await?;
+            let (ips, answer_ttl) = parse_response(&response, qtype).map_err(dns_error)?;
+
+            addrs.extend(ips.into_iter().map(|ip| SocketAddr::new(ip, port)));
+            ttl = match (ttl, answer_ttl) {
+                (Some(ttl), Some(answer_ttl)) => Some(u32::min(ttl, answer_ttl)),
+                (ttl, answer_ttl) => ttl.or(answer_ttl),
+            };
+        }
+
+        if addrs.is_empty() {
+            return Err(Error::NoAddress(hostname.to_owned()));
+        }
+
+        Ok((addrs, ttl.map(|secs| Duration::from_secs(secs.into()))))
+    }
+
+    /// POST `query` to the provider over `conn`, connected first if empty, and return the DNS
+    /// message it answers with. Leaves `conn` empty if the provider doesn't keep it open.
+    async fn exchange(&self, conn: &mut Option<AsyncTls>, query: &[u8]) -> Result<Vec<u8>> {
+        if conn.is_none() {
+            *conn = Some(self.connect().await?);
+        }
+        let tls = conn.as_mut().unwrap();
+
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nAccept: application/dns-message\r\n\
+             Content-Type: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+            self.path,
+            self.host,
+            query.len()
+        );
+        tls.write_all(request.as_bytes()).await?;
+        tls.write_all(query).await?;
+        tls.flush().await?;
+
+        // Up to the end of the body if the provider sent its length, until it closes otherwise
+        let mut response = Vec::new();
+        let mut head: Option<(String, Option<usize>)> = None;
+        let mut closed = false;
+        let mut buf = [0; 512];
+        loop {
+            if head.is_none() {
+                if let Some(head_len) = response.windows(4).position(|w| w == b"\r\n\r\n") {
+                    let text = String::from_utf8_lossy(&response[..head_len]).into_owned();
+                    let content_len = header(&text, "content-length")
+                        .map(|len| len.parse().map_err(|_| invalid("bad Content-Length")))
+                        .transpose()?;
+
+                    response.drain(..head_len + 4);
+                    head = Some((text, content_len));
+                }
+            }
+
+            if let Some((_, Some(len))) = head {
+                if response.len() >= len {
+                    response.truncate(len);
+                    break;
+                }
+            }
+
+            match tls.read(&mut buf).await? {
+                0 => {
+                    closed = true;
+                    break;
+                }
+                read => response.extend_from_slice(&buf[..read]),
+            }
+
+            if response.len() > MAX_RESPONSE_LEN {
+                return Err(invalid("DoH response too long").into());
+            }
+        }
+
+        let (head, content_len) = head.ok_or_else(|| invalid("malformed DoH response"))?;
+        let status_line = head.lines().next().unwrap_or_default();
+        if status_line.split_whitespace().nth(1) != Some("200") {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("DoH request failed: {status_line}"),
+            )
+            .into());
+        }
+        if header(&head, "transfer-encoding").is_some() {
+            return Err(invalid("chunked DoH response").into());
+        }
+        if matches!(content_len, Some(len) if response.len() < len) {
+            return Err(invalid("truncated DoH response").into());
+        }
+
+        let close = header(&head, "connection").map_or(false, |c| c.eq_ignore_ascii_case("close"));
+        if closed || content_len.is_none() || close {
+            *conn = None;
+        }
+
+        Ok(response)
+    }
+
+    async fn connect(&self) -> Result<AsyncTls> {
+        let cfg = Config {
+            ca_cert: self.ca_cert,
+            #[cfg(esp_idf_mbedtls_certificate_bundle)]
+            use_crt_bundle_attach: self.ca_cert.is_none(),
+            ..Default::default()
+        };
+
+        // Resolving the provider must not go through the provider
+        let tcp = match self.bootstrap {
+            Some(addr) => tcp::connect_addr(addr).await?,
+            None => tcp::connect(&self.host, 443, Default::default()).await?,
+        };
+        TlsConnector::new()
+            .adopt(AsyncTcp::new(tcp), &self.host, &cfg)
+            .await
+    }
+}
+
+/// The value of the header `name` in the response `head`.
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
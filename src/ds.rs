@@ -0,0 +1,111 @@
+//! Client keys protected by the Digital Signature (DS) peripheral of the ESP32-S2, -S3, -C3 and
+//! -C6, so that the device identity can't be cloned from a flash dump.
+//!
+//! The RSA key is provisioned in the factory: `configure_ds.py` of ESP-IDF burns an HMAC key
+//! into an eFuse block and encrypts the private key with it. Only the ciphertext is stored on
+//! the device, usually in the NVS partition the script generates, and only the DS peripheral
+//! can use it, without the key ever being readable by software.
+//!
+//! ```ignore
+//! let nvs = EspNvs::new(nvs_partition, DsKey::NVS_NAMESPACE, false)?;
+//! let connector = TlsConnector::new().ds_key(DsKey::load(&nvs)?);
+//! let cfg = Config {
+//!     client_cert: Some(X509::pem_until_nul(DEVICE_CERT)),
+//!     ..Default::default()
+//! };
+//! let tls = connector.connect("mqtt.example.com", 8883, &cfg).await?;
+//! ```
+//!
+//! Requires `CONFIG_ESP_TLS_USE_DS_PERIPHERAL`. The [`Config`](esp_idf_svc::tls::Config) needs
+//! the client certificate, its `client_key` is ignored.
+
+use esp_idf_svc::nvs::{EspNvs, NvsPartitionId};
+use esp_idf_sys::{self as sys, EspError, ESP_ERR_INVALID_ARG};
+
+use crate::error::{Error, Result};
+
+/// The ciphertext of a DS key, see the [module docs](self).
+pub struct DsKey {
+    data: Box<sys::esp_ds_data_t>,
+    efuse_key_id: u8,
+    rsa_length_bits: u16,
+}
+
+impl DsKey {
+    /// The namespace `configure_ds.py` stores the key in.
+    pub const NVS_NAMESPACE: &'static str = "esp_ds_ns";
+
+    /// A key encrypted with the HMAC key in eFuse key block `efuse_key_id`, as `c` and `iv` of
+    /// the DS parameters.
+    pub fn new(
+        efuse_key_id: u8,
+        rsa_length_bits: u16,
+        iv: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Self, EspError> {
+        let mut data: Box<sys::esp_ds_data_t> = Box::new(unsafe { core::mem::zeroed() });
+
+        let supported = rsa_length_bits % 32 == 0
+            && (1024..=sys::SOC_RSA_MAX_BIT_LEN as u16).contains(&rsa_length_bits);
+        if !supported
+            || iv.len() != core::mem::size_of_val(&data.iv)
+            || ciphertext.len() != data.c.len()
+        {
+            log::error!("invalid DS key parameters for RSA-{rsa_length_bits}");
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        // The length in 32 bit words, minus one
+        data.rsa_length = (rsa_length_bits / 32 - 1) as _;
+        for (word, bytes) in data.iv.iter_mut().zip(iv.chunks_exact(4)) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        data.c.copy_from_slice(ciphertext);
+
+        Ok(Self {
+            data,
+            efuse_key_id,
+            rsa_length_bits,
+        })
+    }
+
+    /// Read the key from the entries `configure_ds.py` writes to the
+    /// [`NVS_NAMESPACE`](Self::NVS_NAMESPACE).
+    pub fn load<T: NvsPartitionId>(nvs: &EspNvs<T>) -> Result<Self> {
+        let missing = |name: &str| {
+            log::error!("no {name} in NVS, is the DS key provisioned?");
+            Error::Nvs(EspError::from_infallible::<{ sys::ESP_ERR_NVS_NOT_FOUND }>())
+        };
+
+        let efuse_key_id = nvs
+            .get_u8("esp_ds_key_id")
+            .map_err(Error::Nvs)?
+            .ok_or_else(|| missing("esp_ds_key_id"))?;
+        let rsa_length_bits = nvs
+            .get_u16("esp_ds_rsa_len")
+            .map_err(Error::Nvs)?
+            .ok_or_else(|| missing("esp_ds_rsa_len"))?;
+
+        let mut iv = [0; 16];
+        let iv = nvs
+            .get_raw("esp_ds_iv", &mut iv)
+            .map_err(Error::Nvs)?
+            .ok_or_else(|| missing("esp_ds_iv"))?;
+        let mut c = vec![0; sys::ESP_DS_C_LEN as usize];
+        let c = nvs
+            .get_raw("esp_ds_c", &mut c)
+            .map_err(Error::Nvs)?
+            .ok_or_else(|| missing("esp_ds_c"))?;
+
+        Self::new(efuse_key_id, rsa_length_bits, iv, c).map_err(Error::TlsSetup)
+    }
+
+    /// The context for `esp_tls_cfg::ds_data`, which esp-tls keeps a pointer to the key from.
+    pub(crate) fn raw_ctx(&self) -> sys::esp_ds_data_ctx_t {
+        sys::esp_ds_data_ctx_t {
+            esp_ds_data: self.data.as_ref() as *const _ as *mut _,
+            efuse_key_id: self.efuse_key_id,
+            rsa_length_bits: self.rsa_length_bits,
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::{io, net::SocketAddr};
+
+#[cfg(feature = "esp")]
+use esp_idf_sys::EspError;
+
+/// Errors returned by the public APIs of this crate.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to resolve {host}: {source}")]
+    Dns { host: String, source: io::Error },
+    #[error("{0} did not resolve to any address")]
+    NoAddress(String),
+    #[error("TCP connect to {addr} failed: {source}")]
+    TcpConnect { addr: SocketAddr, source: io::Error },
+    #[cfg(feature = "esp")]
+    #[error("failed to set up the TLS session: {0}")]
+    TlsSetup(EspError),
+    #[cfg(feature = "esp")]
+    #[error("TLS handshake failed: {0}")]
+    TlsHandshake(EspError),
+    #[error("proxy error: {0}")]
+    Proxy(String),
+    #[error("HTTP error: {0}")]
+    Http(String),
+    #[cfg(feature = "json")]
+    #[error("invalid JSON: {0}")]
+    Json(serde_json::Error),
+    /// An invalid configuration file, starting with the path of the offending key, e.g.
+    /// `wifi.ssid: ...`.
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    #[cfg(feature = "esp")]
+    #[error("OTA update failed: {0}")]
+    Ota(EspError),
+    #[error("firmware image rejected: {0}")]
+    OtaVerification(String),
+    #[cfg(feature = "esp")]
+    #[error("failed to register the eventfd VFS for the async runtime: {0}")]
+    AsyncRuntime(EspError),
+    #[cfg(feature = "backend-rustls")]
+    #[error("rustls error: {0}")]
+    Rustls(futures_rustls::rustls::Error),
+    #[error("WebSocket error: {0}")]
+    WebSocket(String),
+    #[cfg(feature = "esp")]
+    #[error("WiFi setup failed: {0}")]
+    Wifi(EspError),
+    #[cfg(feature = "esp")]
+    #[error("network interface error: {0}")]
+    Netif(EspError),
+    #[cfg(feature = "esp")]
+    #[error("NVS error: {0}")]
+    Nvs(EspError),
+    #[error("credential store: {0}")]
+    Credentials(String),
+    #[error("modem error: {0}")]
+    Modem(String),
+    /// The connection was unused for longer than its idle timeout and has been closed, see
+    /// `AsyncTls::set_idle_timeout`. Reads and writes report it as the source of an
+    /// `io::ErrorKind::NotConnected` error.
+    #[error("the connection was closed after being idle")]
+    IdleClosed,
+    /// The [`ConnectionLimit`](crate::limit::ConnectionLimit) was reached and fails fast.
+    #[error("too many TLS connections, at most {0} may be open")]
+    TooManyConnections(usize),
+    /// Less internal heap was free than the connector's
+    /// [`min_free_heap`](crate::TlsConnector::min_free_heap) before setting up a session.
+    #[error("not enough internal heap for TLS: {free} bytes free, {required} required")]
+    InsufficientMemory { free: usize, required: usize },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T, E = Error> = core::result::Result<T, E>;
@@ -0,0 +1,39 @@
+//! Ethernet as the uplink instead of WiFi, with an SPI (e.g. W5500) or RMII (e.g. LAN8720) PHY.
+//!
+//! The driver is set up by the application, as it depends on the board's wiring, e.g. with
+//! `EthDriver::new_rmii` or `EthDriver::new_spi` of esp-idf-svc. Use [`netif`](crate::netif) to
+//! choose between Ethernet and WiFi at runtime.
+
+use esp_idf_svc::{
+    eth::{BlockingEth, EspEth, EthDriver},
+    eventloop::EspSystemEventLoop,
+};
+use log::*;
+
+use crate::error::{Error, Result};
+
+/// Start `driver` and wait for a link and a DHCP lease.
+///
+/// The interface is up as long as the returned driver is kept around.
+pub fn start<T>(
+    driver: EthDriver<'static, T>,
+    sysloop: EspSystemEventLoop,
+) -> Result<Box<EspEth<'static, T>>> {
+    let mut esp_eth = EspEth::wrap(driver).map_err(Error::Netif)?;
+
+    let mut eth = BlockingEth::wrap(&mut esp_eth, sysloop).map_err(Error::Netif)?;
+
+    info!("Starting ethernet...");
+
+    eth.start().map_err(Error::Netif)?;
+
+    info!("Waiting for DHCP lease...");
+
+    eth.wait_netif_up().map_err(Error::Netif)?;
+
+    let ip_info = eth.eth().netif().get_ip_info().map_err(Error::Netif)?;
+
+    info!("Ethernet DHCP info: {:?}", ip_info);
+
+    Ok(Box::new(esp_eth))
+}
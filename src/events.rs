@@ -0,0 +1,125 @@
+//! Connection lifecycle events on the system event loop, so that other parts of the firmware,
+//! e.g. a status LED or a power manager, can follow the connections without holding them.
+//!
+//! ```ignore
+//! ConnectionEvents::install(sysloop.clone());
+//!
+//! let _subscription = sysloop.subscribe(move |event: &ConnectionEvent| match event {
+//!     ConnectionEvent::TlsHandshakeDone => led.set_high().unwrap(),
+//!     ConnectionEvent::Disconnected { .. } => led.set_low().unwrap(),
+//!     _ => {}
+//! })?;
+//! ```
+//!
+//! Events are posted for the connections of [`TlsConnector`](crate::TlsConnector) and for every
+//! [`AsyncTls`](crate::AsyncTls) that completes a handshake. They are posted without waiting, an
+//! event that doesn't fit the queue of the event loop is dropped with a warning. C components
+//! can subscribe to the `ASYNC_TLS_EVENT` base, with the event ids of [`ConnectionEvent::id`].
+
+use std::{ffi::c_char, sync::Mutex, time::Duration};
+
+use esp_idf_svc::eventloop::{
+    EspEventFetchData, EspEventPostData, EspSystemEventLoop, EspTypedEventDeserializer,
+    EspTypedEventSerializer, EspTypedEventSource,
+};
+
+/// The event base, compared by address by esp_event.
+static EVENT_BASE: &[u8] = b"ASYNC_TLS_EVENT\0";
+
+static EVENT_LOOP: Mutex<Option<EspSystemEventLoop>> = Mutex::new(None);
+
+/// A step in the life of a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// Resolving the name of the server
+    Resolving,
+    /// The TCP connection is established, the handshake is next
+    TcpConnected,
+    /// The session is established
+    TlsHandshakeDone,
+    Disconnected {
+        reason: DisconnectReason,
+    },
+}
+
+/// Why a connection ended, see [`ConnectionEvent::Disconnected`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// Connecting or the handshake failed
+    ConnectFailed,
+    /// The peer closed the connection
+    PeerClosed,
+    /// A read or write failed
+    Error,
+    /// Closed for being idle, see [`set_idle_timeout`](crate::AsyncTls::set_idle_timeout)
+    Idle,
+    /// Closed by [`AsyncTls::suspend`](crate::AsyncTls::suspend)
+    Suspended,
+    /// The connection was dropped
+    Dropped,
+}
+
+impl ConnectionEvent {
+    /// The esp_event id of the event, in the order of the variants.
+    pub fn id(&self) -> i32 {
+        match self {
+            Self::Resolving => 0,
+            Self::TcpConnected => 1,
+            Self::TlsHandshakeDone => 2,
+            Self::Disconnected { .. } => 3,
+        }
+    }
+}
+
+impl EspTypedEventSource for ConnectionEvent {
+    fn source() -> *const c_char {
+        EVENT_BASE.as_ptr() as *const c_char
+    }
+}
+
+impl EspTypedEventSerializer<ConnectionEvent> for ConnectionEvent {
+    fn serialize<R>(
+        payload: &ConnectionEvent,
+        f: impl for<'a> FnOnce(&'a EspEventPostData) -> R,
+    ) -> R {
+        f(&unsafe { EspEventPostData::new(Self::source(), Some(payload.id()), payload) })
+    }
+}
+
+impl EspTypedEventDeserializer<ConnectionEvent> for ConnectionEvent {
+    fn deserialize<R>(
+        data: &EspEventFetchData,
+        f: &mut impl for<'a> FnMut(&'a ConnectionEvent) -> R,
+    ) -> R {
+        // Only ever posted by `post` below
+        f(unsafe { data.as_payload::<ConnectionEvent>() })
+    }
+}
+
+/// Where [`ConnectionEvent`]s are posted, see the [module docs](self).
+pub struct ConnectionEvents;
+
+impl ConnectionEvents {
+    /// Post the events of the connections from now on to `sysloop`.
+    pub fn install(sysloop: EspSystemEventLoop) {
+        *EVENT_LOOP.lock().unwrap() = Some(sysloop);
+    }
+
+    /// Stop posting events.
+    pub fn uninstall() {
+        *EVENT_LOOP.lock().unwrap() = None;
+    }
+}
+
+/// Post `event` to the installed event loop, if any, without waiting.
+pub(crate) fn post(event: ConnectionEvent) {
+    let Some(sysloop) = EVENT_LOOP.lock().unwrap().clone() else {
+        return;
+    };
+
+    match sysloop.post(&event, Some(Duration::ZERO)) {
+        Ok(true) => {}
+        Ok(false) => log::warn!("event loop is full, dropped {event:?}"),
+        Err(e) => log::warn!("posting {event:?} failed: {e}"),
+    }
+}
@@ -0,0 +1,154 @@
+//! Running the crate's futures on executors other than `async_io::block_on`.
+//!
+//! Sockets are registered with the async-io reactor unless another one is selected, see
+//! [`reactor`](crate::reactor). async-io works with any executor: while no thread is inside
+//! `async_io::block_on`, async-io drives the reactor on a thread of its own. All reactors wake
+//! themselves up through an eventfd, so the eventfd VFS has to be registered before the first
+//! socket, whichever executor is used. The crate does that itself, see [`init_async_runtime`].
+//!
+//! An [`Executor`] only matters where the crate runs futures on threads it spawns itself, i.e.
+//! [`TlsConnector::handshake_stack_size`](crate::TlsConnector::handshake_stack_size) and the UART
+//! console. Application tasks can run on anything, e.g. `edge-executor`:
+//!
+//! ```ignore
+//! let local = edge_executor::LocalExecutor::<8>::new();
+//! let tls = futures_lite::future::block_on(local.run(connector.connect("example.com", 443, &cfg)))?;
+//! ```
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use esp_idf_sys::{EspError, ESP_ERR_INVALID_STATE};
+
+use crate::{
+    error::{Error, Result},
+    reactor::{DefaultReactor, Reactor},
+    tcp,
+};
+
+/// The eventfd descriptors registered when the crate sets up the runtime itself.
+pub const DEFAULT_MAX_FDS: usize = 5;
+
+static INITIALIZED: Mutex<bool> = Mutex::new(false);
+
+type BlockOnFn = dyn Fn(Pin<&mut dyn Future<Output = ()>>) + Send + Sync;
+
+/// Runs a future to completion on the current thread, see the [module docs](self).
+#[derive(Clone)]
+pub struct Executor {
+    block_on: Arc<BlockOnFn>,
+}
+
+/// The one that drives the [`DefaultReactor`](crate::reactor::DefaultReactor): `async_io`,
+/// `select`, or `tokio` with the runtime current where it is created.
+impl Default for Executor {
+    #[cfg(not(any(feature = "select-reactor", feature = "tokio-reactor")))]
+    fn default() -> Self {
+        Self::async_io()
+    }
+
+    #[cfg(feature = "select-reactor")]
+    fn default() -> Self {
+        Self::select()
+    }
+
+    #[cfg(feature = "tokio-reactor")]
+    fn default() -> Self {
+        Self::tokio()
+    }
+}
+
+impl Executor {
+    /// `async_io::block_on`, which also drives the reactor on the calling thread. The default.
+    pub fn async_io() -> Self {
+        Self::custom(|future| async_io::block_on(future))
+    }
+
+    /// `futures_lite::future::block_on`, which only parks the thread and leaves the reactor to
+    /// the async-io thread or another thread in `async_io::block_on`.
+    pub fn futures_lite() -> Self {
+        Self::custom(|future| futures_lite::future::block_on(future))
+    }
+
+    /// [`reactor::select::block_on`](crate::reactor::select::block_on), which drives the
+    /// `select` reactor.
+    #[cfg(feature = "select-reactor")]
+    pub fn select() -> Self {
+        Self::custom(|future| crate::reactor::select::block_on(future))
+    }
+
+    /// `Handle::block_on` of the tokio runtime current at the time of the call, or
+    /// `futures_lite::future::block_on` if there is none.
+    #[cfg(feature = "tokio-reactor")]
+    pub fn tokio() -> Self {
+        let handle = tokio::runtime::Handle::try_current().ok();
+        if handle.is_none() {
+            log::warn!("no tokio runtime to run the crate's threads with");
+        }
+
+        Self::custom(move |future| match &handle {
+            Some(handle) => handle.block_on(future),
+            None => futures_lite::future::block_on(future),
+        })
+    }
+
+    /// Any other executor, `block_on` has to return once `future` completed, e.g.
+    /// `|future| futures_lite::future::block_on(local_executor.run(future))`.
+    pub fn custom<F>(block_on: F) -> Self
+    where
+        F: Fn(Pin<&mut dyn Future<Output = ()>>) + Send + Sync + 'static,
+    {
+        Self {
+            block_on: Arc::new(block_on),
+        }
+    }
+
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let mut output = None;
+        {
+            let future = async {
+                output = Some(future.await);
+            };
+            futures_lite::pin!(future);
+            (self.block_on)(future);
+        }
+
+        output.expect("executor returned before the future completed")
+    }
+}
+
+/// Prepare the [reactor](crate::reactor) for any executor by registering the eventfd VFS with
+/// room for `max_fds` descriptors. Every thread inside `async_io::block_on` and the async-io
+/// thread use one of them, the `select` and tokio reactors one each.
+///
+/// Sockets and listeners of the crate call this with [`DEFAULT_MAX_FDS`] before they are
+/// created, so it is only needed to register more descriptors. Only the first call registers,
+/// later ones return `Ok` right away, as does a call after the application registered the VFS
+/// itself. With async-io, the first call also starts the async-io thread, with the settings of
+/// [`RuntimeConfig::reactor`](crate::RuntimeConfig::reactor).
+pub fn init_async_runtime(max_fds: usize) -> Result<()> {
+    let mut initialized = INITIALIZED.lock().unwrap();
+    if *initialized {
+        return Ok(());
+    }
+
+    match tcp::register_eventfd(max_fds) {
+        Ok(()) => log::info!("registered the eventfd VFS for {max_fds} descriptors"),
+        Err(e) if e.code() == ESP_ERR_INVALID_STATE => {
+            log::debug!("the eventfd VFS was already registered")
+        }
+        Err(e) => return Err(Error::AsyncRuntime(e)),
+    }
+    *initialized = true;
+
+    DefaultReactor::start();
+
+    Ok(())
+}
+
+pub(crate) fn is_initialized() -> bool {
+    *INITIALIZED.lock().unwrap()
+}
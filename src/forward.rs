@@ -0,0 +1,179 @@
+//! Forwarding TCP connections through TLS, turning the device into a secure bridge for equipment
+//! that only speaks plain TCP on the local network.
+//!
+//! ```ignore
+//! // Local clients connect to port 1883 of the device, which forwards them to the broker
+//! let forward = PortForward::to_tls(connector, "mqtt.example.com", 8883, cfg).listen_port(1883);
+//! forward.run().await?;
+//!
+//! // The reverse: remote TLS clients reach a PLC on the local network
+//! let forward = PortForward::from_tls(acceptor, connector, "192.168.1.20", 502).listen_port(8502);
+//! forward.run().await?;
+//! ```
+//!
+//! Each accepted connection gets a connection of its own on the other side, the data is copied
+//! with [`copy_bidirectional`]. All of them are driven by the task calling
+//! [`run`](PortForward::run), without spawning threads.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Poll},
+};
+use std::{future::poll_fn, net::SocketAddr};
+
+use esp_idf_svc::tls::Config;
+
+use crate::{
+    acceptor::TlsAcceptor,
+    connector::TlsConnector,
+    copy::copy_bidirectional,
+    error::Result,
+    tcp::{AsyncTcp, AsyncTcpListener},
+};
+
+enum Tunnel {
+    /// Plain clients, TLS to the remote end
+    ToTls {
+        connector: TlsConnector,
+        host: String,
+        port: u16,
+        cfg: Config<'static>,
+    },
+    /// TLS clients, plain TCP to the target
+    FromTls {
+        acceptor: TlsAcceptor,
+        connector: TlsConnector,
+        host: String,
+        port: u16,
+    },
+}
+
+/// Listens for connections and forwards them, see the [module docs](self).
+pub struct PortForward {
+    tunnel: Tunnel,
+    listen_port: u16,
+    max_connections: usize,
+}
+
+impl PortForward {
+    /// Accept plain TCP connections and forward each over TLS to `host`, set up by `connector`
+    /// with `cfg`.
+    ///
+    /// Listens on `port` unless set otherwise with [`listen_port`](Self::listen_port).
+    pub fn to_tls(connector: TlsConnector, host: &str, port: u16, cfg: Config<'static>) -> Self {
+        Self::new(Tunnel::ToTls {
+            connector,
+            host: host.to_owned(),
+            port,
+            cfg,
+        })
+    }
+
+    /// Accept TLS connections with `acceptor` and forward each over plain TCP to `host`,
+    /// resolved and connected by `connector`.
+    ///
+    /// Listens on `port` unless set otherwise with [`listen_port`](Self::listen_port).
+    pub fn from_tls(acceptor: TlsAcceptor, connector: TlsConnector, host: &str, port: u16) -> Self {
+        Self::new(Tunnel::FromTls {
+            acceptor,
+            connector,
+            host: host.to_owned(),
+            port,
+        })
+    }
+
+    fn new(tunnel: Tunnel) -> Self {
+        let port = match &tunnel {
+            Tunnel::ToTls { port, .. } | Tunnel::FromTls { port, .. } => *port,
+        };
+
+        Self {
+            tunnel,
+            listen_port: port,
+            max_connections: 4,
+        }
+    }
+
+    /// Listen on `port`.
+    pub fn listen_port(mut self, port: u16) -> Self {
+        self.listen_port = port;
+        self
+    }
+
+    /// Forward up to `max` connections at once, 4 by default. Further clients wait in the
+    /// backlog of the listener until one closes. Each forwarded connection holds a TLS session
+    /// of tens of KiB.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max.max(1);
+        self
+    }
+
+    /// Accept and forward connections until the listener fails. A connection that fails only
+    /// ends itself.
+    pub async fn run(&self) -> Result<()> {
+        let listener = AsyncTcpListener::bind(([0, 0, 0, 0], self.listen_port))?;
+        log::info!("forwarding connections to port {}", self.listen_port);
+
+        let mut sessions: Vec<Pin<Box<dyn Future<Output = ()> + '_>>> = Vec::new();
+        let mut accept = None;
+
+        loop {
+            let accepted = poll_fn(|cx| {
+                sessions.retain_mut(|session| session.as_mut().poll(cx).is_pending());
+
+                // Woken by the sessions once one ends
+                if sessions.len() >= self.max_connections {
+                    return Poll::Pending;
+                }
+
+                let accepting = accept.get_or_insert_with(|| Box::pin(listener.accept()));
+                let accepted = ready!(accepting.as_mut().poll(cx));
+                accept = None;
+
+                Poll::Ready(accepted)
+            })
+            .await;
+
+            let (client, peer) = accepted?;
+            sessions.push(Box::pin(self.forward(client, peer)));
+        }
+    }
+
+    async fn forward(&self, client: AsyncTcp, peer: SocketAddr) {
+        match self.copy(client).await {
+            Ok((sent, received)) => {
+                log::info!("forwarded {peer}: sent {sent}, received {received} bytes")
+            }
+            Err(e) => log::warn!("forwarding {peer} failed: {e}"),
+        }
+    }
+
+    /// Connect the other side for `client` and copy until both are done, returning the bytes
+    /// copied from and to `client`.
+    async fn copy(&self, mut client: AsyncTcp) -> Result<(u64, u64)> {
+        let copied = match &self.tunnel {
+            Tunnel::ToTls {
+                connector,
+                host,
+                port,
+                cfg,
+            } => {
+                let mut remote = connector.connect(host, *port, cfg).await?;
+                copy_bidirectional(&mut client, &mut remote).await?
+            }
+            Tunnel::FromTls {
+                acceptor,
+                connector,
+                host,
+                port,
+            } => {
+                let mut client = acceptor.accept(client).await?;
+                let mut target = connector.connect_plain(host, *port).await?;
+                copy_bidirectional(&mut client, &mut target).await?
+            }
+        };
+
+        Ok(copied)
+    }
+}
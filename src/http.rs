@@ -0,0 +1,1082 @@
+//! A minimal HTTP/1.1 client over [`AsyncTls`], for talking to HTTPS APIs without pulling in a
+//! full HTTP stack.
+//!
+//! Response bodies are streamed, so downloads of any size only need the buffers of the
+//! connection:
+//!
+//! ```ignore
+//! let client = HttpClient::new(TlsConnector::new());
+//! let response = client.get("https://example.com/firmware.bin").send().await?;
+//! let mut body = response.into_body();
+//! let mut buf = [0; 1024];
+//! loop {
+//!     match body.read(&mut buf).await? {
+//!         0 => break,
+//!         read => flash.write(&buf[..read])?,
+//!     }
+//! }
+//! ```
+//!
+//! Without the `esp` feature there is no connector, requests are sent over a connection the
+//! caller provides with [`RequestBuilder::send_over`], e.g. a
+//! [`MockSocket`](crate::mock::MockSocket) on the host.
+
+use core::{
+    mem::ManuallyDrop,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use std::{io, time::Duration};
+
+#[cfg(feature = "esp")]
+use esp_idf_svc::tls::{Config, X509};
+use futures_lite::{
+    io::BufReader, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite,
+    AsyncWriteExt,
+};
+
+#[cfg(feature = "decompress")]
+mod decompress;
+mod pool;
+#[cfg(feature = "esp")]
+mod retry;
+
+#[cfg(feature = "esp")]
+pub use self::retry::RetryPolicy;
+
+#[cfg(feature = "decompress")]
+use self::decompress::Decoder;
+use self::pool::Pool;
+#[cfg(feature = "esp")]
+use crate::connector::TlsConnector;
+#[cfg(feature = "esp")]
+use crate::time;
+use crate::{
+    error::{Error, Result},
+    mem,
+};
+
+/// Upper bound for the status line and headers of a response.
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+/// Upper bound for a chunk size line or trailer.
+const MAX_LINE_LEN: usize = 1024;
+
+/// Size of the read buffer of a connection, larger reads bypass it.
+const BUF_LEN: usize = 1024;
+
+/// How much of a streamed request body is read and sent at once.
+const UPLOAD_CHUNK_LEN: usize = 4096;
+
+/// Upper bound for a body read by [`Response::json`].
+#[cfg(feature = "json")]
+const MAX_JSON_LEN: usize = 64 * 1024;
+
+/// What requests are sent over, TLS on the device or anything else given to
+/// [`RequestBuilder::send_over`].
+trait Transport: AsyncRead + AsyncWrite + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Transport for T {}
+
+type Conn = BufReader<Box<dyn Transport>>;
+
+/// Sends HTTPS requests through a [`TlsConnector`], see the [module docs](self).
+///
+/// Without the `esp` feature, start from `HttpClient::default()`.
+#[derive(Clone, Default)]
+pub struct HttpClient {
+    #[cfg(feature = "esp")]
+    connector: TlsConnector,
+    #[cfg(feature = "esp")]
+    ca_cert: Option<X509<'static>>,
+    #[cfg(feature = "esp")]
+    retry: Option<RetryPolicy>,
+    #[cfg(feature = "esp")]
+    timeout: Option<Duration>,
+    pool: Option<Pool>,
+    #[cfg(feature = "decompress")]
+    decompress: bool,
+}
+
+impl HttpClient {
+    /// Connect with `connector`, i.e. with its proxy, DNS and TLS settings.
+    ///
+    /// Servers are verified with the certificate bundle, unless a CA certificate is set.
+    #[cfg(feature = "esp")]
+    pub fn new(connector: TlsConnector) -> Self {
+        Self {
+            connector,
+            ca_cert: None,
+            retry: None,
+            timeout: None,
+            pool: None,
+            #[cfg(feature = "decompress")]
+            decompress: false,
+        }
+    }
+
+    /// Verify servers with `ca_cert` instead of the certificate bundle.
+    #[cfg(feature = "esp")]
+    pub fn ca_cert(mut self, ca_cert: X509<'static>) -> Self {
+        self.ca_cert = Some(ca_cert);
+        self
+    }
+
+    /// Retry failed requests according to `policy`, instead of sending each request once.
+    #[cfg(feature = "esp")]
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Give up on a request that didn't get its response head within `timeout`, from
+    /// connecting to the server on. Applies to each attempt of a retried request, a timed out
+    /// attempt is retried like other I/O errors. Reading the body is not limited.
+    #[cfg(feature = "esp")]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Keep up to `max_idle` connections open after their response has been read completely,
+    /// and reuse them for requests to the same server within `idle_timeout`, which saves the
+    /// handshake. Clones of the client share these connections.
+    ///
+    /// Each idle connection holds on to its TLS buffers, so keep `max_idle` small.
+    pub fn keep_alive(mut self, max_idle: usize, idle_timeout: Duration) -> Self {
+        self.pool = Some(Pool::new(max_idle, idle_timeout));
+        self
+    }
+
+    /// Ask for gzip or deflate compressed responses and decompress them while the [`Body`] is
+    /// read, unless a request sets its own `Accept-Encoding`.
+    ///
+    /// Each compressed body needs about 45 KiB of heap for the decompressor and its window.
+    #[cfg(feature = "decompress")]
+    pub fn decompress(mut self, decompress: bool) -> Self {
+        self.decompress = decompress;
+        self
+    }
+
+    pub fn get(&self, url: &str) -> RequestBuilder<'_> {
+        self.request("GET", url)
+    }
+
+    pub fn post(&self, url: &str) -> RequestBuilder<'_> {
+        self.request("POST", url)
+    }
+
+    pub fn put(&self, url: &str) -> RequestBuilder<'_> {
+        self.request("PUT", url)
+    }
+
+    pub fn delete(&self, url: &str) -> RequestBuilder<'_> {
+        self.request("DELETE", url)
+    }
+
+    /// A request with any `method`, e.g. `HEAD` or `PATCH`.
+    pub fn request(&self, method: &str, url: &str) -> RequestBuilder<'_> {
+        RequestBuilder {
+            client: self,
+            method: method.to_owned(),
+            url: url.to_owned(),
+            headers: Vec::new(),
+            body: RequestBody::Bytes(Vec::new()),
+        }
+    }
+}
+
+/// A request about to be sent, see [`HttpClient::request`].
+pub struct RequestBuilder<'a> {
+    client: &'a HttpClient,
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: RequestBody<'a>,
+}
+
+enum RequestBody<'a> {
+    Bytes(Vec<u8>),
+    Reader {
+        reader: Box<dyn AsyncRead + Unpin + 'a>,
+        len: Option<u64>,
+    },
+}
+
+impl<'a> RequestBuilder<'a> {
+    /// Add a header. `Host`, `Content-Length` and `Connection` are set by the client.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = RequestBody::Bytes(body.into());
+        self
+    }
+
+    /// Send `body` serialized as JSON, with the matching `Content-Type`.
+    #[cfg(feature = "json")]
+    pub fn json<T: serde::Serialize + ?Sized>(self, body: &T) -> Result<Self> {
+        let body = serde_json::to_vec(body).map_err(Error::Json)?;
+
+        Ok(self.header("Content-Type", "application/json").body(body))
+    }
+
+    /// Stream the body from `reader`, e.g. a file on flash or SD, without holding it in memory.
+    ///
+    /// With a `len` exactly that many bytes are sent and a reader that ends early fails the
+    /// request. Without one the body is sent chunked until the reader ends, which not every
+    /// server accepts.
+    pub fn body_reader(mut self, reader: impl AsyncRead + Unpin + 'a, len: Option<u64>) -> Self {
+        self.body = RequestBody::Reader {
+            reader: Box::new(reader),
+            len,
+        };
+        self
+    }
+
+    /// Send the request and read the response head. The body is left on the connection until
+    /// it is read from the [`Response`].
+    ///
+    /// With a [`RetryPolicy`] a request that failed is sent again after a while, see there for
+    /// which ones.
+    #[cfg(feature = "esp")]
+    pub async fn send(mut self) -> Result<Response> {
+        let client = self.client;
+        let policy = match &client.retry {
+            Some(policy) if self.is_retryable(policy) => policy,
+            _ => return self.send_attempt().await,
+        };
+
+        let mut attempt = 1;
+        loop {
+            let result = self.send_attempt().await;
+            if attempt >= policy.max_attempts {
+                return result;
+            }
+
+            let delay = match &result {
+                Ok(response) if retry::is_retryable_status(response.status()) => {
+                    policy.delay(attempt, response.header("retry-after"))
+                }
+                Err(e) if retry::is_transient(e) => policy.delay(attempt, None),
+                _ => return result,
+            };
+            let reason = match result {
+                Ok(response) => format!("status {}", response.status()),
+                Err(e) => e.to_string(),
+            };
+
+            log::warn!(
+                "{} {} failed ({reason}), retrying in {delay:?} (attempt {attempt}/{})",
+                self.method,
+                self.url,
+                policy.max_attempts
+            );
+            time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Send the request over `transport` instead of connecting to the server, e.g. a tunnel set
+    /// up by other means or a [`MockSocket`](crate::mock::MockSocket). The URL only provides
+    /// the `Host` header and the path.
+    ///
+    /// The request is sent once and the connection is not kept for further requests.
+    pub async fn send_over<T>(mut self, transport: T) -> Result<Response>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        let url = Url::parse(&self.url)?;
+        let request_head = self.request_head(&url);
+
+        let conn = BufReader::with_capacity(BUF_LEN, Box::new(transport) as Box<dyn Transport>);
+        let (conn, head) = self.exchange(conn, &request_head).await?;
+        self.response(url, conn, head, false)
+    }
+
+    #[cfg(feature = "esp")]
+    fn is_retryable(&self, policy: &RetryPolicy) -> bool {
+        matches!(self.body, RequestBody::Bytes(_))
+            && (policy.retry_non_idempotent || retry::is_idempotent(&self.method))
+    }
+
+    /// [`send_once`](Self::send_once) within the timeout of the client.
+    #[cfg(feature = "esp")]
+    async fn send_attempt(&mut self) -> Result<Response> {
+        match self.client.timeout {
+            Some(timeout) => time::with_timeout(self.send_once(), timeout).await?,
+            None => self.send_once().await,
+        }
+    }
+
+    #[cfg(feature = "esp")]
+    async fn send_once(&mut self) -> Result<Response> {
+        let url = Url::parse(&self.url)?;
+        let request_head = self.request_head(&url);
+
+        // The server might have closed an idle connection in the meantime, so a request that
+        // fails on one is sent again on a new connection. A streamed body can't be sent twice.
+        let client = self.client;
+        let reused = match (&client.pool, &self.body) {
+            (Some(pool), RequestBody::Bytes(_)) => pool.take(&url.host, url.port),
+            _ => None,
+        };
+        if let Some(conn) = reused {
+            match self.exchange(conn, &request_head).await {
+                Ok((conn, head)) => return self.response(url, conn, head, true),
+                Err(e) => log::debug!("reused connection to {} failed: {e}", url.host),
+            }
+        }
+
+        let cfg = Config {
+            ca_cert: self.client.ca_cert,
+            #[cfg(esp_idf_mbedtls_certificate_bundle)]
+            use_crt_bundle_attach: self.client.ca_cert.is_none(),
+            ..Default::default()
+        };
+        let tls = self
+            .client
+            .connector
+            .connect(&url.host, url.port, &cfg)
+            .await?;
+
+        let conn = BufReader::with_capacity(BUF_LEN, Box::new(tls) as Box<dyn Transport>);
+        let (conn, head) = self.exchange(conn, &request_head).await?;
+        self.response(url, conn, head, true)
+    }
+
+    fn request_head(&self, url: &Url) -> String {
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\n",
+            self.method,
+            url.path,
+            url.host_header()
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        match &self.body {
+            RequestBody::Bytes(body)
+                if !body.is_empty() || matches!(self.method.as_str(), "POST" | "PUT" | "PATCH") =>
+            {
+                head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+            }
+            RequestBody::Bytes(_) => (),
+            RequestBody::Reader { len: Some(len), .. } => {
+                head.push_str(&format!("Content-Length: {len}\r\n"));
+            }
+            RequestBody::Reader { len: None, .. } => {
+                head.push_str("Transfer-Encoding: chunked\r\n");
+            }
+        }
+        #[cfg(feature = "decompress")]
+        if self.client.decompress && find_header(&self.headers, "accept-encoding").is_none() {
+            head.push_str("Accept-Encoding: gzip, deflate\r\n");
+        }
+        // Connections are kept alive by default in HTTP/1.1
+        if self.client.pool.is_none() {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+
+        head
+    }
+
+    /// Send the request on `conn` and read the response head.
+    async fn exchange(
+        &mut self,
+        mut conn: Conn,
+        request_head: &str,
+    ) -> Result<(Conn, ResponseHead)> {
+        let transport = conn.get_mut();
+        transport.write_all(request_head.as_bytes()).await?;
+        match &mut self.body {
+            RequestBody::Bytes(body) => transport.write_all(body).await?,
+            RequestBody::Reader { reader, len } => write_body(transport, reader, *len).await?,
+        }
+        transport.flush().await?;
+
+        let head = read_head(&mut conn).await?;
+
+        Ok((conn, head))
+    }
+
+    /// The response on `conn`, which goes back to the pool afterwards if `reuse` allows it.
+    fn response(&self, url: Url, conn: Conn, head: ResponseHead, reuse: bool) -> Result<Response> {
+        let ResponseHead {
+            status,
+            headers,
+            keep_alive,
+        } = head;
+
+        let framing = if self.method == "HEAD" || matches!(status, 100..=199 | 204 | 304) {
+            Framing::Length(0)
+        } else if find_header(&headers, "transfer-encoding")
+            .map_or(false, |te| te.to_ascii_lowercase().contains("chunked"))
+        {
+            Framing::ChunkSize
+        } else if let Some(len) = find_header(&headers, "content-length") {
+            Framing::Length(
+                len.trim()
+                    .parse()
+                    .map_err(|_| Error::Http(format!("invalid Content-Length {len}")))?,
+            )
+        } else {
+            Framing::UntilClose
+        };
+
+        #[cfg(feature = "decompress")]
+        let decoder = match find_header(&headers, "content-encoding") {
+            Some(encoding) if self.client.decompress => Decoder::new(encoding).map(Box::new),
+            _ => None,
+        };
+
+        // A body that is delimited by the end of the connection leaves nothing to reuse
+        let reusable =
+            reuse && keep_alive && status != 101 && !matches!(framing, Framing::UntilClose);
+        let checkin = self
+            .client
+            .pool
+            .clone()
+            .filter(|_| reusable)
+            .map(|pool| (pool, url.host, url.port));
+
+        Ok(Response {
+            status,
+            headers,
+            body: Body {
+                framed: Framed::new(conn, framing, checkin),
+                #[cfg(feature = "decompress")]
+                decoder,
+            },
+        })
+    }
+}
+
+/// A response whose head has been read, see [`RequestBuilder::send`].
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Body,
+}
+
+impl Response {
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// The first value of the header `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /// All headers in the order they were received.
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// The length of the body, if the server announced it. For a compressed body this is the
+    /// compressed length.
+    pub fn content_length(&self) -> Option<u64> {
+        self.header("content-length")?.trim().parse().ok()
+    }
+
+    pub fn into_body(self) -> Body {
+        self.body
+    }
+
+    /// Read the whole body, up to 64 KiB, and deserialize it from JSON.
+    #[cfg(feature = "json")]
+    pub async fn json<T: serde::de::DeserializeOwned>(self) -> Result<T> {
+        let mut body = Vec::with_capacity(
+            self.content_length()
+                .map_or(0, |len| len.min(MAX_JSON_LEN as u64) as usize),
+        );
+        self.body
+            .take(MAX_JSON_LEN as u64 + 1)
+            .read_to_end(&mut body)
+            .await?;
+        if body.len() > MAX_JSON_LEN {
+            return Err(Error::Http("JSON body too long".to_owned()));
+        }
+
+        serde_json::from_slice(&body).map_err(Error::Json)
+    }
+}
+
+/// The body of a [`Response`], decoded from its `Content-Length` or chunked framing as it is
+/// read.
+///
+/// Reads return 0 once the body is complete. A connection that ends before that is reported as
+/// [`io::ErrorKind::UnexpectedEof`].
+///
+/// With [`HttpClient::decompress`] a gzip or deflate encoded body is decompressed as well.
+pub struct Body {
+    framed: Framed,
+    #[cfg(feature = "decompress")]
+    decoder: Option<Box<Decoder>>,
+}
+
+impl AsyncRead for Body {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        #[cfg(feature = "decompress")]
+        if let Some(decoder) = &mut this.decoder {
+            return decoder.poll_read(&mut this.framed, cx, buf);
+        }
+
+        Pin::new(&mut this.framed).poll_read(cx, buf)
+    }
+}
+
+/// The body as sent over the connection, with the framing removed.
+struct Framed {
+    /// Only taken when dropped.
+    conn: ManuallyDrop<Conn>,
+    framing: Framing,
+    /// A partially received chunk size line or trailer.
+    line: Vec<u8>,
+    /// Where to return the connection to once the body has been read.
+    checkin: Option<(Pool, String, u16)>,
+}
+
+impl Drop for Framed {
+    fn drop(&mut self) {
+        let conn = unsafe { ManuallyDrop::take(&mut self.conn) };
+
+        // Anything buffered beyond the body would be taken for the next response
+        if let (Framing::Done, Some((pool, host, port))) = (self.framing, self.checkin.take()) {
+            if conn.buffer().is_empty() {
+                pool.put(host, port, conn);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Framing {
+    /// The remaining bytes of a `Content-Length` body
+    Length(u64),
+    /// Everything until the server closes the connection
+    UntilClose,
+    ChunkSize,
+    /// The remaining bytes of the current chunk
+    ChunkData(u64),
+    /// The line break after a chunk
+    ChunkEnd,
+    Trailers,
+    Done,
+}
+
+impl Framed {
+    fn new(conn: Conn, framing: Framing, checkin: Option<(Pool, String, u16)>) -> Self {
+        Self {
+            conn: ManuallyDrop::new(conn),
+            framing: match framing {
+                Framing::Length(0) => Framing::Done,
+                framing => framing,
+            },
+            line: Vec::new(),
+            checkin,
+        }
+    }
+
+    /// Receive a line into `self.line`, which the caller clears once it processed it.
+    fn poll_line(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let available = ready!(Pin::new(&mut *self.conn).poll_fill_buf(cx))?;
+            if available.is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+
+            let (used, complete) = match available.iter().position(|&b| b == b'\n') {
+                Some(pos) => (pos + 1, true),
+                None => (available.len(), false),
+            };
+            self.line.extend_from_slice(&available[..used]);
+            Pin::new(&mut *self.conn).consume(used);
+
+            if self.line.len() > MAX_LINE_LEN {
+                return Poll::Ready(Err(invalid("chunk line too long")));
+            }
+            if complete {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl AsyncRead for Framed {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        loop {
+            match this.framing {
+                Framing::Done => return Poll::Ready(Ok(0)),
+                Framing::UntilClose => return Pin::new(&mut *this.conn).poll_read(cx, buf),
+                Framing::Length(remaining) | Framing::ChunkData(remaining) => {
+                    let len = remaining.min(buf.len() as u64) as usize;
+                    let read = ready!(Pin::new(&mut *this.conn).poll_read(cx, &mut buf[..len]))?;
+                    if read == 0 && len > 0 {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+
+                    let remaining = remaining - read as u64;
+                    this.framing = match this.framing {
+                        Framing::Length(_) if remaining == 0 => Framing::Done,
+                        Framing::Length(_) => Framing::Length(remaining),
+                        _ if remaining == 0 => Framing::ChunkEnd,
+                        _ => Framing::ChunkData(remaining),
+                    };
+
+                    return Poll::Ready(Ok(read));
+                }
+                Framing::ChunkSize | Framing::ChunkEnd | Framing::Trailers => {
+                    ready!(this.poll_line(cx))?;
+                    let line = String::from_utf8_lossy(&this.line);
+                    let line = line.trim();
+
+                    this.framing = match this.framing {
+                        Framing::ChunkSize => {
+                            // Chunk extensions are ignored
+                            let size = line.split(';').next().unwrap_or_default().trim();
+                            match u64::from_str_radix(size, 16) {
+                                Ok(0) => Framing::Trailers,
+                                Ok(size) => Framing::ChunkData(size),
+                                Err(_) => return Poll::Ready(Err(invalid("invalid chunk size"))),
+                            }
+                        }
+                        Framing::ChunkEnd if line.is_empty() => Framing::ChunkSize,
+                        Framing::ChunkEnd => {
+                            return Poll::Ready(Err(invalid("missing line break after chunk")))
+                        }
+                        // Trailers are ignored
+                        _ if line.is_empty() => Framing::Done,
+                        _ => Framing::Trailers,
+                    };
+                    this.line.clear();
+                }
+            }
+        }
+    }
+}
+
+struct Url {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("https://")
+            .ok_or_else(|| Error::Http(format!("only https URLs are supported: {url}")))?;
+
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(pos) => (&rest[..pos], &rest[pos..]),
+            None => (rest, "/"),
+        };
+        let path = if path.starts_with('?') {
+            format!("/{path}")
+        } else {
+            path.to_owned()
+        };
+
+        // The colon of a port comes after the brackets of an IPv6 literal
+        let (host, port) = match authority.rfind(':') {
+            Some(pos) if !authority[pos..].contains(']') => {
+                let port = authority[pos + 1..]
+                    .parse()
+                    .map_err(|_| Error::Http(format!("invalid port in {url}")))?;
+                (&authority[..pos], port)
+            }
+            _ => (authority, 443),
+        };
+        if host.is_empty() {
+            return Err(Error::Http(format!("missing host in {url}")));
+        }
+
+        Ok(Self {
+            host: host.to_owned(),
+            port,
+            path,
+        })
+    }
+
+    fn host_header(&self) -> String {
+        match self.port {
+            443 => self.host.clone(),
+            port => format!("{}:{port}", self.host),
+        }
+    }
+}
+
+/// Copy the body from `reader` to `transport`, chunked if there is no `len`.
+async fn write_body(
+    transport: &mut dyn Transport,
+    mut reader: impl AsyncRead + Unpin,
+    len: Option<u64>,
+) -> Result<()> {
+    // Room for the chunk size line in front of the data and the line break after it, so that
+    // each chunk goes out in a single write
+    const PREFIX_LEN: usize = 8;
+    let mut buf = mem::alloc_buffer(PREFIX_LEN + UPLOAD_CHUNK_LEN + 2);
+    let mut remaining = len;
+
+    loop {
+        let max = remaining.map_or(UPLOAD_CHUNK_LEN, |remaining| {
+            remaining.min(UPLOAD_CHUNK_LEN as u64) as usize
+        });
+        if max == 0 {
+            break;
+        }
+
+        let read = reader.read(&mut buf[PREFIX_LEN..PREFIX_LEN + max]).await?;
+        if read == 0 {
+            break;
+        }
+
+        match &mut remaining {
+            Some(remaining) => {
+                transport
+                    .write_all(&buf[PREFIX_LEN..PREFIX_LEN + read])
+                    .await?;
+                *remaining -= read as u64;
+            }
+            None => {
+                let size_line = format!("{read:x}\r\n");
+                let start = PREFIX_LEN - size_line.len();
+                buf[start..PREFIX_LEN].copy_from_slice(size_line.as_bytes());
+                buf[PREFIX_LEN + read..PREFIX_LEN + read + 2].copy_from_slice(b"\r\n");
+                transport
+                    .write_all(&buf[start..PREFIX_LEN + read + 2])
+                    .await?;
+            }
+        }
+    }
+
+    match remaining {
+        Some(0) => Ok(()),
+        Some(missing) => Err(Error::Http(format!(
+            "request body ended {missing} bytes early"
+        ))),
+        None => Ok(transport.write_all(b"0\r\n\r\n").await?),
+    }
+}
+
+struct ResponseHead {
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// Whether the server keeps the connection open after the response.
+    keep_alive: bool,
+}
+
+async fn read_head(conn: &mut Conn) -> Result<ResponseHead> {
+    let mut http10 = false;
+    let mut status = None;
+    let mut headers = Vec::new();
+    let mut line = Vec::new();
+    let mut head_len = 0;
+
+    loop {
+        line.clear();
+        (&mut *conn)
+            .take((MAX_HEAD_LEN - head_len) as u64)
+            .read_until(b'\n', &mut line)
+            .await?;
+        head_len += line.len();
+
+        if !line.ends_with(b"\n") {
+            return Err(Error::Http(if head_len >= MAX_HEAD_LEN {
+                "response head too long".to_owned()
+            } else {
+                "connection closed before the response head".to_owned()
+            }));
+        }
+
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end();
+
+        match status {
+            None => {
+                let code = line
+                    .strip_prefix("HTTP/1.")
+                    .and_then(|rest| rest.split_whitespace().nth(1))
+                    .and_then(|code| code.parse().ok())
+                    .ok_or_else(|| Error::Http(format!("invalid status line {line}")))?;
+                http10 = line.starts_with("HTTP/1.0");
+                status = Some(code);
+            }
+            // Informational responses like 100 Continue precede the actual one
+            Some(code @ 100..=199) if code != 101 && line.is_empty() => {
+                status = None;
+                headers.clear();
+            }
+            Some(code) if line.is_empty() => {
+                let connection = find_header(&headers, "connection")
+                    .unwrap_or_default()
+                    .to_ascii_lowercase();
+                let keep_alive = if http10 {
+                    connection.contains("keep-alive")
+                } else {
+                    !connection.contains("close")
+                };
+
+                return Ok(ResponseHead {
+                    status: code,
+                    headers,
+                    keep_alive,
+                });
+            }
+            Some(_) => {
+                let (name, value) = line
+                    .split_once(':')
+                    .ok_or_else(|| Error::Http(format!("invalid header {line}")))?;
+                headers.push((name.trim().to_owned(), value.trim().to_owned()));
+            }
+        }
+    }
+}
+
+pub(crate) fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::future;
+
+    use super::*;
+    use crate::mock::MockSocket;
+
+    /// Receive a request with a body of `body_len` bytes on `socket` and answer with `response`,
+    /// a few bytes at a time as the pipe is small. Returns the request.
+    async fn serve(mut socket: MockSocket, response: &[u8], body_len: usize) -> String {
+        let mut request = Vec::new();
+        let mut byte = [0; 1];
+        while !request.ends_with(b"\r\n\r\n") {
+            socket.read_exact(&mut byte).await.unwrap();
+            request.push(byte[0]);
+        }
+        let mut body = vec![0; body_len];
+        socket.read_exact(&mut body).await.unwrap();
+        request.extend_from_slice(&body);
+
+        // The client stops reading at the first thing it rejects
+        let _ = socket.write_all(response).await;
+        String::from_utf8(request).unwrap()
+    }
+
+    /// What the client made of the response.
+    struct Received {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: io::Result<Vec<u8>>,
+    }
+
+    /// Send `request` to a server that expects a body of `body_len` bytes and answers with
+    /// `response`. Returns the request as the server received it.
+    fn exchange(
+        request: RequestBuilder<'_>,
+        response: &[u8],
+        body_len: usize,
+    ) -> (String, Result<Received>) {
+        let (a, b) = MockSocket::pair_with_capacity(3);
+
+        future::block_on(future::zip(serve(b, response, body_len), async {
+            let response = request.send_over(a).await?;
+            let (status, headers) = (response.status(), response.headers().to_vec());
+
+            let mut body = Vec::new();
+            let body = response
+                .into_body()
+                .read_to_end(&mut body)
+                .await
+                .map(|_| body);
+
+            Ok(Received {
+                status,
+                headers,
+                body,
+            })
+        }))
+    }
+
+    #[test]
+    fn content_length() {
+        let client = HttpClient::default();
+        let (request, received) = exchange(
+            client.get("https://example.com:8443/status?verbose"),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-Request-Id: 7\r\n\r\nhello",
+            0,
+        );
+
+        assert_eq!(
+            request,
+            "GET /status?verbose HTTP/1.1\r\nHost: example.com:8443\r\nConnection: close\r\n\r\n"
+        );
+        let received = received.unwrap();
+        assert_eq!(received.status, 200);
+        assert_eq!(find_header(&received.headers, "x-request-id"), Some("7"));
+        assert_eq!(received.body.unwrap(), b"hello");
+    }
+
+    #[test]
+    fn chunked() {
+        let client = HttpClient::default();
+        let (_, received) = exchange(
+            client.get("https://example.com/"),
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5;name=value\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: 1\r\n\r\n",
+            0,
+        );
+
+        assert_eq!(received.unwrap().body.unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn invalid_chunks() {
+        let client = HttpClient::default();
+
+        let invalid: [&[u8]; 2] = [
+            b"zz\r\nhello\r\n0\r\n\r\n",
+            // Longer than its size
+            b"5\r\nhello!\r\n0\r\n\r\n",
+        ];
+        for chunks in invalid {
+            let mut response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+            response.extend_from_slice(chunks);
+
+            let (_, received) = exchange(client.get("https://example.com/"), &response, 0);
+            let err = received.unwrap().body.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn body_until_close() {
+        let client = HttpClient::default();
+        let (_, received) = exchange(
+            client.get("https://example.com/"),
+            b"HTTP/1.0 200 OK\r\n\r\nall of it",
+            0,
+        );
+
+        assert_eq!(received.unwrap().body.unwrap(), b"all of it");
+    }
+
+    #[test]
+    fn truncated_body() {
+        let client = HttpClient::default();
+        let responses: [&[u8]; 2] = [
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello",
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n",
+        ];
+
+        for response in responses {
+            let (_, received) = exchange(client.get("https://example.com/"), response, 0);
+            let err = received.unwrap().body.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn no_body() {
+        let client = HttpClient::default();
+
+        let (_, received) = exchange(
+            client.request("HEAD", "https://example.com/"),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n",
+            0,
+        );
+        assert_eq!(received.unwrap().body.unwrap(), b"");
+
+        let (_, received) = exchange(
+            client.delete("https://example.com/"),
+            b"HTTP/1.1 204 No Content\r\n\r\n",
+            0,
+        );
+        let received = received.unwrap();
+        assert_eq!(received.status, 204);
+        assert_eq!(received.body.unwrap(), b"");
+    }
+
+    #[test]
+    fn informational_response() {
+        let client = HttpClient::default();
+        let (_, received) = exchange(
+            client.get("https://example.com/"),
+            b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+            0,
+        );
+
+        let received = received.unwrap();
+        assert_eq!(received.status, 200);
+        assert_eq!(received.body.unwrap(), b"ok");
+    }
+
+    #[test]
+    fn invalid_head() {
+        let client = HttpClient::default();
+
+        let responses: [&[u8]; 2] = [
+            b"SSH-2.0-OpenSSH_9.3\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nno colon\r\n\r\n",
+        ];
+        for response in responses {
+            let (_, received) = exchange(client.get("https://example.com/"), response, 0);
+            assert!(matches!(received, Err(Error::Http(_))));
+        }
+    }
+
+    #[test]
+    fn request_body() {
+        let client = HttpClient::default();
+        let (request, received) = exchange(
+            client.post("https://example.com/upload").body("data"),
+            b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n",
+            4,
+        );
+
+        assert!(request.starts_with("POST /upload HTTP/1.1\r\n"));
+        assert!(request.contains("\r\nContent-Length: 4\r\n"));
+        assert!(request.ends_with("\r\n\r\ndata"));
+        assert_eq!(received.unwrap().status, 201);
+    }
+
+    #[test]
+    fn chunked_request_body() {
+        let client = HttpClient::default();
+        let chunked = "4\r\ndata\r\n0\r\n\r\n";
+        let (request, received) = exchange(
+            client
+                .put("https://example.com/upload")
+                .body_reader(&b"data"[..], None),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n",
+            chunked.len(),
+        );
+
+        assert!(request.contains("\r\nTransfer-Encoding: chunked\r\n"));
+        assert!(request.ends_with(&format!("\r\n\r\n{chunked}")));
+        assert_eq!(received.unwrap().status, 200);
+    }
+}
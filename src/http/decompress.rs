@@ -0,0 +1,202 @@
+//! Streaming decompression of gzip and deflate encoded response bodies.
+
+use core::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use std::io;
+
+use futures_lite::AsyncRead;
+use miniz_oxide::{
+    inflate::stream::{inflate, InflateState},
+    DataFormat, MZError, MZFlush, MZStatus,
+};
+
+/// Size of the buffer for compressed data, also the upper bound for a gzip header.
+const INPUT_LEN: usize = 1024;
+
+const GZIP_FHCRC: u8 = 0x02;
+const GZIP_FEXTRA: u8 = 0x04;
+const GZIP_FNAME: u8 = 0x08;
+const GZIP_FCOMMENT: u8 = 0x10;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+pub(super) struct Decoder {
+    encoding: Encoding,
+    /// Created once the header has been received.
+    state: Option<Box<InflateState>>,
+    input: Box<[u8]>,
+    /// The compressed data not yet passed to the decompressor.
+    start: usize,
+    end: usize,
+    eof: bool,
+    done: bool,
+}
+
+impl Decoder {
+    /// A decoder for the `Content-Encoding` `encoding`, `None` if it is not compressed or not
+    /// supported.
+    pub(super) fn new(encoding: &str) -> Option<Self> {
+        let encoding = match encoding.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Encoding::Gzip,
+            "deflate" => Encoding::Deflate,
+            _ => return None,
+        };
+
+        Some(Self {
+            encoding,
+            state: None,
+            input: vec![0; INPUT_LEN].into_boxed_slice(),
+            start: 0,
+            end: 0,
+            eof: false,
+            done: false,
+        })
+    }
+
+    /// Read compressed data from `reader` and decompress it into `buf`.
+    ///
+    /// The gzip trailer is not checked, the TLS record MACs already protect the data in transit.
+    pub(super) fn poll_read<R>(
+        &mut self,
+        reader: &mut R,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        loop {
+            if self.done || buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+
+            let state = match &mut self.state {
+                Some(state) => state,
+                // Bodies of e.g. HEAD requests are empty despite the encoding
+                None if self.eof && self.start == self.end => return Poll::Ready(Ok(0)),
+                None => {
+                    match self.parse_header()? {
+                        Some((format, header_len)) => {
+                            self.start += header_len;
+                            self.state = Some(InflateState::new_boxed(format));
+                        }
+                        None if self.eof => {
+                            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                        }
+                        None => ready!(self.poll_fill(reader, cx))?,
+                    }
+                    continue;
+                }
+            };
+
+            let flush = if self.eof {
+                MZFlush::Finish
+            } else {
+                MZFlush::None
+            };
+            let result = inflate(state, &self.input[self.start..self.end], buf, flush);
+            self.start += result.bytes_consumed;
+
+            match result.status {
+                Ok(MZStatus::StreamEnd) => {
+                    self.done = true;
+                    return Poll::Ready(Ok(result.bytes_written));
+                }
+                Ok(_) | Err(MZError::Buf) if result.bytes_written > 0 => {
+                    return Poll::Ready(Ok(result.bytes_written))
+                }
+                Ok(_) | Err(MZError::Buf) if self.eof => {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                }
+                // All input has been consumed without producing anything yet
+                Ok(_) | Err(MZError::Buf) => ready!(self.poll_fill(reader, cx))?,
+                Err(e) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("failed to decompress body: {e:?}"),
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Read more compressed data behind what is left in the buffer.
+    fn poll_fill<R>(&mut self, reader: &mut R, cx: &mut Context<'_>) -> Poll<io::Result<()>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        if self.start > 0 {
+            self.input.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+
+        if self.end == self.input.len() {
+            return Poll::Ready(Err(invalid("gzip header too long")));
+        }
+
+        let read = ready!(Pin::new(reader).poll_read(cx, &mut self.input[self.end..]))?;
+        self.end += read;
+        self.eof = read == 0;
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// The format of the compressed data and the length of the header in front of it, `None` if
+    /// more data is needed to tell.
+    fn parse_header(&self) -> io::Result<Option<(DataFormat, usize)>> {
+        let data = &self.input[self.start..self.end];
+
+        if self.encoding == Encoding::Deflate {
+            // Supposed to be zlib, but some servers send raw deflate
+            return Ok(match data {
+                [cmf, flg, ..] if cmf & 0x0f == 8 && u16::from_be_bytes([*cmf, *flg]) % 31 == 0 => {
+                    Some((DataFormat::Zlib, 0))
+                }
+                [_, _, ..] => Some((DataFormat::Raw, 0)),
+                _ => None,
+            });
+        }
+
+        if data.len() < 10 {
+            return Ok(None);
+        }
+        if data[..3] != [0x1f, 0x8b, 8] {
+            return Err(invalid("invalid gzip header"));
+        }
+
+        let flags = data[3];
+        let mut pos = 10;
+
+        if flags & GZIP_FEXTRA != 0 {
+            let Some(len) = data.get(pos..pos + 2) else {
+                return Ok(None);
+            };
+            pos += 2 + u16::from_le_bytes([len[0], len[1]]) as usize;
+        }
+        for flag in [GZIP_FNAME, GZIP_FCOMMENT] {
+            if flags & flag != 0 {
+                // Zero terminated
+                match data.get(pos..).and_then(|s| s.iter().position(|&b| b == 0)) {
+                    Some(len) => pos += len + 1,
+                    None => return Ok(None),
+                }
+            }
+        }
+        if flags & GZIP_FHCRC != 0 {
+            pos += 2;
+        }
+
+        Ok((pos <= data.len()).then_some((DataFormat::Raw, pos)))
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,69 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::Conn;
+
+/// Idle keep-alive connections of an [`HttpClient`](super::HttpClient), shared by its clones.
+///
+/// Only HTTPS is supported, so host and port identify a server.
+#[derive(Clone)]
+pub(super) struct Pool {
+    idle: Arc<Mutex<Vec<Idle>>>,
+    max_idle: usize,
+    idle_timeout: Duration,
+}
+
+struct Idle {
+    host: String,
+    port: u16,
+    conn: Conn,
+    since: Instant,
+}
+
+impl Pool {
+    pub(super) fn new(max_idle: usize, idle_timeout: Duration) -> Self {
+        Self {
+            idle: Default::default(),
+            max_idle,
+            idle_timeout,
+        }
+    }
+
+    /// An idle connection to `host:port`, if there is one that did not time out yet.
+    // Only `RequestBuilder::send` connects on its own
+    #[cfg_attr(not(feature = "esp"), allow(dead_code))]
+    pub(super) fn take(&self, host: &str, port: u16) -> Option<Conn> {
+        let mut idle = self.idle.lock().unwrap();
+        idle.retain(|conn| conn.since.elapsed() < self.idle_timeout);
+
+        let pos = idle
+            .iter()
+            .rposition(|conn| conn.host == host && conn.port == port)?;
+        log::debug!("reusing connection to {host}:{port}");
+
+        Some(idle.remove(pos).conn)
+    }
+
+    /// Keep `conn` for the next request to `host:port`, closing the longest idle connection if
+    /// the pool is full.
+    pub(super) fn put(&self, host: String, port: u16, conn: Conn) {
+        if self.max_idle == 0 {
+            return;
+        }
+
+        let mut idle = self.idle.lock().unwrap();
+        // Ordered from the longest idle one
+        if idle.len() >= self.max_idle {
+            idle.remove(0);
+        }
+
+        idle.push(Idle {
+            host,
+            port,
+            conn,
+            since: Instant::now(),
+        });
+    }
+}
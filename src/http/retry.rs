@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+use crate::error::Error;
+
+/// When and how often [`HttpClient`](super::HttpClient) retries a failed request, see
+/// [`HttpClient::retry`](super::HttpClient::retry).
+///
+/// Requests are retried if connecting or the exchange fails, or if the server answers with
+/// 429, 502, 503 or 504. Only methods that are safe to repeat are retried unless configured
+/// otherwise, and never requests with a streamed body, as that cannot be read twice.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub(super) max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    pub(super) retry_non_idempotent: bool,
+    honor_retry_after: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(3)
+    }
+}
+
+impl RetryPolicy {
+    /// Send a request up to `max_attempts` times, waiting 500 ms before the first retry and
+    /// doubling that up to 30 seconds.
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            retry_non_idempotent: false,
+            honor_retry_after: true,
+        }
+    }
+
+    /// Wait `initial` before the first retry and double it for each further retry, up to `max`.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Also retry methods like `POST`, which the server might have processed before failing.
+    pub fn retry_non_idempotent(mut self, retry: bool) -> Self {
+        self.retry_non_idempotent = retry;
+        self
+    }
+
+    /// Wait as long as a `Retry-After` header asks for (in seconds, up to the maximum backoff)
+    /// instead of the backoff. Enabled by default.
+    pub fn honor_retry_after(mut self, honor: bool) -> Self {
+        self.honor_retry_after = honor;
+        self
+    }
+
+    /// How long to wait before attempt `attempt + 1`.
+    pub(super) fn delay(&self, attempt: u32, retry_after: Option<&str>) -> Duration {
+        let retry_after = retry_after
+            .filter(|_| self.honor_retry_after)
+            .and_then(|value| value.trim().parse().ok())
+            .map(Duration::from_secs);
+
+        retry_after
+            .unwrap_or_else(|| {
+                self.initial_backoff
+                    .saturating_mul(1 << (attempt - 1).min(16))
+            })
+            .min(self.max_backoff)
+    }
+}
+
+pub(super) fn is_idempotent(method: &str) -> bool {
+    matches!(
+        method,
+        "GET" | "HEAD" | "PUT" | "DELETE" | "OPTIONS" | "TRACE"
+    )
+}
+
+pub(super) fn is_retryable_status(status: u16) -> bool {
+    matches!(status, 429 | 502 | 503 | 504)
+}
+
+/// Whether `err` might go away by trying again, e.g. because the WiFi link dropped.
+pub(super) fn is_transient(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::Dns { .. }
+            | Error::NoAddress(_)
+            | Error::TcpConnect { .. }
+            | Error::TlsHandshake(_)
+            | Error::Io(_)
+    )
+}
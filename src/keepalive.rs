@@ -0,0 +1,300 @@
+//! Application level keepalive, so that a connection silently dropped by a NAT or the network is
+//! noticed within seconds instead of at the next write.
+//!
+//! ```ignore
+//! // MQTT PINGREQ after 30 s without traffic, give up if nothing arrives within 10 s
+//! let mut tls = KeepAlive::new(tls, Duration::from_secs(30), [0xc0, 0x00])
+//!     .timeout(Duration::from_secs(10));
+//! loop {
+//!     let read = tls.read(&mut buf).await?; // fails with `TimedOut` once the peer is gone
+//!     ...
+//! }
+//! ```
+//!
+//! For protocols without a message that is safe to send at any time, TCP keepalive is the
+//! fallback, see [`TlsConnector::tcp_keepalive`](crate::TlsConnector::tcp_keepalive).
+
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use futures_lite::{AsyncRead, AsyncWrite};
+
+use crate::reactor::DefaultTimer;
+#[cfg(feature = "esp")]
+use crate::reactor::Timer as _;
+
+/// Sends a probe on an idle connection and fails it if nothing comes back, see the
+/// [module docs](self).
+///
+/// Probes are only sent while a read is pending, as with a task that reads continuously, and
+/// only after the connection was idle, so the application must not leave a message half
+/// written for that long. Any data from the peer counts as the answer.
+pub struct KeepAlive<T> {
+    inner: T,
+    idle: Duration,
+    timeout: Duration,
+    probe: Vec<u8>,
+    /// Last read or write
+    last_activity: Instant,
+    state: Probe,
+    timer: DefaultTimer,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Probe {
+    Idle,
+    /// This much of the probe has been written
+    Writing(usize),
+    Flushing,
+    /// Waiting for data since then
+    Sent(Instant),
+    /// No data arrived in time
+    Dead,
+}
+
+impl<T> KeepAlive<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Write `probe` after the connection was idle for `idle`, and fail it unless data arrives
+    /// within the same time again.
+    pub fn new(inner: T, idle: Duration, probe: impl Into<Vec<u8>>) -> Self {
+        Self {
+            inner,
+            idle,
+            timeout: idle,
+            probe: probe.into(),
+            last_activity: Instant::now(),
+            state: Probe::Idle,
+            timer: DefaultTimer::never(),
+        }
+    }
+
+    /// Wait `timeout` for data after a probe before failing the connection.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Finish writing a probe that is under way, so that it does not interleave with the
+    /// application's data.
+    fn poll_probe_write(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            match self.state {
+                Probe::Writing(written) if written < self.probe.len() => {
+                    let len =
+                        ready!(Pin::new(&mut self.inner).poll_write(cx, &self.probe[written..]))?;
+                    if len == 0 {
+                        return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                    }
+                    self.state = Probe::Writing(written + len);
+                }
+                Probe::Writing(_) => self.state = Probe::Flushing,
+                Probe::Flushing => {
+                    ready!(Pin::new(&mut self.inner).poll_flush(cx))?;
+                    log::debug!("keepalive probe sent");
+                    self.state = Probe::Sent(Instant::now());
+                }
+                Probe::Dead => return Poll::Ready(Err(timed_out())),
+                Probe::Idle | Probe::Sent(_) => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+
+    /// Send a probe or fail the connection when it is time to, and arrange to be woken up for
+    /// the next deadline otherwise.
+    fn poll_keepalive(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        loop {
+            if self.poll_probe_write(cx)?.is_pending() {
+                return Ok(());
+            }
+
+            let deadline = match self.state {
+                Probe::Sent(sent) => sent + self.timeout,
+                _ => self.last_activity + self.idle,
+            };
+            if Instant::now() >= deadline {
+                self.state = match self.state {
+                    Probe::Sent(_) => {
+                        log::warn!("no answer to the keepalive probe, giving up the connection");
+                        Probe::Dead
+                    }
+                    _ => Probe::Writing(0),
+                };
+                continue;
+            }
+
+            self.timer.set_at(deadline);
+            if Pin::new(&mut self.timer).poll(cx).is_pending() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<T> AsyncRead for KeepAlive<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if let Probe::Dead = this.state {
+            return Poll::Ready(Err(timed_out()));
+        }
+
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(read)) => {
+                if read > 0 {
+                    this.last_activity = Instant::now();
+                    if let Probe::Sent(_) = this.state {
+                        this.state = Probe::Idle;
+                    }
+                }
+
+                Poll::Ready(Ok(read))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => {
+                this.poll_keepalive(cx)?;
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> AsyncWrite for KeepAlive<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_probe_write(cx))?;
+
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.last_activity = Instant::now();
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_probe_write(cx))?;
+
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "no answer to the keepalive probe")
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::{future, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{mock::MockSocket, reactor::Timer};
+
+    const IDLE: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn probe_answered() {
+        let (socket, mut peer) = MockSocket::pair();
+        let mut tls = KeepAlive::new(socket, IDLE, *b"ping").timeout(Duration::from_secs(10));
+        let start = Instant::now();
+
+        let client = async {
+            let mut buf = [0; 4];
+            tls.read_exact(&mut buf).await.unwrap();
+            buf
+        };
+        let server = async {
+            let mut probe = [0; 4];
+            peer.read_exact(&mut probe).await.unwrap();
+            assert_eq!(&probe, b"ping");
+            assert!(start.elapsed() >= IDLE);
+
+            peer.write_all(b"pong").await.unwrap();
+        };
+
+        let (answer, ()) = future::block_on(future::zip(client, server));
+        assert_eq!(&answer, b"pong");
+        assert!(matches!(tls.state, Probe::Idle));
+    }
+
+    #[test]
+    fn probe_timed_out() {
+        let (socket, mut peer) = MockSocket::pair();
+        let mut tls = KeepAlive::new(socket, IDLE, *b"ping");
+        let start = Instant::now();
+
+        let mut buf = [0; 16];
+        let err = future::block_on(tls.read(&mut buf)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        // Idle, then the default timeout of the same length
+        assert!(start.elapsed() >= 2 * IDLE);
+
+        // Only one probe went out, and the connection stays failed
+        let mut probe = [0; 16];
+        let read = future::block_on(peer.read(&mut probe)).unwrap();
+        assert_eq!(&probe[..read], b"ping");
+        assert_eq!(peer.pending(), 0);
+        let err = future::block_on(tls.write_all(b"data")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn no_probe_while_active() {
+        let (socket, mut peer) = MockSocket::pair();
+        let mut tls = KeepAlive::new(socket, IDLE, *b"ping");
+
+        let client = async {
+            let mut received = Vec::new();
+            tls.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        let server = async {
+            // Longer than the idle time in total, but never idle for that long
+            for _ in 0..8 {
+                peer.write_all(b"x").await.unwrap();
+                DefaultTimer::after(IDLE / 5).await;
+            }
+            assert_eq!(peer.pending(), 0);
+            drop(peer);
+        };
+
+        let (received, ()) = future::block_on(future::zip(client, server));
+        assert_eq!(received, b"xxxxxxxx");
+    }
+}
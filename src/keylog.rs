@@ -0,0 +1,92 @@
+//! TLS secrets in the NSS key log format (`SSLKEYLOGFILE`), so that Wireshark can decrypt
+//! captures of the device's traffic.
+//!
+//! ```ignore
+//! let connector = TlsConnector::new().keylog(|line| println!("{line}"));
+//! ```
+//!
+//! Paste the lines into a file and point Wireshark's TLS "(Pre)-Master-Secret log filename"
+//! preference at it. Anyone with the lines can read the traffic, so this is only built with the
+//! `debug-keylog` feature and must never be enabled in production firmware.
+
+use core::{ffi::c_void, fmt::Write, slice};
+use std::sync::Arc;
+
+use esp_idf_sys as sys;
+
+/// Receives one key log line per secret, without the line break, e.g.
+/// `CLIENT_RANDOM <client random> <master secret>` for TLS 1.2.
+pub type KeyLogFn = dyn Fn(&str) + Send + Sync;
+
+pub(crate) struct KeyLogHook {
+    callback: Arc<KeyLogFn>,
+}
+
+impl KeyLogHook {
+    pub(crate) fn new(callback: Arc<KeyLogFn>) -> Box<Self> {
+        Box::new(Self { callback })
+    }
+
+    /// Register the hook on an mbedtls session.
+    ///
+    /// # Safety
+    ///
+    /// `ssl` must be set up already (`mbedtls_ssl_setup`), before any keys were derived, and the
+    /// hook must outlive the session.
+    pub(crate) unsafe fn install(&mut self, ssl: *mut sys::mbedtls_ssl_context) {
+        log::warn!("!!! TLS secrets are logged, the connection is NOT confidential !!!");
+
+        sys::mbedtls_ssl_set_export_keys_cb(
+            ssl,
+            Some(export_keys_trampoline),
+            self as *mut Self as *mut c_void,
+        );
+    }
+}
+
+unsafe extern "C" fn export_keys_trampoline(
+    ctx: *mut c_void,
+    kind: sys::mbedtls_ssl_key_export_type,
+    secret: *const u8,
+    secret_len: usize,
+    client_random: *const u8,
+    _server_random: *const u8,
+    _tls_prf_type: sys::mbedtls_tls_prf_types,
+) {
+    let hook = &*(ctx as *const KeyLogHook);
+
+    let label = match kind {
+        sys::mbedtls_ssl_key_export_type_MBEDTLS_SSL_KEY_EXPORT_TLS12_MASTER_SECRET => {
+            "CLIENT_RANDOM"
+        }
+        sys::mbedtls_ssl_key_export_type_MBEDTLS_SSL_KEY_EXPORT_TLS1_3_CLIENT_EARLY_SECRET => {
+            "CLIENT_EARLY_TRAFFIC_SECRET"
+        }
+        sys::mbedtls_ssl_key_export_type_MBEDTLS_SSL_KEY_EXPORT_TLS1_3_CLIENT_HANDSHAKE_TRAFFIC_SECRET => {
+            "CLIENT_HANDSHAKE_TRAFFIC_SECRET"
+        }
+        sys::mbedtls_ssl_key_export_type_MBEDTLS_SSL_KEY_EXPORT_TLS1_3_SERVER_HANDSHAKE_TRAFFIC_SECRET => {
+            "SERVER_HANDSHAKE_TRAFFIC_SECRET"
+        }
+        sys::mbedtls_ssl_key_export_type_MBEDTLS_SSL_KEY_EXPORT_TLS1_3_CLIENT_APPLICATION_TRAFFIC_SECRET => {
+            "CLIENT_TRAFFIC_SECRET_0"
+        }
+        sys::mbedtls_ssl_key_export_type_MBEDTLS_SSL_KEY_EXPORT_TLS1_3_SERVER_APPLICATION_TRAFFIC_SECRET => {
+            "SERVER_TRAFFIC_SECRET_0"
+        }
+        _ => return,
+    };
+
+    let client_random = slice::from_raw_parts(client_random, 32);
+    let secret = slice::from_raw_parts(secret, secret_len);
+    (hook.callback)(&format!("{label} {} {}", hex(client_random), hex(secret)));
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
@@ -0,0 +1,164 @@
+// The `embedded-nal-async` and `embedded_svc::io::asynch` traits are async traits
+#![cfg_attr(
+    any(feature = "embedded-nal", feature = "nightly"),
+    feature(async_fn_in_trait),
+    allow(incomplete_features)
+)]
+
+#[cfg(feature = "esp")]
+pub mod acceptor;
+#[cfg(feature = "esp")]
+pub mod aws_iot;
+#[cfg(feature = "esp")]
+pub mod azure_iot;
+pub mod backpressure;
+#[cfg(feature = "esp")]
+pub mod bench;
+#[cfg(feature = "esp")]
+pub mod cert;
+pub mod codec;
+#[cfg(feature = "esp")]
+mod conf;
+#[cfg(feature = "esp")]
+pub mod config;
+#[cfg(feature = "esp")]
+pub mod connector;
+#[cfg(all(feature = "esp", esp_idf_esp_console_uart))]
+pub mod console;
+pub mod copy;
+pub mod credstore;
+#[cfg(feature = "esp")]
+pub mod dns;
+#[cfg_attr(not(feature = "esp"), allow(dead_code))]
+mod dns_message;
+#[cfg(feature = "esp")]
+pub mod doh;
+#[cfg(all(feature = "esp", esp_idf_esp_tls_use_ds_peripheral))]
+pub mod ds;
+pub mod error;
+#[cfg(all(feature = "esp", esp_idf_comp_esp_eth_enabled))]
+pub mod eth;
+#[cfg(feature = "esp")]
+pub mod events;
+#[cfg(feature = "esp")]
+pub mod executor;
+#[cfg(feature = "esp")]
+pub mod forward;
+pub mod http;
+pub mod keepalive;
+#[cfg(feature = "debug-keylog")]
+pub mod keylog;
+#[cfg(feature = "esp")]
+pub mod limit;
+#[cfg(feature = "esp")]
+pub mod manager;
+#[cfg(feature = "esp")]
+pub mod maybe_tls;
+pub mod mem;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod mux;
+#[cfg(feature = "embedded-nal")]
+pub mod nal;
+#[cfg(feature = "esp")]
+pub mod netif;
+#[cfg(feature = "esp")]
+pub mod ota;
+#[cfg(all(feature = "esp", esp_idf_lwip_ppp_support))]
+pub mod ppp;
+#[cfg(feature = "esp")]
+pub mod proxy;
+pub mod reactor;
+#[cfg(feature = "esp")]
+pub mod remote_log;
+#[cfg(feature = "esp")]
+pub mod runtime;
+#[cfg(feature = "backend-rustls")]
+pub mod rustls_backend;
+#[cfg(all(feature = "esp", esp_idf_esp_tls_client_session_tickets))]
+pub mod session;
+#[cfg(feature = "status-server")]
+pub mod status;
+#[cfg(feature = "esp")]
+pub mod stream;
+#[cfg(all(feature = "esp", esp_idf_esp_tls_client_session_tickets))]
+pub mod suspend;
+#[cfg(feature = "debug-tap")]
+pub mod tap;
+#[cfg(feature = "esp")]
+pub mod tcp;
+#[cfg(feature = "esp")]
+pub mod telemetry;
+pub mod time;
+#[cfg(feature = "esp")]
+pub mod tls;
+#[cfg(feature = "esp")]
+pub mod transport;
+mod util;
+#[cfg(feature = "esp")]
+pub mod verify;
+#[cfg(feature = "esp")]
+mod watchdog;
+pub mod websocket;
+#[cfg(feature = "esp")]
+pub mod wifi;
+
+#[cfg(feature = "esp")]
+pub use acceptor::TlsAcceptor;
+pub use backpressure::WatermarkWriter;
+#[cfg(feature = "esp")]
+pub use config::AppConfig;
+#[cfg(feature = "esp")]
+pub use connector::{
+    connect_async_tls, connect_async_tls_to_addr, MaxFragmentLength, TlsConnector,
+};
+pub use copy::copy_bidirectional;
+#[cfg(feature = "esp")]
+pub use credstore::CredStore;
+pub use credstore::Credentials;
+#[cfg(feature = "esp")]
+pub use dns::{DnsCache, IpPreference};
+#[cfg(feature = "esp")]
+pub use doh::DohResolver;
+#[cfg(all(feature = "esp", esp_idf_esp_tls_use_ds_peripheral))]
+pub use ds::DsKey;
+pub use error::{Error, Result};
+#[cfg(feature = "esp")]
+pub use events::{ConnectionEvent, ConnectionEvents};
+#[cfg(feature = "esp")]
+pub use executor::{init_async_runtime, Executor};
+#[cfg(feature = "esp")]
+pub use forward::PortForward;
+pub use http::HttpClient;
+pub use keepalive::KeepAlive;
+#[cfg(feature = "esp")]
+pub use limit::ConnectionLimit;
+#[cfg(feature = "esp")]
+pub use manager::ConnectionManager;
+#[cfg(feature = "esp")]
+pub use maybe_tls::MaybeTls;
+pub use mem::BufferPool;
+pub use mux::Multiplexer;
+#[cfg(feature = "esp")]
+pub use proxy::Proxy;
+#[cfg(feature = "esp")]
+pub use remote_log::{LogFormat, LogShipper, RemoteLog};
+#[cfg(feature = "esp")]
+pub use runtime::{RuntimeConfig, TaskConfig};
+#[cfg(feature = "backend-rustls")]
+pub use rustls_backend::RustlsTls;
+#[cfg(all(feature = "esp", esp_idf_esp_tls_client_session_tickets))]
+pub use session::TlsSession;
+#[cfg(feature = "esp")]
+pub use stream::TlsStream;
+#[cfg(all(feature = "esp", esp_idf_esp_tls_client_session_tickets))]
+pub use suspend::Suspended;
+#[cfg(feature = "esp")]
+pub use tcp::{AsyncTcp, AsyncTcpListener, FdSocket, TcpKeepAlive, TcpOptions};
+#[cfg(feature = "esp")]
+pub use telemetry::Telemetry;
+pub use time::{with_timeout, Deadline};
+#[cfg(feature = "esp")]
+pub use tls::{AsyncTls, ConnectTimings, ConnectionStats, ProtocolVersion};
+#[cfg(feature = "esp")]
+pub use transport::Transport;
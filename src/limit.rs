@@ -0,0 +1,135 @@
+//! A limit on the TLS sessions the connector has open at once, so that tasks racing to connect
+//! can't exhaust the heap with handshakes. Each session takes tens of KiB for its buffers and
+//! certificates.
+//!
+//! ```ignore
+//! ConnectionLimit::new(2).install();
+//!
+//! // A third connection waits until one of the first two is dropped
+//! let tls = connect_async_tls(&Tcp, "example.com", 443, &cfg).await?;
+//! ```
+//!
+//! The limit covers the sessions [`TlsConnector`](crate::TlsConnector) sets up, both
+//! [`AsyncTls`](crate::AsyncTls) and [`TlsStream`](crate::TlsStream), also the ones on sockets
+//! passed to [`adopt`](crate::TlsConnector::adopt). A session counts against the limit from just
+//! before the handshake until it is dropped or closed for being idle, see
+//! [`AsyncTls::set_idle_timeout`](crate::AsyncTls::set_idle_timeout). Name resolution and the TCP
+//! connect happen before, so a waiting connection holds a socket but no TLS session.
+
+use std::{
+    future::poll_fn,
+    sync::{Arc, Mutex},
+    task::{Poll, Waker},
+};
+
+use crate::error::{Error, Result};
+
+static LIMIT: Mutex<Option<Arc<Limiter>>> = Mutex::new(None);
+
+/// The most TLS sessions to have open at once, see the [module docs](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionLimit {
+    max: usize,
+    fail_fast: bool,
+}
+
+impl ConnectionLimit {
+    /// Allow up to `max` sessions, further connections wait for one to close.
+    pub fn new(max: usize) -> Self {
+        Self {
+            max: max.max(1),
+            fail_fast: false,
+        }
+    }
+
+    /// Fail further connections with [`Error::TooManyConnections`] instead of waiting.
+    pub fn fail_fast(mut self, fail_fast: bool) -> Self {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    /// Apply the limit to the connections opened from now on. Sessions open already count
+    /// against the limit they were opened under.
+    pub fn install(self) {
+        *LIMIT.lock().unwrap() = Some(Arc::new(Limiter {
+            limit: self,
+            state: Default::default(),
+        }));
+    }
+
+    /// Lift the limit for the connections opened from now on.
+    pub fn uninstall() {
+        *LIMIT.lock().unwrap() = None;
+    }
+
+    /// How many sessions are open under the installed limit, if there is one.
+    pub fn open_connections() -> Option<usize> {
+        let limiter = LIMIT.lock().unwrap().clone()?;
+        let open = limiter.state.lock().unwrap().open;
+
+        Some(open)
+    }
+}
+
+struct Limiter {
+    limit: ConnectionLimit,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    open: usize,
+    /// Connections waiting for a session to close
+    waiters: Vec<Waker>,
+}
+
+/// A session counted against the limit, released on drop.
+pub(crate) struct ConnectionPermit(Arc<Limiter>);
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let waiters = {
+            let mut state = self.0.state.lock().unwrap();
+            state.open -= 1;
+            core::mem::take(&mut state.waiters)
+        };
+
+        // The ones that don't get the free slot register again
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+}
+
+/// Count a new session against the installed limit, waiting for a free slot unless the limit
+/// fails fast. `None` without a limit.
+pub(crate) async fn acquire() -> Result<Option<ConnectionPermit>> {
+    let Some(limiter) = LIMIT.lock().unwrap().clone() else {
+        return Ok(None);
+    };
+
+    let mut logged = false;
+    poll_fn(|cx| {
+        let mut state = limiter.state.lock().unwrap();
+        let max = limiter.limit.max;
+
+        if state.open < max {
+            state.open += 1;
+            return Poll::Ready(Ok(Some(ConnectionPermit(limiter.clone()))));
+        }
+        if limiter.limit.fail_fast {
+            return Poll::Ready(Err(Error::TooManyConnections(max)));
+        }
+
+        if !logged {
+            log::debug!("{max} TLS sessions are open, waiting for one to close");
+            logged = true;
+        }
+        if !state.waiters.iter().any(|w| w.will_wake(cx.waker())) {
+            state.waiters.push(cx.waker().clone());
+        }
+
+        Poll::Pending
+    })
+    .await
+}
@@ -1,26 +1,20 @@
-use std::{
-    ffi::CStr,
-    io,
-    net::{TcpStream, ToSocketAddrs},
-    os::fd::{AsRawFd, IntoRawFd},
-    pin::{pin, Pin},
-    task::{Context, Poll},
-    time::Duration,
-};
+use std::{ffi::CStr, time::Duration};
 
-use anyhow::bail;
-use async_io::Async;
-use embedded_svc::wifi::AuthMethod;
 use esp_idf_hal::prelude::Peripherals;
 use esp_idf_svc::{
-    errors::EspIOError,
     eventloop::EspSystemEventLoop,
-    tls::{self, AsyncEspTls, PollableSocket, Socket, X509},
-    wifi::{BlockingWifi, EspWifi},
+    nvs::{EspDefaultNvsPartition, EspNvs},
+    tls::{self, X509},
 };
-use esp_idf_sys::{self as _, EspError, ESP_FAIL};
-use futures_lite::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Future};
+use esp_idf_sys as _;
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
 use log::*;
+use repro_async_tls::{
+    connect_async_tls, mem, time,
+    transport::Tcp,
+    wifi::{self, WifiCredentials},
+    AppConfig,
+};
 
 const CA_CERT: &str = "-----BEGIN CERTIFICATE-----
 MIIEvjCCA6agAwIBAgIQBtjZBNVYQ0b2ii+nVCJ+xDANBgkqhkiG9w0BAQsFADBh
@@ -51,121 +45,55 @@ ce1XR2bFuAJKZTRei9AqPCCcUZlM51Ke92sRKw2Sfh3oius2FkOH6ipjv3U/697E
 A7sKPPcw7+uvTPyLNhBzPvOk
 -----END CERTIFICATE-----\0";
 
-pub struct AsyncTcp(Option<Async<TcpStream>>);
-
-impl Socket for AsyncTcp {
-    fn handle(&self) -> i32 {
-        self.0.as_ref().unwrap().as_raw_fd()
-    }
-
-    fn release(&mut self) -> Result<(), esp_idf_sys::EspError> {
-        let socket = self.0.take().unwrap();
-        socket.into_inner().unwrap().into_raw_fd();
-
-        Ok(())
-    }
-}
-
-impl PollableSocket for AsyncTcp {
-    fn poll_readable(
-        &self,
-        ctx: &mut std::task::Context,
-    ) -> std::task::Poll<Result<(), esp_idf_sys::EspError>> {
-        pin!(&mut self.0.as_ref().unwrap().readable())
-            .poll(ctx)
-            .map_err(|e| {
-                log::error!("readable future returned error {e}");
-                EspError::from_infallible::<ESP_FAIL>()
-            })
-    }
-
-    fn poll_writable(
-        &self,
-        ctx: &mut std::task::Context,
-    ) -> std::task::Poll<Result<(), esp_idf_sys::EspError>> {
-        pin!(&mut self.0.as_ref().unwrap().writable())
-            .poll(ctx)
-            .map_err(|e| {
-                log::error!("writable future returned error {e}");
-                EspError::from_infallible::<ESP_FAIL>()
-            })
-    }
-}
-
-pub struct AsyncTls(pub AsyncEspTls<AsyncTcp>);
-
-impl AsyncRead for AsyncTls {
-    fn poll_read(
-        self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &mut [u8],
-    ) -> Poll<io::Result<usize>> {
-        pin!(self.0.read(buf))
-            .poll(cx)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, EspIOError(e)))
-    }
-}
-
-impl AsyncWrite for AsyncTls {
-    fn poll_write(
-        self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &[u8],
-    ) -> Poll<io::Result<usize>> {
-        pin!(self.0.write(buf))
-            .poll(cx)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, EspIOError(e)))
-    }
-
-    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Poll::Ready(Ok(()))
-    }
-
-    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Poll::Ready(Ok(()))
-    }
-}
-
-pub async fn connect_async_tls(
-    hostname: &str,
-    port: u16,
-    cfg: &esp_idf_svc::tls::Config<'_>,
-) -> anyhow::Result<AsyncTls> {
-    let tcp =
-        Async::<TcpStream>::connect((hostname, port).to_socket_addrs()?.next().unwrap()).await?;
-    let mut tls = AsyncEspTls::adopt(AsyncTcp(Some(tcp)))
-        .map_err(|e| anyhow::anyhow!("failed to create EspTls: {e}"))?;
-    log::info!("adopted async tcp stream");
-    dbg!(tls.negotiate(hostname, cfg).await)?;
-
-    Ok(AsyncTls(tls))
-}
+async fn get_request(config: &AppConfig) -> anyhow::Result<()> {
+    let host = config.target_host;
 
-async fn get_request() -> anyhow::Result<()> {
     info!("Connecting tls...");
     let mut tls = connect_async_tls(
-        "example.com",
-        443,
+        &Tcp,
+        host,
+        config.target_port,
         &tls::Config {
             ca_cert: Some(X509::pem(
                 CStr::from_bytes_with_nul(CA_CERT.as_bytes()).unwrap(),
             )),
-            common_name: Some("example.com"),
+            common_name: Some(host),
             timeout_ms: 0,
             ..Default::default()
         },
     )
     .await?;
     info!("Connected tls");
-    tls.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n")
-        .await?;
+    if let Some(timings) = tls.connect_timings() {
+        info!(
+            "DNS {:?}, TCP connect {:?}, TLS handshake {:?}",
+            timings.dns.unwrap_or_default(),
+            timings.tcp_connect.unwrap_or_default(),
+            timings.tls_handshake
+        );
+    }
+    if let Some(cert) = tls.peer_certificate() {
+        info!(
+            "Peer certificate: subject \"{}\", issuer \"{}\", valid until {}",
+            cert.subject, cert.issuer, cert.not_after
+        );
+    }
+    tls.write_all(
+        format!("GET / HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n").as_bytes(),
+    )
+    .await?;
     info!("Wrote tls");
-    async_io::Timer::after(Duration::from_secs(1)).await;
-    let mut buf = [0; 1024];
-    tls.read(&mut buf).await?;
+    let mut buf = mem::alloc_buffer(config.read_buffer_size);
+    time::with_timeout(tls.read(&mut buf), Duration::from_secs(10)).await??;
     let s = String::from_utf8_lossy(&buf);
     info!("response:\n{s}");
 
+    let stats = tls.stats();
+    info!(
+        "Read {} and wrote {} bytes in {:?}",
+        stats.bytes_read, stats.bytes_written, stats.duration
+    );
+
     Ok(())
 }
 
@@ -179,87 +107,25 @@ fn main() -> anyhow::Result<()> {
     let peripherals = Peripherals::take().unwrap();
     let sysloop = EspSystemEventLoop::take().unwrap();
 
-    let _wifi = {
-        let ssid = "ssid";
-        let pass = "pass";
+    let config = AppConfig::from_sdkconfig();
 
-        let mut auth_method = AuthMethod::WPA2Personal;
-        if ssid.is_empty() {
-            bail!("Missing WiFi name");
+    // Credentials provisioned at runtime take precedence over the ones of the build
+    let nvs = EspNvs::new(EspDefaultNvsPartition::take()?, "app", true)?;
+    let creds = match WifiCredentials::load(&nvs)? {
+        Some(creds) => {
+            info!("using the WiFi credentials stored in NVS");
+            creds
         }
-        if pass.is_empty() {
-            auth_method = AuthMethod::None;
-            info!("Wifi password is empty");
-        }
-        let mut esp_wifi = EspWifi::new(peripherals.modem, sysloop.clone(), None)?;
-
-        let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop)?;
-
-        wifi.set_configuration(&embedded_svc::wifi::Configuration::Client(
-            embedded_svc::wifi::ClientConfiguration::default(),
-        ))?;
-
-        info!("Starting wifi...");
-
-        wifi.start()?;
-
-        info!("Scanning...");
-
-        let ap_infos = wifi.scan()?;
-
-        let ours = ap_infos.into_iter().find(|a| a.ssid == ssid);
-
-        let channel = if let Some(ours) = ours {
-            info!(
-                "Found configured access point {} on channel {}",
-                ssid, ours.channel
-            );
-            Some(ours.channel)
-        } else {
-            info!(
-            "Configured access point {} not found during scanning, will go with unknown channel",
-            ssid
-        );
-            None
-        };
-
-        wifi.set_configuration(&embedded_svc::wifi::Configuration::Client(
-            embedded_svc::wifi::ClientConfiguration {
-                ssid: ssid.into(),
-                password: pass.into(),
-                channel,
-                auth_method,
-                ..Default::default()
-            },
-        ))?;
-
-        info!("Connecting wifi...");
-
-        wifi.connect()?;
-
-        info!("Waiting for DHCP lease...");
-
-        wifi.wait_netif_up()?;
-
-        let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
-
-        info!("Wifi STA DHCP info: {:?}", ip_info);
-
-        Box::new(esp_wifi)
+        None => WifiCredentials::new(config.wifi_ssid, config.wifi_pass),
     };
 
-    log::info!("setting eventfd config");
-    {
-        esp_idf_sys::esp!(unsafe {
-            esp_idf_sys::esp_vfs_eventfd_register(&esp_idf_sys::esp_vfs_eventfd_config_t {
-                max_fds: 5,
-                ..Default::default()
-            })
-        })?;
-    }
+    let _wifi = wifi::connect(peripherals.modem, sysloop, &creds.ssid, &creds.pass)?;
+
+    #[cfg(esp_idf_esp_console_uart)]
+    repro_async_tls::console::Console::new(Default::default()).spawn()?;
 
     log::info!("starting executor");
-    async_io::block_on(get_request())?;
+    async_io::block_on(get_request(&config))?;
     log::info!("stopped executor");
 
     loop {}
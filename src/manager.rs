@@ -0,0 +1,401 @@
+//! Several named TLS connections shared by the tasks of one executor, e.g. an MQTT broker, an
+//! OTA server and a log sink.
+//!
+//! ```ignore
+//! let mut manager = ConnectionManager::new(TlsConnector::new());
+//! let broker = manager.add("broker", "mqtt.example.com", 8883, broker_cfg);
+//! let logs = manager.add("logs", "logs.example.com", 443, logs_cfg);
+//! manager.connect_all().await;
+//!
+//! // In an application task
+//! let mut tls = broker.get().await?;
+//! if tls.write_all(&packet).await.is_err() {
+//!     tls.disconnect();
+//! }
+//! ```
+//!
+//! Connections are established on first use and after they were disconnected, and each is used
+//! by one task at a time. Handles are not `Send`, like [`AsyncTls`] itself, so all tasks using a
+//! manager run on the same executor.
+//!
+//! A status display can follow a connection with [`ConnectionHandle::state_changes`]:
+//!
+//! ```ignore
+//! let mut states = broker.state_changes();
+//! while let Some(state) = states.next().await {
+//!     display.show_broker(&state);
+//! }
+//! ```
+
+use core::{
+    cell::{Cell, RefCell},
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use std::{
+    future::poll_fn,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use esp_idf_svc::tls::Config;
+use futures_lite::Stream;
+
+use crate::{connector::TlsConnector, error::Result, time, tls::AsyncTls};
+
+/// What a managed connection is doing, see [`ConnectionHandle::state`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Not connected yet, or closed with [`ConnectionGuard::disconnect`]
+    Disconnected,
+    Connecting,
+    Connected,
+    /// The last attempt to connect failed, the next use tries again
+    Failed(String),
+    /// Waiting until `retry_at` to connect again after `failures` failed attempts in a row, see
+    /// [`ConnectionManager::backoff`]
+    Backoff {
+        failures: u32,
+        retry_at: Instant,
+    },
+}
+
+/// Owns the connections, see the [module docs](self).
+pub struct ConnectionManager {
+    connector: TlsConnector,
+    connections: Vec<ConnectionHandle>,
+    backoff: Option<Backoff>,
+}
+
+/// Delays between failed attempts to connect.
+#[derive(Clone, Copy)]
+struct Backoff {
+    initial: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    /// How long to wait after `failures` failed attempts in a row.
+    fn delay(&self, failures: u32) -> Duration {
+        self.initial
+            .saturating_mul(1 << (failures - 1).min(16))
+            .min(self.max)
+    }
+}
+
+impl ConnectionManager {
+    /// Establish connections with `connector`, i.e. with its proxy, DNS and TLS settings.
+    pub fn new(connector: TlsConnector) -> Self {
+        Self {
+            connector,
+            connections: Vec::new(),
+            backoff: None,
+        }
+    }
+
+    /// Wait `initial` after a failed attempt to connect before the next one, and double that for
+    /// each further failure in a row, up to `max`. Applies to the connections added afterwards.
+    ///
+    /// Without a backoff, the default, every use after a failure tries again right away.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.backoff = Some(Backoff { initial, max });
+        self
+    }
+
+    /// Manage a connection to `host:port` named `name`, which is established on first use or by
+    /// [`connect_all`](Self::connect_all).
+    ///
+    /// # Panics
+    ///
+    /// If a connection named `name` was added before.
+    pub fn add(
+        &mut self,
+        name: &str,
+        host: &str,
+        port: u16,
+        cfg: Config<'static>,
+    ) -> ConnectionHandle {
+        assert!(
+            self.get(name).is_none(),
+            "connection {name} was already added"
+        );
+
+        let handle = ConnectionHandle(Rc::new(Slot {
+            name: name.to_owned(),
+            host: host.to_owned(),
+            port,
+            cfg,
+            connector: self.connector.clone(),
+            backoff: self.backoff,
+            state: RefCell::new(ConnectionState::Disconnected),
+            changes: Cell::new(0),
+            watchers: RefCell::new(Vec::new()),
+            failures: Cell::new(0),
+            retry_at: Cell::new(None),
+            tls: RefCell::new(None),
+            busy: Cell::new(false),
+            waiters: RefCell::new(Vec::new()),
+        }));
+        self.connections.push(handle.clone());
+
+        handle
+    }
+
+    /// The connection named `name`.
+    pub fn get(&self, name: &str) -> Option<ConnectionHandle> {
+        self.connections
+            .iter()
+            .find(|handle| handle.name() == name)
+            .cloned()
+    }
+
+    /// The state of every connection, in the order they were added.
+    pub fn states(&self) -> Vec<(String, ConnectionState)> {
+        self.connections
+            .iter()
+            .map(|handle| (handle.name().to_owned(), handle.state()))
+            .collect()
+    }
+
+    /// Establish all connections that are not connected, concurrently. Failures are logged and
+    /// show up in the [states](Self::states).
+    pub async fn connect_all(&self) {
+        let mut pending: Vec<Pin<Box<dyn Future<Output = ()> + '_>>> = self
+            .connections
+            .iter()
+            .map(|handle| {
+                Box::pin(async move {
+                    if let Err(e) = handle.get().await {
+                        log::warn!("connecting {} failed: {e}", handle.name());
+                    }
+                }) as _
+            })
+            .collect();
+
+        poll_fn(|cx| {
+            pending.retain_mut(|connect| connect.as_mut().poll(cx).is_pending());
+            if pending.is_empty() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+/// Shared access to a managed connection, see [`ConnectionManager::add`].
+#[derive(Clone)]
+pub struct ConnectionHandle(Rc<Slot>);
+
+struct Slot {
+    name: String,
+    host: String,
+    port: u16,
+    cfg: Config<'static>,
+    connector: TlsConnector,
+    backoff: Option<Backoff>,
+    state: RefCell<ConnectionState>,
+    /// Counts the changes of `state`, for [`StateChanges`]
+    changes: Cell<u64>,
+    /// Tasks waiting for `state` to change
+    watchers: RefCell<Vec<Waker>>,
+    /// Failed attempts to connect in a row
+    failures: Cell<u32>,
+    /// When the backoff after the last failure ends
+    retry_at: Cell<Option<Instant>>,
+    /// The connection while no task holds it.
+    tls: RefCell<Option<AsyncTls>>,
+    busy: Cell<bool>,
+    /// Tasks waiting for `busy` to clear.
+    waiters: RefCell<Vec<Waker>>,
+}
+
+impl Slot {
+    fn set_state(&self, state: ConnectionState) {
+        log::debug!("connection {}: {state:?}", self.name);
+        *self.state.borrow_mut() = state;
+
+        self.changes.set(self.changes.get() + 1);
+        for waker in self.watchers.take() {
+            waker.wake();
+        }
+    }
+
+    /// The connection failed to connect, back off if configured to.
+    fn connect_failed(&self) {
+        let failures = self.failures.get().saturating_add(1);
+        self.failures.set(failures);
+
+        if let Some(backoff) = &self.backoff {
+            self.retry_at
+                .set(Some(Instant::now() + backoff.delay(failures)));
+        }
+    }
+}
+
+impl ConnectionHandle {
+    pub fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.0.state.borrow().clone()
+    }
+
+    /// The current state, followed by every change of it.
+    ///
+    /// States that change while the stream isn't polled are skipped in favor of the latest one.
+    /// The stream never ends.
+    pub fn state_changes(&self) -> StateChanges {
+        StateChanges {
+            slot: self.0.clone(),
+            seen: None,
+        }
+    }
+
+    /// Exclusive use of the connection, connecting first unless it is connected. Waits while
+    /// another task holds it.
+    pub async fn get(&self) -> Result<ConnectionGuard<'_>> {
+        let mut guard = self.acquire().await;
+        if guard.tls.is_some() {
+            return Ok(guard);
+        }
+
+        let slot = &*self.0;
+        if let Some(retry_at) = slot.retry_at.get().filter(|&at| at > Instant::now()) {
+            slot.set_state(ConnectionState::Backoff {
+                failures: slot.failures.get(),
+                retry_at,
+            });
+            time::sleep_until(retry_at).await;
+        }
+
+        slot.set_state(ConnectionState::Connecting);
+        match slot
+            .connector
+            .connect(&slot.host, slot.port, &slot.cfg)
+            .await
+        {
+            Ok(tls) => {
+                guard.tls = Some(tls);
+                slot.failures.set(0);
+                slot.retry_at.set(None);
+                slot.set_state(ConnectionState::Connected);
+
+                Ok(guard)
+            }
+            Err(e) => {
+                slot.connect_failed();
+                slot.set_state(ConnectionState::Failed(e.to_string()));
+
+                Err(e)
+            }
+        }
+    }
+
+    /// Close the connection once no task holds it, the next [`get`](Self::get) reconnects.
+    pub async fn disconnect(&self) {
+        self.acquire().await.disconnect();
+    }
+
+    async fn acquire(&self) -> ConnectionGuard<'_> {
+        let slot = &*self.0;
+        poll_fn(|cx| {
+            if slot.busy.get() {
+                slot.waiters.borrow_mut().push(cx.waker().clone());
+                Poll::Pending
+            } else {
+                slot.busy.set(true);
+                Poll::Ready(())
+            }
+        })
+        .await;
+
+        ConnectionGuard {
+            slot,
+            tls: slot.tls.borrow_mut().take(),
+        }
+    }
+}
+
+/// A connection held by one task, returned to its [`ConnectionHandle`] when dropped.
+pub struct ConnectionGuard<'a> {
+    slot: &'a Slot,
+    tls: Option<AsyncTls>,
+}
+
+impl ConnectionGuard<'_> {
+    /// Close the connection, e.g. after an I/O error. The next
+    /// [`ConnectionHandle::get`] reconnects.
+    pub fn disconnect(mut self) {
+        if self.tls.take().is_some() {
+            self.slot.set_state(ConnectionState::Disconnected);
+        }
+    }
+}
+
+impl Deref for ConnectionGuard<'_> {
+    type Target = AsyncTls;
+
+    fn deref(&self) -> &AsyncTls {
+        self.tls.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for ConnectionGuard<'_> {
+    fn deref_mut(&mut self) -> &mut AsyncTls {
+        self.tls.as_mut().unwrap()
+    }
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        match self.tls.take() {
+            Some(tls) => *self.slot.tls.borrow_mut() = Some(tls),
+            // Cancelled while connecting
+            None if matches!(
+                *self.slot.state.borrow(),
+                ConnectionState::Connecting | ConnectionState::Backoff { .. }
+            ) =>
+            {
+                self.slot.set_state(ConnectionState::Disconnected)
+            }
+            None => (),
+        }
+
+        self.slot.busy.set(false);
+        for waker in self.slot.waiters.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The states of a managed connection, see [`ConnectionHandle::state_changes`].
+pub struct StateChanges {
+    slot: Rc<Slot>,
+    /// The change count of the last state yielded
+    seen: Option<u64>,
+}
+
+impl Stream for StateChanges {
+    type Item = ConnectionState;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ConnectionState>> {
+        let changes = self.slot.changes.get();
+
+        if self.seen == Some(changes) {
+            let mut watchers = self.slot.watchers.borrow_mut();
+            if !watchers.iter().any(|w| w.will_wake(cx.waker())) {
+                watchers.push(cx.waker().clone());
+            }
+            return Poll::Pending;
+        }
+
+        self.seen = Some(changes);
+
+        Poll::Ready(Some(self.slot.state.borrow().clone()))
+    }
+}
@@ -0,0 +1,104 @@
+//! Connections that are encrypted or not, so that protocol code is written once for both, e.g.
+//! for a broker on the local network and one in the cloud.
+//!
+//! ```ignore
+//! let conn = match broker.tls {
+//!     true => connector.connect_maybe_tls(&broker.host, 8883, Some(&cfg)).await?,
+//!     false => connector.connect_maybe_tls(&broker.host, 1883, None).await?,
+//! };
+//! run_mqtt(conn).await
+//! ```
+
+use std::{
+    io::{self, IoSlice},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use esp_idf_svc::tls::PollableSocket;
+use futures_lite::{AsyncRead, AsyncWrite};
+
+use crate::{tcp::AsyncTcp, tls::AsyncTls};
+
+/// A plain socket or a TLS session on one, see the [module docs](self).
+pub enum MaybeTls<S: PollableSocket = AsyncTcp> {
+    Plain(S),
+    Tls(AsyncTls<S>),
+}
+
+impl<S: PollableSocket> MaybeTls<S> {
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Self::Tls(_))
+    }
+
+    /// The TLS session, if the connection is encrypted.
+    pub fn as_tls(&self) -> Option<&AsyncTls<S>> {
+        match self {
+            Self::Plain(_) => None,
+            Self::Tls(tls) => Some(tls),
+        }
+    }
+}
+
+impl<S: PollableSocket> From<AsyncTls<S>> for MaybeTls<S> {
+    fn from(tls: AsyncTls<S>) -> Self {
+        Self::Tls(tls)
+    }
+}
+
+impl<S> AsyncRead for MaybeTls<S>
+where
+    S: PollableSocket + AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(plain) => Pin::new(plain).poll_read(cx, buf),
+            Self::Tls(tls) => Pin::new(tls).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S> AsyncWrite for MaybeTls<S>
+where
+    S: PollableSocket + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(plain) => Pin::new(plain).poll_write(cx, buf),
+            Self::Tls(tls) => Pin::new(tls).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(plain) => Pin::new(plain).poll_write_vectored(cx, bufs),
+            Self::Tls(tls) => Pin::new(tls).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(plain) => Pin::new(plain).poll_flush(cx),
+            Self::Tls(tls) => Pin::new(tls).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(plain) => Pin::new(plain).poll_close(cx),
+            Self::Tls(tls) => Pin::new(tls).poll_close(cx),
+        }
+    }
+}
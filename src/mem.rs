@@ -0,0 +1,377 @@
+//! Buffers and memory statistics.
+//!
+//! The I/O buffers of the crate, e.g. the read buffers of [`AsyncTls`](crate::AsyncTls) and the
+//! chunks of OTA updates and uploads, come from [`alloc_buffer`]. Devices that run for months can
+//! allocate them once instead, from a [`BufferPool`], so that connecting and disconnecting doesn't
+//! fragment the heap:
+//!
+//! ```ignore
+//! // At init, before connecting
+//! BufferPool::new()
+//!     .buffers(1024, 4)
+//!     .buffers(4096, 2)
+//!     .install();
+//! ```
+
+use core::{
+    fmt,
+    ops::{Deref, DerefMut},
+};
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "esp")]
+use esp_idf_sys as sys;
+
+static POOL: Mutex<Option<Arc<Pool>>> = Mutex::new(None);
+
+/// Buffers allocated up front, see the [module docs](self).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BufferPool {
+    /// Buffer length and count
+    classes: Vec<(usize, usize)>,
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `count` buffers of `len` bytes. A request is served by the smallest free buffer that
+    /// is large enough.
+    pub fn buffers(mut self, len: usize, count: usize) -> Self {
+        match self.classes.iter_mut().find(|(class, _)| *class == len) {
+            Some((_, total)) => *total += count,
+            None => self.classes.push((len, count)),
+        }
+        self
+    }
+
+    /// Allocate the buffers and serve [`alloc_buffer`] from them from now on. Requests that no
+    /// free buffer fits are allocated from the heap as before, see [`misses`](Self::misses).
+    ///
+    /// Buffers handed out already return to the pool they came from.
+    pub fn install(self) {
+        *POOL.lock().unwrap() = Some(Arc::new(self.allocate()));
+    }
+
+    fn allocate(mut self) -> Pool {
+        self.classes.sort_unstable();
+
+        let classes = self
+            .classes
+            .into_iter()
+            .map(|(len, count)| Class {
+                len,
+                free: (0..count).map(|_| alloc_heap(len)).collect(),
+            })
+            .collect();
+
+        Pool {
+            state: Mutex::new(PoolState { classes, misses: 0 }),
+        }
+    }
+
+    /// Allocate the buffers from the heap from now on. The pool is freed once its buffers that
+    /// are in use are dropped.
+    pub fn uninstall() {
+        *POOL.lock().unwrap() = None;
+    }
+
+    /// How many requests the installed pool, if any, couldn't serve, because all buffers large
+    /// enough were in use.
+    pub fn misses() -> Option<u64> {
+        let pool = POOL.lock().unwrap().clone()?;
+        let misses = pool.state.lock().unwrap().misses;
+
+        Some(misses)
+    }
+}
+
+struct Pool {
+    state: Mutex<PoolState>,
+}
+
+struct PoolState {
+    /// By length, ascending
+    classes: Vec<Class>,
+    misses: u64,
+}
+
+struct Class {
+    len: usize,
+    free: Vec<Box<[u8]>>,
+}
+
+impl Pool {
+    fn take(&self, len: usize) -> Option<Box<[u8]>> {
+        let mut state = self.state.lock().unwrap();
+
+        let taken = state
+            .classes
+            .iter_mut()
+            .filter(|class| class.len >= len)
+            .find_map(|class| class.free.pop());
+        if taken.is_none() {
+            state.misses += 1;
+        }
+
+        taken
+    }
+
+    fn put(&self, data: Box<[u8]>) {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(class) = state
+            .classes
+            .iter_mut()
+            .find(|class| class.len == data.len())
+        {
+            class.free.push(data);
+        }
+    }
+}
+
+/// A zeroed buffer of [`alloc_buffer`], which returns to its [`BufferPool`] when dropped.
+#[derive(Default)]
+pub struct Buffer {
+    data: Box<[u8]>,
+    /// Of `data`, which is longer if it came from a pool
+    len: usize,
+    pool: Option<Arc<Pool>>,
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data[..self.len]
+    }
+}
+
+impl fmt::Debug for Buffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Buffer")
+            .field("len", &self.len)
+            .field("pooled", &self.pool.is_some())
+            .finish()
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.put(core::mem::take(&mut self.data));
+        }
+    }
+}
+
+/// Allocate a zeroed buffer for the crate's I/O, from the installed [`BufferPool`] if it has a
+/// free buffer that fits.
+///
+/// With the `psram` feature buffers are placed in external PSRAM when there is any, keeping
+/// internal RAM free for WiFi and the executor. They fall back to the regular heap otherwise.
+pub fn alloc_buffer(len: usize) -> Buffer {
+    let pool = POOL.lock().unwrap().clone();
+
+    alloc_from(pool, len)
+}
+
+fn alloc_from(pool: Option<Arc<Pool>>, len: usize) -> Buffer {
+    if let Some(pool) = pool.filter(|_| len > 0) {
+        if let Some(mut data) = pool.take(len) {
+            // Cleared like a fresh allocation, for what the previous user left
+            data[..len].fill(0);
+
+            return Buffer {
+                data,
+                len,
+                pool: Some(pool),
+            };
+        }
+        log::debug!("no pooled buffer of {len} bytes free, allocating one");
+    }
+
+    Buffer {
+        data: alloc_heap(len),
+        len,
+        pool: None,
+    }
+}
+
+fn alloc_heap(len: usize) -> Box<[u8]> {
+    #[cfg(feature = "psram")]
+    if len > 0 {
+        let ptr =
+            unsafe { sys::heap_caps_calloc(1, len, sys::MALLOC_CAP_SPIRAM | sys::MALLOC_CAP_8BIT) }
+                as *mut u8;
+
+        if !ptr.is_null() {
+            // The global allocator of ESP-IDF uses `malloc`/`free`, and `free` releases memory of
+            // any capability region, so the box can be dropped as usual
+            return unsafe { Box::from_raw(core::ptr::slice_from_raw_parts_mut(ptr, len)) };
+        }
+    }
+
+    vec![0; len].into_boxed_slice()
+}
+
+/// Free bytes in external PSRAM, 0 if there is none.
+#[cfg(feature = "esp")]
+pub fn free_psram() -> usize {
+    unsafe { sys::heap_caps_get_free_size(sys::MALLOC_CAP_SPIRAM) }
+}
+
+/// Free bytes of internal RAM, where mbedtls allocates its contexts and buffers unless
+/// `CONFIG_MBEDTLS_EXTERNAL_MEM_ALLOC` is set.
+#[cfg(feature = "esp")]
+pub fn free_internal() -> usize {
+    unsafe { sys::heap_caps_get_free_size(sys::MALLOC_CAP_INTERNAL | sys::MALLOC_CAP_8BIT) }
+}
+
+/// Heap and stack usage at one point in time, see [`MemSnapshot::take`].
+#[cfg(feature = "esp")]
+#[derive(Clone, Copy, Debug)]
+pub struct MemSnapshot {
+    /// Free heap in bytes, all capability regions
+    pub free_heap: usize,
+    /// Free internal RAM in bytes
+    pub free_internal: usize,
+    /// Lowest free heap ever seen since boot
+    pub min_free_heap: usize,
+    /// Largest block that can currently be allocated
+    pub largest_free_block: usize,
+    /// Minimum free stack of the current task since it started, in bytes
+    pub stack_high_water_mark: usize,
+}
+
+#[cfg(feature = "esp")]
+impl MemSnapshot {
+    pub fn take() -> Self {
+        unsafe {
+            Self {
+                free_heap: sys::esp_get_free_heap_size() as usize,
+                free_internal: sys::esp_get_free_internal_heap_size() as usize,
+                min_free_heap: sys::esp_get_minimum_free_heap_size() as usize,
+                largest_free_block: sys::heap_caps_get_largest_free_block(sys::MALLOC_CAP_8BIT),
+                stack_high_water_mark: sys::uxTaskGetStackHighWaterMark(core::ptr::null_mut())
+                    as usize,
+            }
+        }
+    }
+
+    /// Log how memory usage changed from `self` to `later`.
+    pub fn log_diff(&self, later: &MemSnapshot, what: &str) {
+        log::info!(
+            "{what}: free heap {} -> {} ({:+}), free internal {} -> {} ({:+}), min free heap {}, largest free block {}, stack high water mark {} bytes",
+            self.free_heap,
+            later.free_heap,
+            later.free_heap as isize - self.free_heap as isize,
+            self.free_internal,
+            later.free_internal,
+            later.free_internal as isize - self.free_internal as isize,
+            later.min_free_heap,
+            later.largest_free_block,
+            later.stack_high_water_mark,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(classes: &[(usize, usize)]) -> Arc<Pool> {
+        let pool = classes
+            .iter()
+            .fold(BufferPool::new(), |pool, &(len, count)| {
+                pool.buffers(len, count)
+            });
+
+        Arc::new(pool.allocate())
+    }
+
+    fn free(pool: &Pool) -> Vec<(usize, usize)> {
+        let state = pool.state.lock().unwrap();
+
+        state
+            .classes
+            .iter()
+            .map(|class| (class.len, class.free.len()))
+            .collect()
+    }
+
+    #[test]
+    fn reuse() {
+        let pool = pool(&[(64, 1)]);
+
+        let mut buf = alloc_from(Some(pool.clone()), 10);
+        assert_eq!(buf.len(), 10);
+        assert!(buf.pool.is_some());
+        let ptr = buf.data.as_ptr();
+        buf.fill(0xff);
+        drop(buf);
+
+        let buf = alloc_from(Some(pool.clone()), 64);
+        assert_eq!(buf.data.as_ptr(), ptr);
+        assert!(buf.iter().all(|&b| b == 0));
+        assert_eq!(pool.state.lock().unwrap().misses, 0);
+    }
+
+    #[test]
+    fn smallest_fit() {
+        let pool = pool(&[(4096, 1), (1024, 1)]);
+
+        let small = alloc_from(Some(pool.clone()), 100);
+        assert_eq!(small.data.len(), 1024);
+        let large = alloc_from(Some(pool.clone()), 100);
+        assert_eq!(large.data.len(), 4096);
+        assert_eq!(free(&pool), [(1024, 0), (4096, 0)]);
+    }
+
+    #[test]
+    fn capacity() {
+        let pool = pool(&[(64, 2)]);
+
+        let a = alloc_from(Some(pool.clone()), 64);
+        let b = alloc_from(Some(pool.clone()), 64);
+        let c = alloc_from(Some(pool.clone()), 64);
+        assert!(a.pool.is_some() && b.pool.is_some());
+        assert!(c.pool.is_none());
+        assert_eq!(c.len(), 64);
+
+        let too_long = alloc_from(Some(pool.clone()), 65);
+        assert!(too_long.pool.is_none());
+        assert_eq!(pool.state.lock().unwrap().misses, 2);
+
+        // Heap buffers don't join the pool
+        drop((c, too_long));
+        assert_eq!(free(&pool), [(64, 0)]);
+    }
+
+    #[test]
+    fn returned_on_drop() {
+        let pool = pool(&[(64, 2)]);
+
+        let a = alloc_from(Some(pool.clone()), 32);
+        let b = alloc_from(Some(pool.clone()), 32);
+        assert_eq!(free(&pool), [(64, 0)]);
+
+        drop(a);
+        assert_eq!(free(&pool), [(64, 1)]);
+        drop(b);
+        assert_eq!(free(&pool), [(64, 2)]);
+    }
+
+    #[test]
+    fn no_pool() {
+        let buf = alloc_from(None, 16);
+        assert_eq!(&*buf, &[0; 16]);
+        assert!(buf.pool.is_none());
+    }
+}
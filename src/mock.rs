@@ -0,0 +1,209 @@
+//! An in-memory socket for exercising the stream adapters and protocol layers without a network,
+//! e.g. a [`TlsStream`](crate::TlsStream) client against a [`TlsAcceptor`](crate::TlsAcceptor)
+//! or a [`WebSocket`](crate::websocket::WebSocket) against a scripted peer.
+
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+#[cfg(feature = "esp")]
+use esp_idf_svc::tls::{PollableSocket, Socket};
+#[cfg(feature = "esp")]
+use esp_idf_sys::{EspError, ESP_FAIL};
+use futures_lite::{AsyncRead, AsyncWrite};
+
+/// One end of an in-memory duplex pipe, see [`MockSocket::pair`].
+///
+/// Implements the same traits as [`AsyncTcp`](crate::AsyncTcp). It has no file descriptor
+/// though, so esp-tls can't adopt it, use [`TlsStream`](crate::TlsStream) instead.
+pub struct MockSocket {
+    read: Arc<Mutex<Pipe>>,
+    write: Arc<Mutex<Pipe>>,
+}
+
+/// One direction of the connection.
+struct Pipe {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    /// The writing end closed or was dropped, reads return EOF once `buf` is drained.
+    write_closed: bool,
+    /// The reading end was dropped, writes fail.
+    read_closed: bool,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+}
+
+impl Pipe {
+    fn new(capacity: usize) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            buf: VecDeque::new(),
+            capacity,
+            write_closed: false,
+            read_closed: false,
+            reader: None,
+            writer: None,
+        }))
+    }
+
+    fn wake_reader(&mut self) {
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(waker) = self.writer.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl MockSocket {
+    /// Two connected sockets, each buffering up to 16 KiB in either direction.
+    pub fn pair() -> (Self, Self) {
+        Self::pair_with_capacity(16 * 1024)
+    }
+
+    /// Two connected sockets buffering up to `capacity` bytes per direction, a small capacity
+    /// exercises partial writes and `WouldBlock` paths.
+    pub fn pair_with_capacity(capacity: usize) -> (Self, Self) {
+        let (a_to_b, b_to_a) = (Pipe::new(capacity), Pipe::new(capacity));
+
+        (
+            Self {
+                read: b_to_a.clone(),
+                write: a_to_b.clone(),
+            },
+            Self {
+                read: a_to_b,
+                write: b_to_a,
+            },
+        )
+    }
+
+    /// Bytes written by the peer that have not been read yet.
+    pub fn pending(&self) -> usize {
+        self.read.lock().unwrap().buf.len()
+    }
+}
+
+impl Drop for MockSocket {
+    fn drop(&mut self) {
+        let mut write = self.write.lock().unwrap();
+        write.write_closed = true;
+        write.wake_reader();
+        drop(write);
+
+        let mut read = self.read.lock().unwrap();
+        read.read_closed = true;
+        read.wake_writer();
+    }
+}
+
+impl AsyncRead for MockSocket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut pipe = self.read.lock().unwrap();
+
+        if pipe.buf.is_empty() {
+            if pipe.write_closed || buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+
+            pipe.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let len = buf.len().min(pipe.buf.len());
+        for (dst, src) in buf.iter_mut().zip(pipe.buf.drain(..len)) {
+            *dst = src;
+        }
+        pipe.wake_writer();
+
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for MockSocket {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut pipe = self.write.lock().unwrap();
+
+        if pipe.read_closed || pipe.write_closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        let len = buf.len().min(pipe.capacity - pipe.buf.len());
+        if len == 0 && !buf.is_empty() {
+            pipe.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        pipe.buf.extend(&buf[..len]);
+        pipe.wake_reader();
+
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Shuts down the writing direction, the peer reads EOF after the buffered data.
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut pipe = self.write.lock().unwrap();
+        pipe.write_closed = true;
+        pipe.wake_reader();
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "esp")]
+impl Socket for MockSocket {
+    fn handle(&self) -> i32 {
+        -1
+    }
+
+    fn release(&mut self) -> Result<(), EspError> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "esp")]
+impl PollableSocket for MockSocket {
+    fn poll_readable(&self, ctx: &mut Context) -> Poll<Result<(), EspError>> {
+        let mut pipe = self.read.lock().unwrap();
+
+        if pipe.buf.is_empty() && !pipe.write_closed {
+            pipe.reader = Some(ctx.waker().clone());
+            return Poll::Pending;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_writable(&self, ctx: &mut Context) -> Poll<Result<(), EspError>> {
+        let mut pipe = self.write.lock().unwrap();
+
+        if pipe.read_closed {
+            return Poll::Ready(Err(EspError::from_infallible::<ESP_FAIL>()));
+        }
+        if pipe.buf.len() == pipe.capacity {
+            pipe.writer = Some(ctx.waker().clone());
+            return Poll::Pending;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,875 @@
+//! Several byte streams over one connection, e.g. telemetry, commands and file transfers over one
+//! [`AsyncTls`](crate::AsyncTls), for the RAM of a single TLS session.
+//!
+//! ```ignore
+//! let (mux, driver) = Multiplexer::new(Role::Client).build(tls);
+//! thread::spawn(move || async_io::block_on(driver.run()));
+//!
+//! let mut commands = mux.open()?;
+//! commands.write_all(b"status\n").await?;
+//!
+//! // A stream the server opened
+//! let mut upload = mux.accept().await?;
+//! ```
+//!
+//! The framing is the one of yamux: 12 byte headers with SYN, ACK, FIN and RST flags, a window
+//! per stream that the receiver extends with window updates as it reads, pings and a go away.
+//! Unlike yamux, streams start with a window of [`DEFAULT_WINDOW`] rather than 256 KiB, so both
+//! ends have to agree on the [`window`](Multiplexer::window). Each stream buffers up to a window
+//! of received data, a slow reader holds up its own stream only.
+//!
+//! The connection is read and written by the [`MuxDriver`], streams only make progress while it
+//! runs.
+
+use core::{
+    future::poll_fn,
+    pin::Pin,
+    task::{ready, Context, Poll, Waker},
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    io, mem,
+    sync::{Arc, Mutex},
+};
+
+use futures_lite::{AsyncRead, AsyncWrite};
+
+/// Initial window of the streams unless configured otherwise.
+pub const DEFAULT_WINDOW: u32 = 16 * 1024;
+
+/// Most streams open at once unless configured otherwise.
+pub const DEFAULT_MAX_STREAMS: usize = 16;
+
+const HEADER_LEN: usize = 12;
+
+/// Largest data frame sent, so that a busy stream doesn't hold up the others for long
+const MAX_DATA_FRAME: u32 = 4 * 1024;
+
+/// Bytes queued for the driver before writers wait
+const MAX_OUTBOUND: usize = 8 * 1024;
+
+const TYPE_DATA: u8 = 0;
+const TYPE_WINDOW_UPDATE: u8 = 1;
+const TYPE_PING: u8 = 2;
+const TYPE_GO_AWAY: u8 = 3;
+
+const FLAG_SYN: u16 = 1;
+const FLAG_ACK: u16 = 2;
+const FLAG_FIN: u16 = 4;
+const FLAG_RST: u16 = 8;
+
+/// The end of the connection, the client's streams have odd ids and the server's even ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// Sets up the multiplexer, see the [module docs](self).
+pub struct Multiplexer {
+    role: Role,
+    window: u32,
+    max_streams: usize,
+}
+
+impl Multiplexer {
+    pub fn new(role: Role) -> Self {
+        Self {
+            role,
+            window: DEFAULT_WINDOW,
+            max_streams: DEFAULT_MAX_STREAMS,
+        }
+    }
+
+    /// Let the peer send up to `window` bytes per stream ahead of the reader, which is also what
+    /// a stream buffers at most. Has to match the peer's.
+    pub fn window(mut self, window: u32) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    /// Allow up to `max` streams open at once, opened by either end. The peer's further ones are
+    /// reset.
+    pub fn max_streams(mut self, max: usize) -> Self {
+        self.max_streams = max.max(1);
+        self
+    }
+
+    pub fn build<T>(self, io: T) -> (Mux, MuxDriver<T>)
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let shared = Arc::new(Shared {
+            window: self.window,
+            max_streams: self.max_streams,
+            state: Mutex::new(State {
+                next_id: match self.role {
+                    Role::Client => 1,
+                    Role::Server => 2,
+                },
+                ..Default::default()
+            }),
+        });
+
+        (
+            Mux {
+                shared: shared.clone(),
+            },
+            MuxDriver {
+                io,
+                shared,
+                rx: vec![0; HEADER_LEN],
+                filled: 0,
+                tx: VecDeque::new(),
+                unflushed: false,
+            },
+        )
+    }
+}
+
+struct Shared {
+    window: u32,
+    max_streams: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    streams: HashMap<u32, Stream>,
+    next_id: u32,
+    /// Streams the peer opened that weren't accepted yet
+    incoming: VecDeque<u32>,
+    accept_waker: Option<Waker>,
+    /// Frames for the driver to send
+    outbound: VecDeque<u8>,
+    /// Writers waiting for `outbound` to drain
+    outbound_waiters: Vec<Waker>,
+    driver_waker: Option<Waker>,
+    /// Set once the go away is queued
+    closing: bool,
+    /// Set once the driver stopped
+    closed: bool,
+}
+
+impl State {
+    fn frame(&mut self, kind: u8, flags: u16, id: u32, len: u32, payload: &[u8]) {
+        self.outbound.extend([0, kind]);
+        self.outbound.extend(flags.to_be_bytes());
+        self.outbound.extend(id.to_be_bytes());
+        self.outbound.extend(len.to_be_bytes());
+        self.outbound.extend(payload);
+
+        if let Some(waker) = self.driver_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn shut_down(&mut self) {
+        self.closed = true;
+
+        for stream in self.streams.values_mut() {
+            stream.wake();
+        }
+        if let Some(waker) = self.accept_waker.take() {
+            waker.wake();
+        }
+        for waker in mem::take(&mut self.outbound_waiters) {
+            waker.wake();
+        }
+    }
+}
+
+struct Stream {
+    recv: VecDeque<u8>,
+    /// What the peer may send before the next window update
+    recv_window: u32,
+    /// Read since the last window update
+    consumed: u32,
+    send_window: u32,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    /// FIN sent
+    local_closed: bool,
+    /// FIN received
+    remote_closed: bool,
+    reset: bool,
+}
+
+impl Stream {
+    fn new(window: u32) -> Self {
+        Self {
+            recv: VecDeque::new(),
+            recv_window: window,
+            consumed: 0,
+            send_window: window,
+            read_waker: None,
+            write_waker: None,
+            local_closed: false,
+            remote_closed: false,
+            reset: false,
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Opens and accepts streams, cheap to clone.
+#[derive(Clone)]
+pub struct Mux {
+    shared: Arc<Shared>,
+}
+
+impl Mux {
+    /// Open a stream, which the peer gets from its [`accept`](Self::accept).
+    pub fn open(&self) -> io::Result<MuxStream> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closing || state.closed {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        if state.streams.len() >= self.shared.max_streams {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "{} multiplexed streams are open already",
+                    self.shared.max_streams
+                ),
+            ));
+        }
+
+        let id = state.next_id;
+        state.next_id = id
+            .checked_add(2)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "stream ids exhausted"))?;
+        state.streams.insert(id, Stream::new(self.shared.window));
+        state.frame(TYPE_WINDOW_UPDATE, FLAG_SYN, id, 0, &[]);
+
+        Ok(MuxStream {
+            id,
+            shared: self.shared.clone(),
+        })
+    }
+
+    /// Wait for the next stream the peer opens. Fails once the connection is closed.
+    pub async fn accept(&self) -> io::Result<MuxStream> {
+        poll_fn(|cx| self.poll_accept(cx)).await
+    }
+
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<MuxStream>> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        if let Some(id) = state.incoming.pop_front() {
+            return Poll::Ready(Ok(MuxStream {
+                id,
+                shared: self.shared.clone(),
+            }));
+        }
+        if state.closing || state.closed {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionAborted.into()));
+        }
+
+        state.accept_waker = Some(cx.waker().clone());
+
+        Poll::Pending
+    }
+
+    /// Tell the peer to go away and close the connection once the queued frames are sent, which
+    /// ends all streams.
+    pub fn close(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+
+        if !state.closing && !state.closed {
+            state.closing = true;
+            state.frame(TYPE_GO_AWAY, 0, 0, 0, &[]);
+        }
+    }
+
+    /// Whether the driver stopped, because the connection was closed or failed.
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock().unwrap().closed
+    }
+}
+
+/// One of the streams of a [`Mux`].
+///
+/// Closing sends a FIN, after which the peer reads to the end. Dropping a stream before closing
+/// it resets it, discarding what the peer didn't read yet. Flushing waits until the driver took
+/// the written data.
+pub struct MuxStream {
+    id: u32,
+    shared: Arc<Shared>,
+}
+
+impl MuxStream {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let window = self.shared.window;
+        let mut state = self.shared.state.lock().unwrap();
+        let state = &mut *state;
+        // Removed only when the stream is dropped
+        let stream = state.streams.get_mut(&self.id).unwrap();
+
+        if !stream.recv.is_empty() {
+            let n = buf.len().min(stream.recv.len());
+            for (dst, src) in buf.iter_mut().zip(stream.recv.drain(..n)) {
+                *dst = src;
+            }
+
+            stream.consumed += n as u32;
+            if stream.consumed >= (window / 2).max(1) && !stream.remote_closed {
+                let delta = mem::take(&mut stream.consumed);
+                stream.recv_window += delta;
+                state.frame(TYPE_WINDOW_UPDATE, 0, self.id, delta, &[]);
+            }
+
+            return Poll::Ready(Ok(n));
+        }
+
+        if stream.reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if stream.remote_closed {
+            return Poll::Ready(Ok(0));
+        }
+        if state.closed {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionAborted.into()));
+        }
+
+        stream.read_waker = Some(cx.waker().clone());
+
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let mut state = self.shared.state.lock().unwrap();
+        let state = &mut *state;
+        let stream = state.streams.get_mut(&self.id).unwrap();
+
+        if stream.reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if stream.local_closed || state.closing || state.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        // Wait for the peer to read
+        if stream.send_window == 0 {
+            stream.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        // Wait for the connection to catch up
+        if state.outbound.len() >= MAX_OUTBOUND {
+            state.outbound_waiters.push(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let len = buf
+            .len()
+            .min(stream.send_window as usize)
+            .min(MAX_DATA_FRAME as usize);
+        stream.send_window -= len as u32;
+        state.frame(TYPE_DATA, 0, self.id, len as u32, &buf[..len]);
+
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        if state.outbound.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        if state.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        state.outbound_waiters.push(cx.waker().clone());
+
+        Poll::Pending
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            let state = &mut *state;
+            let stream = state.streams.get_mut(&self.id).unwrap();
+
+            if !stream.local_closed && !stream.reset && !state.closed {
+                stream.local_closed = true;
+                state.frame(TYPE_DATA, FLAG_FIN, self.id, 0, &[]);
+            }
+        }
+
+        self.poll_flush(cx)
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        let Some(stream) = state.streams.remove(&self.id) else {
+            return;
+        };
+
+        if !stream.local_closed && !stream.reset && !state.closed {
+            state.frame(TYPE_WINDOW_UPDATE, FLAG_RST, self.id, 0, &[]);
+        }
+    }
+}
+
+/// Reads and writes the connection on behalf of the streams, returned by
+/// [`Multiplexer::build`].
+pub struct MuxDriver<T> {
+    io: T,
+    shared: Arc<Shared>,
+    /// The frame being read, header first
+    rx: Vec<u8>,
+    filled: usize,
+    /// Frames taken from the queue, being written
+    tx: VecDeque<u8>,
+    unflushed: bool,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> MuxDriver<T> {
+    /// Run the connection until it is closed, by either end, or fails. The streams fail
+    /// afterwards, and so they do if the driver is dropped.
+    pub async fn run(mut self) -> io::Result<()> {
+        poll_fn(|cx| self.poll_run(cx)).await
+    }
+
+    fn poll_run(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.state.lock().unwrap().driver_waker = Some(cx.waker().clone());
+
+        loop {
+            let sent = self.poll_send(cx)?;
+
+            let closing = {
+                let state = self.shared.state.lock().unwrap();
+                state.closing && state.outbound.is_empty()
+            };
+            if closing && self.tx.is_empty() && !self.unflushed {
+                ready!(Pin::new(&mut self.io).poll_close(cx))?;
+                return Poll::Ready(Ok(()));
+            }
+
+            let Some(received) = self.poll_receive(cx)? else {
+                return Poll::Ready(Ok(()));
+            };
+
+            if !sent && !received {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    /// Write queued frames until the connection isn't ready. Returns whether anything was
+    /// written.
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> io::Result<bool> {
+        let mut progress = false;
+
+        loop {
+            if self.tx.is_empty() {
+                let mut state = self.shared.state.lock().unwrap();
+                mem::swap(&mut self.tx, &mut state.outbound);
+                for waker in mem::take(&mut state.outbound_waiters) {
+                    waker.wake();
+                }
+
+                if self.tx.is_empty() {
+                    break;
+                }
+            }
+
+            let (chunk, _) = self.tx.as_slices();
+            match Pin::new(&mut self.io).poll_write(cx, chunk) {
+                Poll::Ready(Ok(0)) => return Err(io::ErrorKind::WriteZero.into()),
+                Poll::Ready(Ok(n)) => {
+                    self.tx.drain(..n);
+                    self.unflushed = true;
+                    progress = true;
+                }
+                Poll::Ready(Err(e)) => return Err(e),
+                Poll::Pending => return Ok(progress),
+            }
+        }
+
+        if self.unflushed {
+            if let Poll::Ready(result) = Pin::new(&mut self.io).poll_flush(cx) {
+                result?;
+                self.unflushed = false;
+            }
+        }
+
+        Ok(progress)
+    }
+
+    /// Read and dispatch frames until the connection has no more data. Returns whether anything
+    /// was read, or `None` once the peer closed the connection or went away.
+    fn poll_receive(&mut self, cx: &mut Context<'_>) -> io::Result<Option<bool>> {
+        let mut progress = false;
+
+        loop {
+            match Pin::new(&mut self.io).poll_read(cx, &mut self.rx[self.filled..]) {
+                Poll::Ready(Ok(0)) if self.filled > 0 => {
+                    return Err(io::ErrorKind::UnexpectedEof.into())
+                }
+                Poll::Ready(Ok(0)) => return Ok(None),
+                Poll::Ready(Ok(n)) => {
+                    self.filled += n;
+                    progress = true;
+                }
+                Poll::Ready(Err(e)) => return Err(e),
+                Poll::Pending => return Ok(Some(progress)),
+            }
+
+            if self.filled < self.rx.len() {
+                continue;
+            }
+
+            let header = Header::parse(&self.rx[..HEADER_LEN])?;
+            if header.kind == TYPE_DATA && header.len > 0 && self.rx.len() == HEADER_LEN {
+                if header.len > self.shared.window {
+                    return Err(invalid("data frame larger than the window"));
+                }
+                self.rx.resize(HEADER_LEN + header.len as usize, 0);
+                continue;
+            }
+
+            let went_away = self.dispatch(header)?;
+            self.rx.truncate(HEADER_LEN);
+            self.filled = 0;
+
+            if went_away {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Handle a complete frame, returns whether the peer went away.
+    fn dispatch(&self, header: Header) -> io::Result<bool> {
+        let Header {
+            kind,
+            flags,
+            id,
+            len,
+        } = header;
+        let mut state = self.shared.state.lock().unwrap();
+        let state = &mut *state;
+
+        match kind {
+            TYPE_DATA | TYPE_WINDOW_UPDATE => {}
+            TYPE_PING => {
+                if flags & FLAG_SYN != 0 {
+                    state.frame(TYPE_PING, FLAG_ACK, 0, len, &[]);
+                }
+                return Ok(false);
+            }
+            TYPE_GO_AWAY => {
+                log::debug!("multiplexer peer went away with code {len}");
+                return Ok(true);
+            }
+            _ => return Err(invalid("unknown frame type")),
+        }
+
+        if flags & FLAG_SYN != 0 {
+            // Ours have the parity of `next_id`
+            if id == 0 || id % 2 == state.next_id % 2 || state.streams.contains_key(&id) {
+                return Err(invalid("invalid stream id"));
+            }
+            if state.closing || state.streams.len() >= self.shared.max_streams {
+                state.frame(TYPE_WINDOW_UPDATE, FLAG_RST, id, 0, &[]);
+                return Ok(false);
+            }
+
+            state.streams.insert(id, Stream::new(self.shared.window));
+            state.incoming.push_back(id);
+            if let Some(waker) = state.accept_waker.take() {
+                waker.wake();
+            }
+            state.frame(TYPE_WINDOW_UPDATE, FLAG_ACK, id, 0, &[]);
+        }
+
+        // Frames for streams dropped meanwhile are discarded
+        let Some(stream) = state.streams.get_mut(&id) else {
+            return Ok(false);
+        };
+
+        if kind == TYPE_DATA {
+            let payload = &self.rx[HEADER_LEN..];
+            if payload.len() > stream.recv_window as usize {
+                return Err(invalid("peer exceeded the stream window"));
+            }
+            stream.recv_window -= payload.len() as u32;
+            stream.recv.extend(payload);
+        } else {
+            stream.send_window = stream.send_window.saturating_add(len);
+        }
+        if flags & FLAG_FIN != 0 {
+            stream.remote_closed = true;
+        }
+        if flags & FLAG_RST != 0 {
+            stream.reset = true;
+        }
+        stream.wake();
+
+        Ok(false)
+    }
+}
+
+impl<T> Drop for MuxDriver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shut_down();
+    }
+}
+
+struct Header {
+    kind: u8,
+    flags: u16,
+    id: u32,
+    len: u32,
+}
+
+impl Header {
+    fn parse(buf: &[u8]) -> io::Result<Self> {
+        if buf[0] != 0 {
+            return Err(invalid("unsupported multiplexer version"));
+        }
+
+        Ok(Self {
+            kind: buf[1],
+            flags: u16::from_be_bytes([buf[2], buf[3]]),
+            id: u32::from_be_bytes(buf[4..8].try_into().unwrap()),
+            len: u32::from_be_bytes(buf[8..12].try_into().unwrap()),
+        })
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::future::Future;
+
+    use futures_lite::{future, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::mock::MockSocket;
+
+    fn frame(kind: u8, flags: u16, id: u32, len: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0, kind];
+        frame.extend_from_slice(&flags.to_be_bytes());
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn data(flags: u16, id: u32, payload: &[u8]) -> Vec<u8> {
+        frame(TYPE_DATA, flags, id, payload.len() as u32, payload)
+    }
+
+    /// Run `f` while `driver` serves the connection.
+    fn with_driver<T, F>(driver: MuxDriver<T>, f: F) -> F::Output
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+        F: Future,
+    {
+        future::block_on(future::or(f, async {
+            let result = driver.run().await;
+            panic!("driver stopped: {result:?}");
+        }))
+    }
+
+    /// The error the driver of a client with a window of 16 bytes fails with after `frames`.
+    fn driver_error(frames: &[Vec<u8>]) -> io::Error {
+        let (a, mut b) = MockSocket::pair();
+        let (_mux, driver) = Multiplexer::new(Role::Client).window(16).build(a);
+
+        future::block_on(b.write_all(&frames.concat())).unwrap();
+        future::block_on(driver.run()).unwrap_err()
+    }
+
+    #[test]
+    fn header() {
+        let header =
+            Header::parse(&frame(TYPE_WINDOW_UPDATE, FLAG_SYN | FLAG_FIN, 3, 256, &[])).unwrap();
+        assert_eq!(header.kind, TYPE_WINDOW_UPDATE);
+        assert_eq!(header.flags, FLAG_SYN | FLAG_FIN);
+        assert_eq!(header.id, 3);
+        assert_eq!(header.len, 256);
+
+        let mut unsupported = frame(TYPE_DATA, 0, 1, 0, &[]);
+        unsupported[0] = 1;
+        assert!(Header::parse(&unsupported).is_err());
+    }
+
+    #[test]
+    fn streams_roundtrip() {
+        // A window and a connection smaller than the data, so that window updates are needed
+        let (a, b) = MockSocket::pair_with_capacity(64);
+        let (client, client_driver) = Multiplexer::new(Role::Client).window(1000).build(a);
+        let (server, server_driver) = Multiplexer::new(Role::Server).window(1000).build(b);
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i * 7) as u8).collect();
+
+        let client_side = async {
+            let mut first = client.open().unwrap();
+            let mut second = client.open().unwrap();
+            assert_eq!((first.id(), second.id()), (1, 3));
+
+            first.write_all(&data).await.unwrap();
+            first.close().await.unwrap();
+            second.write_all(b"hello").await.unwrap();
+            second.close().await.unwrap();
+
+            let mut reply = Vec::new();
+            first.read_to_end(&mut reply).await.unwrap();
+            assert_eq!(reply, b"thanks");
+
+            client.close();
+        };
+        let server_side = async {
+            let mut first = server.accept().await.unwrap();
+            let mut second = server.accept().await.unwrap();
+
+            let mut received = Vec::new();
+            first.read_to_end(&mut received).await.unwrap();
+            assert!(received == data);
+            received.clear();
+            second.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, b"hello");
+            second.close().await.unwrap();
+
+            first.write_all(b"thanks").await.unwrap();
+            first.close().await.unwrap();
+        };
+
+        let (_, (client_result, server_result)) = future::block_on(future::zip(
+            future::zip(client_side, server_side),
+            future::zip(client_driver.run(), server_driver.run()),
+        ));
+        client_result.unwrap();
+        server_result.unwrap();
+        assert!(server.is_closed());
+    }
+
+    #[test]
+    fn streams_over_the_limit_are_reset() {
+        let (a, mut b) = MockSocket::pair();
+        let (mux, driver) = Multiplexer::new(Role::Client).max_streams(1).build(a);
+
+        let replies = with_driver(driver, async {
+            let syn = |id| frame(TYPE_WINDOW_UPDATE, FLAG_SYN, id, 0, &[]);
+            b.write_all(&[syn(2), syn(4)].concat()).await.unwrap();
+
+            let mut replies = [0; 2 * HEADER_LEN];
+            b.read_exact(&mut replies).await.unwrap();
+            assert_eq!(mux.accept().await.unwrap().id(), 2);
+            replies
+        });
+
+        let ack = Header::parse(&replies[..HEADER_LEN]).unwrap();
+        assert_eq!((ack.flags, ack.id), (FLAG_ACK, 2));
+        let reset = Header::parse(&replies[HEADER_LEN..]).unwrap();
+        assert_eq!((reset.flags, reset.id), (FLAG_RST, 4));
+    }
+
+    #[test]
+    fn pings_are_answered() {
+        let (a, mut b) = MockSocket::pair();
+        let (_mux, driver) = Multiplexer::new(Role::Server).build(a);
+
+        let reply = with_driver(driver, async {
+            b.write_all(&frame(TYPE_PING, FLAG_SYN, 0, 42, &[]))
+                .await
+                .unwrap();
+
+            let mut reply = [0; HEADER_LEN];
+            b.read_exact(&mut reply).await.unwrap();
+            reply
+        });
+
+        let pong = Header::parse(&reply).unwrap();
+        assert_eq!((pong.kind, pong.flags, pong.len), (TYPE_PING, FLAG_ACK, 42));
+    }
+
+    #[test]
+    fn window_exceeded() {
+        // Each frame fits, but the second one is sent before the first was read
+        let err = driver_error(&[data(FLAG_SYN, 2, &[0; 10]), data(0, 2, &[0; 10])]);
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "peer exceeded the stream window");
+
+        let err = driver_error(&[data(FLAG_SYN, 2, &[0; 17])]);
+        assert_eq!(err.to_string(), "data frame larger than the window");
+    }
+
+    #[test]
+    fn own_stream_id_from_peer() {
+        // Odd ids are the client's
+        let err = driver_error(&[frame(TYPE_WINDOW_UPDATE, FLAG_SYN, 1, 0, &[])]);
+        assert_eq!(err.to_string(), "invalid stream id");
+    }
+
+    #[test]
+    fn window_update_extends_send_window() {
+        let (a, mut b) = MockSocket::pair();
+        let (mux, driver) = Multiplexer::new(Role::Client).window(16).build(a);
+
+        let sent = with_driver(driver, async {
+            let mut stream = mux.open().unwrap();
+            let mut syn = [0; HEADER_LEN];
+            b.read_exact(&mut syn).await.unwrap();
+
+            // 16 bytes fit the window, the rest only once the peer extends it
+            let write = async {
+                stream.write_all(&[1; 24]).await.unwrap();
+                stream.flush().await.unwrap();
+            };
+            let peer = async {
+                let mut received = vec![0; HEADER_LEN + 16];
+                b.read_exact(&mut received).await.unwrap();
+                b.write_all(&frame(TYPE_WINDOW_UPDATE, 0, 1, 8, &[]))
+                    .await
+                    .unwrap();
+
+                let mut rest = vec![0; HEADER_LEN + 8];
+                b.read_exact(&mut rest).await.unwrap();
+                received.extend_from_slice(&rest);
+                received
+            };
+            future::zip(write, peer).await.1
+        });
+
+        let first = Header::parse(&sent).unwrap();
+        assert_eq!((first.kind, first.id, first.len), (TYPE_DATA, 1, 16));
+        let second = Header::parse(&sent[HEADER_LEN + 16..]).unwrap();
+        assert_eq!((second.kind, second.id, second.len), (TYPE_DATA, 1, 8));
+    }
+}
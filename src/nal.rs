@@ -0,0 +1,168 @@
+//! The crate as the network layer of `embedded-nal-async` consumers such as reqwless.
+//!
+//! ```ignore
+//! let dns = NalDns::new(TlsConnector::new());
+//! let tls = NalTls::new(TlsConnector::new(), "example.com", cfg);
+//! let mut client = reqwless::client::HttpClient::new(&tls, &dns);
+//! ```
+//!
+//! `TcpConnect` only hands over an address, so [`NalTls`] is set up for one server name, which
+//! SNI and the certificate verification use. For consumers that do TLS themselves use [`NalTcp`].
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr as StdSocketAddr},
+};
+
+use embedded_nal_async::{AddrType, Dns, IpAddr as NalIpAddr, SocketAddr, TcpConnect};
+use esp_idf_svc::tls::Config;
+use futures_lite::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    connector::TlsConnector,
+    dns::IpPreference,
+    error::{Error, Result},
+    tcp::{self, AsyncTcp},
+    tls::AsyncTls,
+};
+
+/// A connection of [`NalTcp`] or [`NalTls`], implementing the `embedded-io-async` traits.
+pub struct NalConnection<T>(pub T);
+
+impl<T> embedded_io_async::ErrorType for NalConnection<T> {
+    type Error = io::Error;
+}
+
+impl<T: AsyncRead + Unpin> embedded_io_async::Read for NalConnection<T> {
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).await
+    }
+}
+
+impl<T: AsyncWrite + Unpin> embedded_io_async::Write for NalConnection<T> {
+    async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf).await
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.0.flush().await
+    }
+}
+
+/// Plain TCP connections.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NalTcp;
+
+impl TcpConnect for NalTcp {
+    type Error = io::Error;
+    type Connection<'a>
+        = NalConnection<AsyncTcp>
+    where
+        Self: 'a;
+
+    async fn connect<'a>(&'a self, remote: SocketAddr) -> io::Result<Self::Connection<'a>>
+    where
+        Self: 'a,
+    {
+        let tcp = tcp::connect_addr(to_std(remote))
+            .await
+            .map_err(into_io_error)?;
+
+        Ok(NalConnection(AsyncTcp::new(tcp)))
+    }
+}
+
+/// TLS connections to one server, see the [module docs](self).
+pub struct NalTls {
+    connector: TlsConnector,
+    hostname: String,
+    cfg: Config<'static>,
+}
+
+impl NalTls {
+    /// Establish TLS with `connector` and `cfg`, verifying that the server is `hostname`.
+    pub fn new(connector: TlsConnector, hostname: &str, cfg: Config<'static>) -> Self {
+        Self {
+            connector,
+            hostname: hostname.to_owned(),
+            cfg,
+        }
+    }
+}
+
+impl TcpConnect for NalTls {
+    type Error = io::Error;
+    type Connection<'a>
+        = NalConnection<AsyncTls>
+    where
+        Self: 'a;
+
+    async fn connect<'a>(&'a self, remote: SocketAddr) -> io::Result<Self::Connection<'a>>
+    where
+        Self: 'a,
+    {
+        let tls = self
+            .connector
+            .connect_to_addr(to_std(remote), &self.hostname, &self.cfg)
+            .await
+            .map_err(into_io_error)?;
+
+        Ok(NalConnection(tls))
+    }
+}
+
+/// Name resolution with the resolver, cache and DoH settings of a [`TlsConnector`].
+pub struct NalDns {
+    connector: TlsConnector,
+}
+
+impl NalDns {
+    pub fn new(connector: TlsConnector) -> Self {
+        Self { connector }
+    }
+
+    fn only(&self, pref: IpPreference) -> TlsConnector {
+        self.connector.clone().ip_preference(pref)
+    }
+}
+
+impl Dns for NalDns {
+    type Error = Error;
+
+    async fn get_host_by_name(&self, host: &str, addr_type: AddrType) -> Result<NalIpAddr> {
+        let addrs = match addr_type {
+            AddrType::IPv4 => self.only(IpPreference::V4Only).resolve(host, 0).await?,
+            AddrType::IPv6 => self.only(IpPreference::V6Only).resolve(host, 0).await?,
+            AddrType::Either => self.connector.resolve(host, 0).await?,
+        };
+        let addr = addrs
+            .first()
+            .ok_or_else(|| Error::NoAddress(host.to_owned()))?;
+
+        Ok(match addr.ip() {
+            IpAddr::V4(ip) => NalIpAddr::V4(ip.octets().into()),
+            IpAddr::V6(ip) => NalIpAddr::V6(ip.octets().into()),
+        })
+    }
+
+    /// Reverse lookups are not supported by the lwIP resolver.
+    async fn get_host_by_address(&self, addr: NalIpAddr, _result: &mut [u8]) -> Result<usize> {
+        Err(Error::NoAddress(addr.to_string()))
+    }
+}
+
+fn to_std(addr: SocketAddr) -> StdSocketAddr {
+    let ip = match addr.ip() {
+        NalIpAddr::V4(ip) => IpAddr::from(ip.octets()),
+        NalIpAddr::V6(ip) => IpAddr::from(ip.octets()),
+    };
+
+    StdSocketAddr::new(ip, addr.port())
+}
+
+fn into_io_error(e: Error) -> io::Error {
+    match e {
+        Error::Io(e) | Error::TcpConnect { source: e, .. } => e,
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
@@ -0,0 +1,73 @@
+//! Choosing the network interface that connections go out on, e.g. Ethernet with WiFi as a
+//! fallback on gateways.
+//!
+//! lwIP routes sockets through the default interface unless their destination is on the subnet
+//! of another one, so switching the default at runtime moves all new connections, including
+//! those of [`TlsConnector`](crate::TlsConnector). Established connections stay on the
+//! interface they were opened on.
+//!
+//! ```ignore
+//! let eth = eth::start(driver, sysloop.clone())?;
+//! let wifi = wifi::connect(modem, sysloop, ssid, pass)?;
+//!
+//! let uplink: &dyn Uplink = if netif::is_up(&*eth) { &*eth } else { &*wifi };
+//! netif::set_default(uplink)?;
+//! ```
+
+use std::{ffi::CStr, net::Ipv4Addr};
+
+#[cfg(esp_idf_comp_esp_eth_enabled)]
+use esp_idf_svc::eth::EspEth;
+use esp_idf_svc::wifi::EspWifi;
+use esp_idf_sys::{self as sys, esp};
+
+use crate::error::{Error, Result};
+
+/// A driver with a network interface connections can use.
+pub trait Uplink {
+    /// The esp-netif handle of the interface.
+    fn netif_handle(&self) -> *mut sys::esp_netif_t;
+}
+
+impl Uplink for EspWifi<'_> {
+    /// The station interface.
+    fn netif_handle(&self) -> *mut sys::esp_netif_t {
+        self.sta_netif().handle()
+    }
+}
+
+#[cfg(esp_idf_comp_esp_eth_enabled)]
+impl<T> Uplink for EspEth<'_, T> {
+    fn netif_handle(&self) -> *mut sys::esp_netif_t {
+        self.netif().handle()
+    }
+}
+
+/// Route new connections through `uplink`.
+pub fn set_default(uplink: &dyn Uplink) -> Result<()> {
+    let netif = uplink.netif_handle();
+    esp!(unsafe { sys::esp_netif_set_default_netif(netif) }).map_err(Error::Netif)?;
+
+    let key = unsafe { CStr::from_ptr(sys::esp_netif_get_ifkey(netif)) }.to_string_lossy();
+    match ipv4(netif) {
+        Some(ip) => log::info!("default network interface: {key} ({ip})"),
+        None => log::info!("default network interface: {key}"),
+    }
+
+    Ok(())
+}
+
+/// Whether `uplink` has a link and an IP address.
+pub fn is_up(uplink: &dyn Uplink) -> bool {
+    let netif = uplink.netif_handle();
+
+    ipv4(netif).is_some() && unsafe { sys::esp_netif_is_netif_up(netif) }
+}
+
+/// The IPv4 address of `netif`, if it has one.
+pub(crate) fn ipv4(netif: *mut sys::esp_netif_t) -> Option<Ipv4Addr> {
+    let mut info: sys::esp_netif_ip_info_t = Default::default();
+    esp!(unsafe { sys::esp_netif_get_ip_info(netif, &mut info) }).ok()?;
+
+    Some(Ipv4Addr::from(u32::from_be(info.ip.addr))).filter(|ip| !ip.is_unspecified())
+}
@@ -0,0 +1,426 @@
+//! Firmware updates streamed from an HTTPS URL straight into the next OTA partition.
+//!
+//! Requires a partition table with OTA app partitions, e.g. `CONFIG_PARTITION_TABLE_TWO_OTA=y`.
+//!
+//! Besides the checks ESP-IDF does itself, the image can be verified against a known SHA-256
+//! digest or a detached signature while it is downloaded, see [`OtaUpdater::sha256`] and
+//! [`OtaUpdater::signature`]. An image that fails is never marked bootable.
+//!
+//! With `CONFIG_BOOTLOADER_APP_ROLLBACK_ENABLE` the new firmware also has to prove that it still
+//! reaches the backend before it is kept, see [`HealthCheck`].
+
+use core::ptr;
+use std::time::Duration;
+
+use esp_idf_sys::{self as sys, esp, EspError, ESP_ERR_INVALID_SIZE, ESP_ERR_NOT_FOUND};
+use futures_lite::AsyncReadExt;
+
+use crate::{
+    error::{Error, Result},
+    http::HttpClient,
+    mem, time,
+};
+
+/// How much of the image is downloaded before it is written to flash.
+const CHUNK_LEN: usize = 4096;
+
+/// How far a download got, see [`OtaUpdater::progress`].
+#[derive(Clone, Copy, Debug)]
+pub struct OtaProgress {
+    /// Bytes written to the partition so far
+    pub written: u64,
+    /// Size of the image, if the server announced it
+    pub total: Option<u64>,
+}
+
+type ProgressFn = dyn Fn(OtaProgress) + Send + Sync;
+
+/// Downloads a firmware image and boots into it, see the [module docs](self).
+pub struct OtaUpdater {
+    client: HttpClient,
+    reboot: bool,
+    progress: Option<Box<ProgressFn>>,
+    sha256: Option<[u8; 32]>,
+    signature: Option<Signature>,
+}
+
+struct Signature {
+    public_key: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl OtaUpdater {
+    /// Download images with `client`. Don't enable decompression on it, the image is written as
+    /// received.
+    pub fn new(client: HttpClient) -> Self {
+        Self {
+            client,
+            reboot: true,
+            progress: None,
+            sha256: None,
+            signature: None,
+        }
+    }
+
+    /// Restart into the new firmware once it has been written, enabled by default.
+    pub fn reboot(mut self, reboot: bool) -> Self {
+        self.reboot = reboot;
+        self
+    }
+
+    /// Call `progress` after each chunk of the image has been written.
+    pub fn progress<F>(mut self, progress: F) -> Self
+    where
+        F: Fn(OtaProgress) + Send + Sync + 'static,
+    {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Only accept an image with the SHA-256 digest `digest`.
+    pub fn sha256(mut self, digest: [u8; 32]) -> Self {
+        self.sha256 = Some(digest);
+        self
+    }
+
+    /// Only accept an image whose SHA-256 digest is signed by `signature`, made with the private
+    /// key of `public_key` (PEM or DER, RSA or ECDSA).
+    pub fn signature(mut self, public_key: &[u8], signature: &[u8]) -> Self {
+        self.signature = Some(Signature {
+            public_key: public_key.to_vec(),
+            signature: signature.to_vec(),
+        });
+        self
+    }
+
+    /// Write the image at `url` to the next OTA partition and make it the boot partition.
+    ///
+    /// The running firmware stays in place until the image has been received completely and
+    /// validated by ESP-IDF, so a failed or cancelled update leaves the device as it was. Unless
+    /// disabled, this does not return on success as the device restarts.
+    pub async fn update(&self, url: &str) -> Result<()> {
+        let response = self.client.get(url).send().await?;
+        if !(200..300).contains(&response.status()) {
+            return Err(Error::Http(format!(
+                "firmware download failed with status {}",
+                response.status()
+            )));
+        }
+
+        let total = response.content_length();
+        let mut ota = OtaWrite::begin(total)?;
+        log::info!(
+            "downloading firmware from {url} ({} bytes)",
+            total.map_or("unknown".to_owned(), |total| total.to_string())
+        );
+
+        let mut sha256 = (self.sha256.is_some() || self.signature.is_some()).then(Sha256::new);
+
+        let mut body = response.into_body();
+        let mut buf = mem::alloc_buffer(CHUNK_LEN);
+        let mut written = 0;
+        loop {
+            let read = body.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+
+            if let Some(sha256) = &mut sha256 {
+                sha256.update(&buf[..read]);
+            }
+            ota.write(&buf[..read])?;
+            written += read as u64;
+
+            if let Some(progress) = &self.progress {
+                progress(OtaProgress { written, total });
+            }
+        }
+
+        if let Some(sha256) = sha256 {
+            self.verify(&sha256.finish())?;
+        }
+
+        ota.finish()?;
+        log::info!("firmware update written ({written} bytes)");
+
+        if self.reboot {
+            log::info!("restarting into the new firmware");
+            unsafe { sys::esp_restart() };
+        }
+
+        Ok(())
+    }
+
+    fn verify(&self, digest: &[u8; 32]) -> Result<()> {
+        if self.sha256.map_or(false, |expected| expected != *digest) {
+            return Err(Error::OtaVerification("SHA-256 digest mismatch".to_owned()));
+        }
+
+        if let Some(signature) = &self.signature {
+            verify_signature(&signature.public_key, digest, &signature.signature)?;
+        }
+
+        log::info!("firmware image verified");
+        Ok(())
+    }
+}
+
+/// How much of a response body [`HealthCheck::expect_body`] searches.
+const MAX_HEALTH_BODY_LEN: usize = 4096;
+
+/// Decides whether freshly updated firmware is kept, by requesting an HTTPS URL of the backend
+/// before the firmware marks itself valid:
+///
+/// ```ignore
+/// HealthCheck::new(client, "https://api.example.com/health")
+///     .expect_body("\"ok\"")
+///     .attempts(5)
+///     .run()
+///     .await?;
+/// ```
+///
+/// Requires `CONFIG_BOOTLOADER_APP_ROLLBACK_ENABLE`, without it the bootloader never boots the
+/// previous firmware again and there is nothing to decide.
+pub struct HealthCheck {
+    client: HttpClient,
+    url: String,
+    status: u16,
+    body: Option<String>,
+    attempts: u32,
+    retry_delay: Duration,
+}
+
+impl HealthCheck {
+    /// Request `url` with `client`, expecting status 200, up to 3 times 10 s apart.
+    pub fn new(client: HttpClient, url: &str) -> Self {
+        Self {
+            client,
+            url: url.to_owned(),
+            status: 200,
+            body: None,
+            attempts: 3,
+            retry_delay: Duration::from_secs(10),
+        }
+    }
+
+    /// Expect status `status` instead of 200.
+    pub fn expect_status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Also expect `text` in the first 4 KiB of the response body.
+    pub fn expect_body(mut self, text: &str) -> Self {
+        self.body = Some(text.to_owned());
+        self
+    }
+
+    /// Give up after `attempts` failed checks, at least one.
+    pub fn attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    /// Wait `delay` after a failed check before the next attempt.
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Check the backend if the running firmware has just been updated, i.e. is still pending
+    /// verification, and mark it valid once a check passes. If every attempt fails the device
+    /// restarts into the previous firmware, so this only returns on success or if the firmware
+    /// was no update.
+    pub async fn run(&self) -> Result<()> {
+        let mut state = 0;
+        let running = unsafe { sys::esp_ota_get_running_partition() };
+        esp!(unsafe { sys::esp_ota_get_state_partition(running, &mut state) })
+            .map_err(Error::Ota)?;
+        if state != sys::esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY {
+            log::debug!("the running firmware is not pending verification");
+            return Ok(());
+        }
+
+        for attempt in 1..=self.attempts {
+            match self.check().await {
+                Ok(()) => {
+                    esp!(unsafe { sys::esp_ota_mark_app_valid_cancel_rollback() })
+                        .map_err(Error::Ota)?;
+                    log::info!("health check passed, keeping the new firmware");
+
+                    return Ok(());
+                }
+                Err(e) => {
+                    log::warn!("health check {attempt}/{} failed: {e}", self.attempts);
+                    if attempt < self.attempts {
+                        time::sleep(self.retry_delay).await;
+                    }
+                }
+            }
+        }
+
+        log::error!("health check failed, rolling back to the previous firmware");
+        // Restarts unless there is no previous firmware to boot
+        esp!(unsafe { sys::esp_ota_mark_app_invalid_rollback_and_reboot() }).map_err(Error::Ota)
+    }
+
+    async fn check(&self) -> Result<()> {
+        let response = self.client.get(&self.url).send().await?;
+        if response.status() != self.status {
+            return Err(Error::Http(format!(
+                "expected status {}, got {}",
+                self.status,
+                response.status()
+            )));
+        }
+
+        let Some(expected) = &self.body else {
+            return Ok(());
+        };
+
+        let mut body = Vec::new();
+        response
+            .into_body()
+            .take(MAX_HEALTH_BODY_LEN as u64)
+            .read_to_end(&mut body)
+            .await?;
+        let found = expected.is_empty()
+            || body
+                .windows(expected.len())
+                .any(|window| window == expected.as_bytes());
+        if !found {
+            return Err(Error::Http(format!(
+                "the body does not contain {expected:?}"
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// An OTA partition being written, aborted unless finished.
+struct OtaWrite {
+    handle: sys::esp_ota_handle_t,
+    partition: *const sys::esp_partition_t,
+    finished: bool,
+}
+
+impl OtaWrite {
+    fn begin(image_len: Option<u64>) -> Result<Self> {
+        let partition = unsafe { sys::esp_ota_get_next_update_partition(ptr::null()) };
+        let Some(info) = (unsafe { partition.as_ref() }) else {
+            return Err(Error::Ota(EspError::from_infallible::<ESP_ERR_NOT_FOUND>()));
+        };
+
+        if image_len.map_or(false, |len| len > info.size as u64) {
+            return Err(Error::Ota(
+                EspError::from_infallible::<ESP_ERR_INVALID_SIZE>(),
+            ));
+        }
+
+        // Erases as much of the partition as needed up front if the size is known
+        let image_len = image_len.map_or(sys::OTA_SIZE_UNKNOWN as usize, |len| len as usize);
+
+        let mut handle = 0;
+        esp!(unsafe { sys::esp_ota_begin(partition, image_len, &mut handle) })
+            .map_err(Error::Ota)?;
+
+        Ok(Self {
+            handle,
+            partition,
+            finished: false,
+        })
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        esp!(unsafe { sys::esp_ota_write(self.handle, data.as_ptr() as _, data.len()) })
+            .map_err(Error::Ota)
+    }
+
+    /// Validate the image and boot from it on the next restart.
+    fn finish(mut self) -> Result<()> {
+        // Frees the handle even if validation fails
+        self.finished = true;
+        esp!(unsafe { sys::esp_ota_end(self.handle) }).map_err(Error::Ota)?;
+        esp!(unsafe { sys::esp_ota_set_boot_partition(self.partition) }).map_err(Error::Ota)
+    }
+}
+
+impl Drop for OtaWrite {
+    fn drop(&mut self) {
+        if !self.finished {
+            unsafe { sys::esp_ota_abort(self.handle) };
+        }
+    }
+}
+
+struct Sha256(Box<sys::mbedtls_sha256_context>);
+
+impl Sha256 {
+    fn new() -> Self {
+        let mut ctx = Box::new(Default::default());
+        unsafe {
+            sys::mbedtls_sha256_init(&mut *ctx);
+            sys::mbedtls_sha256_starts(&mut *ctx, 0);
+        }
+
+        Self(ctx)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        unsafe { sys::mbedtls_sha256_update(&mut *self.0, data.as_ptr(), data.len()) };
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let mut digest = [0; 32];
+        unsafe { sys::mbedtls_sha256_finish(&mut *self.0, digest.as_mut_ptr()) };
+
+        digest
+    }
+}
+
+impl Drop for Sha256 {
+    fn drop(&mut self) {
+        unsafe { sys::mbedtls_sha256_free(&mut *self.0) };
+    }
+}
+
+fn verify_signature(public_key: &[u8], digest: &[u8; 32], signature: &[u8]) -> Result<()> {
+    // mbedtls wants PEM including the terminating nul
+    let mut key = public_key.to_vec();
+    if key.starts_with(b"-----") && key.last() != Some(&0) {
+        key.push(0);
+    }
+
+    let mut pk = Default::default();
+    unsafe { sys::mbedtls_pk_init(&mut pk) };
+
+    let ret = unsafe {
+        match sys::mbedtls_pk_parse_public_key(&mut pk, key.as_ptr(), key.len()) {
+            0 => sys::mbedtls_pk_verify(
+                &mut pk,
+                sys::mbedtls_md_type_t_MBEDTLS_MD_SHA256,
+                digest.as_ptr(),
+                digest.len(),
+                signature.as_ptr(),
+                signature.len(),
+            ),
+            ret => {
+                sys::mbedtls_pk_free(&mut pk);
+                return Err(Error::OtaVerification(format!(
+                    "invalid public key: -0x{:04x}",
+                    -ret
+                )));
+            }
+        }
+    };
+    unsafe { sys::mbedtls_pk_free(&mut pk) };
+
+    match ret {
+        0 => Ok(()),
+        ret => Err(Error::OtaVerification(format!(
+            "invalid signature: -0x{:04x}",
+            -ret
+        ))),
+    }
+}
@@ -0,0 +1,279 @@
+//! A PPP link over a cellular modem on a UART (PPPoS), so that cellular devices run the same
+//! connections as WiFi or Ethernet ones.
+//!
+//! ```ignore
+//! let uart = UartDriver::new(peripherals.uart1, tx, rx, None::<AnyIOPin>, None::<AnyIOPin>, &config)?;
+//! let link = PppModem::new(uart, "internet").connect()?;
+//! netif::set_default(&link)?;
+//! let tls = connect_async_tls(&Tcp, "example.com", 443, &cfg).await?;
+//! ```
+//!
+//! Requires `CONFIG_LWIP_PPP_SUPPORT`. The modem is dialed with standard AT commands, modems
+//! that need more setup (PIN, network selection) can be prepared with [`PppModem::command`]
+//! before.
+
+use core::{
+    ffi::c_void,
+    ptr, slice,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use std::{
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use esp_idf_hal::uart::UartDriver;
+use esp_idf_sys::{self as sys, esp, EspError, ESP_ERR_NO_MEM, ESP_FAIL, ESP_OK};
+use log::*;
+
+use crate::{
+    error::{Error, Result},
+    netif::{self, Uplink},
+    runtime,
+};
+
+/// Size of the buffer received bytes are handed to lwIP with.
+const RX_BUF_LEN: usize = 512;
+
+/// Dials a modem and brings up a PPP interface over it, see the [module docs](self).
+pub struct PppModem {
+    uart: UartDriver<'static>,
+    apn: String,
+    connect_timeout: Duration,
+}
+
+impl PppModem {
+    /// Use the modem on `uart` with the access point name `apn` of the operator.
+    pub fn new(uart: UartDriver<'static>, apn: &str) -> Self {
+        Self {
+            uart,
+            apn: apn.to_owned(),
+            connect_timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// How long to wait for the modem to connect and the interface to get an address, 30
+    /// seconds by default.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Send the AT command `cmd` and wait for a response containing `expect`, returning the
+    /// whole response.
+    pub fn command(&self, cmd: &str, expect: &str, timeout: Duration) -> Result<String> {
+        debug!("modem <- {cmd}");
+        self.uart
+            .write(format!("{cmd}\r").as_bytes())
+            .map_err(Error::Netif)?;
+
+        let deadline = Instant::now() + timeout;
+        let mut response = Vec::new();
+        let mut buf = [0; 64];
+        while Instant::now() < deadline {
+            let read = self.uart.read(&mut buf, ticks(100)).map_err(Error::Netif)?;
+            response.extend_from_slice(&buf[..read]);
+
+            let text = String::from_utf8_lossy(&response);
+            if text.contains(expect) {
+                debug!("modem -> {}", text.trim());
+                return Ok(text.into_owned());
+            }
+            if text.contains("ERROR") || text.contains("NO CARRIER") {
+                return Err(Error::Modem(format!("{cmd} failed: {}", text.trim())));
+            }
+        }
+
+        Err(Error::Modem(format!("no response to {cmd}")))
+    }
+
+    /// Dial the data connection and wait until the PPP interface has an address.
+    pub fn connect(self) -> Result<PppLink> {
+        let timeout = self.connect_timeout;
+
+        self.command("AT", "OK", Duration::from_secs(1))
+            // Maybe still in data mode from before a restart
+            .or_else(|_| {
+                thread::sleep(Duration::from_secs(1));
+                self.uart.write(b"+++").map_err(Error::Netif)?;
+                thread::sleep(Duration::from_secs(1));
+                self.command("AT", "OK", Duration::from_secs(1))
+            })?;
+        self.command("ATE0", "OK", Duration::from_secs(1))?;
+        self.command(
+            &format!("AT+CGDCONT=1,\"IP\",\"{}\"", self.apn),
+            "OK",
+            Duration::from_secs(5),
+        )?;
+        self.command("ATD*99#", "CONNECT", timeout)?;
+        info!("modem connected, starting PPP");
+
+        let link = PppLink::start(self.uart)?;
+
+        let deadline = Instant::now() + timeout;
+        while !netif::is_up(&link) {
+            if Instant::now() >= deadline {
+                return Err(Error::Modem("PPP did not come up".to_owned()));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        info!(
+            "PPP up, ip {}",
+            netif::ipv4(link.driver.base.netif).unwrap()
+        );
+
+        Ok(link)
+    }
+}
+
+/// A PPP interface over a modem, up until dropped. See [`netif`] for making it the default.
+pub struct PppLink {
+    driver: Box<Driver>,
+    rx: Option<JoinHandle<()>>,
+}
+
+/// The esp-netif driver of the interface.
+///
+/// esp-netif hands the address of `base` back to the callbacks, so it has to come first.
+#[repr(C)]
+struct Driver {
+    base: sys::esp_netif_driver_base_t,
+    uart: UartDriver<'static>,
+    stopped: AtomicBool,
+}
+
+impl PppLink {
+    fn start(uart: UartDriver<'static>) -> Result<Self> {
+        let cfg = sys::esp_netif_config_t {
+            base: unsafe { &sys::_g_esp_netif_inherent_ppp_config },
+            driver: ptr::null(),
+            stack: unsafe { sys::_g_esp_netif_netstack_default_ppp },
+        };
+        let netif = unsafe { sys::esp_netif_new(&cfg) };
+        if netif.is_null() {
+            return Err(Error::Netif(EspError::from_infallible::<ESP_ERR_NO_MEM>()));
+        }
+
+        // Constructed first so that the interface is destroyed if any of the calls below fail
+        let mut link = Self {
+            driver: Box::new(Driver {
+                base: sys::esp_netif_driver_base_t {
+                    post_attach: Some(post_attach),
+                    netif,
+                },
+                uart,
+                stopped: AtomicBool::new(false),
+            }),
+            rx: None,
+        };
+
+        let handle = &mut link.driver.base as *mut sys::esp_netif_driver_base_t;
+        esp!(unsafe { sys::esp_netif_attach(netif, handle as *mut c_void) })
+            .map_err(Error::Netif)?;
+
+        // The driver is boxed and outlives the thread, see `Drop`
+        let driver = &*link.driver as *const Driver as usize;
+        link.rx = Some(runtime::spawn_with(runtime::current().background, || {
+            thread::Builder::new()
+                .name("ppp-rx".into())
+                .stack_size(4 * 1024)
+                .spawn(move || receive(unsafe { &*(driver as *const Driver) }))
+        })?);
+
+        unsafe {
+            sys::esp_netif_action_start(netif as _, ptr::null(), 0, ptr::null_mut());
+            sys::esp_netif_action_connected(netif as _, ptr::null(), 0, ptr::null_mut());
+        }
+
+        Ok(link)
+    }
+}
+
+impl Uplink for PppLink {
+    fn netif_handle(&self) -> *mut sys::esp_netif_t {
+        self.driver.base.netif
+    }
+}
+
+impl Drop for PppLink {
+    /// Takes the interface down and hangs up, which takes about two seconds for the guard times
+    /// of the modem's escape sequence.
+    fn drop(&mut self) {
+        let netif = self.driver.base.netif;
+
+        // Only started once the receiving thread is running
+        if let Some(rx) = self.rx.take() {
+            unsafe {
+                sys::esp_netif_action_disconnected(netif as _, ptr::null(), 0, ptr::null_mut());
+                sys::esp_netif_action_stop(netif as _, ptr::null(), 0, ptr::null_mut());
+            }
+
+            self.driver.stopped.store(true, Ordering::Relaxed);
+            let _ = rx.join();
+        }
+        unsafe { sys::esp_netif_destroy(netif) };
+
+        thread::sleep(Duration::from_secs(1));
+        let _ = self.driver.uart.write(b"+++");
+        thread::sleep(Duration::from_secs(1));
+        let _ = self.driver.uart.write(b"ATH\r");
+    }
+}
+
+/// Called by esp-netif once the driver is attached, with the address of its `base`.
+unsafe extern "C" fn post_attach(
+    netif: *mut sys::esp_netif_t,
+    handle: *mut c_void,
+) -> sys::esp_err_t {
+    let ifconfig = sys::esp_netif_driver_ifconfig_t {
+        handle,
+        transmit: Some(transmit),
+        ..Default::default()
+    };
+
+    sys::esp_netif_set_driver_config(netif, &ifconfig)
+}
+
+/// Called by lwIP with PPP frames to send.
+unsafe extern "C" fn transmit(
+    handle: *mut c_void,
+    data: *mut c_void,
+    len: usize,
+) -> sys::esp_err_t {
+    let driver = &*(handle as *const Driver);
+
+    match driver
+        .uart
+        .write(slice::from_raw_parts(data as *const u8, len))
+    {
+        Ok(written) if written == len => ESP_OK,
+        _ => ESP_FAIL,
+    }
+}
+
+/// Hand everything the modem sends to lwIP until the link is dropped.
+fn receive(driver: &Driver) {
+    let mut buf = [0; RX_BUF_LEN];
+
+    while !driver.stopped.load(Ordering::Relaxed) {
+        match driver.uart.read(&mut buf, ticks(100)) {
+            Ok(0) => (),
+            Ok(read) => unsafe {
+                sys::esp_netif_receive(
+                    driver.base.netif,
+                    buf.as_mut_ptr() as *mut c_void,
+                    read,
+                    ptr::null_mut(),
+                );
+            },
+            Err(e) => {
+                warn!("PPP receive failed: {e}");
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+}
+
+fn ticks(ms: u32) -> sys::TickType_t {
+    ms * sys::CONFIG_FREERTOS_HZ / 1000
+}
@@ -0,0 +1,250 @@
+//! Reaching the server through an HTTP or SOCKS5 proxy before the TLS handshake.
+
+use std::net::IpAddr;
+
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    error::{Error, Result},
+    reactor, tcp,
+    util::base64,
+};
+
+/// Upper bound for the response headers of an HTTP proxy.
+const MAX_HTTP_RESPONSE_LEN: usize = 4096;
+
+/// A proxy to tunnel connections through, see [`TlsConnector::proxy`](crate::TlsConnector::proxy).
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    kind: ProxyKind,
+    host: String,
+    port: u16,
+    auth: Option<(String, String)>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ProxyKind {
+    Http,
+    Socks5,
+}
+
+impl Proxy {
+    /// An HTTP proxy that supports the `CONNECT` method.
+    pub fn http(host: &str, port: u16) -> Self {
+        Self {
+            kind: ProxyKind::Http,
+            host: host.to_owned(),
+            port,
+            auth: None,
+        }
+    }
+
+    /// A SOCKS5 proxy. The target hostname is resolved by the proxy.
+    pub fn socks5(host: &str, port: u16) -> Self {
+        Self {
+            kind: ProxyKind::Socks5,
+            host: host.to_owned(),
+            port,
+            auth: None,
+        }
+    }
+
+    /// Authenticate with the proxy, using basic auth for HTTP proxies and username/password
+    /// authentication (RFC 1929) for SOCKS5 proxies.
+    pub fn auth(mut self, username: &str, password: &str) -> Self {
+        self.auth = Some((username.to_owned(), password.to_owned()));
+        self
+    }
+
+    /// Connect to the proxy and have it open a tunnel to `hostname:port`.
+    pub(crate) async fn connect(&self, hostname: &str, port: u16) -> Result<reactor::Tcp> {
+        let mut stream = tcp::connect(&self.host, self.port, Default::default()).await?;
+
+        match self.kind {
+            ProxyKind::Http => self.http_connect(&mut stream, hostname, port).await?,
+            ProxyKind::Socks5 => self.socks5_connect(&mut stream, hostname, port).await?,
+        }
+
+        log::info!(
+            "tunneling to {hostname}:{port} through proxy {}:{}",
+            self.host,
+            self.port
+        );
+
+        Ok(stream)
+    }
+
+    async fn http_connect(
+        &self,
+        stream: &mut reactor::Tcp,
+        hostname: &str,
+        port: u16,
+    ) -> Result<()> {
+        let authority = if hostname.contains(':') {
+            format!("[{hostname}]:{port}")
+        } else {
+            format!("{hostname}:{port}")
+        };
+
+        let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+        if let Some((username, password)) = &self.auth {
+            let credentials = base64(format!("{username}:{password}").as_bytes());
+            request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+        }
+        request.push_str("\r\n");
+
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read byte by byte so that nothing the server sends after the tunnel is up (i.e. the
+        // start of the TLS handshake) ends up in our buffer
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() == MAX_HTTP_RESPONSE_LEN {
+                return Err(Error::Proxy("response headers too long".into()));
+            }
+
+            let mut byte = 0;
+            if stream.read(core::slice::from_mut(&mut byte)).await? == 0 {
+                return Err(Error::Proxy("connection closed during CONNECT".into()));
+            }
+            response.push(byte);
+        }
+
+        let status_line = String::from_utf8_lossy(&response);
+        let status_line = status_line.lines().next().unwrap_or_default();
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok());
+
+        match status {
+            Some(200..=299) => Ok(()),
+            _ => Err(Error::Proxy(format!(
+                "CONNECT to {authority} was refused: {status_line}"
+            ))),
+        }
+    }
+
+    async fn socks5_connect(
+        &self,
+        stream: &mut reactor::Tcp,
+        hostname: &str,
+        port: u16,
+    ) -> Result<()> {
+        const VERSION: u8 = 5;
+        const NO_AUTH: u8 = 0;
+        const USERNAME_PASSWORD: u8 = 2;
+        const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+        const CMD_CONNECT: u8 = 1;
+        const ATYP_IPV4: u8 = 1;
+        const ATYP_DOMAIN: u8 = 3;
+        const ATYP_IPV6: u8 = 4;
+
+        let method = if self.auth.is_some() {
+            USERNAME_PASSWORD
+        } else {
+            NO_AUTH
+        };
+        stream.write_all(&[VERSION, 1, method]).await?;
+
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != VERSION {
+            return Err(Error::Proxy(format!(
+                "not a SOCKS5 proxy (version {})",
+                reply[0]
+            )));
+        }
+        match reply[1] {
+            NO_AUTH => (),
+            USERNAME_PASSWORD if method == USERNAME_PASSWORD => {
+                let (username, password) = self.auth.as_ref().unwrap();
+                if username.len() > 255 || password.len() > 255 {
+                    return Err(Error::Proxy("SOCKS5 credentials too long".into()));
+                }
+
+                let mut request = vec![1, username.len() as u8];
+                request.extend_from_slice(username.as_bytes());
+                request.push(password.len() as u8);
+                request.extend_from_slice(password.as_bytes());
+                stream.write_all(&request).await?;
+
+                stream.read_exact(&mut reply).await?;
+                if reply[1] != 0 {
+                    return Err(Error::Proxy("SOCKS5 authentication failed".into()));
+                }
+            }
+            NO_ACCEPTABLE_METHOD => {
+                return Err(Error::Proxy(
+                    "no acceptable SOCKS5 authentication method".into(),
+                ))
+            }
+            other => {
+                return Err(Error::Proxy(format!(
+                    "unexpected SOCKS5 authentication method {other}"
+                )))
+            }
+        }
+
+        let mut request = vec![VERSION, CMD_CONNECT, 0];
+        match hostname.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(ATYP_IPV4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(ATYP_IPV6);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                if hostname.len() > 255 {
+                    return Err(Error::Proxy(format!("hostname {hostname} too long")));
+                }
+
+                request.extend_from_slice(&[ATYP_DOMAIN, hostname.len() as u8]);
+                request.extend_from_slice(hostname.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(Error::Proxy(format!(
+                "SOCKS5 connect to {hostname}:{port} failed: {}",
+                socks5_reply_message(reply[1])
+            )));
+        }
+
+        // Skip the bound address, we have no use for it
+        let addr_len = match reply[3] {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            ATYP_DOMAIN => {
+                let mut len = 0;
+                stream.read_exact(core::slice::from_mut(&mut len)).await?;
+                len as usize
+            }
+            other => return Err(Error::Proxy(format!("invalid SOCKS5 address type {other}"))),
+        };
+        let mut bound = [0; 255 + 2];
+        stream.read_exact(&mut bound[..addr_len + 2]).await?;
+
+        Ok(())
+    }
+}
+
+fn socks5_reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
@@ -0,0 +1,254 @@
+//! The reactor behind the crate's sockets and timers, selected with cargo features:
+//!
+//! - async-io, the default, see [`AsyncIo`]
+//! - `select-reactor`, a `select` based reactor without a thread of its own, see [`select`]
+//! - `tokio-reactor`, the I/O and time drivers of a tokio runtime, see [`tokio`]
+//!
+//! [`AsyncTcp`](crate::AsyncTcp), [`AsyncTcpListener`](crate::AsyncTcpListener) and the
+//! timeouts of [`AsyncTls`](crate::AsyncTls) and the other layers use the selected one,
+//! [`DefaultReactor`]. Futures of the crate have to be polled where that reactor can wake them:
+//! anywhere for async-io, in [`select::block_on`] for the `select` reactor, and within the
+//! runtime for tokio. The threads the crate spawns itself use a fitting
+//! [`Executor`](crate::Executor) by default.
+//!
+//! Sockets of the other reactors can still be adopted, e.g. an async-io `Async<TcpStream>` with
+//! `tokio-reactor`, as long as their reactor runs. Without the `esp` feature only the timers
+//! are left, which are async-io's.
+
+use core::future::Future;
+#[cfg(feature = "esp")]
+use core::{mem, pin::Pin, ptr};
+use std::time::{Duration, Instant};
+#[cfg(feature = "esp")]
+use std::{
+    io,
+    net::{SocketAddr, TcpStream},
+    os::fd::FromRawFd,
+};
+
+#[cfg(feature = "esp")]
+use esp_idf_sys as sys;
+#[cfg(feature = "esp")]
+use futures_lite::{AsyncRead, AsyncWrite};
+
+#[cfg(feature = "esp")]
+use crate::tcp::Readiness;
+
+#[cfg(feature = "esp")]
+mod async_io;
+#[cfg(feature = "select-reactor")]
+pub mod select;
+#[cfg(feature = "tokio-reactor")]
+pub mod tokio;
+
+#[cfg(feature = "esp")]
+pub use self::async_io::AsyncIo;
+
+#[cfg(all(feature = "select-reactor", feature = "tokio-reactor"))]
+compile_error!("the `select-reactor` and `tokio-reactor` features are mutually exclusive");
+
+/// The reactor selected by the features, see the [module docs](self).
+#[cfg(all(
+    feature = "esp",
+    not(any(feature = "select-reactor", feature = "tokio-reactor"))
+))]
+pub type DefaultReactor = AsyncIo;
+#[cfg(feature = "select-reactor")]
+pub type DefaultReactor = self::select::Select;
+#[cfg(feature = "tokio-reactor")]
+pub type DefaultReactor = self::tokio::Tokio;
+
+/// A TCP stream registered with the [`DefaultReactor`].
+#[cfg(feature = "esp")]
+pub type Tcp = <DefaultReactor as Reactor>::TcpStream;
+
+/// A TCP listener registered with the [`DefaultReactor`].
+#[cfg(feature = "esp")]
+pub type Listener = <DefaultReactor as Reactor>::TcpListener;
+
+/// A timer of the [`DefaultReactor`].
+#[cfg(feature = "esp")]
+pub type DefaultTimer = <DefaultReactor as Reactor>::Timer;
+#[cfg(not(feature = "esp"))]
+pub type DefaultTimer = ::async_io::Timer;
+
+#[cfg(feature = "esp")]
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Readiness notifications and timers, everything the crate needs of an async runtime.
+#[cfg(feature = "esp")]
+pub trait Reactor: 'static {
+    type TcpStream: Readiness<Socket = TcpStream> + AsyncRead + AsyncWrite + Unpin + Send + 'static;
+    type TcpListener: Send + Sync + 'static;
+    type Timer: Timer;
+
+    /// Called once by [`init_async_runtime`](crate::init_async_runtime), after the eventfd VFS
+    /// was registered.
+    fn start() {}
+
+    fn connect(addr: SocketAddr) -> BoxFuture<'static, io::Result<Self::TcpStream>>;
+
+    /// Register `stream`, which is in non-blocking mode and might still be connecting.
+    fn register(stream: TcpStream) -> io::Result<Self::TcpStream>;
+
+    fn bind(addr: SocketAddr) -> io::Result<Self::TcpListener>;
+
+    fn accept(
+        listener: &Self::TcpListener,
+    ) -> BoxFuture<'_, io::Result<(Self::TcpStream, SocketAddr)>>;
+
+    fn local_addr(listener: &Self::TcpListener) -> io::Result<SocketAddr>;
+}
+
+/// A future that completes at a deadline, with the deadline.
+pub trait Timer: Future<Output = Instant> + Unpin + Send + Sized + 'static {
+    /// A timer that never completes, until set with [`set_at`](Self::set_at).
+    fn never() -> Self;
+
+    fn at(deadline: Instant) -> Self;
+
+    fn after(duration: Duration) -> Self {
+        Self::at(Instant::now() + duration)
+    }
+
+    /// Complete at `deadline` instead.
+    fn set_at(&mut self, deadline: Instant);
+}
+
+impl Timer for ::async_io::Timer {
+    fn never() -> Self {
+        ::async_io::Timer::never()
+    }
+
+    fn at(deadline: Instant) -> Self {
+        ::async_io::Timer::at(deadline)
+    }
+
+    fn set_at(&mut self, deadline: Instant) {
+        ::async_io::Timer::set_at(self, deadline)
+    }
+}
+
+#[cfg(feature = "esp")]
+#[derive(Clone, Copy)]
+pub(crate) enum Interest {
+    Read,
+    Write,
+}
+
+/// Descriptors `select` can watch, the bits of an `fd_set`.
+#[cfg(feature = "esp")]
+const FD_SETSIZE: usize = mem::size_of::<sys::fd_set>() * 8;
+
+#[cfg(feature = "esp")]
+pub(crate) fn check_fd(fd: i32) -> io::Result<()> {
+    match usize::try_from(fd) {
+        Ok(fd) if fd < FD_SETSIZE => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("descriptor {fd} is out of range for select"),
+        )),
+    }
+}
+
+// The bits of `fd_set` in order, whether newlib's array of words or lwIP's array of bytes
+#[cfg(feature = "esp")]
+pub(crate) fn fd_insert(set: &mut sys::fd_set, fd: i32) {
+    assert!(
+        (fd as usize) < FD_SETSIZE,
+        "descriptor {fd} is out of range for select"
+    );
+    let bits = set as *mut sys::fd_set as *mut u8;
+    unsafe { *bits.add(fd as usize / 8) |= 1 << (fd % 8) };
+}
+
+#[cfg(feature = "esp")]
+pub(crate) fn fd_contains(set: &sys::fd_set, fd: i32) -> bool {
+    let bits = set as *const sys::fd_set as *const u8;
+    unsafe { *bits.add(fd as usize / 8) & (1 << (fd % 8)) != 0 }
+}
+
+/// Whether `fd` is ready for `interest` right now, for reactors that report edges where esp-tls
+/// expects levels.
+#[cfg(feature = "esp")]
+pub(crate) fn poll_fd(fd: i32, interest: Interest) -> io::Result<bool> {
+    let mut set: sys::fd_set = unsafe { mem::zeroed() };
+    fd_insert(&mut set, fd);
+    let (read_set, write_set) = match interest {
+        Interest::Read => (&mut set as *mut _, ptr::null_mut()),
+        Interest::Write => (ptr::null_mut(), &mut set as *mut _),
+    };
+    let mut timeout = sys::timeval {
+        tv_sec: 0,
+        tv_usec: 0,
+    };
+
+    match unsafe { sys::select(fd + 1, read_set, write_set, ptr::null_mut(), &mut timeout) } {
+        ready if ready < 0 => Err(io::Error::last_os_error()),
+        ready => Ok(ready > 0),
+    }
+}
+
+/// Open a TCP socket in non-blocking mode, let `prepare` set it up, e.g. bind it to an
+/// interface, and start connecting it to `addr`. The connection is established once the socket
+/// is writable, unless `take_error` reports otherwise.
+#[cfg(feature = "esp")]
+pub(crate) fn start_connect(
+    addr: SocketAddr,
+    prepare: impl FnOnce(i32) -> io::Result<()>,
+) -> io::Result<TcpStream> {
+    let domain = match addr {
+        SocketAddr::V4(_) => sys::AF_INET,
+        SocketAddr::V6(_) => sys::AF_INET6,
+    };
+    let fd = unsafe { sys::lwip_socket(domain as _, sys::SOCK_STREAM as _, sys::IPPROTO_TCP as _) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Owned from here on, so that it is closed on errors
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    stream.set_nonblocking(true)?;
+    prepare(fd)?;
+
+    let connected = match addr {
+        SocketAddr::V4(addr) => {
+            let mut raw: sys::sockaddr_in = unsafe { mem::zeroed() };
+            raw.sin_len = mem::size_of::<sys::sockaddr_in>() as _;
+            raw.sin_family = sys::AF_INET as _;
+            raw.sin_port = addr.port().to_be();
+            raw.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+
+            unsafe {
+                sys::lwip_connect(
+                    fd,
+                    &raw as *const _ as *const sys::sockaddr,
+                    raw.sin_len as _,
+                )
+            }
+        }
+        SocketAddr::V6(addr) => {
+            let mut raw: sys::sockaddr_in6 = unsafe { mem::zeroed() };
+            raw.sin6_len = mem::size_of::<sys::sockaddr_in6>() as _;
+            raw.sin6_family = sys::AF_INET6 as _;
+            raw.sin6_port = addr.port().to_be();
+            raw.sin6_addr.un.u8_addr = addr.ip().octets();
+            raw.sin6_scope_id = addr.scope_id();
+
+            unsafe {
+                sys::lwip_connect(
+                    fd,
+                    &raw as *const _ as *const sys::sockaddr,
+                    raw.sin6_len as _,
+                )
+            }
+        }
+    };
+    if connected < 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(sys::EINPROGRESS as i32) {
+            return Err(e);
+        }
+    }
+
+    Ok(stream)
+}
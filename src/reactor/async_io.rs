@@ -0,0 +1,50 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, TcpStream},
+    time::Duration,
+};
+
+use async_io::{Async, Timer};
+
+use super::{BoxFuture, Reactor};
+use crate::runtime;
+
+/// async-io, whose reactor runs on a thread of its own while no thread is inside
+/// `async_io::block_on`.
+pub struct AsyncIo;
+
+impl Reactor for AsyncIo {
+    type TcpStream = Async<TcpStream>;
+    type TcpListener = Async<TcpListener>;
+    type Timer = Timer;
+
+    fn start() {
+        // async-io starts its thread along with the reactor, which a timer sets up right away.
+        // Not `async_io::block_on`, this might be called from within it
+        runtime::spawn_with(runtime::current().reactor, || {
+            futures_lite::future::block_on(Timer::after(Duration::from_millis(1)))
+        });
+    }
+
+    fn connect(addr: SocketAddr) -> BoxFuture<'static, io::Result<Self::TcpStream>> {
+        Box::pin(Async::<TcpStream>::connect(addr))
+    }
+
+    fn register(stream: TcpStream) -> io::Result<Self::TcpStream> {
+        Async::new(stream)
+    }
+
+    fn bind(addr: SocketAddr) -> io::Result<Self::TcpListener> {
+        Async::<TcpListener>::bind(addr)
+    }
+
+    fn accept(
+        listener: &Self::TcpListener,
+    ) -> BoxFuture<'_, io::Result<(Self::TcpStream, SocketAddr)>> {
+        Box::pin(listener.accept())
+    }
+
+    fn local_addr(listener: &Self::TcpListener) -> io::Result<SocketAddr> {
+        listener.get_ref().local_addr()
+    }
+}
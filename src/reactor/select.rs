@@ -0,0 +1,598 @@
+//! A minimal reactor for the handful of sockets of a device, instead of async-io: no thread of
+//! its own and no timer machinery beyond a sorted list, just `select` over the registered
+//! sockets and an eventfd to interrupt it.
+//!
+//! ```ignore
+//! let tls = reactor::select::block_on(connector.connect("example.com", 443, &cfg))?;
+//! ```
+//!
+//! [`Registered`] sockets implement [`Readiness`] with the semantics of async-io's `Async`, so
+//! they work with [`FdSocket`], [`AsyncTls`](crate::AsyncTls) and
+//! [`TlsConnector::adopt`](crate::TlsConnector::adopt) alike. The reactor is driven by a thread
+//! inside [`block_on`]. With several of them, one selects on behalf of all, futures polled by
+//! other executors only make progress while some thread is in [`block_on`].
+
+use core::{
+    ffi::c_void,
+    future::{poll_fn, Future},
+    mem,
+    pin::Pin,
+    ptr,
+    task::{ready, Context, Poll, Waker},
+};
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    os::fd::{AsRawFd, IntoRawFd},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::Wake,
+    thread::{self, Thread},
+    time::Instant,
+};
+
+use esp_idf_sys::{self as sys, ESP_ERR_INVALID_STATE};
+
+use super::{
+    check_fd, fd_contains, fd_insert, poll_fd, start_connect, BoxFuture, Interest, Reactor,
+};
+use crate::{
+    executor::DEFAULT_MAX_FDS,
+    tcp::{self, FdSocket, Readiness},
+};
+
+static REACTOR: Mutex<Option<&'static Driver>> = Mutex::new(None);
+
+/// A TCP stream driven by this reactor.
+pub type SelectTcp = FdSocket<Registered<TcpStream>>;
+
+/// The `select` reactor, see the [module docs](self).
+pub struct Select;
+
+impl Reactor for Select {
+    type TcpStream = Registered<TcpStream>;
+    type TcpListener = Registered<TcpListener>;
+    type Timer = Timer;
+
+    fn connect(addr: SocketAddr) -> BoxFuture<'static, io::Result<Self::TcpStream>> {
+        Box::pin(Registered::<TcpStream>::connect(addr))
+    }
+
+    fn register(stream: TcpStream) -> io::Result<Self::TcpStream> {
+        Registered::new(stream)
+    }
+
+    fn bind(addr: SocketAddr) -> io::Result<Self::TcpListener> {
+        Registered::new(TcpListener::bind(addr)?)
+    }
+
+    fn accept(
+        listener: &Self::TcpListener,
+    ) -> BoxFuture<'_, io::Result<(Self::TcpStream, SocketAddr)>> {
+        Box::pin(listener.accept())
+    }
+
+    fn local_addr(listener: &Self::TcpListener) -> io::Result<SocketAddr> {
+        listener.get_ref().local_addr()
+    }
+}
+
+struct Driver {
+    /// Written to interrupt `select`
+    event_fd: i32,
+    /// A thread is blocked in `select` and has to be interrupted for new registrations
+    selecting: AtomicBool,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    readers: Vec<(i32, Waker)>,
+    writers: Vec<(i32, Waker)>,
+    timers: BTreeMap<(Instant, u64), Waker>,
+    next_timer: u64,
+    /// A thread in `block_on` drives the reactor
+    driving: bool,
+    /// Threads in `block_on` waiting for the driver to leave
+    parked: Vec<Thread>,
+}
+
+/// The reactor, set up on first use.
+fn reactor() -> io::Result<&'static Driver> {
+    let mut reactor = REACTOR.lock().unwrap();
+    if let Some(reactor) = *reactor {
+        return Ok(reactor);
+    }
+
+    match tcp::register_eventfd(DEFAULT_MAX_FDS) {
+        Err(e) if e.code() != ESP_ERR_INVALID_STATE => {
+            return Err(io::Error::new(io::ErrorKind::Other, e))
+        }
+        // Registered by us or async-io already
+        _ => {}
+    }
+
+    let event_fd = unsafe { sys::eventfd(0, 0) };
+    if event_fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    check_fd(event_fd)?;
+
+    let new = Box::leak(Box::new(Driver {
+        event_fd,
+        selecting: AtomicBool::new(false),
+        state: Default::default(),
+    }));
+    *reactor = Some(new);
+
+    Ok(new)
+}
+
+impl Driver {
+    fn register(&self, fd: i32, interest: Interest, waker: &Waker) {
+        let mut state = self.state.lock().unwrap();
+        let wakers = match interest {
+            Interest::Read => &mut state.readers,
+            Interest::Write => &mut state.writers,
+        };
+
+        if !wakers.iter().any(|(f, w)| *f == fd && w.will_wake(waker)) {
+            wakers.push((fd, waker.clone()));
+            drop(state);
+            self.interrupt();
+        }
+    }
+
+    fn deregister(&self, fd: i32) {
+        let mut state = self.state.lock().unwrap();
+        state.readers.retain(|&(f, _)| f != fd);
+        state.writers.retain(|&(f, _)| f != fd);
+        drop(state);
+
+        // Before the descriptor is closed
+        self.interrupt();
+    }
+
+    /// Make a thread blocked in `select` start over with the current registrations.
+    fn interrupt(&self) {
+        if self.selecting.load(Ordering::SeqCst) {
+            self.notify();
+        }
+    }
+
+    fn notify(&self) {
+        let one = 1u64;
+        unsafe { sys::write(self.event_fd, &one as *const u64 as *const c_void, 8) };
+    }
+
+    /// Block in `select` until a registered socket is ready, a timer expires or the reactor is
+    /// notified, and wake the wakers of what is ready.
+    fn turn(&self) {
+        let mut read_set: sys::fd_set = unsafe { mem::zeroed() };
+        let mut write_set: sys::fd_set = unsafe { mem::zeroed() };
+        fd_insert(&mut read_set, self.event_fd);
+        let mut max_fd = self.event_fd;
+
+        let next_timer = {
+            let state = self.state.lock().unwrap();
+            for &(fd, _) in &state.readers {
+                fd_insert(&mut read_set, fd);
+                max_fd = max_fd.max(fd);
+            }
+            for &(fd, _) in &state.writers {
+                fd_insert(&mut write_set, fd);
+                max_fd = max_fd.max(fd);
+            }
+            self.selecting.store(true, Ordering::SeqCst);
+
+            state.timers.keys().next().map(|&(deadline, _)| deadline)
+        };
+
+        let mut timeout = next_timer.map(|deadline| {
+            // Rounded up, so that the timer has expired when `select` returns
+            let micros = (deadline
+                .saturating_duration_since(Instant::now())
+                .as_nanos()
+                + 999)
+                / 1000;
+            sys::timeval {
+                tv_sec: (micros / 1_000_000) as _,
+                tv_usec: (micros % 1_000_000) as _,
+            }
+        });
+        let timeout = timeout
+            .as_mut()
+            .map_or(ptr::null_mut(), |timeout| timeout as *mut _);
+
+        let ready = unsafe {
+            sys::select(
+                max_fd + 1,
+                &mut read_set,
+                &mut write_set,
+                ptr::null_mut(),
+                timeout,
+            )
+        };
+        let error = (ready < 0).then(io::Error::last_os_error);
+        self.selecting.store(false, Ordering::SeqCst);
+
+        if ready > 0 && fd_contains(&read_set, self.event_fd) {
+            let mut count = [0u8; 8];
+            unsafe { sys::read(self.event_fd, count.as_mut_ptr() as *mut c_void, 8) };
+        }
+
+        let mut woken = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+
+            if let Some(e) = error {
+                // E.g. a descriptor closed meanwhile, let everyone find out for themselves
+                log::debug!("select failed: {e}");
+                woken.extend(state.readers.drain(..).map(|(_, waker)| waker));
+                woken.extend(state.writers.drain(..).map(|(_, waker)| waker));
+            } else if ready > 0 {
+                take_ready(&mut state.readers, &read_set, &mut woken);
+                take_ready(&mut state.writers, &write_set, &mut woken);
+            }
+
+            let now = Instant::now();
+            while let Some(timer) = state.timers.first_entry() {
+                if timer.key().0 > now {
+                    break;
+                }
+                woken.push(timer.remove());
+            }
+        }
+
+        for waker in woken {
+            waker.wake();
+        }
+    }
+
+    /// Wait until `signal` is woken, driving the reactor meanwhile unless another thread does.
+    fn wait_for(&self, signal: &Signal) {
+        while !signal.woken.swap(false, Ordering::SeqCst) {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.driving {
+                    let current = thread::current();
+                    if !state.parked.iter().any(|t| t.id() == current.id()) {
+                        state.parked.push(current);
+                    }
+                    drop(state);
+
+                    thread::park();
+                    continue;
+                }
+                state.driving = true;
+            }
+
+            while !signal.woken.load(Ordering::SeqCst) {
+                self.turn();
+            }
+
+            let parked = {
+                let mut state = self.state.lock().unwrap();
+                state.driving = false;
+                mem::take(&mut state.parked)
+            };
+            // One of them takes over
+            for thread in parked {
+                thread.unpark();
+            }
+        }
+    }
+}
+
+fn take_ready(wakers: &mut Vec<(i32, Waker)>, set: &sys::fd_set, woken: &mut Vec<Waker>) {
+    let mut i = 0;
+    while i < wakers.len() {
+        if fd_contains(set, wakers[i].0) {
+            woken.push(wakers.swap_remove(i).1);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+/// Wakes a thread in [`block_on`].
+struct Signal {
+    thread: Thread,
+    woken: AtomicBool,
+    reactor: &'static Driver,
+}
+
+impl Wake for Signal {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.woken.swap(true, Ordering::SeqCst) {
+            self.thread.unpark();
+            // In case the thread is the one in `select`
+            self.reactor.interrupt();
+        }
+    }
+}
+
+/// Run `future` to completion on the current thread, driving the reactor while it waits.
+///
+/// # Panics
+///
+/// If the reactor can't be set up, e.g. because all eventfd descriptors are in use, see
+/// [`init_async_runtime`](crate::init_async_runtime).
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let reactor = reactor().expect("failed to set up the reactor");
+    futures_lite::pin!(future);
+
+    let signal = Arc::new(Signal {
+        thread: thread::current(),
+        woken: AtomicBool::new(false),
+        reactor,
+    });
+    let waker = Waker::from(signal.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+
+        reactor.wait_for(&signal);
+    }
+}
+
+/// Completes at a deadline, with the deadline.
+pub struct Timer {
+    /// `None` for a timer that never completes
+    deadline: Option<Instant>,
+    /// Key in the timers of the reactor once registered
+    key: Option<(Instant, u64)>,
+}
+
+impl Timer {
+    fn deregister(&mut self) {
+        if let (Some(key), Some(reactor)) = (self.key.take(), *REACTOR.lock().unwrap()) {
+            reactor.state.lock().unwrap().timers.remove(&key);
+        }
+    }
+}
+
+impl super::Timer for Timer {
+    fn never() -> Self {
+        Self {
+            deadline: None,
+            key: None,
+        }
+    }
+
+    fn at(deadline: Instant) -> Self {
+        Self {
+            deadline: Some(deadline),
+            key: None,
+        }
+    }
+
+    fn set_at(&mut self, deadline: Instant) {
+        if self.deadline != Some(deadline) {
+            self.deregister();
+            self.deadline = Some(deadline);
+        }
+    }
+}
+
+impl Future for Timer {
+    type Output = Instant;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Instant> {
+        let Some(deadline) = self.deadline else {
+            return Poll::Pending;
+        };
+        if Instant::now() >= deadline {
+            return Poll::Ready(deadline);
+        }
+
+        let reactor = reactor().expect("failed to set up the reactor");
+        let mut state = reactor.state.lock().unwrap();
+        let key = match self.key {
+            Some(key) => key,
+            None => {
+                state.next_timer += 1;
+                (deadline, state.next_timer)
+            }
+        };
+        state.timers.insert(key, cx.waker().clone());
+        drop(state);
+
+        if self.key.is_none() {
+            self.key = Some(key);
+            // `select` might wait past the new deadline
+            reactor.interrupt();
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.deregister();
+    }
+}
+
+/// An I/O object in non-blocking mode whose readiness the reactor reports, like async-io's
+/// `Async`.
+pub struct Registered<T: AsRawFd> {
+    /// `None` once handed back by `into_inner`
+    io: Option<T>,
+    reactor: &'static Driver,
+}
+
+impl<T: AsRawFd> Registered<T> {
+    /// Put `io` in non-blocking mode and register it.
+    pub fn new(io: T) -> io::Result<Self> {
+        let fd = io.as_raw_fd();
+        check_fd(fd)?;
+
+        let flags = unsafe { sys::fcntl(fd, sys::F_GETFL as _) };
+        if flags < 0
+            || unsafe { sys::fcntl(fd, sys::F_SETFL as _, flags | sys::O_NONBLOCK as i32) } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            io: Some(io),
+            reactor: reactor()?,
+        })
+    }
+
+    pub fn get_ref(&self) -> &T {
+        self.io.as_ref().unwrap()
+    }
+
+    /// Deregister and return the I/O object, still in non-blocking mode.
+    pub fn into_inner(mut self) -> io::Result<T> {
+        let io = self.io.take().unwrap();
+        self.reactor.deregister(io.as_raw_fd());
+
+        Ok(io)
+    }
+
+    pub fn poll_readable(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_ready(cx, Interest::Read)
+    }
+
+    pub fn poll_writable(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_ready(cx, Interest::Write)
+    }
+
+    pub async fn readable(&self) -> io::Result<()> {
+        poll_fn(|cx| self.poll_readable(cx)).await
+    }
+
+    pub async fn writable(&self) -> io::Result<()> {
+        poll_fn(|cx| self.poll_writable(cx)).await
+    }
+
+    fn poll_ready(&self, cx: &mut Context<'_>, interest: Interest) -> Poll<io::Result<()>> {
+        let fd = self.get_ref().as_raw_fd();
+        if poll_fd(fd, interest)? {
+            return Poll::Ready(Ok(()));
+        }
+
+        // Should it become ready right after the check, the next `select` reports it
+        self.reactor.register(fd, interest, cx.waker());
+
+        Poll::Pending
+    }
+}
+
+impl Registered<TcpStream> {
+    /// Connect to `addr` without blocking the thread.
+    pub async fn connect(addr: SocketAddr) -> io::Result<Self> {
+        let stream = Self::new(start_connect(addr, |_| Ok(()))?)?;
+
+        stream.writable().await?;
+        match stream.get_ref().take_error()? {
+            Some(e) => Err(e),
+            None => Ok(stream),
+        }
+    }
+}
+
+impl Registered<TcpListener> {
+    /// Wait for the next incoming connection.
+    pub async fn accept(&self) -> io::Result<(Registered<TcpStream>, SocketAddr)> {
+        loop {
+            match self.get_ref().accept() {
+                Ok((stream, peer)) => return Ok((Registered::new(stream)?, peer)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.readable().await?,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<T: AsRawFd> Drop for Registered<T> {
+    fn drop(&mut self) {
+        if let Some(io) = &self.io {
+            self.reactor.deregister(io.as_raw_fd());
+        }
+    }
+}
+
+impl<T> Readiness for Registered<T>
+where
+    T: AsRawFd + IntoRawFd,
+{
+    type Socket = T;
+
+    fn get_ref(&self) -> &T {
+        Registered::get_ref(self)
+    }
+
+    fn into_inner(self) -> io::Result<T> {
+        Registered::into_inner(self)
+    }
+
+    fn poll_readable(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Registered::poll_readable(self, cx)
+    }
+
+    fn poll_writable(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Registered::poll_writable(self, cx)
+    }
+}
+
+impl<T: AsRawFd + Unpin> futures_lite::AsyncRead for Registered<T>
+where
+    for<'a> &'a T: Read,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut io = Registered::get_ref(&self);
+        loop {
+            match io.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => ready!(self.poll_readable(cx))?,
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+}
+
+impl<T: AsRawFd + Unpin> futures_lite::AsyncWrite for Registered<T>
+where
+    for<'a> &'a T: Write,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut io = Registered::get_ref(&self);
+        loop {
+            match io.write(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => ready!(self.poll_writable(cx))?,
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut io = Registered::get_ref(&self);
+
+        Poll::Ready(io.flush())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
@@ -0,0 +1,227 @@
+//! The I/O and time drivers of a tokio runtime as the reactor.
+//!
+//! ```ignore
+//! let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+//! let tls = runtime.block_on(connector.connect("example.com", 443, &cfg))?;
+//! ```
+//!
+//! Sockets and timers register with the runtime they are created in, so create and poll them
+//! within one. The threads the crate spawns itself enter the runtime that was current where
+//! their [`Executor`](crate::Executor) was created, i.e. the default one of the
+//! [`TlsConnector`](crate::TlsConnector).
+
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    os::fd::{AsRawFd, IntoRawFd},
+    time::Instant,
+};
+
+use tokio::{
+    io::unix::AsyncFd,
+    time::{sleep_until, Sleep},
+};
+
+use super::{poll_fd, BoxFuture, Interest, Reactor};
+use crate::tcp::{FdSocket, Readiness};
+
+/// A TCP stream driven by tokio.
+pub type TokioTcp = FdSocket<Registered<TcpStream>>;
+
+/// The tokio reactor, see the [module docs](self).
+pub struct Tokio;
+
+impl Reactor for Tokio {
+    type TcpStream = Registered<TcpStream>;
+    type TcpListener = tokio::net::TcpListener;
+    type Timer = Timer;
+
+    fn connect(addr: SocketAddr) -> BoxFuture<'static, io::Result<Self::TcpStream>> {
+        Box::pin(async move {
+            let stream = tokio::net::TcpStream::connect(addr).await?;
+
+            Registered::new(stream.into_std()?)
+        })
+    }
+
+    fn register(stream: TcpStream) -> io::Result<Self::TcpStream> {
+        Registered::new(stream)
+    }
+
+    fn bind(addr: SocketAddr) -> io::Result<Self::TcpListener> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+
+        tokio::net::TcpListener::from_std(listener)
+    }
+
+    fn accept(
+        listener: &Self::TcpListener,
+    ) -> BoxFuture<'_, io::Result<(Self::TcpStream, SocketAddr)>> {
+        Box::pin(async move {
+            let (stream, peer) = listener.accept().await?;
+
+            Ok((Registered::new(stream.into_std()?)?, peer))
+        })
+    }
+
+    fn local_addr(listener: &Self::TcpListener) -> io::Result<SocketAddr> {
+        listener.local_addr()
+    }
+}
+
+/// An I/O object in non-blocking mode registered with tokio.
+///
+/// tokio reports readiness once per edge, while esp-tls polls for readiness whenever mbedtls
+/// wants to read or write. Readiness is therefore confirmed with `select` before it is
+/// reported, and cleared if the descriptor turns out not to be ready after all.
+pub struct Registered<T: AsRawFd>(AsyncFd<T>);
+
+impl<T: AsRawFd> Registered<T> {
+    /// Register `io`, which has to be in non-blocking mode already, with the current runtime.
+    pub fn new(io: T) -> io::Result<Self> {
+        Ok(Self(AsyncFd::new(io)?))
+    }
+
+    pub fn get_ref(&self) -> &T {
+        self.0.get_ref()
+    }
+
+    /// Deregister and return the I/O object, still in non-blocking mode.
+    pub fn into_inner(self) -> T {
+        self.0.into_inner()
+    }
+
+    pub fn poll_readable(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+            if poll_fd(self.0.as_raw_fd(), Interest::Read)? {
+                return Poll::Ready(Ok(()));
+            }
+            guard.clear_ready();
+        }
+    }
+
+    pub fn poll_writable(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.0.poll_write_ready(cx))?;
+            if poll_fd(self.0.as_raw_fd(), Interest::Write)? {
+                return Poll::Ready(Ok(()));
+            }
+            guard.clear_ready();
+        }
+    }
+}
+
+impl<T> Readiness for Registered<T>
+where
+    T: AsRawFd + IntoRawFd,
+{
+    type Socket = T;
+
+    fn get_ref(&self) -> &T {
+        Registered::get_ref(self)
+    }
+
+    fn into_inner(self) -> io::Result<T> {
+        Ok(Registered::into_inner(self))
+    }
+
+    fn poll_readable(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Registered::poll_readable(self, cx)
+    }
+
+    fn poll_writable(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Registered::poll_writable(self, cx)
+    }
+}
+
+impl<T: AsRawFd + Unpin> futures_lite::AsyncRead for Registered<T>
+where
+    for<'a> &'a T: Read,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+            if let Ok(result) = guard.try_io(|io| {
+                let mut io = io.get_ref();
+                io.read(buf)
+            }) {
+                return Poll::Ready(result);
+            }
+        }
+    }
+}
+
+impl<T: AsRawFd + Unpin> futures_lite::AsyncWrite for Registered<T>
+where
+    for<'a> &'a T: Write,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.0.poll_write_ready(cx))?;
+            if let Ok(result) = guard.try_io(|io| {
+                let mut io = io.get_ref();
+                io.write(buf)
+            }) {
+                return Poll::Ready(result);
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut io = self.0.get_ref();
+
+        Poll::Ready(io.flush())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// A tokio `Sleep`, boxed to be `Unpin`.
+pub struct Timer(Option<Pin<Box<Sleep>>>);
+
+impl super::Timer for Timer {
+    fn never() -> Self {
+        Self(None)
+    }
+
+    fn at(deadline: Instant) -> Self {
+        Self(Some(Box::pin(sleep_until(deadline.into()))))
+    }
+
+    fn set_at(&mut self, deadline: Instant) {
+        match &mut self.0 {
+            Some(sleep) => sleep.as_mut().reset(deadline.into()),
+            None => *self = Self::at(deadline),
+        }
+    }
+}
+
+impl Future for Timer {
+    type Output = Instant;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Instant> {
+        let Some(sleep) = &mut self.0 else {
+            return Poll::Pending;
+        };
+        ready!(sleep.as_mut().poll(cx));
+
+        Poll::Ready(sleep.deadline().into_std())
+    }
+}
@@ -0,0 +1,300 @@
+//! Shipping `log` records to a collector over TLS, to debug devices in the field without a
+//! serial connection.
+//!
+//! ```ignore
+//! // Instead of `EspLogger::initialize_default()`
+//! let shipper = RemoteLog::new(LogFormat::JsonLines)
+//!     .level(LevelFilter::Info)
+//!     .install()?;
+//!
+//! thread::spawn(move || {
+//!     async_io::block_on(shipper.run(&connector, "logs.example.com", 6514, &cfg))
+//! });
+//! ```
+//!
+//! Records still go to the serial console as well. The ones to ship are kept in a buffer and
+//! flushed periodically. When the buffer is full, e.g. while the collector is unreachable, the
+//! oldest records are dropped and the next flush reports how many.
+
+use core::fmt::{self, Write as _};
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use esp_idf_svc::{log::EspLogger, tls::Config};
+use esp_idf_sys as sys;
+use futures_lite::{AsyncWrite, AsyncWriteExt};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::{
+    connector::TlsConnector,
+    error::{Error, Result},
+    time,
+    tls::AsyncTls,
+    util::json_escape,
+};
+
+static ESP_LOGGER: EspLogger = EspLogger;
+
+/// How records are framed on the connection.
+#[derive(Clone, Debug)]
+pub enum LogFormat {
+    /// One JSON object per line, e.g.
+    /// `{"uptime_ms":81234,"level":"WARN","target":"app","msg":"retrying"}`
+    JsonLines,
+    /// RFC 5424 messages with the octet counting framing of RFC 5425 (syslog over TLS). The
+    /// timestamp is left for the collector to fill in
+    Syslog { hostname: String, app_name: String },
+}
+
+impl LogFormat {
+    fn encode(&self, level: Level, target: &str, args: &fmt::Arguments<'_>) -> Vec<u8> {
+        let mut line = String::new();
+
+        match self {
+            Self::JsonLines => {
+                let uptime_ms = unsafe { sys::esp_timer_get_time() } / 1000;
+                let _ = writeln!(
+                    line,
+                    r#"{{"uptime_ms":{uptime_ms},"level":"{level}","target":"{}","msg":"{}"}}"#,
+                    json_escape(target),
+                    json_escape(&args.to_string()),
+                );
+            }
+            Self::Syslog { hostname, app_name } => {
+                let severity = match level {
+                    Level::Error => 3,
+                    Level::Warn => 4,
+                    Level::Info => 6,
+                    Level::Debug | Level::Trace => 7,
+                };
+                // Facility 1, user-level messages
+                let message = format!(
+                    "<{}>1 - {hostname} {app_name} - - - {target}: {args}",
+                    8 + severity
+                );
+                let _ = write!(line, "{} {message}", message.len());
+            }
+        }
+
+        line.into_bytes()
+    }
+}
+
+/// Sets up the logger, see the [module docs](self).
+pub struct RemoteLog {
+    format: LogFormat,
+    level: LevelFilter,
+    capacity: usize,
+    flush_interval: Duration,
+}
+
+impl RemoteLog {
+    /// Ship records of level `Info` and above in `format`, buffering up to 8 KiB of them and
+    /// flushing every 10 s.
+    pub fn new(format: LogFormat) -> Self {
+        Self {
+            format,
+            level: LevelFilter::Info,
+            capacity: 8 * 1024,
+            flush_interval: Duration::from_secs(10),
+        }
+    }
+
+    /// Ship records up to `level`, independently of what goes to the serial console.
+    pub fn level(mut self, level: LevelFilter) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Buffer up to `capacity` bytes of encoded records.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Flush every `interval` in [`LogShipper::run`].
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    /// Install the logger for the `log` crate, which also logs to the serial console like
+    /// `EspLogger`. Fails if a logger is installed already.
+    pub fn install(self) -> Result<LogShipper> {
+        let shared = Arc::new(Shared {
+            format: self.format,
+            level: self.level,
+            capacity: self.capacity,
+            buffer: Default::default(),
+        });
+
+        let logger = Box::leak(Box::new(Logger(shared.clone())));
+        log::set_logger(logger).map_err(|e| Error::Io(io::Error::new(io::ErrorKind::Other, e)))?;
+        ESP_LOGGER.initialize();
+        log::set_max_level(log::max_level().max(self.level));
+
+        Ok(LogShipper {
+            shared,
+            flush_interval: self.flush_interval,
+        })
+    }
+}
+
+struct Shared {
+    format: LogFormat,
+    level: LevelFilter,
+    capacity: usize,
+    buffer: Mutex<Buffer>,
+}
+
+#[derive(Default)]
+struct Buffer {
+    /// Encoded records with a sequence number
+    records: VecDeque<(u64, Vec<u8>)>,
+    len: usize,
+    next_seq: u64,
+    dropped: u64,
+}
+
+impl Shared {
+    fn push(&self, record: Vec<u8>) {
+        let mut buffer = self.buffer.lock().unwrap();
+
+        while buffer.len + record.len() > self.capacity {
+            let Some((_, oldest)) = buffer.records.pop_front() else {
+                // Larger than the whole buffer
+                buffer.dropped += 1;
+                return;
+            };
+            buffer.len -= oldest.len();
+            buffer.dropped += 1;
+        }
+
+        let seq = buffer.next_seq;
+        buffer.next_seq += 1;
+        buffer.len += record.len();
+        buffer.records.push_back((seq, record));
+    }
+}
+
+struct Logger(Arc<Shared>);
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.0.level || ESP_LOGGER.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if ESP_LOGGER.enabled(record.metadata()) {
+            ESP_LOGGER.log(record);
+        }
+
+        if record.level() <= self.0.level {
+            let encoded = self
+                .0
+                .format
+                .encode(record.level(), record.target(), record.args());
+            self.0.push(encoded);
+        }
+    }
+
+    fn flush(&self) {
+        ESP_LOGGER.flush();
+    }
+}
+
+/// Sends the buffered records, returned by [`RemoteLog::install`].
+pub struct LogShipper {
+    shared: Arc<Shared>,
+    flush_interval: Duration,
+}
+
+impl LogShipper {
+    /// Write the buffered records to `writer`, returning how many. They are only removed from
+    /// the buffer once written, so after an error the next flush sends them again.
+    pub async fn flush_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<usize> {
+        let (mut batch, dropped, count, last_seq) = {
+            let mut buffer = self.shared.buffer.lock().unwrap();
+            let dropped = core::mem::take(&mut buffer.dropped);
+            let batch: Vec<u8> = buffer
+                .records
+                .iter()
+                .flat_map(|(_, record)| record.iter().copied())
+                .collect();
+
+            (
+                batch,
+                dropped,
+                buffer.records.len(),
+                buffer.records.back().map(|&(seq, _)| seq),
+            )
+        };
+
+        if dropped > 0 {
+            let notice = self.shared.format.encode(
+                Level::Warn,
+                module_path!(),
+                &format_args!("dropped {dropped} records, the buffer was full"),
+            );
+            batch.splice(0..0, notice);
+        }
+        if batch.is_empty() {
+            return Ok(0);
+        }
+
+        let written = async {
+            writer.write_all(&batch).await?;
+            writer.flush().await
+        }
+        .await;
+
+        let mut buffer = self.shared.buffer.lock().unwrap();
+        if let Err(e) = written {
+            buffer.dropped += dropped;
+            return Err(e);
+        }
+        // Records pushed meanwhile stay, and the ones dropped meanwhile are already gone
+        while let Some((seq, record)) = buffer.records.pop_front() {
+            if Some(seq) > last_seq {
+                buffer.records.push_front((seq, record));
+                break;
+            }
+            buffer.len -= record.len();
+        }
+
+        Ok(count)
+    }
+
+    /// Flush the buffered records to `hostname` periodically, connecting with `connector` and
+    /// `cfg` as needed and reconnecting after errors. Never returns.
+    pub async fn run(&self, connector: &TlsConnector, hostname: &str, port: u16, cfg: &Config<'_>) {
+        let mut tls: Option<AsyncTls> = None;
+
+        loop {
+            time::sleep(self.flush_interval).await;
+            if self.shared.buffer.lock().unwrap().records.is_empty() {
+                continue;
+            }
+
+            let mut conn = match tls.take() {
+                Some(conn) => conn,
+                None => match connector.connect(hostname, port, cfg).await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        log::warn!("connecting to the log collector failed: {e}");
+                        continue;
+                    }
+                },
+            };
+
+            match self.flush_to(&mut conn).await {
+                Ok(_) => tls = Some(conn),
+                Err(e) => log::warn!("shipping logs failed: {e}"),
+            }
+        }
+    }
+}
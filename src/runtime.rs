@@ -0,0 +1,110 @@
+//! Core affinity and FreeRTOS priorities of the tasks the crate spawns, so that TLS crypto
+//! doesn't starve time-critical application tasks on dual-core chips.
+//!
+//! ```ignore
+//! RuntimeConfig {
+//!     reactor: TaskConfig::pinned(Core::Core0, 5),
+//!     handshake: TaskConfig::pinned(Core::Core0, 2),
+//!     ..Default::default()
+//! }
+//! .install();
+//! ```
+//!
+//! Install the configuration before the first connection: the async-io reactor thread starts
+//! with the async runtime (see [`init_async_runtime`](crate::init_async_runtime)) and keeps the
+//! settings it started with. The other tasks pick up changes the next time they are spawned.
+//! Tasks of the application, including the one running the executor, are configured by the
+//! application, e.g. with `ThreadSpawnConfiguration`.
+
+use std::sync::Mutex;
+
+use esp_idf_hal::{cpu::Core, task::thread::ThreadSpawnConfiguration};
+
+use crate::executor;
+
+static CONFIG: Mutex<RuntimeConfig> = Mutex::new(RuntimeConfig {
+    reactor: TaskConfig::INHERIT,
+    handshake: TaskConfig::INHERIT,
+    link_monitor: TaskConfig::INHERIT,
+    background: TaskConfig::INHERIT,
+});
+
+/// Where and at which priority a task runs. `None` keeps the pthread default of ESP-IDF
+/// (`CONFIG_PTHREAD_TASK_PRIO_DEFAULT`, `CONFIG_PTHREAD_TASK_CORE_DEFAULT`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TaskConfig {
+    pub core: Option<Core>,
+    pub priority: Option<u8>,
+}
+
+impl TaskConfig {
+    const INHERIT: Self = Self {
+        core: None,
+        priority: None,
+    };
+
+    pub fn pinned(core: Core, priority: u8) -> Self {
+        Self {
+            core: Some(core),
+            priority: Some(priority),
+        }
+    }
+}
+
+/// The tasks of the crate, see the [module docs](self).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RuntimeConfig {
+    /// The async-io thread driving the reactor while no thread is inside `async_io::block_on`
+    pub reactor: TaskConfig,
+    /// The handshake thread of
+    /// [`TlsConnector::handshake_stack_size`](crate::TlsConnector::handshake_stack_size)
+    pub handshake: TaskConfig,
+    /// The [`LinkMonitor`](crate::wifi::LinkMonitor) thread
+    pub link_monitor: TaskConfig,
+    /// The UART console and the PPP receive thread
+    pub background: TaskConfig,
+}
+
+impl RuntimeConfig {
+    /// Use the configuration for the tasks spawned from now on.
+    pub fn install(self) {
+        if executor::is_initialized() && self.reactor != current().reactor {
+            log::warn!("the async runtime is already running, the reactor settings won't apply");
+        }
+
+        *CONFIG.lock().unwrap() = self;
+    }
+}
+
+pub(crate) fn current() -> RuntimeConfig {
+    *CONFIG.lock().unwrap()
+}
+
+/// Run `spawn` with the pthread configuration of the calling thread adjusted to `task`, so that
+/// the threads it spawns start with it.
+pub(crate) fn spawn_with<R>(task: TaskConfig, spawn: impl FnOnce() -> R) -> R {
+    if task == TaskConfig::INHERIT {
+        return spawn();
+    }
+
+    let previous = ThreadSpawnConfiguration::get();
+    let mut conf = ThreadSpawnConfiguration::get().unwrap_or_default();
+    if let Some(priority) = task.priority {
+        conf.priority = priority;
+    }
+    if task.core.is_some() {
+        conf.pin_to_core = task.core;
+    }
+    if let Err(e) = conf.set() {
+        log::warn!("ignoring the task configuration {task:?}: {e}");
+    }
+
+    let result = spawn();
+
+    let restored = previous.unwrap_or_default().set();
+    if let Err(e) = restored {
+        log::warn!("failed to restore the pthread configuration: {e}");
+    }
+
+    result
+}
@@ -0,0 +1,237 @@
+//! TLS with rustls instead of esp-tls/mbedtls, to compare footprint and behavior or to work
+//! around an mbedtls issue.
+//!
+//! ```ignore
+//! let tls = TlsConnector::new().connect_rustls("example.com", 443, &cfg).await?;
+//! log::info!("{:?} with {:?}", tls.protocol_version(), tls.cipher_suite());
+//! ```
+//!
+//! The [`Config`] is translated: the CA certificate (or the webpki roots for
+//! `use_crt_bundle_attach`), the client certificate and key, ALPN and the common name carry
+//! over, the other options fail the connection. Of the [`TlsConnector`](crate::TlsConnector)
+//! options only the name resolution, proxy and TCP ones apply.
+//!
+//! [`connect_async_tls`](crate::connect_async_tls) stays on esp-tls even with the
+//! `backend-rustls` feature, as features have to be additive: a dependency enabling it must not
+//! change the types other crates in the build get. rustls needs ring, which builds for the
+//! RISC-V chips (ESP32-C3, -C6) but not for the Xtensa ones.
+
+use std::{
+    io::{self, BufReader, IoSlice},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use esp_idf_svc::tls::{Config, X509};
+use futures_lite::{AsyncRead, AsyncWrite};
+use futures_rustls::{
+    client::TlsStream,
+    rustls::{
+        self, Certificate as RustlsCertificate, ClientConfig, OwnedTrustAnchor, PrivateKey,
+        RootCertStore, ServerName,
+    },
+    TlsConnector as RustlsConnector,
+};
+
+use crate::{
+    cert::Certificate,
+    error::{Error, Result},
+    tcp::AsyncTcp,
+    tls::ProtocolVersion,
+};
+
+/// A TLS session established by rustls, with the same accessors as
+/// [`AsyncTls`](crate::AsyncTls) where rustls has an equivalent.
+pub struct RustlsTls {
+    stream: TlsStream<AsyncTcp>,
+}
+
+impl RustlsTls {
+    /// Perform the TLS handshake on the connected `tcp`.
+    pub async fn negotiate(tcp: AsyncTcp, hostname: &str, cfg: &Config<'_>) -> Result<Self> {
+        let server_name = cfg.common_name.unwrap_or(hostname);
+        let server_name = ServerName::try_from(server_name)
+            .map_err(|_| config_error(&format!("invalid server name {server_name}")))?;
+
+        let stream = RustlsConnector::from(Arc::new(client_config(cfg)?))
+            .connect(server_name, tcp)
+            .await?;
+
+        Ok(Self { stream })
+    }
+
+    /// The certificate the server presented during the handshake, if any.
+    pub fn peer_certificate(&self) -> Option<Certificate> {
+        self.peer_certificate_chain().into_iter().next()
+    }
+
+    /// The full chain the server presented during the handshake, leaf first.
+    pub fn peer_certificate_chain(&self) -> Vec<Certificate> {
+        self.stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|cert| Certificate::from_der(&cert.0))
+            .collect()
+    }
+
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        match self.stream.get_ref().1.protocol_version()? {
+            rustls::ProtocolVersion::TLSv1_2 => Some(ProtocolVersion::Tls1_2),
+            rustls::ProtocolVersion::TLSv1_3 => Some(ProtocolVersion::Tls1_3),
+            _ => None,
+        }
+    }
+
+    /// The IANA name of the cipher suite, e.g. `TLS13_AES_128_GCM_SHA256`.
+    pub fn cipher_suite(&self) -> Option<&'static str> {
+        self.stream
+            .get_ref()
+            .1
+            .negotiated_cipher_suite()
+            .and_then(|suite| suite.suite().as_str())
+    }
+
+    /// The protocol the server selected with ALPN, if any.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.stream.get_ref().1.alpn_protocol()
+    }
+
+    pub fn get_ref(&self) -> &AsyncTcp {
+        self.stream.get_ref().0
+    }
+}
+
+impl AsyncRead for RustlsTls {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for RustlsTls {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    /// Sends a close_notify alert.
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_close(cx)
+    }
+}
+
+/// Translate the esp-tls `cfg`, see the [module docs](self).
+fn client_config(cfg: &Config<'_>) -> Result<ClientConfig> {
+    let unsupported = [
+        (cfg.skip_common_name, "skip_common_name"),
+        (cfg.use_global_ca_store, "use_global_ca_store"),
+        (cfg.use_secure_element, "use_secure_element"),
+        (cfg.psk_hint_key.is_some(), "psk_hint_key"),
+        (cfg.client_key_password.is_some(), "client_key_password"),
+    ];
+    if let Some((_, option)) = unsupported.iter().find(|(set, _)| *set) {
+        return Err(config_error(&format!(
+            "{option} is not supported with rustls"
+        )));
+    }
+
+    let mut roots = RootCertStore::empty();
+    if let Some(ca_cert) = cfg.ca_cert {
+        for cert in certs(ca_cert)? {
+            roots.add(&cert).map_err(Error::Rustls)?;
+        }
+    }
+    if cfg.use_crt_bundle_attach {
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let mut config = match (cfg.client_cert, cfg.client_key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(certs(cert)?, private_key(key)?)
+            .map_err(Error::Rustls)?,
+        _ => builder.with_no_client_auth(),
+    };
+
+    config.alpn_protocols = cfg
+        .alpn_protos
+        .unwrap_or_default()
+        .iter()
+        .map(|proto| proto.as_bytes().to_vec())
+        .collect();
+
+    Ok(config)
+}
+
+/// The certificates of a PEM bundle, or a single DER certificate.
+fn certs(x509: X509<'_>) -> Result<Vec<RustlsCertificate>> {
+    let data = x509.data();
+    if !is_pem(data) {
+        return Ok(vec![RustlsCertificate(data.to_vec())]);
+    }
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(data))?;
+    if certs.is_empty() {
+        return Err(config_error("no certificate in PEM data"));
+    }
+
+    Ok(certs.into_iter().map(RustlsCertificate).collect())
+}
+
+/// A PKCS #8, PKCS #1 or SEC1 key, PEM or DER encoded.
+fn private_key(x509: X509<'_>) -> Result<PrivateKey> {
+    let data = x509.data();
+    if !is_pem(data) {
+        return Ok(PrivateKey(data.to_vec()));
+    }
+
+    let mut reader = BufReader::new(data);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => (),
+        }
+    }
+
+    Err(config_error("no private key in PEM data"))
+}
+
+fn is_pem(data: &[u8]) -> bool {
+    data.starts_with(b"-----BEGIN")
+}
+
+fn config_error(msg: &str) -> Error {
+    Error::Rustls(rustls::Error::General(msg.to_owned()))
+}
@@ -0,0 +1,174 @@
+//! TLS sessions kept in NVS across deep sleep, so that a node waking up to report resumes the
+//! session with an abbreviated handshake instead of a full one.
+//!
+//! ```ignore
+//! let mut nvs = EspNvs::new(nvs_partition, "tls", true)?;
+//!
+//! let mut connector = TlsConnector::new();
+//! if let Some(session) = TlsSession::load(&nvs, "broker")? {
+//!     connector = connector.with_saved_session(session);
+//! }
+//! let mut tls = connector.connect("mqtt.example.com", 8883, &cfg).await?;
+//! report(&mut tls).await?;
+//!
+//! tls.save_session()?.store(&mut nvs, "broker")?;
+//! enter_deep_sleep();
+//! ```
+//!
+//! Requires `CONFIG_ESP_TLS_CLIENT_SESSION_TICKETS` and a server that issues session tickets.
+//! With TLS 1.3 the ticket arrives after the handshake, so save the session once some data was
+//! read.
+
+use core::ptr;
+
+use esp_idf_svc::nvs::{EspNvs, NvsPartitionId};
+use esp_idf_sys::{self as sys, EspError};
+
+use crate::error::{Error, Result};
+
+/// Blobs larger than this are not stored, as they could not be loaded. A session includes the
+/// server certificate with `CONFIG_MBEDTLS_SSL_KEEP_PEER_CERTIFICATE`.
+const MAX_STORED_LEN: usize = 4096;
+
+/// The resumption state of a TLS session with a server, see the [module docs](self).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsSession {
+    hostname: String,
+    /// As serialized by mbedtls
+    data: Vec<u8>,
+}
+
+impl TlsSession {
+    /// Serialize `session`, established with `hostname`.
+    ///
+    /// # Safety
+    ///
+    /// `session` must point to a valid mbedtls session.
+    pub(crate) unsafe fn from_raw(
+        hostname: &str,
+        session: *const sys::mbedtls_ssl_session,
+    ) -> Result<Self, EspError> {
+        // Fails with `BUFFER_TOO_SMALL` and the required length
+        let mut len = 0;
+        sys::mbedtls_ssl_session_save(session, ptr::null_mut(), 0, &mut len);
+
+        let mut data = vec![0; len];
+        match sys::mbedtls_ssl_session_save(session, data.as_mut_ptr(), len, &mut len) {
+            0 => (),
+            err => return Err(EspError::from(err).unwrap()),
+        }
+        data.truncate(len);
+
+        Ok(Self {
+            hostname: hostname.to_owned(),
+            data,
+        })
+    }
+
+    /// The server the session was established with, it is only resumed with that name.
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// Write the session to `nvs` under `key`.
+    pub fn store<T: NvsPartitionId>(&self, nvs: &mut EspNvs<T>, key: &str) -> Result<()> {
+        let mut blob = Vec::new();
+        self.encode(&mut blob);
+
+        store_blob(nvs, key, &blob)
+    }
+
+    /// Read the session stored under `key`, if any.
+    pub fn load<T: NvsPartitionId>(nvs: &EspNvs<T>, key: &str) -> Result<Option<Self>> {
+        load_blob(nvs, key, |blob| Self::decode(blob))
+    }
+
+    /// Append the session to `blob`, see [`decode`](Self::decode).
+    pub(crate) fn encode(&self, blob: &mut Vec<u8>) {
+        blob.push(self.hostname.len() as u8);
+        blob.extend_from_slice(self.hostname.as_bytes());
+        blob.extend_from_slice(&self.data);
+    }
+
+    /// Read a session written by [`encode`](Self::encode), which takes up the rest of `blob`.
+    pub(crate) fn decode(blob: &[u8]) -> Option<Self> {
+        let (&len, rest) = blob.split_first()?;
+        let (hostname, data) = (len as usize <= rest.len()).then(|| rest.split_at(len as usize))?;
+
+        Some(Self {
+            hostname: String::from_utf8(hostname.to_vec()).ok()?,
+            data: data.to_vec(),
+        })
+    }
+
+    /// The session in the form esp-tls resumes it from.
+    pub(crate) fn to_client_session(&self) -> Result<ClientSession, EspError> {
+        let mut session = ClientSession(Box::new(unsafe { core::mem::zeroed() }));
+        let saved = &mut session.0.saved_session;
+
+        unsafe {
+            sys::mbedtls_ssl_session_init(saved);
+            match sys::mbedtls_ssl_session_load(saved, self.data.as_ptr(), self.data.len()) {
+                0 => Ok(session),
+                err => Err(EspError::from(err).unwrap()),
+            }
+        }
+    }
+}
+
+/// Store `blob` under `key`, or nothing if it is too long to be loaded again. That is not an
+/// error, the next connection just can't resume.
+pub(crate) fn store_blob<T: NvsPartitionId>(
+    nvs: &mut EspNvs<T>,
+    key: &str,
+    blob: &[u8],
+) -> Result<()> {
+    if blob.len() > MAX_STORED_LEN {
+        log::warn!(
+            "not storing {key}, {} bytes are more than the {MAX_STORED_LEN} that can be loaded",
+            blob.len()
+        );
+        // Rather than leave an older one to be resumed
+        nvs.remove(key).map_err(Error::Nvs)?;
+
+        return Ok(());
+    }
+
+    nvs.set_raw(key, blob).map_err(Error::Nvs)?;
+
+    Ok(())
+}
+
+/// Read the blob stored under `key` with `decode`, ignoring it if it can't be decoded.
+pub(crate) fn load_blob<T: NvsPartitionId, R>(
+    nvs: &EspNvs<T>,
+    key: &str,
+    decode: impl FnOnce(&[u8]) -> Option<R>,
+) -> Result<Option<R>> {
+    let mut buf = vec![0; MAX_STORED_LEN];
+    let Some(blob) = nvs.get_raw(key, &mut buf).map_err(Error::Nvs)? else {
+        return Ok(None);
+    };
+
+    let value = decode(blob);
+    if value.is_none() {
+        log::warn!("ignoring the invalid data stored as {key}");
+    }
+
+    Ok(value)
+}
+
+/// A deserialized session for `esp_tls_cfg::client_session`.
+pub(crate) struct ClientSession(Box<sys::esp_tls_client_session_t>);
+
+impl ClientSession {
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut sys::esp_tls_client_session_t {
+        self.0.as_mut()
+    }
+}
+
+impl Drop for ClientSession {
+    fn drop(&mut self) {
+        unsafe { sys::mbedtls_ssl_session_free(&mut self.0.saved_session) };
+    }
+}
@@ -0,0 +1,175 @@
+//! A small HTTPS server reporting the device status as JSON.
+//!
+//! `GET /status` (or `/`) returns e.g.
+//!
+//! ```json
+//! {"uptime_ms":81234,"free_heap":151220,"min_free_heap":120344,"rssi":-61,
+//!  "connections":[{"name":"mqtt","bytes_read":5120,"bytes_written":880,"duration_ms":60211}]}
+//! ```
+
+use std::fmt::Write;
+
+use esp_idf_sys as sys;
+use futures_lite::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    acceptor::TlsAcceptor,
+    error::{Error, Result},
+    mem::MemSnapshot,
+    stream::TlsStream,
+    tcp::AsyncTcpListener,
+    tls::ConnectionStats,
+    util::json_escape,
+};
+
+/// Upper bound for the request line and headers.
+const MAX_REQUEST_HEAD_LEN: usize = 2048;
+
+type StatsFn = dyn Fn() -> Vec<(String, ConnectionStats)> + Send + Sync;
+
+/// Serves the device status over HTTPS, see the [module docs](self).
+pub struct StatusServer {
+    acceptor: TlsAcceptor,
+    port: u16,
+    connection_stats: Option<Box<StatsFn>>,
+}
+
+impl StatusServer {
+    pub fn new(acceptor: TlsAcceptor) -> Self {
+        Self {
+            acceptor,
+            port: 443,
+            connection_stats: None,
+        }
+    }
+
+    /// Listen on `port` instead of 443.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Include the named connection statistics that `stats` returns in the report.
+    pub fn connection_stats<F>(mut self, stats: F) -> Self
+    where
+        F: Fn() -> Vec<(String, ConnectionStats)> + Send + Sync + 'static,
+    {
+        self.connection_stats = Some(Box::new(stats));
+        self
+    }
+
+    /// Serve requests one at a time until the listener fails.
+    pub async fn run(&self) -> Result<()> {
+        let listener = AsyncTcpListener::bind(([0, 0, 0, 0], self.port))?;
+        log::info!("status server listening on port {}", self.port);
+
+        loop {
+            let (tcp, peer) = listener.accept().await?;
+
+            // A misbehaving client must not take the server down
+            if let Err(e) = self.serve(tcp).await {
+                log::warn!("status request from {peer} failed: {e}");
+            }
+        }
+    }
+
+    async fn serve<T>(&self, transport: T) -> Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut tls = self.acceptor.accept(transport).await?;
+
+        let head = read_request_head(&mut tls).await?;
+        let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+        let (method, path) = (request_line.next(), request_line.next());
+
+        let (status, body) = match (method, path) {
+            (Some("GET"), Some("/" | "/status")) => ("200 OK", self.status_json()),
+            (Some("GET"), _) => ("404 Not Found", r#"{"error":"not found"}"#.to_owned()),
+            _ => (
+                "405 Method Not Allowed",
+                r#"{"error":"method not allowed"}"#.to_owned(),
+            ),
+        };
+
+        let response = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{body}",
+            body.len()
+        );
+        tls.write_all(response.as_bytes()).await?;
+        tls.close().await?;
+
+        Ok(())
+    }
+
+    fn status_json(&self) -> String {
+        let mem = MemSnapshot::take();
+        let uptime_ms = unsafe { sys::esp_timer_get_time() } / 1000;
+
+        let mut json = format!(
+            r#"{{"uptime_ms":{uptime_ms},"free_heap":{},"min_free_heap":{},"rssi":"#,
+            mem.free_heap, mem.min_free_heap
+        );
+        match rssi() {
+            Some(rssi) => write!(json, "{rssi}").unwrap(),
+            None => json.push_str("null"),
+        }
+
+        json.push_str(r#","connections":["#);
+        let connections = self.connection_stats.as_ref().map(|stats| stats());
+        for (i, (name, stats)) in connections.iter().flatten().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+
+            write!(
+                json,
+                r#"{{"name":"{}","bytes_read":{},"bytes_written":{},"duration_ms":{}}}"#,
+                json_escape(name),
+                stats.bytes_read,
+                stats.bytes_written,
+                stats.duration.as_millis()
+            )
+            .unwrap();
+        }
+        json.push_str("]}");
+
+        json
+    }
+}
+
+async fn read_request_head<T>(tls: &mut TlsStream<T>) -> Result<String>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = Vec::new();
+    let mut buf = [0; 256];
+
+    // Any request body is ignored, so reading past the end of the head does no harm
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD_LEN {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "request head too long",
+            )));
+        }
+
+        match tls.read(&mut buf).await? {
+            0 => break,
+            read => head.extend_from_slice(&buf[..read]),
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// The signal strength of the access point we are connected to, if any.
+fn rssi() -> Option<i8> {
+    let mut info: sys::wifi_ap_record_t = Default::default();
+
+    match unsafe { sys::esp_wifi_sta_get_ap_info(&mut info) } {
+        sys::ESP_OK => Some(info.rssi),
+        _ => None,
+    }
+}
@@ -0,0 +1,789 @@
+use core::{
+    ffi::{c_char, c_int, c_uchar, c_void},
+    mem::MaybeUninit,
+    ptr, slice,
+};
+use std::{
+    ffi::CString,
+    future::poll_fn,
+    io::{self, IoSlice},
+    pin::Pin,
+    str,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use esp_idf_svc::tls::Config;
+use esp_idf_sys::{self as sys, EspError, ESP_ERR_INVALID_ARG, ESP_FAIL};
+use futures_lite::{AsyncRead, AsyncWrite};
+
+#[cfg(feature = "debug-keylog")]
+use crate::keylog::{KeyLogFn, KeyLogHook};
+#[cfg(feature = "debug-tap")]
+use crate::tap::{self, Tap};
+use crate::{
+    acceptor::ServerName,
+    cert::Certificate,
+    conf::ConfFn,
+    error::{Error, Result},
+    limit::ConnectionPermit,
+    tls::{write_limit, MBEDTLS_ERR_NET_RECV_FAILED, MBEDTLS_ERR_NET_SEND_FAILED},
+    util::coalesce,
+    verify::{VerifyFn, VerifyHook},
+    watchdog::HandshakeWatchdog,
+};
+
+/// A TLS session over any [`AsyncRead`] + [`AsyncWrite`] transport, e.g. an in-memory pipe or a
+/// PPP serial stream.
+///
+/// Unlike [`AsyncTls`](crate::AsyncTls), which needs a socket file descriptor for esp-tls, this
+/// drives mbedtls directly and moves the records through the transport from the mbedtls I/O
+/// callbacks.
+///
+/// Reads and writes are cancellation safe, see [`AsyncTls`](crate::AsyncTls#cancellation).
+pub struct TlsStream<T> {
+    session: Box<Session>,
+    bio: Box<Bio<T>>,
+    verify: Option<Box<VerifyHook>>,
+    #[cfg(feature = "debug-keylog")]
+    keylog: Option<Box<KeyLogHook>>,
+    #[cfg(feature = "debug-tap")]
+    tap: Option<Tap>,
+    tweaks: Vec<Box<ConfFn>>,
+    /// See [`add_trust`](Self::add_trust)
+    extra_cas: Vec<Arc<[u8]>>,
+    crt_bundle: bool,
+    watchdog: Option<HandshakeWatchdog>,
+    /// The payload of a record that mbedtls still has to send.
+    pending_write: Vec<u8>,
+    /// See [`set_write_chunk_size`](Self::set_write_chunk_size)
+    write_chunk: Option<usize>,
+    /// Counts the session against the [`ConnectionLimit`](crate::limit::ConnectionLimit)
+    permit: Option<ConnectionPermit>,
+}
+
+/// The mbedtls state of a [`TlsStream`]. Boxed, as mbedtls keeps pointers between its parts.
+struct Session {
+    ctx: Contexts,
+    /// Certificates to select from by SNI, see [`sni_callback`].
+    server_names: Arc<[ServerName]>,
+    alpn_protos: Vec<CString>,
+    alpn_ptrs: Vec<*const c_char>,
+}
+
+/// The mbedtls contexts of a [`Session`], plain C data that may be zeroed before their `init`
+/// functions run.
+#[repr(C)]
+struct Contexts {
+    ssl: sys::mbedtls_ssl_context,
+    conf: sys::mbedtls_ssl_config,
+    entropy: sys::mbedtls_entropy_context,
+    drbg: sys::mbedtls_ctr_drbg_context,
+    ca_chain: sys::mbedtls_x509_crt,
+    own_cert: sys::mbedtls_x509_crt,
+    own_key: sys::mbedtls_pk_context,
+    /// The certificate and key selected by SNI, if any.
+    sni_cert: sys::mbedtls_x509_crt,
+    sni_key: sys::mbedtls_pk_context,
+}
+
+impl Session {
+    /// Parse the certificate chain and private key to authenticate with.
+    unsafe fn load_own_cert(
+        &mut self,
+        cert: &[u8],
+        key: &[u8],
+        password: &str,
+    ) -> Result<(), EspError> {
+        parse_certs(&mut self.ctx.own_cert, cert)?;
+        self.parse_key(&mut self.ctx.own_key, key, password)?;
+
+        mbedtls_check(sys::mbedtls_ssl_conf_own_cert(
+            &mut self.ctx.conf,
+            &mut self.ctx.own_cert,
+            &mut self.ctx.own_key,
+        ))
+    }
+
+    /// Parse the certificate chain and private key for the name a client asked for, and use
+    /// them for the handshake on `ssl` instead of the own certificate.
+    unsafe fn load_sni_cert(
+        &mut self,
+        ssl: *mut sys::mbedtls_ssl_context,
+        cert: &[u8],
+        key: &[u8],
+    ) -> Result<(), EspError> {
+        parse_certs(&mut self.ctx.sni_cert, cert)?;
+        self.parse_key(&mut self.ctx.sni_key, key, "")?;
+
+        mbedtls_check(sys::mbedtls_ssl_set_hs_own_cert(
+            ssl,
+            &mut self.ctx.sni_cert,
+            &mut self.ctx.sni_key,
+        ))
+    }
+
+    unsafe fn parse_key(
+        &mut self,
+        pk: *mut sys::mbedtls_pk_context,
+        key: &[u8],
+        password: &str,
+    ) -> Result<(), EspError> {
+        mbedtls_check(sys::mbedtls_pk_parse_key(
+            pk,
+            key.as_ptr(),
+            key.len(),
+            password.as_ptr(),
+            password.len(),
+            Some(sys::mbedtls_ctr_drbg_random),
+            &mut self.ctx.drbg as *mut _ as *mut c_void,
+        ))
+    }
+}
+
+/// What the mbedtls I/O callbacks operate on.
+struct Bio<T> {
+    transport: T,
+    /// The context of the poll currently calling into mbedtls, see [`TlsStream::with_context`].
+    cx: *mut c_void,
+    /// The last transport error, reported instead of the generic mbedtls error code.
+    error: Option<io::Error>,
+}
+
+impl<T> TlsStream<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Wrap the supplied transport, which should be connected to the peer.
+    pub fn new(transport: T) -> Result<Self> {
+        let mut session = Box::new(Session {
+            ctx: unsafe { MaybeUninit::zeroed().assume_init() },
+            server_names: Arc::new([]),
+            alpn_protos: Vec::new(),
+            alpn_ptrs: Vec::new(),
+        });
+
+        unsafe {
+            sys::mbedtls_ssl_init(&mut session.ctx.ssl);
+            sys::mbedtls_ssl_config_init(&mut session.ctx.conf);
+            sys::mbedtls_entropy_init(&mut session.ctx.entropy);
+            sys::mbedtls_ctr_drbg_init(&mut session.ctx.drbg);
+            sys::mbedtls_x509_crt_init(&mut session.ctx.ca_chain);
+            sys::mbedtls_x509_crt_init(&mut session.ctx.own_cert);
+            sys::mbedtls_pk_init(&mut session.ctx.own_key);
+            sys::mbedtls_x509_crt_init(&mut session.ctx.sni_cert);
+            sys::mbedtls_pk_init(&mut session.ctx.sni_key);
+        }
+
+        // Construct first so that everything is freed if seeding fails
+        let mut stream = Self {
+            session,
+            bio: Box::new(Bio {
+                transport,
+                cx: ptr::null_mut(),
+                error: None,
+            }),
+            verify: None,
+            #[cfg(feature = "debug-keylog")]
+            keylog: None,
+            #[cfg(feature = "debug-tap")]
+            tap: None,
+            tweaks: Vec::new(),
+            extra_cas: Vec::new(),
+            crt_bundle: false,
+            watchdog: None,
+            pending_write: Vec::new(),
+            write_chunk: None,
+            permit: None,
+        };
+
+        let session = stream.session.as_mut();
+        mbedtls_check(unsafe {
+            sys::mbedtls_ctr_drbg_seed(
+                &mut session.ctx.drbg,
+                Some(sys::mbedtls_entropy_func),
+                &mut session.ctx.entropy as *mut _ as *mut c_void,
+                ptr::null(),
+                0,
+            )
+        })
+        .map_err(Error::TlsSetup)?;
+
+        Ok(stream)
+    }
+
+    /// Run `callback` on the server's certificate chain during [`negotiate`](Self::negotiate),
+    /// after the validation set up from the [`Config`]. See [`VerifyFn`].
+    pub fn set_verify_callback(&mut self, callback: Arc<VerifyFn>) {
+        self.verify = Some(VerifyHook::new(callback));
+    }
+
+    /// Hand the secrets of the session to `callback` during the handshake, see
+    /// [`keylog`](crate::keylog).
+    #[cfg(feature = "debug-keylog")]
+    pub fn set_keylog_callback(&mut self, callback: Arc<KeyLogFn>) {
+        self.keylog = Some(KeyLogHook::new(callback));
+    }
+
+    /// Mirror the decrypted data read and written from now on to `tap`, see [`tap`](crate::tap).
+    #[cfg(feature = "debug-tap")]
+    pub fn set_tap(&mut self, tap: Tap) {
+        log::warn!("!!! TLS traffic is tapped, the connection is NOT confidential !!!");
+        self.tap = Some(tap);
+    }
+
+    /// Keep the task watchdog from firing during the handshake by yielding to the idle task
+    /// between the handshake steps, see
+    /// [`AsyncTls::set_handshake_watchdog`](crate::AsyncTls::set_handshake_watchdog).
+    pub fn set_handshake_watchdog(&mut self, enabled: bool) {
+        self.watchdog = enabled.then(HandshakeWatchdog::new);
+    }
+
+    /// Encrypt writes in records of at most `size` bytes, see
+    /// [`AsyncTls::set_write_chunk_size`](crate::AsyncTls::set_write_chunk_size).
+    pub fn set_write_chunk_size(&mut self, size: Option<usize>) {
+        self.write_chunk = size.map(|size| size.max(1));
+    }
+
+    /// Trust the PEM (nul terminated) or DER certificates `cas`, and the ESP x509 certificate
+    /// bundle with `crt_bundle`, in addition to the verification set up from the [`Config`].
+    pub(crate) fn add_trust(&mut self, cas: &[Arc<[u8]>], crt_bundle: bool) {
+        self.extra_cas.extend_from_slice(cas);
+        self.crt_bundle |= crt_bundle;
+    }
+
+    /// Adjust the mbedtls configuration after it was set up from the [`Config`].
+    pub(crate) fn add_conf_tweak(&mut self, tweak: Box<ConfFn>) {
+        self.tweaks.push(tweak);
+    }
+
+    pub(crate) fn set_permit(&mut self, permit: Option<ConnectionPermit>) {
+        self.permit = permit;
+    }
+
+    /// Perform the TLS handshake over the transport.
+    ///
+    /// Of the [`Config`] only the server verification options, the client certificate, ALPN and
+    /// the common name are taken into account, the rest concerns the socket handling of esp-tls.
+    pub async fn negotiate(&mut self, hostname: &str, cfg: &Config<'_>) -> Result<()> {
+        self.setup_client(hostname, cfg).map_err(Error::TlsSetup)?;
+
+        self.handshake().await
+    }
+
+    /// Perform the server side of the TLS handshake, presenting `cert` (the chain, leaf first)
+    /// and authenticating with `key`, or the certificate of the matching `server_names` entry
+    /// for the name the client asked for. All are PEM (nul terminated) or DER encoded.
+    pub(crate) async fn accept(
+        &mut self,
+        cert: &[u8],
+        key: &[u8],
+        server_names: Arc<[ServerName]>,
+    ) -> Result<()> {
+        self.setup_server(cert, key, server_names)
+            .map_err(Error::TlsSetup)?;
+
+        self.handshake().await
+    }
+
+    async fn handshake(&mut self) -> Result<()> {
+        poll_fn(|cx| {
+            let ret = self.with_context(cx, |ssl| unsafe { sys::mbedtls_ssl_handshake(ssl) });
+
+            if let Some(watchdog) = &mut self.watchdog {
+                watchdog.step();
+            }
+
+            match ret {
+                0 => Poll::Ready(Ok(())),
+                sys::MBEDTLS_ERR_SSL_WANT_READ | sys::MBEDTLS_ERR_SSL_WANT_WRITE => Poll::Pending,
+                _ => Poll::Ready(Err(match self.bio.error.take() {
+                    Some(e) => Error::Io(e),
+                    None => {
+                        log::error!("TLS handshake failed: -0x{:04x}", -ret);
+                        Error::TlsHandshake(EspError::from(ret).unwrap())
+                    }
+                })),
+            }
+        })
+        .await
+    }
+
+    /// The certificate the server presented during the handshake, if any.
+    pub fn peer_certificate(&self) -> Option<Certificate> {
+        self.peer_certificate_chain().into_iter().next()
+    }
+
+    /// The full chain the server presented during the handshake, leaf first.
+    ///
+    /// Requires `CONFIG_MBEDTLS_SSL_KEEP_PEER_CERTIFICATE` (enabled by default).
+    pub fn peer_certificate_chain(&self) -> Vec<Certificate> {
+        unsafe {
+            Certificate::chain_from_raw(sys::mbedtls_ssl_get_peer_cert(&self.session.ctx.ssl))
+        }
+    }
+
+    /// The underlying transport.
+    pub fn get_ref(&self) -> &T {
+        &self.bio.transport
+    }
+
+    fn setup_client(&mut self, hostname: &str, cfg: &Config<'_>) -> Result<(), EspError> {
+        let conf = self.init_conf(sys::MBEDTLS_SSL_IS_CLIENT)?;
+        let session = self.session.as_mut();
+
+        unsafe {
+            sys::mbedtls_ssl_conf_authmode(conf, sys::MBEDTLS_SSL_VERIFY_REQUIRED as c_int);
+
+            let extra = !self.extra_cas.is_empty() || self.crt_bundle;
+
+            // Same order of precedence as in esp-tls. Without any of them the handshake fails,
+            // unless a tweak relaxes the authentication mode.
+            if let Some(attach) = crt_bundle_attach(cfg) {
+                sys::esp!(attach(conf as *mut c_void))?;
+            } else if cfg.use_global_ca_store && extra {
+                log::error!(
+                    "additional CA certificates can't be combined with the global CA store"
+                );
+                return Err(EspError::from_infallible::<ESP_FAIL>());
+            } else if cfg.use_global_ca_store {
+                let ca_chain = sys::esp_tls_get_global_ca_store();
+                if ca_chain.is_null() {
+                    log::error!("global CA store is not initialized");
+                    return Err(EspError::from_infallible::<ESP_FAIL>());
+                }
+
+                sys::mbedtls_ssl_conf_ca_chain(conf, ca_chain, ptr::null_mut());
+            } else if let Some(ca_cert) = cfg.ca_cert {
+                parse_certs(&mut session.ctx.ca_chain, ca_cert.data())?;
+                sys::mbedtls_ssl_conf_ca_chain(conf, &mut session.ctx.ca_chain, ptr::null_mut());
+            } else if let Some(psk) = &cfg.psk_hint_key {
+                mbedtls_check(sys::mbedtls_ssl_conf_psk(
+                    conf,
+                    psk.key.as_ptr(),
+                    psk.key.len(),
+                    psk.hint.as_ptr() as *const u8,
+                    psk.hint.to_bytes().len(),
+                ))?;
+            }
+
+            // The bundle verifies what the CA chain doesn't, so the chain is set up after it,
+            // replacing the placeholder chain of the bundle
+            #[cfg(esp_idf_mbedtls_certificate_bundle)]
+            if self.crt_bundle && crt_bundle_attach(cfg).is_none() {
+                sys::esp!(sys::esp_crt_bundle_attach(conf as *mut c_void))?;
+            }
+            #[cfg(not(esp_idf_mbedtls_certificate_bundle))]
+            if self.crt_bundle {
+                log::error!("the certificate bundle requires CONFIG_MBEDTLS_CERTIFICATE_BUNDLE");
+                return Err(EspError::from_infallible::<ESP_FAIL>());
+            }
+            if !self.extra_cas.is_empty() {
+                for ca in &self.extra_cas {
+                    parse_certs(&mut session.ctx.ca_chain, ca)?;
+                }
+                sys::mbedtls_ssl_conf_ca_chain(conf, &mut session.ctx.ca_chain, ptr::null_mut());
+            }
+
+            if let (Some(client_cert), Some(client_key)) = (cfg.client_cert, cfg.client_key) {
+                session.load_own_cert(
+                    client_cert.data(),
+                    client_key.data(),
+                    cfg.client_key_password.unwrap_or_default(),
+                )?;
+            }
+
+            session.alpn_protos = cfg
+                .alpn_protos
+                .unwrap_or_default()
+                .iter()
+                .map(|p| {
+                    CString::new(*p).map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if !session.alpn_protos.is_empty() {
+                session.alpn_ptrs = session.alpn_protos.iter().map(|p| p.as_ptr()).collect();
+                session.alpn_ptrs.push(ptr::null());
+                mbedtls_check(sys::mbedtls_ssl_conf_alpn_protocols(
+                    conf,
+                    session.alpn_ptrs.as_mut_ptr(),
+                ))?;
+            }
+        }
+
+        self.finish_setup()?;
+
+        if !cfg.skip_common_name {
+            let common_name = CString::new(cfg.common_name.unwrap_or(hostname))
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+            mbedtls_check(unsafe {
+                sys::mbedtls_ssl_set_hostname(&mut self.session.ctx.ssl, common_name.as_ptr())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn setup_server(
+        &mut self,
+        cert: &[u8],
+        key: &[u8],
+        server_names: Arc<[ServerName]>,
+    ) -> Result<(), EspError> {
+        self.init_conf(sys::MBEDTLS_SSL_IS_SERVER)?;
+
+        let session = self.session.as_mut();
+        unsafe {
+            // No client authentication
+            sys::mbedtls_ssl_conf_authmode(
+                &mut session.ctx.conf,
+                sys::MBEDTLS_SSL_VERIFY_NONE as c_int,
+            );
+            session.load_own_cert(cert, key, "")?;
+
+            if !server_names.is_empty() {
+                session.server_names = server_names;
+                sys::mbedtls_ssl_conf_sni(
+                    &mut session.ctx.conf,
+                    Some(sni_callback),
+                    session as *mut Session as *mut c_void,
+                );
+            }
+        }
+
+        self.finish_setup()
+    }
+
+    /// Apply the defaults for `endpoint` (client or server) to the configuration.
+    fn init_conf(&mut self, endpoint: u32) -> Result<*mut sys::mbedtls_ssl_config, EspError> {
+        let session = self.session.as_mut();
+        let conf = &mut session.ctx.conf as *mut sys::mbedtls_ssl_config;
+
+        unsafe {
+            mbedtls_check(sys::mbedtls_ssl_config_defaults(
+                conf,
+                endpoint as c_int,
+                sys::MBEDTLS_SSL_TRANSPORT_STREAM as c_int,
+                sys::MBEDTLS_SSL_PRESET_DEFAULT as c_int,
+            ))?;
+            sys::mbedtls_ssl_conf_rng(
+                conf,
+                Some(sys::mbedtls_ctr_drbg_random),
+                &mut session.ctx.drbg as *mut _ as *mut c_void,
+            );
+        }
+
+        Ok(conf)
+    }
+
+    /// Apply the tweaks and set up the session from the configuration.
+    fn finish_setup(&mut self) -> Result<(), EspError> {
+        let session = self.session.as_mut();
+
+        unsafe {
+            for tweak in &self.tweaks {
+                tweak(&mut session.ctx.conf)?;
+            }
+
+            mbedtls_check(sys::mbedtls_ssl_setup(
+                &mut session.ctx.ssl,
+                &session.ctx.conf,
+            ))?;
+
+            sys::mbedtls_ssl_set_bio(
+                &mut session.ctx.ssl,
+                self.bio.as_mut() as *mut Bio<T> as *mut c_void,
+                Some(bio_send::<T>),
+                Some(bio_recv::<T>),
+                None,
+            );
+
+            if let Some(verify) = &mut self.verify {
+                verify.install(&mut session.ctx.ssl);
+            }
+
+            #[cfg(feature = "debug-keylog")]
+            if let Some(keylog) = &mut self.keylog {
+                keylog.install(&mut session.ctx.ssl);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run `f` on the mbedtls session, with the I/O callbacks polling the transport in `cx`.
+    fn with_context<R>(
+        &mut self,
+        cx: &mut Context<'_>,
+        f: impl FnOnce(*mut sys::mbedtls_ssl_context) -> R,
+    ) -> R {
+        self.bio.cx = cx as *mut Context<'_> as *mut c_void;
+        let res = f(&mut self.session.ctx.ssl);
+        self.bio.cx = ptr::null_mut();
+
+        res
+    }
+
+    /// Send the record left over from a previous write, if any.
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.pending_write.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+
+        let pending = core::mem::take(&mut self.pending_write);
+        let ret = self.with_context(cx, |ssl| unsafe {
+            sys::mbedtls_ssl_write(ssl, pending.as_ptr(), pending.len())
+        });
+
+        match ret {
+            0.. => Poll::Ready(Ok(())),
+            sys::MBEDTLS_ERR_SSL_WANT_READ | sys::MBEDTLS_ERR_SSL_WANT_WRITE => {
+                self.pending_write = pending;
+                Poll::Pending
+            }
+            _ => Poll::Ready(Err(self.io_error(ret))),
+        }
+    }
+
+    fn io_error(&mut self, ret: c_int) -> io::Error {
+        self.bio.error.take().unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("mbedtls error -0x{:04x}", -ret),
+            )
+        })
+    }
+}
+
+impl<T> Drop for TlsStream<T> {
+    fn drop(&mut self) {
+        let session = self.session.as_mut();
+
+        unsafe {
+            sys::mbedtls_ssl_free(&mut session.ctx.ssl);
+            sys::mbedtls_ssl_config_free(&mut session.ctx.conf);
+            sys::mbedtls_x509_crt_free(&mut session.ctx.ca_chain);
+            sys::mbedtls_x509_crt_free(&mut session.ctx.own_cert);
+            sys::mbedtls_pk_free(&mut session.ctx.own_key);
+            sys::mbedtls_x509_crt_free(&mut session.ctx.sni_cert);
+            sys::mbedtls_pk_free(&mut session.ctx.sni_key);
+            sys::mbedtls_ctr_drbg_free(&mut session.ctx.drbg);
+            sys::mbedtls_entropy_free(&mut session.ctx.entropy);
+        }
+    }
+}
+
+impl<T> AsyncRead for TlsStream<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        loop {
+            let ret = self.with_context(cx, |ssl| unsafe {
+                sys::mbedtls_ssl_read(ssl, buf.as_mut_ptr(), buf.len())
+            });
+
+            // TLS 1.3 servers may send tickets at any time, just keep reading
+            if ret == sys::MBEDTLS_ERR_SSL_RECEIVED_NEW_SESSION_TICKET {
+                continue;
+            }
+
+            return match ret {
+                0.. => {
+                    #[cfg(feature = "debug-tap")]
+                    if let Some(tap) = &self.tap {
+                        tap.record(tap::Direction::Read, &buf[..ret as usize]);
+                    }
+
+                    Poll::Ready(Ok(ret as usize))
+                }
+                sys::MBEDTLS_ERR_SSL_PEER_CLOSE_NOTIFY | sys::MBEDTLS_ERR_SSL_CONN_EOF => {
+                    Poll::Ready(Ok(0))
+                }
+                sys::MBEDTLS_ERR_SSL_WANT_READ | sys::MBEDTLS_ERR_SSL_WANT_WRITE => Poll::Pending,
+                _ => Poll::Ready(Err(self.io_error(ret))),
+            };
+        }
+    }
+}
+
+impl<T> AsyncWrite for TlsStream<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_write_pending(cx))?;
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        // Stick to a single record, so that a `WANT_WRITE` below always refers to all of `buf`
+        let buf = &buf[..buf
+            .len()
+            .min(write_limit(&self.session.ctx.ssl, self.write_chunk))];
+
+        let ret = self.with_context(cx, |ssl| unsafe {
+            sys::mbedtls_ssl_write(ssl, buf.as_ptr(), buf.len())
+        });
+
+        let written = match ret {
+            0.. => ret as usize,
+            // The record is queued in mbedtls already, see `AsyncTls::poll_write_raw`
+            sys::MBEDTLS_ERR_SSL_WANT_WRITE => {
+                self.pending_write.extend_from_slice(buf);
+                buf.len()
+            }
+            sys::MBEDTLS_ERR_SSL_WANT_READ => return Poll::Pending,
+            _ => return Poll::Ready(Err(self.io_error(ret))),
+        };
+
+        #[cfg(feature = "debug-tap")]
+        if let Some(tap) = &self.tap {
+            tap.record(tap::Direction::Write, &buf[..written]);
+        }
+
+        Poll::Ready(Ok(written))
+    }
+
+    /// Coalesces the slices into a single TLS record, rather than sending one record per slice.
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let record = coalesce(bufs, write_limit(&self.session.ctx.ssl, self.write_chunk));
+
+        self.poll_write(cx, &record)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_pending(cx))?;
+
+        Pin::new(&mut self.bio.transport).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_pending(cx))?;
+
+        let ret = self.with_context(cx, |ssl| unsafe { sys::mbedtls_ssl_close_notify(ssl) });
+
+        match ret {
+            0 => Pin::new(&mut self.bio.transport).poll_close(cx),
+            sys::MBEDTLS_ERR_SSL_WANT_READ | sys::MBEDTLS_ERR_SSL_WANT_WRITE => Poll::Pending,
+            _ => Poll::Ready(Err(self.io_error(ret))),
+        }
+    }
+}
+
+unsafe extern "C" fn bio_send<T: AsyncWrite + Unpin>(
+    ctx: *mut c_void,
+    buf: *const c_uchar,
+    len: usize,
+) -> c_int {
+    let bio = &mut *(ctx as *mut Bio<T>);
+    let cx = &mut *(bio.cx as *mut Context<'_>);
+
+    match Pin::new(&mut bio.transport).poll_write(cx, slice::from_raw_parts(buf, len)) {
+        Poll::Ready(Ok(written)) => written as c_int,
+        Poll::Ready(Err(e)) => {
+            bio.error = Some(e);
+            MBEDTLS_ERR_NET_SEND_FAILED
+        }
+        Poll::Pending => sys::MBEDTLS_ERR_SSL_WANT_WRITE,
+    }
+}
+
+unsafe extern "C" fn bio_recv<T: AsyncRead + Unpin>(
+    ctx: *mut c_void,
+    buf: *mut c_uchar,
+    len: usize,
+) -> c_int {
+    let bio = &mut *(ctx as *mut Bio<T>);
+    let cx = &mut *(bio.cx as *mut Context<'_>);
+
+    match Pin::new(&mut bio.transport).poll_read(cx, slice::from_raw_parts_mut(buf, len)) {
+        Poll::Ready(Ok(read)) => read as c_int,
+        Poll::Ready(Err(e)) => {
+            bio.error = Some(e);
+            MBEDTLS_ERR_NET_RECV_FAILED
+        }
+        Poll::Pending => sys::MBEDTLS_ERR_SSL_WANT_READ,
+    }
+}
+
+/// Called by mbedtls with the name a client asked for, picks the certificate of the matching
+/// [`ServerName`]. Without a match the handshake goes on with the own certificate.
+unsafe extern "C" fn sni_callback(
+    ctx: *mut c_void,
+    ssl: *mut sys::mbedtls_ssl_context,
+    name: *const c_uchar,
+    len: usize,
+) -> c_int {
+    let session = &mut *(ctx as *mut Session);
+
+    let Ok(name) = str::from_utf8(slice::from_raw_parts(name, len)) else {
+        return 0;
+    };
+    let Some(server_name) = session
+        .server_names
+        .iter()
+        .find(|server_name| server_name.matches(name))
+        .cloned()
+    else {
+        log::debug!("no certificate for {name}, presenting the default one");
+        return 0;
+    };
+
+    match session.load_sni_cert(ssl, &server_name.cert, &server_name.key) {
+        Ok(()) => 0,
+        Err(e) => {
+            log::error!("failed to load the certificate for {name}: {e}");
+            e.code()
+        }
+    }
+}
+
+fn crt_bundle_attach(
+    cfg: &Config<'_>,
+) -> Option<unsafe extern "C" fn(*mut c_void) -> sys::esp_err_t> {
+    #[cfg(esp_idf_mbedtls_certificate_bundle)]
+    if cfg.use_crt_bundle_attach {
+        return Some(sys::esp_crt_bundle_attach);
+    }
+
+    None
+}
+
+/// Parse PEM (nul terminated) or DER certificates into `chain`.
+unsafe fn parse_certs(chain: *mut sys::mbedtls_x509_crt, data: &[u8]) -> Result<(), EspError> {
+    let ret = sys::mbedtls_x509_crt_parse(chain, data.as_ptr(), data.len());
+    if ret < 0 {
+        log::error!("failed to parse certificate: -0x{:04x}", -ret);
+        return Err(EspError::from_infallible::<ESP_FAIL>());
+    }
+
+    Ok(())
+}
+
+fn mbedtls_check(ret: c_int) -> Result<(), EspError> {
+    match EspError::from(ret) {
+        Some(e) => {
+            log::error!("mbedtls call failed: -0x{:04x}", -ret);
+            Err(e)
+        }
+        None => Ok(()),
+    }
+}
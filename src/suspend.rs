@@ -0,0 +1,152 @@
+//! Closing a connection before deep sleep and re-establishing it quickly on wake: the address
+//! skips name resolution, the access point skips the WiFi scan and the TLS session an abbreviated
+//! handshake instead of a full one.
+//!
+//! ```ignore
+//! let suspended = tls.suspend().await?;
+//! suspended.store(&mut nvs, "broker")?;
+//! enter_deep_sleep();
+//!
+//! // After waking up
+//! let suspended = Suspended::load(&nvs, "broker")?;
+//! let hint = suspended.as_ref().and_then(Suspended::ap_hint);
+//! let _wifi = wifi::connect_with_hint(modem, sysloop, ssid, pass, hint)?;
+//! let mut tls = match &suspended {
+//!     Some(suspended) => connector.resume(suspended, &cfg).await?,
+//!     None => connector.connect("mqtt.example.com", 8883, &cfg).await?,
+//! };
+//! ```
+//!
+//! Requires `CONFIG_ESP_TLS_CLIENT_SESSION_TICKETS`, see [`session`](crate::session).
+
+use std::net::{IpAddr, SocketAddr};
+
+use esp_idf_svc::nvs::{EspNvs, NvsPartitionId};
+
+use crate::{
+    error::Result,
+    session::{self, TlsSession},
+    wifi::ApHint,
+};
+
+/// What is needed to resume a connection closed by [`AsyncTls::suspend`](crate::AsyncTls::suspend).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Suspended {
+    hostname: String,
+    addr: SocketAddr,
+    session: Option<TlsSession>,
+    ap_hint: Option<ApHint>,
+}
+
+impl Suspended {
+    /// Records the access point the station is connected to, if any.
+    pub(crate) fn new(hostname: &str, addr: SocketAddr, session: Option<TlsSession>) -> Self {
+        Self {
+            hostname: hostname.to_owned(),
+            addr,
+            session,
+            ap_hint: ApHint::current().ok(),
+        }
+    }
+
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// The address the connection was established with.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The TLS session, unless the server issued no ticket.
+    pub fn session(&self) -> Option<&TlsSession> {
+        self.session.as_ref()
+    }
+
+    /// The access point to reconnect to, unless the connection wasn't over WiFi.
+    pub fn ap_hint(&self) -> Option<ApHint> {
+        self.ap_hint
+    }
+
+    /// Write the state to `nvs` under `key`.
+    pub fn store<T: NvsPartitionId>(&self, nvs: &mut EspNvs<T>, key: &str) -> Result<()> {
+        let mut blob = Vec::new();
+        blob.push(self.hostname.len() as u8);
+        blob.extend_from_slice(self.hostname.as_bytes());
+        match self.addr.ip() {
+            IpAddr::V4(ip) => {
+                blob.push(4);
+                blob.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                blob.push(6);
+                blob.extend_from_slice(&ip.octets());
+            }
+        }
+        blob.extend_from_slice(&self.addr.port().to_be_bytes());
+        match self.ap_hint {
+            Some(hint) => {
+                blob.push(1);
+                blob.extend_from_slice(&hint.bssid);
+                blob.push(hint.channel);
+            }
+            None => blob.push(0),
+        }
+        if let Some(session) = &self.session {
+            session.encode(&mut blob);
+        }
+
+        session::store_blob(nvs, key, &blob)
+    }
+
+    /// Read the state stored under `key`, if any.
+    pub fn load<T: NvsPartitionId>(nvs: &EspNvs<T>, key: &str) -> Result<Option<Self>> {
+        session::load_blob(nvs, key, Self::decode)
+    }
+
+    fn decode(blob: &[u8]) -> Option<Self> {
+        let mut reader = Reader(blob);
+
+        let len = reader.take(1)?[0] as usize;
+        let hostname = String::from_utf8(reader.take(len)?.to_vec()).ok()?;
+        let ip = match reader.take(1)?[0] {
+            4 => IpAddr::from(<[u8; 4]>::try_from(reader.take(4)?).ok()?),
+            6 => IpAddr::from(<[u8; 16]>::try_from(reader.take(16)?).ok()?),
+            _ => return None,
+        };
+        let port = u16::from_be_bytes(reader.take(2)?.try_into().ok()?);
+        let ap_hint = match reader.take(1)?[0] {
+            0 => None,
+            _ => Some(ApHint {
+                bssid: reader.take(6)?.try_into().ok()?,
+                channel: reader.take(1)?[0],
+            }),
+        };
+        let session = match reader.0 {
+            [] => None,
+            rest => Some(TlsSession::decode(rest)?),
+        };
+
+        Some(Self {
+            hostname,
+            addr: SocketAddr::new(ip, port),
+            session,
+            ap_hint,
+        })
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+
+        Some(taken)
+    }
+}
@@ -0,0 +1,182 @@
+//! The decrypted data of TLS connections, mirrored to a callback or a ring buffer, to inspect
+//! protocol bugs without capturing the traffic and decrypting it with a [key log].
+//!
+//! ```ignore
+//! let (tap, buffer) = Tap::ring_buffer(4096);
+//! let tap = tap.redact_after(b"Authorization: ");
+//! let connector = TlsConnector::new().tap(tap);
+//!
+//! // After the failing exchange
+//! for (direction, data) in buffer.take() {
+//!     log::info!("{direction:?}: {}", String::from_utf8_lossy(&data));
+//! }
+//! ```
+//!
+//! The tap sees what the application reads and writes, in the chunks it reads and writes them.
+//! Redaction works on one chunk at a time, so a secret split over two writes is only partly
+//! masked. Anyone with the mirrored data can read the traffic, so this is only built with the
+//! `debug-tap` feature and must never be enabled in production firmware.
+//!
+//! [key log]: crate::keylog
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+/// Whether the application read or wrote the data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Read,
+    Write,
+}
+
+/// Receives the data after redaction.
+pub type TapFn = dyn Fn(Direction, &[u8]) + Send + Sync;
+
+/// Masks secrets in a copy of the data before it is handed on.
+pub type RedactFn = dyn Fn(Direction, &mut [u8]) + Send + Sync;
+
+/// Where the decrypted data goes, see the [module docs](self).
+#[derive(Clone)]
+pub struct Tap {
+    sink: Arc<TapFn>,
+    redact: Vec<Arc<RedactFn>>,
+}
+
+impl Tap {
+    /// Hand the data to `callback`.
+    pub fn new<F>(callback: F) -> Self
+    where
+        F: Fn(Direction, &[u8]) + Send + Sync + 'static,
+    {
+        Self {
+            sink: Arc::new(callback),
+            redact: Vec::new(),
+        }
+    }
+
+    /// Keep the last `capacity` bytes in a [`TapBuffer`].
+    pub fn ring_buffer(capacity: usize) -> (Self, TapBuffer) {
+        let buffer = TapBuffer {
+            inner: Arc::new(Mutex::new(Ring {
+                capacity,
+                len: 0,
+                chunks: VecDeque::new(),
+            })),
+        };
+
+        let ring = buffer.inner.clone();
+        let tap = Self::new(move |direction, data| ring.lock().unwrap().push(direction, data));
+
+        (tap, buffer)
+    }
+
+    /// Let `redact` mask secrets before the data is handed on, after the redactions added
+    /// before.
+    pub fn redact<F>(mut self, redact: F) -> Self
+    where
+        F: Fn(Direction, &mut [u8]) + Send + Sync + 'static,
+    {
+        self.redact.push(Arc::new(redact));
+        self
+    }
+
+    /// Replace what follows `prefix` up to the end of the line with `*`, e.g. the value of
+    /// `Authorization: ` headers, in both directions.
+    pub fn redact_after(self, prefix: &'static [u8]) -> Self {
+        self.redact(move |_, data| {
+            let mut start = 0;
+
+            while let Some(found) = find(&data[start..], prefix) {
+                let value = start + found + prefix.len();
+                let end = data[value..]
+                    .iter()
+                    .position(|&b| b == b'\r' || b == b'\n')
+                    .map_or(data.len(), |len| value + len);
+
+                data[value..end].fill(b'*');
+                start = end;
+            }
+        })
+    }
+
+    pub(crate) fn record(&self, direction: Direction, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+
+        if self.redact.is_empty() {
+            (self.sink)(direction, data);
+        } else {
+            let mut data = data.to_vec();
+            for redact in &self.redact {
+                redact(direction, &mut data);
+            }
+
+            (self.sink)(direction, &data);
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return None;
+    }
+
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// The last bytes that passed a tap of [`Tap::ring_buffer`], cheap to clone and to send to
+/// other tasks.
+#[derive(Clone)]
+pub struct TapBuffer {
+    inner: Arc<Mutex<Ring>>,
+}
+
+impl TapBuffer {
+    /// The buffered data in the order it was read and written, consecutive chunks of the same
+    /// direction merged. Leaves the buffer empty.
+    pub fn take(&self) -> Vec<(Direction, Vec<u8>)> {
+        let mut ring = self.inner.lock().unwrap();
+        ring.len = 0;
+
+        ring.chunks.drain(..).collect()
+    }
+}
+
+struct Ring {
+    capacity: usize,
+    /// Bytes in `chunks`
+    len: usize,
+    chunks: VecDeque<(Direction, Vec<u8>)>,
+}
+
+impl Ring {
+    fn push(&mut self, direction: Direction, data: &[u8]) {
+        // Only the end of data longer than the buffer fits
+        let data = &data[data.len().saturating_sub(self.capacity)..];
+
+        match self.chunks.back_mut() {
+            Some((last, chunk)) if *last == direction => chunk.extend_from_slice(data),
+            _ => self.chunks.push_back((direction, data.to_vec())),
+        }
+        self.len += data.len();
+
+        // Drop the oldest bytes beyond the capacity
+        while self.len > self.capacity {
+            let excess = self.len - self.capacity;
+            let (_, oldest) = self.chunks.front_mut().unwrap();
+
+            if oldest.len() <= excess {
+                self.len -= oldest.len();
+                self.chunks.pop_front();
+            } else {
+                oldest.drain(..excess);
+                self.len -= excess;
+            }
+        }
+    }
+}
@@ -0,0 +1,410 @@
+use std::{
+    ffi::c_void,
+    future::poll_fn,
+    io, mem,
+    net::SocketAddr,
+    os::fd::{AsRawFd, IntoRawFd},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use async_io::Async;
+use esp_idf_svc::tls::{PollableSocket, Socket};
+use esp_idf_sys::{self as sys, EspError, ESP_FAIL};
+use futures_lite::{AsyncRead, AsyncWrite};
+
+use crate::{
+    dns::{self, IpPreference},
+    error::{Error, Result},
+    executor::{self, DEFAULT_MAX_FDS},
+    reactor::{self, DefaultReactor, Reactor as _},
+};
+
+/// Readiness notifications for a socket that [`FdSocket`] can hand to esp-tls.
+///
+/// Implemented for [`Async`] and the sockets of the other [reactors](crate::reactor), so any
+/// socket registered with one of them can be adopted. Other reactors can be plugged in by
+/// implementing this trait for their socket wrapper.
+pub trait Readiness {
+    type Socket: AsRawFd + IntoRawFd;
+
+    fn get_ref(&self) -> &Self::Socket;
+
+    /// Deregister from the reactor and return the socket.
+    fn into_inner(self) -> io::Result<Self::Socket>;
+
+    fn poll_readable(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>>;
+
+    fn poll_writable(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>>;
+}
+
+impl<T> Readiness for Async<T>
+where
+    T: AsRawFd + IntoRawFd,
+{
+    type Socket = T;
+
+    fn get_ref(&self) -> &T {
+        Async::get_ref(self)
+    }
+
+    fn into_inner(self) -> io::Result<T> {
+        Async::into_inner(self)
+    }
+
+    fn poll_readable(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Async::poll_readable(self, cx)
+    }
+
+    fn poll_writable(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Async::poll_writable(self, cx)
+    }
+}
+
+/// Socket options applied before the TLS handshake, see [`TlsConnector::tcp_options`].
+///
+/// Unset options keep the lwIP defaults. The send buffer is not a socket option on lwIP, it is
+/// `CONFIG_LWIP_TCP_SND_BUF_DEFAULT` for all sockets.
+///
+/// [`TlsConnector::tcp_options`]: crate::TlsConnector::tcp_options
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TcpOptions {
+    nodelay: Option<bool>,
+    keepalive: Option<TcpKeepAlive>,
+    recv_buffer_size: Option<usize>,
+}
+
+impl TcpOptions {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Send small writes right away instead of coalescing them (Nagle's algorithm), which
+    /// lowers the latency of request/response protocols.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /// See [`FdSocket::set_keepalive`].
+    pub fn keepalive(mut self, keepalive: TcpKeepAlive) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Limit the data lwIP buffers for the socket before the application reads it, which is
+    /// also the window advertised to the peer. Requires `CONFIG_LWIP_SO_RCVBUF`.
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+}
+
+/// TCP keepalive settings, see [`FdSocket::set_keepalive`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TcpKeepAlive {
+    /// Idle time before the first probe, in whole seconds
+    pub idle: Duration,
+    /// Time between unanswered probes, in whole seconds
+    pub interval: Duration,
+    /// Unanswered probes before the connection is reset
+    pub count: u32,
+}
+
+/// A connected socket that can be adopted by [`AsyncTls`](crate::AsyncTls).
+///
+/// When the TLS session is dropped, ownership of the file descriptor passes to esp-tls, which
+/// closes it.
+pub struct FdSocket<R: Readiness>(Option<R>);
+
+/// A TCP stream driven by the [`DefaultReactor`](crate::reactor::DefaultReactor), async-io
+/// unless another reactor is selected.
+pub type AsyncTcp = FdSocket<reactor::Tcp>;
+
+impl<R: Readiness> FdSocket<R> {
+    pub fn new(socket: R) -> Self {
+        Self(Some(socket))
+    }
+
+    pub fn get_ref(&self) -> &R {
+        self.0.as_ref().unwrap()
+    }
+
+    /// Let lwIP probe the idle connection and reset it if the peer stops answering, or turn
+    /// keepalive off with `None`.
+    ///
+    /// The fallback for protocols without a message that can be sent at any time, see
+    /// [`KeepAlive`](crate::keepalive::KeepAlive). A reset shows up as an error of the next read
+    /// or write. Requires `CONFIG_LWIP_TCP_KEEPALIVE`.
+    pub fn set_keepalive(&self, keepalive: Option<TcpKeepAlive>) -> io::Result<()> {
+        let fd = self.handle();
+
+        set_option(
+            fd,
+            sys::SOL_SOCKET,
+            sys::SO_KEEPALIVE,
+            keepalive.is_some() as _,
+        )?;
+        if let Some(keepalive) = keepalive {
+            let tcp = sys::IPPROTO_TCP;
+            set_option(fd, tcp, sys::TCP_KEEPIDLE, keepalive.idle.as_secs() as _)?;
+            set_option(
+                fd,
+                tcp,
+                sys::TCP_KEEPINTVL,
+                keepalive.interval.as_secs() as _,
+            )?;
+            set_option(fd, tcp, sys::TCP_KEEPCNT, keepalive.count as _)?;
+        }
+
+        Ok(())
+    }
+
+    /// Disable or enable Nagle's algorithm, see [`TcpOptions::nodelay`].
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        set_option(
+            self.handle(),
+            sys::IPPROTO_TCP,
+            sys::TCP_NODELAY,
+            nodelay as _,
+        )
+    }
+
+    /// See [`TcpOptions::recv_buffer_size`].
+    pub fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        set_option(
+            self.handle(),
+            sys::SOL_SOCKET,
+            sys::SO_RCVBUF,
+            size.try_into().unwrap_or(i32::MAX),
+        )
+    }
+
+    /// Apply all options that are set in `options`.
+    pub fn set_options(&self, options: &TcpOptions) -> io::Result<()> {
+        if let Some(nodelay) = options.nodelay {
+            self.set_nodelay(nodelay)?;
+        }
+        if options.keepalive.is_some() {
+            self.set_keepalive(options.keepalive)?;
+        }
+        if let Some(size) = options.recv_buffer_size {
+            self.set_recv_buffer_size(size)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn set_option(fd: i32, level: u32, name: u32, value: i32) -> io::Result<()> {
+    let result = unsafe {
+        sys::lwip_setsockopt(
+            fd,
+            level as _,
+            name as _,
+            &value as *const i32 as *const c_void,
+            mem::size_of::<i32>() as _,
+        )
+    };
+
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+impl<R: Readiness> Socket for FdSocket<R> {
+    fn handle(&self) -> i32 {
+        self.get_ref().get_ref().as_raw_fd()
+    }
+
+    fn release(&mut self) -> Result<(), EspError> {
+        let socket = self.0.take().unwrap();
+        socket.into_inner().unwrap().into_raw_fd();
+
+        Ok(())
+    }
+}
+
+/// Plain, unencrypted I/O, e.g. for protocols that upgrade to TLS later or to hand the
+/// connection to a [`TlsStream`](crate::TlsStream).
+impl<R> AsyncRead for FdSocket<R>
+where
+    R: Readiness + AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(self.0.as_mut().unwrap()).poll_read(cx, buf)
+    }
+}
+
+impl<R> AsyncWrite for FdSocket<R>
+where
+    R: Readiness + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(self.0.as_mut().unwrap()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(self.0.as_mut().unwrap()).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(self.0.as_mut().unwrap()).poll_close(cx)
+    }
+}
+
+impl<R: Readiness> PollableSocket for FdSocket<R> {
+    fn poll_readable(&self, ctx: &mut Context) -> Poll<Result<(), EspError>> {
+        self.get_ref().poll_readable(ctx).map_err(|e| {
+            log::error!("polling readable returned error {e}");
+            EspError::from_infallible::<ESP_FAIL>()
+        })
+    }
+
+    fn poll_writable(&self, ctx: &mut Context) -> Poll<Result<(), EspError>> {
+        self.get_ref().poll_writable(ctx).map_err(|e| {
+            log::error!("polling writable returned error {e}");
+            EspError::from_infallible::<ESP_FAIL>()
+        })
+    }
+}
+
+/// A TCP listener driven by the [`DefaultReactor`](crate::reactor::DefaultReactor), the
+/// building block for on-device servers.
+pub struct AsyncTcpListener(reactor::Listener);
+
+impl AsyncTcpListener {
+    /// Listen on `addr`, e.g. `([0, 0, 0, 0], 443)`.
+    pub fn bind(addr: impl Into<SocketAddr>) -> Result<Self> {
+        executor::init_async_runtime(DEFAULT_MAX_FDS)?;
+
+        Ok(Self(DefaultReactor::bind(addr.into())?))
+    }
+
+    /// Wait for the next incoming connection.
+    pub async fn accept(&self) -> Result<(AsyncTcp, SocketAddr)> {
+        let (stream, peer) = DefaultReactor::accept(&self.0).await?;
+
+        Ok((AsyncTcp::new(stream), peer))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        DefaultReactor::local_addr(&self.0)
+    }
+
+    pub fn get_ref(&self) -> &reactor::Listener {
+        &self.0
+    }
+}
+
+/// Resolve `hostname` and connect to the first of its addresses that accepts the connection.
+pub(crate) async fn connect(hostname: &str, port: u16, pref: IpPreference) -> Result<reactor::Tcp> {
+    connect_any(&dns::resolve(hostname, port, pref)?).await
+}
+
+/// Connect to the first of `addrs` that accepts the connection. Fails with
+/// [`InvalidInput`](io::ErrorKind::InvalidInput) if `addrs` is empty.
+pub(crate) async fn connect_any(addrs: &[SocketAddr]) -> Result<reactor::Tcp> {
+    connect_any_via(addrs, None).await
+}
+
+/// [`connect_any`] through the interface with the lwIP name `interface`, e.g. `en1`, instead of
+/// the one lwIP routes the addresses through.
+pub(crate) async fn connect_any_via(
+    addrs: &[SocketAddr],
+    interface: Option<&str>,
+) -> Result<reactor::Tcp> {
+    let mut last_err = None;
+
+    for &addr in addrs {
+        match connect_addr_via(addr, interface).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                log::warn!("{e}");
+                last_err = Some(e);
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to").into()
+    }))
+}
+
+/// Connect to `addr` directly, without any name resolution.
+pub(crate) async fn connect_addr(addr: SocketAddr) -> Result<reactor::Tcp> {
+    connect_addr_via(addr, None).await
+}
+
+async fn connect_addr_via(addr: SocketAddr, interface: Option<&str>) -> Result<reactor::Tcp> {
+    executor::init_async_runtime(DEFAULT_MAX_FDS)?;
+
+    let connected = match interface {
+        Some(interface) => connect_bound(addr, interface).await,
+        None => DefaultReactor::connect(addr).await,
+    };
+
+    connected.map_err(|source| Error::TcpConnect { addr, source })
+}
+
+async fn connect_bound(addr: SocketAddr, interface: &str) -> io::Result<reactor::Tcp> {
+    let stream = reactor::start_connect(addr, |fd| bind_to_device(fd, interface))?;
+    let stream = DefaultReactor::register(stream)?;
+
+    poll_fn(|cx| stream.poll_writable(cx)).await?;
+    match stream.get_ref().take_error()? {
+        Some(e) => Err(e),
+        None => Ok(stream),
+    }
+}
+
+/// Send the packets of the socket out on `interface` only, whatever the routes say.
+fn bind_to_device(fd: i32, interface: &str) -> io::Result<()> {
+    let mut ifreq: sys::ifreq = unsafe { mem::zeroed() };
+    // Leaves the terminating NUL
+    let max = ifreq.ifr_name.len() - 1;
+    for (dst, src) in ifreq.ifr_name.iter_mut().zip(interface.bytes().take(max)) {
+        *dst = src as _;
+    }
+
+    let result = unsafe {
+        sys::lwip_setsockopt(
+            fd,
+            sys::SOL_SOCKET as _,
+            sys::SO_BINDTODEVICE as _,
+            &ifreq as *const sys::ifreq as *const c_void,
+            mem::size_of::<sys::ifreq>() as _,
+        )
+    };
+
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Register the eventfd VFS, which the reactors need to wake themselves up.
+///
+/// Every executor thread uses one of the `max_fds` descriptors. Fails if the VFS is registered
+/// already, [`init_async_runtime`](crate::executor::init_async_runtime) can be called any number
+/// of times.
+pub fn register_eventfd(max_fds: usize) -> Result<(), EspError> {
+    esp_idf_sys::esp!(unsafe {
+        esp_idf_sys::esp_vfs_eventfd_register(&esp_idf_sys::esp_vfs_eventfd_config_t {
+            max_fds,
+            ..Default::default()
+        })
+    })
+}
@@ -0,0 +1,408 @@
+//! Measurements queued by application tasks and uploaded in batches in the background, so that
+//! sensor loops don't stall when the network does.
+//!
+//! ```ignore
+//! let (sender, uploader) = Telemetry::new().spill_dir("/spiffs").build();
+//!
+//! thread::spawn(move || {
+//!     async_io::block_on(uploader.run_https(client, "https://api.example.com/telemetry"))
+//! });
+//!
+//! // In a sensor task
+//! sender.push("temperature", 21.5);
+//! ```
+//!
+//! Batches are JSON arrays like
+//! `[{"name":"temperature","value":21.5,"uptime_ms":81234,"time_ms":1700000000123}]`, with
+//! `time_ms` `null` while the system time is not set. Batches that fail to upload are written to
+//! the spill directory, if any, and sent before newer ones once uploads succeed again. Without
+//! one, and while the queue is full, the oldest measurements are dropped.
+
+use core::future::Future;
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use esp_idf_sys as sys;
+
+use crate::{
+    cert::clock_is_set,
+    error::{Error, Result},
+    http::HttpClient,
+    time,
+    util::json_escape,
+};
+
+/// Spill files are named `tm<n>.json`, short enough for the 32 characters SPIFFS allows for a
+/// path.
+const SPILL_PREFIX: &str = "tm";
+
+#[derive(Clone, Debug)]
+struct Measurement {
+    name: String,
+    value: f64,
+    uptime_ms: i64,
+    time: SystemTime,
+}
+
+/// Sets up the queue and the uploader, see the [module docs](self).
+pub struct Telemetry {
+    capacity: usize,
+    batch_size: usize,
+    flush_interval: Duration,
+    spill_dir: Option<PathBuf>,
+    max_spill_files: usize,
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Telemetry {
+    /// Queue up to 256 measurements and upload them in batches of up to 32 every 30 s.
+    pub fn new() -> Self {
+        Self {
+            capacity: 256,
+            batch_size: 32,
+            flush_interval: Duration::from_secs(30),
+            spill_dir: None,
+            max_spill_files: 64,
+        }
+    }
+
+    /// Queue up to `capacity` measurements.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Upload up to `batch_size` measurements per request.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Upload every `interval`.
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    /// Keep batches that failed to upload in the existing directory `dir`, e.g. the mount point
+    /// of a SPIFFS or LittleFS partition, up to 64 of them. Batches left from before a restart
+    /// are uploaded as well.
+    pub fn spill_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.spill_dir = Some(dir.into());
+        self
+    }
+
+    /// Keep up to `max` batches in the spill directory, dropping the oldest beyond that.
+    pub fn max_spill_files(mut self, max: usize) -> Self {
+        self.max_spill_files = max.max(1);
+        self
+    }
+
+    pub fn build(self) -> (TelemetrySender, TelemetryUploader) {
+        let queue = Arc::new(Queue {
+            capacity: self.capacity,
+            state: Default::default(),
+        });
+
+        let spill = self
+            .spill_dir
+            .map(|dir| Spill::open(dir, self.max_spill_files));
+
+        (
+            TelemetrySender {
+                queue: queue.clone(),
+            },
+            TelemetryUploader {
+                queue,
+                batch_size: self.batch_size,
+                flush_interval: self.flush_interval,
+                spill,
+            },
+        )
+    }
+}
+
+struct Queue {
+    capacity: usize,
+    state: Mutex<QueueState>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    measurements: VecDeque<Measurement>,
+    dropped: u64,
+}
+
+/// Queues measurements for upload, cheap to clone and to send to other tasks.
+#[derive(Clone)]
+pub struct TelemetrySender {
+    queue: Arc<Queue>,
+}
+
+impl TelemetrySender {
+    /// Queue `value` under `name`, dropping the oldest measurement if the queue is full. Never
+    /// waits for the network.
+    pub fn push(&self, name: &str, value: f64) {
+        let measurement = Measurement {
+            name: name.to_owned(),
+            value,
+            uptime_ms: unsafe { sys::esp_timer_get_time() } / 1000,
+            time: SystemTime::now(),
+        };
+
+        let mut state = self.queue.state.lock().unwrap();
+        if state.measurements.len() >= self.queue.capacity {
+            state.measurements.pop_front();
+            state.dropped += 1;
+        }
+        state.measurements.push_back(measurement);
+    }
+
+    /// How many measurements were dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.queue.state.lock().unwrap().dropped
+    }
+}
+
+/// Uploads the queued measurements, returned by [`Telemetry::build`].
+pub struct TelemetryUploader {
+    queue: Arc<Queue>,
+    batch_size: usize,
+    flush_interval: Duration,
+    spill: Option<Spill>,
+}
+
+impl TelemetryUploader {
+    /// POST each batch to `url` with `client`, which retries according to its
+    /// [`RetryPolicy`](crate::http::RetryPolicy). Never returns.
+    pub async fn run_https(self, client: HttpClient, url: &str) {
+        self.run(|batch| {
+            let request = client
+                .post(url)
+                .header("Content-Type", "application/json")
+                .body(batch);
+
+            async move {
+                let response = request.send().await?;
+                match response.status() {
+                    200..=299 => Ok(()),
+                    status => Err(Error::Http(format!(
+                        "telemetry upload failed with status {status}"
+                    ))),
+                }
+            }
+        })
+        .await
+    }
+
+    /// Hand each batch, encoded as JSON, to `upload`, e.g. to publish it over MQTT. A batch
+    /// counts as delivered once `upload` returns `Ok`. Never returns.
+    pub async fn run<F, Fut>(mut self, mut upload: F)
+    where
+        F: FnMut(Vec<u8>) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        loop {
+            time::sleep(self.flush_interval).await;
+
+            let mut online = match self.upload_spilled(&mut upload).await {
+                Ok(()) => true,
+                Err(e) => {
+                    log::warn!("uploading spilled telemetry failed: {e}");
+                    false
+                }
+            };
+
+            loop {
+                let batch = self.take_batch();
+                if batch.is_empty() {
+                    break;
+                }
+
+                if online {
+                    match upload(encode(&batch)).await {
+                        Ok(()) => continue,
+                        Err(e) => {
+                            log::warn!("uploading telemetry failed: {e}");
+                            online = false;
+                        }
+                    }
+                }
+
+                // Offline, move the queue to the spill directory while there is one
+                if !self.keep(batch) {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Upload the spilled batches, oldest first, until one fails.
+    async fn upload_spilled<F, Fut>(&mut self, upload: &mut F) -> Result<()>
+    where
+        F: FnMut(Vec<u8>) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let Some(spill) = &mut self.spill else {
+            return Ok(());
+        };
+
+        while let Some((path, batch)) = spill.oldest() {
+            upload(batch).await?;
+            spill.remove(&path);
+        }
+
+        Ok(())
+    }
+
+    fn take_batch(&self) -> Vec<Measurement> {
+        let mut state = self.queue.state.lock().unwrap();
+        let len = state.measurements.len().min(self.batch_size);
+
+        state.measurements.drain(..len).collect()
+    }
+
+    /// Hold on to a batch that failed to upload, in the spill directory or back in the queue.
+    /// Returns whether it was spilled.
+    fn keep(&mut self, batch: Vec<Measurement>) -> bool {
+        if let Some(spill) = &mut self.spill {
+            match spill.write(&encode(&batch)) {
+                Ok(()) => return true,
+                Err(e) => log::warn!("spilling telemetry failed: {e}"),
+            }
+        }
+
+        let mut state = self.queue.state.lock().unwrap();
+        for measurement in batch.into_iter().rev() {
+            if state.measurements.len() >= self.queue.capacity {
+                // Newer measurements take precedence
+                state.dropped += 1;
+                continue;
+            }
+            state.measurements.push_front(measurement);
+        }
+
+        false
+    }
+}
+
+fn encode(batch: &[Measurement]) -> Vec<u8> {
+    let mut json = String::from("[");
+
+    for (i, measurement) in batch.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+
+        let value = match measurement.value.is_finite() {
+            true => measurement.value.to_string(),
+            false => "null".to_owned(),
+        };
+        let time_ms = match clock_is_set(measurement.time) {
+            true => measurement
+                .time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis()
+                .to_string(),
+            false => "null".to_owned(),
+        };
+        write!(
+            json,
+            r#"{{"name":"{}","value":{value},"uptime_ms":{},"time_ms":{time_ms}}}"#,
+            json_escape(&measurement.name),
+            measurement.uptime_ms,
+        )
+        .unwrap();
+    }
+    json.push(']');
+
+    json.into_bytes()
+}
+
+/// Batches kept in files, numbered in the order they were written.
+struct Spill {
+    dir: PathBuf,
+    max_files: usize,
+    /// Numbers of the files in `dir`, ascending
+    files: VecDeque<u64>,
+}
+
+impl Spill {
+    fn open(dir: PathBuf, max_files: usize) -> Self {
+        let mut files: Vec<u64> = fs::read_dir(&dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| {
+                        let name = entry.ok()?.file_name().into_string().ok()?;
+                        name.strip_prefix(SPILL_PREFIX)?
+                            .strip_suffix(".json")?
+                            .parse()
+                            .ok()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        files.sort_unstable();
+
+        if !files.is_empty() {
+            log::info!("{} spilled telemetry batches to upload", files.len());
+        }
+
+        Self {
+            dir,
+            max_files,
+            files: files.into(),
+        }
+    }
+
+    fn path(&self, n: u64) -> PathBuf {
+        self.dir.join(format!("{SPILL_PREFIX}{n}.json"))
+    }
+
+    fn write(&mut self, batch: &[u8]) -> Result<()> {
+        while self.files.len() >= self.max_files {
+            let oldest = self.files.pop_front().unwrap();
+            log::warn!("dropping the oldest spilled telemetry batch");
+            let _ = fs::remove_file(self.path(oldest));
+        }
+
+        let n = self.files.back().map_or(0, |n| n + 1);
+        fs::write(self.path(n), batch)?;
+        self.files.push_back(n);
+
+        Ok(())
+    }
+
+    /// The oldest batch, skipping files that can't be read.
+    fn oldest(&mut self) -> Option<(PathBuf, Vec<u8>)> {
+        while let Some(&n) = self.files.front() {
+            let path = self.path(n);
+            match fs::read(&path) {
+                Ok(batch) => return Some((path, batch)),
+                Err(e) => {
+                    log::warn!("dropping the unreadable {}: {e}", path.display());
+                    self.remove(&path);
+                }
+            }
+        }
+
+        None
+    }
+
+    fn remove(&mut self, path: &Path) {
+        self.files.pop_front();
+        let _ = fs::remove_file(path);
+    }
+}
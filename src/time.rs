@@ -0,0 +1,211 @@
+//! Sleeps, timeouts and deadlines on the timers of the
+//! [`DefaultReactor`](crate::reactor::DefaultReactor), async-io unless another reactor is
+//! selected.
+//!
+//! ```ignore
+//! // Give up on the response after 10 s
+//! let read = with_timeout(tls.read(&mut buf), Duration::from_secs(10)).await??;
+//!
+//! // Several steps within one time budget
+//! let deadline = Deadline::after(Duration::from_secs(30));
+//! let mut tls = deadline.run(connector.connect("example.com", 443, &cfg)).await??;
+//! deadline.run(tls.write_all(&request)).await??;
+//! ```
+//!
+//! A timeout drops the future it gave up on, so use them with futures that are cancellation
+//! safe, like the reads and writes of [`AsyncTls`](crate::AsyncTls#cancellation).
+
+use core::{
+    fmt,
+    future::{poll_fn, Future},
+    pin::Pin,
+    task::Poll,
+};
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::Error,
+    reactor::{DefaultTimer, Timer as _},
+};
+
+/// A future didn't complete before its deadline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("timed out")
+    }
+}
+
+impl std::error::Error for Elapsed {}
+
+impl From<Elapsed> for io::Error {
+    fn from(elapsed: Elapsed) -> Self {
+        io::Error::new(io::ErrorKind::TimedOut, elapsed)
+    }
+}
+
+impl From<Elapsed> for Error {
+    fn from(elapsed: Elapsed) -> Self {
+        Error::Io(elapsed.into())
+    }
+}
+
+/// Wait for `duration`.
+pub async fn sleep(duration: Duration) {
+    DefaultTimer::after(duration).await;
+}
+
+/// Wait until `deadline`, right away if it passed already.
+pub async fn sleep_until(deadline: Instant) {
+    DefaultTimer::at(deadline).await;
+}
+
+/// Complete `future` within `duration`, or fail with [`Elapsed`].
+pub async fn with_timeout<F: Future>(future: F, duration: Duration) -> Result<F::Output, Elapsed> {
+    Deadline::after(duration).run(future).await
+}
+
+/// A point in time to be done by, shared by the steps of an operation, see the
+/// [module docs](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    pub fn at(instant: Instant) -> Self {
+        Self(Some(instant))
+    }
+
+    /// `duration` from now, [`never`](Self::never) if that is too far out to represent, e.g.
+    /// for `Duration::MAX`.
+    pub fn after(duration: Duration) -> Self {
+        Self(Instant::now().checked_add(duration))
+    }
+
+    /// A deadline that never passes, for operations without a time limit.
+    pub fn never() -> Self {
+        Self(None)
+    }
+
+    /// When the deadline passes, `None` if never.
+    pub fn instant(&self) -> Option<Instant> {
+        self.0
+    }
+
+    /// The time left, zero once the deadline passed and `None` if it never does.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.0
+            .map(|instant| instant.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn is_elapsed(&self) -> bool {
+        self.remaining() == Some(Duration::ZERO)
+    }
+
+    /// Wait until the deadline passes.
+    pub async fn sleep(&self) {
+        self.timer().await;
+    }
+
+    /// Complete `future` before the deadline, or fail with [`Elapsed`]. A future that is ready
+    /// right away completes even if the deadline passed already.
+    pub async fn run<F: Future>(&self, future: F) -> Result<F::Output, Elapsed> {
+        futures_lite::pin!(future);
+        let mut timer = self.timer();
+
+        poll_fn(|cx| {
+            if let Poll::Ready(output) = future.as_mut().poll(cx) {
+                return Poll::Ready(Ok(output));
+            }
+
+            match Pin::new(&mut timer).poll(cx) {
+                Poll::Ready(_) => Poll::Ready(Err(Elapsed)),
+                Poll::Pending => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    fn timer(&self) -> DefaultTimer {
+        match self.0 {
+            Some(instant) => DefaultTimer::at(instant),
+            None => DefaultTimer::never(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::future;
+
+    use super::*;
+
+    const SHORT: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn timeout_expires() {
+        let start = Instant::now();
+
+        let result = future::block_on(with_timeout(future::pending::<()>(), SHORT));
+        assert_eq!(result, Err(Elapsed));
+        assert!(start.elapsed() >= SHORT);
+
+        let err = io::Error::from(Elapsed);
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn completes_before_timeout() {
+        let result = future::block_on(with_timeout(
+            async {
+                sleep(SHORT).await;
+                42
+            },
+            Duration::from_secs(10),
+        ));
+        assert_eq!(result, Ok(42));
+    }
+
+    #[test]
+    fn ready_after_deadline() {
+        let deadline = Deadline::at(Instant::now());
+        assert!(deadline.is_elapsed());
+        assert_eq!(deadline.remaining(), Some(Duration::ZERO));
+
+        // Ready right away still wins
+        assert_eq!(future::block_on(deadline.run(future::ready(1))), Ok(1));
+        assert_eq!(
+            future::block_on(deadline.run(future::pending::<()>())),
+            Err(Elapsed)
+        );
+    }
+
+    #[test]
+    fn shared_deadline() {
+        let deadline = Deadline::after(3 * SHORT);
+        assert!(!deadline.is_elapsed());
+        assert!(deadline.remaining().unwrap() <= 3 * SHORT);
+
+        future::block_on(async {
+            assert_eq!(deadline.run(sleep(SHORT)).await, Ok(()));
+            // Less than the full budget is left for the second step
+            assert_eq!(deadline.run(sleep(3 * SHORT)).await, Err(Elapsed));
+        });
+        assert!(deadline.is_elapsed());
+    }
+
+    #[test]
+    fn never() {
+        let deadline = Deadline::never();
+        assert_eq!(deadline.instant(), None);
+        assert_eq!(deadline.remaining(), None);
+        assert!(!deadline.is_elapsed());
+        assert_eq!(Deadline::after(Duration::MAX), deadline);
+
+        assert_eq!(future::block_on(deadline.run(sleep(SHORT))), Ok(()));
+    }
+}
@@ -0,0 +1,1126 @@
+use std::{
+    ffi::{c_char, c_void, CStr, CString},
+    fmt,
+    future::{poll_fn, Future},
+    io::{self, IoSlice},
+    pin::Pin,
+    ptr,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_svc::{
+    errors::EspIOError,
+    tls::{Config, PollableSocket, Socket},
+};
+use esp_idf_sys::{
+    self as sys, EspError, ESP_ERR_INVALID_ARG, ESP_ERR_INVALID_STATE, ESP_ERR_NO_MEM, ESP_FAIL,
+    ESP_TLS_ERR_SSL_WANT_READ, ESP_TLS_ERR_SSL_WANT_WRITE, EWOULDBLOCK,
+};
+use futures_lite::{AsyncBufRead, AsyncRead, AsyncWrite};
+
+#[cfg(feature = "debug-keylog")]
+use crate::keylog::{KeyLogFn, KeyLogHook};
+#[cfg(feature = "debug-tap")]
+use crate::tap::{self, Tap};
+use crate::{
+    cert::Certificate,
+    conf::{ConfFn, ConfHook},
+    error::Error,
+    events::{self, ConnectionEvent, DisconnectReason},
+    limit::ConnectionPermit,
+    mem::{self, Buffer},
+    reactor::{DefaultTimer, Timer as _},
+    tcp::AsyncTcp,
+    util::coalesce,
+    verify::{VerifyFn, VerifyHook},
+    watchdog::HandshakeWatchdog,
+};
+#[cfg(esp_idf_esp_tls_client_session_tickets)]
+use crate::{error::Result, session::TlsSession, suspend::Suspended};
+
+const EWOULDBLOCK_I32: i32 = EWOULDBLOCK as i32;
+
+/// Read buffer size for [`AsyncBufRead`] if none was set with [`AsyncTls::set_read_buffer`].
+const DEFAULT_READ_BUFFER_LEN: usize = 1024;
+
+// From `mbedtls/net_sockets.h`, which is not part of the bindings
+pub(crate) const MBEDTLS_ERR_NET_SEND_FAILED: i32 = -0x004E;
+pub(crate) const MBEDTLS_ERR_NET_RECV_FAILED: i32 = -0x004C;
+const MBEDTLS_ERR_NET_CONN_RESET: i32 = -0x0050;
+
+/// A TLS session on top of an adopted, already connected socket, by default an [`AsyncTcp`].
+///
+/// This drives `esp-tls` directly rather than through `esp_idf_svc::tls::AsyncEspTls`, because the
+/// latter keeps the `esp_tls` handle private and we need the mbedtls session to inspect the peer.
+///
+/// # Cancellation
+///
+/// Reads and writes are cancellation safe, i.e. a pending `read()` or `write()` future can be
+/// dropped (e.g. in `select!`) without corrupting the stream:
+///
+/// - mbedtls only consumes a record once it has been received completely, so a cancelled read
+///   loses no data.
+/// - Once mbedtls has encrypted a record it insists on being called again with the same data
+///   until the record is sent. Writes are therefore limited to one record, and a record that
+///   could not be sent right away is reported as written and kept until it went out on the next
+///   write, flush or close.
+pub struct AsyncTls<S: PollableSocket = AsyncTcp> {
+    /// Null once the session was closed for being idle.
+    raw: *mut sys::esp_tls,
+    socket: S,
+    verify: Option<Box<VerifyHook>>,
+    #[cfg(feature = "debug-keylog")]
+    keylog: Option<Box<KeyLogHook>>,
+    #[cfg(feature = "debug-tap")]
+    tap: Option<Tap>,
+    conf: Option<ConfHook>,
+    watchdog: Option<HandshakeWatchdog>,
+    stats: Stats,
+    /// The payload of a record that mbedtls still has to send, see the cancellation notes.
+    pending_write: Vec<u8>,
+    /// See [`set_write_chunk_size`](Self::set_write_chunk_size)
+    write_chunk: Option<usize>,
+    idle: Idle,
+    read_buf: ReadBuffer,
+    negotiated: Option<Negotiated>,
+    /// Counts the session against the [`ConnectionLimit`](crate::limit::ConnectionLimit)
+    permit: Option<ConnectionPermit>,
+    /// Set once [`ConnectionEvent::TlsHandshakeDone`] was posted, until the matching
+    /// `Disconnected`
+    connected_event: bool,
+}
+
+// SAFETY: `AsyncTls` is not `Send` for its raw `esp_tls` pointer, the mbedtls session behind it
+// and the hooks mbedtls calls back into, all of which it owns. Neither esp-tls nor mbedtls ties a
+// session to the thread that created it, the callbacks of the hooks are `Send + Sync`, and the
+// only thread-local state, the active `ConfHook`, is set and cleared within each handshake step.
+unsafe impl<S: PollableSocket + Send> Send for AsyncTls<S> {}
+
+/// TLS protocol version of a session, see [`AsyncTls::protocol_version`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProtocolVersion {
+    Tls1_2,
+    Tls1_3,
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Tls1_2 => "TLSv1.2",
+            Self::Tls1_3 => "TLSv1.3",
+        })
+    }
+}
+
+/// What the handshake settled on, kept for after the session is gone.
+struct Negotiated {
+    #[cfg_attr(not(esp_idf_esp_tls_client_session_tickets), allow(dead_code))]
+    hostname: String,
+    version: Option<ProtocolVersion>,
+    cipher_suite: &'static str,
+}
+
+/// See [`AsyncTls::set_idle_timeout`].
+struct Idle {
+    timeout: Option<Duration>,
+    /// Last read or write that transferred data
+    last_used: Instant,
+    timer: DefaultTimer,
+}
+
+/// See [`AsyncTls::set_read_buffer`].
+#[derive(Default)]
+struct ReadBuffer {
+    data: Buffer,
+    pos: usize,
+    filled: usize,
+}
+
+impl ReadBuffer {
+    fn buffered(&self) -> &[u8] {
+        &self.data[self.pos..self.filled]
+    }
+}
+
+/// Traffic statistics of an [`AsyncTls`] connection, see [`AsyncTls::stats`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnectionStats {
+    /// Application data bytes received
+    pub bytes_read: u64,
+    /// Application data bytes sent
+    pub bytes_written: u64,
+    /// Time since the handshake completed
+    pub duration: Duration,
+}
+
+impl ConnectionStats {
+    /// Average receive rate in bytes per second, 0 while no time has passed.
+    pub fn read_throughput(&self) -> f64 {
+        self.rate(self.bytes_read)
+    }
+
+    /// Average send rate in bytes per second, 0 while no time has passed.
+    pub fn write_throughput(&self) -> f64 {
+        self.rate(self.bytes_written)
+    }
+
+    fn rate(&self, bytes: u64) -> f64 {
+        if self.duration.is_zero() {
+            return 0.0;
+        }
+
+        bytes as f64 / self.duration.as_secs_f64()
+    }
+}
+
+/// How long the phases of establishing an [`AsyncTls`] connection took, see
+/// [`AsyncTls::connect_timings`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnectTimings {
+    /// Resolving the name, `None` if the connection was made to an address, through a proxy or
+    /// on an adopted socket. Zero for names served from a [`DnsCache`](crate::DnsCache)
+    pub dns: Option<Duration>,
+    /// Connecting the TCP socket, including the proxy handshake if there is one. `None` on an
+    /// adopted socket
+    pub tcp_connect: Option<Duration>,
+    pub tls_handshake: Duration,
+}
+
+impl ConnectTimings {
+    /// The time from starting to connect until the handshake completed.
+    pub fn total(&self) -> Duration {
+        self.dns.unwrap_or_default() + self.tcp_connect.unwrap_or_default() + self.tls_handshake
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    bytes_read: u64,
+    bytes_written: u64,
+    established: Option<Instant>,
+    timings: Option<ConnectTimings>,
+}
+
+impl<S: PollableSocket> AsyncTls<S> {
+    /// Adopt the supplied socket. The socket should be in a connected state.
+    pub fn adopt(socket: S) -> Result<Self, EspError> {
+        let raw = unsafe { sys::esp_tls_init() };
+        if raw.is_null() {
+            return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+        }
+
+        // Construct first so that `raw` is destroyed if any of the calls below fail
+        let tls = Self {
+            raw,
+            socket,
+            verify: None,
+            #[cfg(feature = "debug-keylog")]
+            keylog: None,
+            #[cfg(feature = "debug-tap")]
+            tap: None,
+            conf: None,
+            watchdog: None,
+            stats: Default::default(),
+            pending_write: Vec::new(),
+            write_chunk: None,
+            idle: Idle {
+                timeout: None,
+                last_used: Instant::now(),
+                timer: DefaultTimer::never(),
+            },
+            read_buf: Default::default(),
+            negotiated: None,
+            permit: None,
+            connected_event: false,
+        };
+
+        sys::esp!(unsafe { sys::esp_tls_set_conn_sockfd(raw, tls.socket.handle()) })?;
+        sys::esp!(unsafe {
+            sys::esp_tls_set_conn_state(raw, sys::esp_tls_conn_state_ESP_TLS_CONNECTING)
+        })?;
+
+        Ok(tls)
+    }
+
+    /// Run `callback` on the server's certificate chain during [`negotiate`](Self::negotiate),
+    /// after the validation configured in esp-tls. See [`VerifyFn`].
+    pub fn set_verify_callback(&mut self, callback: Arc<VerifyFn>) {
+        self.verify = Some(VerifyHook::new(callback));
+    }
+
+    /// Hand the secrets of the session to `callback` during [`negotiate`](Self::negotiate), see
+    /// [`keylog`](crate::keylog).
+    #[cfg(feature = "debug-keylog")]
+    pub fn set_keylog_callback(&mut self, callback: Arc<KeyLogFn>) {
+        self.keylog = Some(KeyLogHook::new(callback));
+    }
+
+    /// Mirror the decrypted data read and written from now on to `tap`, see [`tap`](crate::tap).
+    #[cfg(feature = "debug-tap")]
+    pub fn set_tap(&mut self, tap: Tap) {
+        log::warn!("!!! TLS traffic is tapped, the connection is NOT confidential !!!");
+        self.tap = Some(tap);
+    }
+
+    /// Keep the task watchdog from firing during [`negotiate`](Self::negotiate): between the
+    /// handshake steps the idle task gets to run and the calling task, if subscribed, is reset.
+    ///
+    /// A single step longer than `CONFIG_ESP_TASK_WDT_TIMEOUT_S` still trips the watchdog.
+    pub fn set_handshake_watchdog(&mut self, enabled: bool) {
+        self.watchdog = enabled.then(HandshakeWatchdog::new);
+    }
+
+    /// Adjust the mbedtls configuration before [`negotiate`](Self::negotiate) starts the
+    /// handshake. See [`ConfHook`] for the requirements.
+    pub(crate) fn add_conf_tweak(&mut self, tweak: Box<ConfFn>) {
+        self.conf.get_or_insert_with(Default::default).push(tweak);
+    }
+
+    /// Trust the PEM (nul terminated) or DER certificates `cas`, and the ESP x509 certificate
+    /// bundle with `crt_bundle`, in addition to the verification set up in the [`Config`].
+    pub(crate) fn add_trust(&mut self, cas: &[Arc<[u8]>], crt_bundle: bool) {
+        self.conf
+            .get_or_insert_with(Default::default)
+            .trust(cas, crt_bundle);
+    }
+
+    /// Perform the TLS handshake on the adopted socket.
+    pub async fn negotiate(&mut self, hostname: &str, cfg: &Config<'_>) -> Result<(), EspError> {
+        self.negotiate_with(hostname, cfg, |_| ())
+            .await
+            .map_err(|e| {
+                events::post(ConnectionEvent::Disconnected {
+                    reason: DisconnectReason::ConnectFailed,
+                });
+                e
+            })
+    }
+
+    /// Same as [`negotiate`](Self::negotiate), but lets the caller adjust the raw esp-tls
+    /// configuration for settings that [`Config`] does not cover. Posting the failure is left to
+    /// the caller, which might have failed before.
+    pub(crate) async fn negotiate_with(
+        &mut self,
+        hostname: &str,
+        cfg: &Config<'_>,
+        tweak: impl FnOnce(&mut sys::esp_tls_cfg),
+    ) -> Result<(), EspError> {
+        let mut rcfg = RawConfig::new(cfg)?;
+        tweak(&mut rcfg.raw);
+
+        if let Some(conf) = &mut self.conf {
+            conf.take_over(&mut rcfg.raw)?;
+        }
+
+        // Same as `AsyncEspTls`: for an adopted socket `non_block` must be false, otherwise
+        // esp-tls tries to check connectivity with a `select()` that was never set up.
+        rcfg.raw.non_block = false;
+
+        let started = Instant::now();
+        let mut hooks_installed = false;
+
+        poll_fn(|cx| loop {
+            let ret = ConfHook::enter(self.conf.as_mut(), || unsafe {
+                sys::esp_tls_conn_new_async(
+                    hostname.as_ptr() as *const c_char,
+                    hostname.len() as i32,
+                    0,
+                    &rcfg.raw,
+                    self.raw,
+                )
+            });
+
+            // esp-tls creates the mbedtls session in the first step of the handshake and only
+            // returns once it has to wait for the server, i.e. before the server's certificate
+            // could have been processed
+            if !hooks_installed {
+                hooks_installed = true;
+                self.install_hooks();
+            }
+
+            if let Some(watchdog) = &mut self.watchdog {
+                watchdog.step();
+            }
+
+            match ret {
+                1 => return Poll::Ready(Ok(())),
+                // 0 is the "in progress" return code of the esp-tls handshake
+                0 => ready!(self.poll_wait(cx, EWOULDBLOCK_I32))?,
+                ESP_TLS_ERR_SSL_WANT_READ | ESP_TLS_ERR_SSL_WANT_WRITE => {
+                    ready!(self.poll_wait(cx, ret))?
+                }
+                _ => return Poll::Ready(Err(EspError::from_infallible::<ESP_FAIL>())),
+            }
+        })
+        .await?;
+
+        self.stats.established = Some(Instant::now());
+        self.stats.timings = Some(ConnectTimings {
+            tls_handshake: started.elapsed(),
+            ..Default::default()
+        });
+        self.idle.last_used = Instant::now();
+
+        let ssl = self.ssl_context();
+        // Both point to static strings of mbedtls
+        let (version, cipher_suite) = unsafe {
+            (
+                CStr::from_ptr(sys::mbedtls_ssl_get_version(ssl)).to_str(),
+                CStr::from_ptr(sys::mbedtls_ssl_get_ciphersuite(ssl)).to_str(),
+            )
+        };
+        let version = version.unwrap_or_default();
+        log::debug!("negotiated {version} with {cipher_suite:?}");
+        self.negotiated = Some(Negotiated {
+            hostname: hostname.to_owned(),
+            version: match version {
+                "TLSv1.2" => Some(ProtocolVersion::Tls1_2),
+                "TLSv1.3" => Some(ProtocolVersion::Tls1_3),
+                _ => None,
+            },
+            cipher_suite: cipher_suite.unwrap_or_default(),
+        });
+
+        self.connected_event = true;
+        events::post(ConnectionEvent::TlsHandshakeDone);
+
+        Ok(())
+    }
+
+    /// The protocol version of the session, once [`negotiate`](Self::negotiate) succeeded.
+    ///
+    /// `None` before, and for versions older than TLS 1.2, which mbedtls only negotiates when
+    /// built with legacy support.
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        self.negotiated
+            .as_ref()
+            .and_then(|negotiated| negotiated.version)
+    }
+
+    /// The mbedtls name of the cipher suite of the session, e.g. `TLS1-3-AES-128-GCM-SHA256` or
+    /// `TLS-ECDHE-RSA-WITH-AES-128-GCM-SHA256`, once [`negotiate`](Self::negotiate) succeeded.
+    pub fn cipher_suite(&self) -> Option<&'static str> {
+        self.negotiated
+            .as_ref()
+            .map(|negotiated| negotiated.cipher_suite)
+    }
+
+    /// Read from the session in chunks of `capacity` bytes, so that the small reads of line and
+    /// header parsers are served from memory rather than by one mbedtls read each.
+    ///
+    /// Off by default. Reads at least as large as the buffer bypass it. Using the stream as
+    /// [`AsyncBufRead`] without a buffer set up allocates one of 1 KiB.
+    pub fn set_read_buffer(&mut self, capacity: usize) {
+        // Keep what is buffered already, even if it doesn't fit the new capacity
+        let buffered = self.read_buf.buffered();
+        let mut data = mem::alloc_buffer(capacity.max(buffered.len()));
+        data[..buffered.len()].copy_from_slice(buffered);
+
+        self.read_buf = ReadBuffer {
+            filled: buffered.len(),
+            pos: 0,
+            data,
+        };
+    }
+
+    /// Encrypt writes in records of at most `size` bytes, e.g. to stay within a small
+    /// `CONFIG_MBEDTLS_SSL_OUT_CONTENT_LEN`, or `None` for records as large as mbedtls allows.
+    ///
+    /// A write of a larger buffer sends one chunk and reports that as written, `write_all`
+    /// carries on with the rest. Writes never exceed the record size that mbedtls allows, with
+    /// or without a chunk size.
+    pub fn set_write_chunk_size(&mut self, size: Option<usize>) {
+        self.write_chunk = size.map(|size| size.max(1));
+    }
+
+    /// Close the connection once no data was read or written for `timeout`, to free the memory
+    /// of the session on long-running devices. `None`, the default, keeps it open.
+    ///
+    /// The connection is closed with a close_notify alert when a read has been pending for
+    /// `timeout`, or on the next use otherwise, which then fails with [`Error::IdleClosed`] as
+    /// does any use after. Owners that hold connections without polling them, e.g. pools, can
+    /// reclaim them with [`close_if_idle`](Self::close_if_idle).
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle.timeout = timeout;
+    }
+
+    /// Close the connection if it was idle for longer than the
+    /// [idle timeout](Self::set_idle_timeout), returns whether it is closed.
+    pub fn close_if_idle(&mut self) -> bool {
+        if !self.is_idle_closed() && self.idle_expired() {
+            self.close_idle();
+        }
+
+        self.is_idle_closed()
+    }
+
+    /// The connection was closed for being idle, see [`set_idle_timeout`](Self::set_idle_timeout).
+    pub fn is_idle_closed(&self) -> bool {
+        self.raw.is_null()
+    }
+
+    fn idle_deadline(&self) -> Option<Instant> {
+        self.idle
+            .timeout
+            .map(|timeout| self.idle.last_used + timeout)
+    }
+
+    fn idle_expired(&self) -> bool {
+        self.idle_deadline()
+            .map_or(false, |deadline| Instant::now() >= deadline)
+    }
+
+    /// Fail with [`Error::IdleClosed`] if the connection is or has to be closed.
+    fn check_idle(&mut self) -> io::Result<()> {
+        self.close_if_idle();
+
+        if self.is_idle_closed() {
+            Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                Error::IdleClosed,
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Wake the task of a pending read when the idle timeout expires.
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        if let Some(deadline) = self.idle_deadline() {
+            self.idle.timer.set_at(deadline);
+            if Pin::new(&mut self.idle.timer).poll(cx).is_ready() {
+                return self.check_idle();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send a close_notify alert and free the session, leaving the socket to be closed by
+    /// esp-tls.
+    fn close_idle(&mut self) {
+        log::info!(
+            "closing the connection after being idle for {:?}",
+            self.idle.last_used.elapsed()
+        );
+
+        self.post_disconnected(DisconnectReason::Idle);
+        self.shut_down();
+    }
+
+    /// Post the end of the connection, once per established one.
+    fn post_disconnected(&mut self, reason: DisconnectReason) {
+        if core::mem::take(&mut self.connected_event) {
+            events::post(ConnectionEvent::Disconnected { reason });
+        }
+    }
+
+    /// Post the failure of a read or write, for `map_err`.
+    fn failed(&mut self, e: EspError) -> io::Error {
+        // Before posting, which might overwrite the errno `io_error` looks at
+        let err = io_error(e);
+        self.post_disconnected(DisconnectReason::Error);
+
+        err
+    }
+
+    /// Send close_notify and release everything but the statistics, leaving `raw` null.
+    fn shut_down(&mut self) {
+        let ssl = self.ssl_context();
+        if !ssl.is_null() {
+            // Best effort, the socket is non-blocking and the peer might be gone anyway
+            unsafe { sys::mbedtls_ssl_close_notify(ssl) };
+        }
+
+        let _ = self.socket.release();
+        unsafe { sys::esp_tls_conn_destroy(self.raw) };
+        self.raw = ptr::null_mut();
+        self.permit = None;
+
+        self.pending_write = Vec::new();
+        self.read_buf = Default::default();
+        self.verify = None;
+        #[cfg(feature = "debug-keylog")]
+        {
+            self.keylog = None;
+        }
+        self.conf = None;
+    }
+
+    /// Bytes transferred since the handshake and how long the connection has been up.
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            bytes_read: self.stats.bytes_read,
+            bytes_written: self.stats.bytes_written,
+            duration: self
+                .stats
+                .established
+                .map(|established| established.elapsed())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// How long resolving, connecting and the handshake took, once [`negotiate`](Self::negotiate)
+    /// succeeded. Only connections made by a [`TlsConnector`](crate::TlsConnector) have the DNS
+    /// and TCP phases, e.g. to watch the network health from the device side.
+    pub fn connect_timings(&self) -> Option<ConnectTimings> {
+        self.stats.timings
+    }
+
+    pub(crate) fn set_permit(&mut self, permit: Option<ConnectionPermit>) {
+        self.permit = permit;
+    }
+
+    /// Record the phases before the handshake, as measured by the connector.
+    pub(crate) fn set_connect_timings(&mut self, dns: Option<Duration>, tcp_connect: Duration) {
+        if let Some(timings) = &mut self.stats.timings {
+            timings.dns = dns;
+            timings.tcp_connect = Some(tcp_connect);
+        }
+    }
+
+    /// Derive `len` bytes of keying material bound to the session (RFC 5705, RFC 8446 section
+    /// 7.5), e.g. for token binding. `context` is optional in TLS 1.2, where no context differs
+    /// from an empty one.
+    ///
+    /// Requires `MBEDTLS_SSL_KEYING_MATERIAL_EXPORT` in the mbedtls configuration and fails with
+    /// `ESP_ERR_INVALID_STATE` before the handshake completed.
+    pub fn export_keying_material(
+        &self,
+        label: &str,
+        context: Option<&[u8]>,
+        len: usize,
+    ) -> Result<Vec<u8>, EspError> {
+        if self.negotiated.is_none() || self.is_idle_closed() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        let mut out = vec![0; len];
+        let ret = unsafe {
+            sys::mbedtls_ssl_export_keying_material(
+                self.ssl_context(),
+                out.as_mut_ptr(),
+                len,
+                label.as_ptr() as *const c_char,
+                label.len(),
+                context.map_or(ptr::null(), |context| context.as_ptr()),
+                context.map_or(0, |context| context.len()),
+                context.is_some() as _,
+            )
+        };
+
+        match ret {
+            0 => Ok(out),
+            err => Err(EspError::from(err).unwrap()),
+        }
+    }
+
+    /// The state to resume the session with after a restart or deep sleep, see
+    /// [`session`](crate::session).
+    ///
+    /// Fails with `ESP_ERR_INVALID_STATE` before the handshake completed.
+    #[cfg(esp_idf_esp_tls_client_session_tickets)]
+    pub fn save_session(&self) -> Result<TlsSession, EspError> {
+        let negotiated = match &self.negotiated {
+            Some(negotiated) if !self.is_idle_closed() => negotiated,
+            _ => return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>()),
+        };
+
+        let session = unsafe { sys::esp_tls_get_client_session(self.raw) };
+        if session.is_null() {
+            return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+        }
+
+        let saved =
+            unsafe { TlsSession::from_raw(&negotiated.hostname, &(*session).saved_session) };
+        unsafe { sys::esp_tls_free_client_session(session) };
+
+        saved
+    }
+
+    /// The certificate the server presented during the handshake, if any.
+    pub fn peer_certificate(&self) -> Option<Certificate> {
+        self.peer_certificate_chain().into_iter().next()
+    }
+
+    /// The full chain the server presented during the handshake, leaf first.
+    ///
+    /// Requires `CONFIG_MBEDTLS_SSL_KEEP_PEER_CERTIFICATE` (enabled by default).
+    pub fn peer_certificate_chain(&self) -> Vec<Certificate> {
+        let ssl = self.ssl_context();
+        if ssl.is_null() {
+            return Vec::new();
+        }
+
+        unsafe { Certificate::chain_from_raw(sys::mbedtls_ssl_get_peer_cert(ssl)) }
+    }
+
+    fn install_hooks(&mut self) {
+        let ssl = self.ssl_context();
+
+        if let Some(verify) = &mut self.verify {
+            unsafe { verify.install(ssl) };
+        }
+
+        #[cfg(feature = "debug-keylog")]
+        if let Some(keylog) = &mut self.keylog {
+            unsafe { keylog.install(ssl) };
+        }
+    }
+
+    /// The mbedtls session. Only set up once the handshake has been started, and gone once the
+    /// connection was closed for being idle.
+    fn ssl_context(&self) -> *mut sys::mbedtls_ssl_context {
+        if self.raw.is_null() {
+            return ptr::null_mut();
+        }
+
+        unsafe { sys::esp_tls_get_ssl_context(self.raw) as *mut sys::mbedtls_ssl_context }
+    }
+
+    fn poll_wait(&self, cx: &mut Context<'_>, code: i32) -> Poll<Result<(), EspError>> {
+        match code {
+            // EWOULDBLOCK models the "0" return code of esp_mbedtls_handshake() which does not
+            // tell us whether the socket needs to become readable or writable, see `AsyncEspTls`
+            EWOULDBLOCK_I32 => {
+                ready!(self.socket.poll_writable(cx))?;
+                FreeRtos::delay_ms(0);
+
+                Poll::Ready(Ok(()))
+            }
+            ESP_TLS_ERR_SSL_WANT_READ => self.socket.poll_readable(cx),
+            ESP_TLS_ERR_SSL_WANT_WRITE => self.socket.poll_writable(cx),
+            _ => Poll::Ready(Err(EspError::from(code).unwrap())),
+        }
+    }
+
+    fn poll_read_raw(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, EspError>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        loop {
+            let ret = unsafe {
+                sys::esp_tls_conn_read(self.raw, buf.as_mut_ptr() as *mut c_void, buf.len())
+            };
+
+            // ESP docs treat 0 as error, but in Rust it's common to return 0 to indicate eof
+            if ret >= 0 {
+                return Poll::Ready(Ok(ret as usize));
+            }
+
+            ready!(self.poll_wait(cx, ret as i32))?;
+        }
+    }
+
+    fn poll_write_raw(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, EspError>> {
+        ready!(self.poll_write_pending(cx))?;
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        // Stick to a single record, so that a `WANT_WRITE` below always refers to all of `buf`
+        let buf = &buf[..buf.len().min(self.max_write())];
+
+        loop {
+            let ret = unsafe {
+                sys::esp_tls_conn_write(self.raw, buf.as_ptr() as *const c_void, buf.len())
+            };
+
+            if ret >= 0 {
+                return Poll::Ready(Ok(ret as usize));
+            }
+
+            if ret as i32 == ESP_TLS_ERR_SSL_WANT_WRITE {
+                // The record is encrypted and queued in mbedtls already, it only needs to be
+                // called again with the same data to send it. Do that later rather than making
+                // the caller retry, as the caller might not.
+                self.pending_write.extend_from_slice(buf);
+                return Poll::Ready(Ok(buf.len()));
+            }
+
+            ready!(self.poll_wait(cx, ret as i32))?;
+        }
+    }
+
+    /// The most to write at once, a single record.
+    fn max_write(&self) -> usize {
+        write_limit(self.ssl_context(), self.write_chunk)
+    }
+
+    /// Send the record left over from a previous write, if any.
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), EspError>> {
+        while !self.pending_write.is_empty() {
+            let ret = unsafe {
+                sys::esp_tls_conn_write(
+                    self.raw,
+                    self.pending_write.as_ptr() as *const c_void,
+                    self.pending_write.len(),
+                )
+            };
+
+            if ret >= 0 {
+                self.pending_write.clear();
+                break;
+            }
+
+            ready!(self.poll_wait(cx, ret as i32))?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(esp_idf_esp_tls_client_session_tickets)]
+impl AsyncTls {
+    /// Flush, save the session and close the connection, e.g. before entering deep sleep, see
+    /// [`suspend`](crate::suspend).
+    ///
+    /// A session is only saved if the server issued a ticket. Fails before the handshake
+    /// completed or when a pending write can't be flushed.
+    pub async fn suspend(mut self) -> Result<Suspended> {
+        let hostname = match &self.negotiated {
+            Some(negotiated) if !self.is_idle_closed() => negotiated.hostname.clone(),
+            _ => return Err(io::Error::from(io::ErrorKind::NotConnected).into()),
+        };
+        poll_fn(|cx| Pin::new(&mut self).poll_flush(cx)).await?;
+
+        let addr = self.socket.get_ref().get_ref().peer_addr()?;
+        let session = self
+            .save_session()
+            .map_err(|e| log::warn!("no session to resume with: {e}"))
+            .ok();
+
+        self.post_disconnected(DisconnectReason::Suspended);
+        self.shut_down();
+
+        Ok(Suspended::new(&hostname, addr, session))
+    }
+}
+
+impl<S: PollableSocket> Drop for AsyncTls<S> {
+    fn drop(&mut self) {
+        if self.is_idle_closed() {
+            return;
+        }
+
+        self.post_disconnected(DisconnectReason::Dropped);
+
+        let _ = self.socket.release();
+
+        unsafe {
+            sys::esp_tls_conn_destroy(self.raw);
+        }
+    }
+}
+
+impl<S: PollableSocket + Unpin> AsyncTls<S> {
+    fn poll_read_unbuffered(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.check_idle()?;
+
+        let read = match self.poll_read_raw(cx, buf) {
+            Poll::Ready(read) => read.map_err(|e| self.failed(e))?,
+            Poll::Pending => {
+                self.poll_idle(cx)?;
+                return Poll::Pending;
+            }
+        };
+        self.stats.bytes_read += read as u64;
+        #[cfg(feature = "debug-tap")]
+        if let Some(tap) = &self.tap {
+            tap.record(tap::Direction::Read, &buf[..read]);
+        }
+        if read > 0 {
+            self.idle.last_used = Instant::now();
+        } else if !buf.is_empty() {
+            self.post_disconnected(DisconnectReason::PeerClosed);
+        }
+
+        Poll::Ready(Ok(read))
+    }
+}
+
+impl<S: PollableSocket + Unpin> AsyncRead for AsyncTls<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.read_buf.buffered().is_empty() && buf.len() >= self.read_buf.data.len() {
+            return self.poll_read_unbuffered(cx, buf);
+        }
+
+        let buffered = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let len = buffered.len().min(buf.len());
+        buf[..len].copy_from_slice(&buffered[..len]);
+        self.consume(len);
+
+        Poll::Ready(Ok(len))
+    }
+}
+
+/// Served from the buffer of [`set_read_buffer`](AsyncTls::set_read_buffer).
+impl<S: PollableSocket + Unpin> AsyncBufRead for AsyncTls<S> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+
+        if this.read_buf.buffered().is_empty() {
+            let mut data = core::mem::take(&mut this.read_buf.data);
+            if data.is_empty() {
+                data = mem::alloc_buffer(DEFAULT_READ_BUFFER_LEN);
+            }
+
+            let read = this.poll_read_unbuffered(cx, &mut data);
+            this.read_buf.data = data;
+
+            this.read_buf.filled = ready!(read)?;
+            this.read_buf.pos = 0;
+        }
+
+        Poll::Ready(Ok(this.read_buf.buffered()))
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        let read_buf = &mut self.read_buf;
+        read_buf.pos = (read_buf.pos + amt).min(read_buf.filled);
+    }
+}
+
+impl<S: PollableSocket + Unpin> AsyncWrite for AsyncTls<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.check_idle()?;
+
+        let written = ready!(self.poll_write_raw(cx, buf)).map_err(|e| self.failed(e))?;
+        self.stats.bytes_written += written as u64;
+        #[cfg(feature = "debug-tap")]
+        if let Some(tap) = &self.tap {
+            tap.record(tap::Direction::Write, &buf[..written]);
+        }
+        if written > 0 {
+            self.idle.last_used = Instant::now();
+        }
+
+        Poll::Ready(Ok(written))
+    }
+
+    /// Coalesces the slices into a single TLS record, rather than sending one record per slice.
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.check_idle()?;
+
+        let record = coalesce(bufs, self.max_write());
+
+        self.poll_write(cx, &record)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.check_idle()?;
+
+        let flushed = ready!(self.poll_write_pending(cx));
+
+        Poll::Ready(flushed.map_err(|e| self.failed(e)))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// For embedded-svc based clients, errors are those of [`AsyncRead`] and [`AsyncWrite`] as
+/// [`EspIOError`]s.
+#[cfg(feature = "nightly")]
+impl<S: PollableSocket + Unpin> embedded_svc::io::Io for AsyncTls<S> {
+    type Error = EspIOError;
+}
+
+#[cfg(feature = "nightly")]
+impl<S: PollableSocket + Unpin> embedded_svc::io::asynch::Read for AsyncTls<S> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspIOError> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_read(cx, buf))
+            .await
+            .map_err(into_esp_io_error)
+    }
+}
+
+#[cfg(feature = "nightly")]
+impl<S: PollableSocket + Unpin> embedded_svc::io::asynch::Write for AsyncTls<S> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, EspIOError> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_write(cx, buf))
+            .await
+            .map_err(into_esp_io_error)
+    }
+
+    async fn flush(&mut self) -> Result<(), EspIOError> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_flush(cx))
+            .await
+            .map_err(into_esp_io_error)
+    }
+}
+
+/// The [`EspIOError`] behind an I/O error of the session, or a generic one for the errors that
+/// do not come from esp-tls, e.g. [`Error::IdleClosed`].
+#[cfg(feature = "nightly")]
+fn into_esp_io_error(e: io::Error) -> EspIOError {
+    e.into_inner()
+        .and_then(|inner| inner.downcast::<EspIOError>().ok())
+        .map(|e| *e)
+        .unwrap_or_else(|| EspIOError(EspError::from_infallible::<ESP_FAIL>()))
+}
+
+/// An I/O error for a failed esp-tls read or write, of the kind of the socket error behind it if
+/// there is one, so that callers can tell e.g. a reset connection from a protocol error.
+fn io_error(e: EspError) -> io::Error {
+    let kind = match e.code() {
+        ESP_TLS_ERR_SSL_WANT_READ | ESP_TLS_ERR_SSL_WANT_WRITE => io::ErrorKind::WouldBlock,
+        MBEDTLS_ERR_NET_CONN_RESET => io::ErrorKind::ConnectionReset,
+        // mbedtls leaves the socket error in errno, e.g. ETIMEDOUT, ENOTCONN or EPIPE
+        MBEDTLS_ERR_NET_RECV_FAILED | MBEDTLS_ERR_NET_SEND_FAILED => {
+            io::Error::last_os_error().kind()
+        }
+        _ => io::ErrorKind::Other,
+    };
+
+    io::Error::new(kind, EspIOError(e))
+}
+
+/// The most application data mbedtls puts into a single record.
+pub(crate) fn max_record_payload(ssl: *const sys::mbedtls_ssl_context) -> usize {
+    let max_len = unsafe { sys::mbedtls_ssl_get_max_out_record_payload(ssl) };
+
+    usize::try_from(max_len)
+        .ok()
+        .filter(|&len| len > 0)
+        .unwrap_or(usize::MAX)
+}
+
+/// The most application data to put into a record, with the chunk size set by the user, if any.
+pub(crate) fn write_limit(ssl: *const sys::mbedtls_ssl_context, chunk: Option<usize>) -> usize {
+    let max_len = max_record_payload(ssl);
+
+    chunk.map_or(max_len, |chunk| chunk.min(max_len))
+}
+
+/// An `esp_tls_cfg` together with the buffers its pointers refer to.
+struct RawConfig {
+    raw: sys::esp_tls_cfg,
+    _alpn_protos: Vec<CString>,
+    _alpn_ptrs: Vec<*const c_char>,
+    _common_name: Option<CString>,
+    _keep_alive: Option<Box<sys::tls_keep_alive_cfg>>,
+    _psk: Option<Box<sys::psk_key_hint>>,
+}
+
+impl RawConfig {
+    fn new(cfg: &Config<'_>) -> Result<Self, EspError> {
+        let mut raw: sys::esp_tls_cfg = Default::default();
+
+        if let Some(ca_cert) = cfg.ca_cert {
+            raw.__bindgen_anon_1.cacert_buf = ca_cert.data().as_ptr();
+            raw.__bindgen_anon_2.cacert_bytes = ca_cert.data().len() as u32;
+        }
+
+        if let Some(client_cert) = cfg.client_cert {
+            raw.__bindgen_anon_3.clientcert_buf = client_cert.data().as_ptr();
+            raw.__bindgen_anon_4.clientcert_bytes = client_cert.data().len() as u32;
+        }
+
+        if let Some(client_key) = cfg.client_key {
+            raw.__bindgen_anon_5.clientkey_buf = client_key.data().as_ptr();
+            raw.__bindgen_anon_6.clientkey_bytes = client_key.data().len() as u32;
+        }
+
+        if let Some(ckp) = cfg.client_key_password {
+            raw.clientkey_password = ckp.as_ptr();
+            raw.clientkey_password_len = ckp.len() as u32;
+        }
+
+        let alpn_protos = cfg
+            .alpn_protos
+            .unwrap_or_default()
+            .iter()
+            .map(|p| {
+                CString::new(*p).map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut alpn_ptrs: Vec<*const c_char> = alpn_protos.iter().map(|p| p.as_ptr()).collect();
+        if !alpn_ptrs.is_empty() {
+            alpn_ptrs.push(core::ptr::null());
+            raw.alpn_protos = alpn_ptrs.as_mut_ptr();
+        }
+
+        raw.non_block = cfg.non_block;
+        raw.use_secure_element = cfg.use_secure_element;
+        raw.timeout_ms = cfg.timeout_ms as i32;
+        raw.use_global_ca_store = cfg.use_global_ca_store;
+
+        let common_name = cfg
+            .common_name
+            .map(|cn| {
+                CString::new(cn).map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+            })
+            .transpose()?;
+        if let Some(cn) = &common_name {
+            raw.common_name = cn.as_ptr();
+        }
+
+        raw.skip_common_name = cfg.skip_common_name;
+
+        let mut keep_alive = cfg.keep_alive_cfg.as_ref().map(|kac| {
+            Box::new(sys::tls_keep_alive_cfg {
+                keep_alive_enable: kac.enable,
+                keep_alive_idle: kac.idle.as_secs() as i32,
+                keep_alive_interval: kac.interval.as_secs() as i32,
+                keep_alive_count: kac.count as i32,
+            })
+        });
+        if let Some(kac) = &mut keep_alive {
+            raw.keep_alive_cfg = kac.as_mut() as *mut _;
+        }
+
+        let mut psk = cfg.psk_hint_key.as_ref().map(|psk| {
+            Box::new(sys::psk_key_hint {
+                key: psk.key.as_ptr(),
+                key_size: psk.key.len(),
+                hint: psk.hint.as_ptr(),
+            })
+        });
+        if let Some(psk) = &mut psk {
+            raw.psk_hint_key = psk.as_mut() as *mut _;
+        }
+
+        #[cfg(esp_idf_mbedtls_certificate_bundle)]
+        if cfg.use_crt_bundle_attach {
+            raw.crt_bundle_attach = Some(sys::esp_crt_bundle_attach);
+        }
+
+        raw.is_plain_tcp = cfg.is_plain_tcp;
+
+        Ok(Self {
+            raw,
+            _alpn_protos: alpn_protos,
+            _alpn_ptrs: alpn_ptrs,
+            _common_name: common_name,
+            _keep_alive: keep_alive,
+            _psk: psk,
+        })
+    }
+}
@@ -0,0 +1,116 @@
+//! The links TLS connections run over, so that a new kind of link doesn't need changes to the
+//! TLS code.
+//!
+//! ```ignore
+//! // Routed by lwIP, through the default interface
+//! let tls = connect_async_tls(&Tcp, "example.com", 443, &cfg).await?;
+//!
+//! // Through the cellular link, even while WiFi is the default interface
+//! let link = PppModem::new(uart, "internet").connect()?;
+//! let tls = connect_async_tls(&Interface::new(&link), "example.com", 443, &cfg).await?;
+//! ```
+//!
+//! A [`Transport`] connects, and its socket hands readiness to esp-tls through
+//! [`PollableSocket`]: `poll_readable`, `poll_writable`, and `release` once the session owns the
+//! descriptor. [`Tcp`] and [`Interface`] cover TCP over the default route and over a given
+//! [`Uplink`], i.e. WiFi, [Ethernet](crate::eth) or [PPP](crate::ppp). There is no UDP transport:
+//! esp-tls only speaks TLS over a stream, DTLS would need a session of its own.
+
+use core::{future::Future, pin::Pin};
+use std::{
+    ffi::{c_char, CStr},
+    time::Duration,
+};
+
+use esp_idf_svc::tls::PollableSocket;
+use esp_idf_sys::{self as sys, esp};
+
+use crate::{
+    connector::TlsConnector,
+    error::{Error, Result},
+    netif::Uplink,
+    tcp::AsyncTcp,
+};
+
+/// What [`Transport::connect`] returns: the socket, and how long resolving the name took if it
+/// was resolved there.
+pub type ConnectFuture<'a, S> = Pin<Box<dyn Future<Output = Result<(S, Option<Duration>)>> + 'a>>;
+
+/// A link to connect over, see the [module docs](self).
+pub trait Transport {
+    type Socket: PollableSocket;
+
+    /// Connect to `hostname`, with the options of `connector` that apply to the link, e.g. its
+    /// name resolution.
+    fn connect<'a>(
+        &'a self,
+        connector: &'a TlsConnector,
+        hostname: &'a str,
+        port: u16,
+    ) -> ConnectFuture<'a, Self::Socket>;
+}
+
+/// TCP through the interface lwIP routes the server's address through, the default of
+/// [`TlsConnector::connect`].
+///
+/// Honors all options of the connector, including its [`Proxy`](crate::Proxy).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Tcp;
+
+impl Transport for Tcp {
+    type Socket = AsyncTcp;
+
+    fn connect<'a>(
+        &'a self,
+        connector: &'a TlsConnector,
+        hostname: &'a str,
+        port: u16,
+    ) -> ConnectFuture<'a, AsyncTcp> {
+        Box::pin(connector.connect_plain_timed(hostname, port, None))
+    }
+}
+
+/// TCP through a given interface, whichever is the default.
+///
+/// Ignores the [`Proxy`](crate::Proxy) of the connector, the other options apply.
+pub struct Interface<'a> {
+    uplink: &'a dyn Uplink,
+}
+
+impl<'a> Interface<'a> {
+    pub fn new(uplink: &'a dyn Uplink) -> Self {
+        Self { uplink }
+    }
+
+    /// The lwIP name of the interface, e.g. `en1`.
+    fn name(&self) -> Result<String> {
+        let mut name: [c_char; 8] = [0; 8];
+        esp!(unsafe {
+            sys::esp_netif_get_netif_impl_name(self.uplink.netif_handle(), name.as_mut_ptr())
+        })
+        .map_err(Error::Netif)?;
+
+        let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+
+        Ok(name.to_string_lossy().into_owned())
+    }
+}
+
+impl Transport for Interface<'_> {
+    type Socket = AsyncTcp;
+
+    fn connect<'a>(
+        &'a self,
+        connector: &'a TlsConnector,
+        hostname: &'a str,
+        port: u16,
+    ) -> ConnectFuture<'a, AsyncTcp> {
+        Box::pin(async move {
+            let interface = self.name()?;
+
+            connector
+                .connect_plain_timed(hostname, port, Some(&interface))
+                .await
+        })
+    }
+}
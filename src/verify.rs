@@ -0,0 +1,87 @@
+use core::ffi::{c_int, c_void};
+use std::{net::IpAddr, sync::Arc};
+
+use esp_idf_sys as sys;
+
+use crate::cert::Certificate;
+
+/// A custom certificate verification callback.
+///
+/// mbedtls calls it for every certificate of the chain presented by the server, root first,
+/// with the depth of the certificate in the chain (0 being the leaf) and the
+/// `MBEDTLS_X509_BADCERT_*` flags set by the stock validation. The returned flags replace the
+/// original ones: return `0` to accept the certificate, anything else fails the handshake.
+pub type VerifyFn = dyn Fn(&Certificate, usize, u32) -> u32 + Send + Sync;
+
+/// Accept a server certificate with `ip` among its iPAddress subjectAltNames for a server
+/// addressed by `ip`, then run `inner` if any.
+///
+/// The name check of mbedtls only compares the name with the DNS names and the common name of
+/// the certificate, so it flags the certificates of servers addressed by IP as mismatching,
+/// which are usually issued for the IP in an iPAddress entry. The flag is only cleared for a
+/// matching entry, so the check still fails for any other certificate.
+pub(crate) fn match_ip_address(ip: IpAddr, inner: Option<Arc<VerifyFn>>) -> Arc<VerifyFn> {
+    Arc::new(move |cert, depth, mut flags| {
+        if depth == 0 && cert.ip_addresses.contains(&ip) {
+            flags &= !sys::MBEDTLS_X509_BADCERT_CN_MISMATCH;
+        }
+
+        match &inner {
+            Some(inner) => inner(cert, depth, flags),
+            None => flags,
+        }
+    })
+}
+
+pub(crate) struct VerifyHook {
+    callback: Arc<VerifyFn>,
+    ssl: *const sys::mbedtls_ssl_context,
+}
+
+impl VerifyHook {
+    pub(crate) fn new(callback: Arc<VerifyFn>) -> Box<Self> {
+        Box::new(Self {
+            callback,
+            ssl: core::ptr::null(),
+        })
+    }
+
+    /// Register the hook on an mbedtls session.
+    ///
+    /// # Safety
+    ///
+    /// `ssl` must be set up already (`mbedtls_ssl_setup`) and the hook must outlive the session.
+    pub(crate) unsafe fn install(&mut self, ssl: *mut sys::mbedtls_ssl_context) {
+        self.ssl = ssl;
+
+        sys::mbedtls_ssl_set_verify(
+            ssl,
+            Some(verify_trampoline),
+            self as *mut Self as *mut c_void,
+        );
+    }
+}
+
+unsafe extern "C" fn verify_trampoline(
+    ctx: *mut c_void,
+    crt: *mut sys::mbedtls_x509_crt,
+    depth: c_int,
+    flags: *mut u32,
+) -> c_int {
+    let hook = &*(ctx as *const VerifyHook);
+
+    // A callback on the session takes precedence over the one on the configuration, so run
+    // whatever esp-tls registered there (e.g. the certificate bundle) first
+    let conf = (*hook.ssl).private_conf;
+    if let Some(conf_vrfy) = (*conf).private_f_vrfy {
+        let ret = conf_vrfy((*conf).private_p_vrfy, crt, depth, flags);
+        if ret != 0 {
+            return ret;
+        }
+    }
+
+    let cert = Certificate::from_raw(&*crt);
+    *flags = (hook.callback)(&cert, depth as usize, *flags);
+
+    0
+}
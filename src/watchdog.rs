@@ -0,0 +1,43 @@
+//! Keeping the task watchdog (TWDT) from resetting the device during long TLS handshakes.
+//!
+//! A handshake step with a large RSA key or without hardware acceleration occupies the CPU for
+//! seconds. Once several of them run back to back, the idle task of the core doesn't get to reset
+//! its watchdog subscription and the TWDT fires mid-handshake. [`HandshakeWatchdog`] yields to it
+//! between the steps, and resets the watchdog of the handshaking task itself if that task is
+//! subscribed. A single step longer than `CONFIG_ESP_TASK_WDT_TIMEOUT_S` still trips it, raise
+//! the timeout in that case.
+
+use std::time::{Duration, Instant};
+
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_sys as sys;
+
+/// Well below the 5 s default timeout of the TWDT.
+const FEED_INTERVAL: Duration = Duration::from_secs(1);
+
+/// See the [module docs](self).
+pub(crate) struct HandshakeWatchdog {
+    last_fed: Instant,
+}
+
+impl HandshakeWatchdog {
+    pub(crate) fn new() -> Self {
+        Self {
+            last_fed: Instant::now(),
+        }
+    }
+
+    /// Call between handshake steps, feeds the watchdog once the last feeding is a while ago.
+    pub(crate) fn step(&mut self) {
+        if self.last_fed.elapsed() < FEED_INTERVAL {
+            return;
+        }
+
+        // Fails with `ESP_ERR_NOT_FOUND` if the task is not subscribed, which is fine
+        unsafe { sys::esp_task_wdt_reset() };
+        // At least one tick, during which the idle task can run
+        FreeRtos::delay_ms(1);
+
+        self.last_fed = Instant::now();
+    }
+}
@@ -0,0 +1,1017 @@
+//! WebSockets (RFC 6455), accepted e.g. for a live control UI that browsers on the LAN open to
+//! the device, or connected as a client.
+//!
+//! ```ignore
+//! let acceptor = TlsAcceptor::new(CERT, KEY);
+//! let listener = AsyncTcpListener::bind(([0, 0, 0, 0], 443))?;
+//! let (tcp, _) = listener.accept().await?;
+//! let mut ws = WebSocket::accept(acceptor.accept(tcp).await?).await?;
+//! while let Message::Text(text) = ws.recv().await? {
+//!     ws.send(Message::Text(text)).await?;
+//! }
+//! ```
+//!
+//! As a client, [`WebSocket::into_stream`] carries a byte stream protocol in binary messages,
+//! e.g. MQTT for brokers only reachable through `wss://`:
+//!
+//! ```ignore
+//! let tls = connector.connect("broker.example.com", 443, &cfg).await?;
+//! let ws = WebSocket::connect(tls, "broker.example.com", "/mqtt", &["mqtt"]).await?;
+//! run_mqtt(ws.into_stream()).await
+//! ```
+
+use core::{
+    hash::{BuildHasher, Hasher},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use std::{collections::hash_map::RandomState, io};
+
+use futures_lite::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    error::{Error, Result},
+    http::find_header,
+    util::{base64, sha1},
+};
+
+/// Upper bound for the request or status line and headers of the handshake.
+const MAX_HEAD_LEN: usize = 2048;
+
+/// Largest frame [`WebSocketStream`] writes, longer writes are split.
+const MAX_STREAM_FRAME_LEN: usize = 4096;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// A message received from or sent to the peer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Pings from the peer are answered automatically and not returned by
+    /// [`WebSocket::recv`].
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The close code and reason, if any. Received close frames are answered automatically.
+    Close(Option<(u16, String)>),
+}
+
+/// A WebSocket connection over any transport, see the [module docs](self).
+pub struct WebSocket<T> {
+    stream: T,
+    path: String,
+    protocol: Option<String>,
+    /// Clients mask the frames they send, servers don't
+    client: bool,
+    max_message_len: usize,
+    close_sent: bool,
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl<T> WebSocket<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Read the HTTP upgrade request from a freshly accepted connection and complete the
+    /// handshake.
+    ///
+    /// Requests that are not a WebSocket upgrade are answered with `400 Bad Request`.
+    pub async fn accept(mut stream: T) -> Result<Self> {
+        let head = read_head(&mut stream).await?;
+        let mut lines = head.lines();
+        let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+        let (method, path) = (request_line.next(), request_line.next());
+        let headers: Vec<_> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+            .collect();
+
+        let has_token = |name, token: &str| {
+            find_header(&headers, name).map_or(false, |value| {
+                value
+                    .split(',')
+                    .any(|v| v.trim().eq_ignore_ascii_case(token))
+            })
+        };
+        let key = find_header(&headers, "sec-websocket-key");
+
+        let (Some("GET"), Some(path), true, true, Some("13"), Some(key)) = (
+            method,
+            path,
+            has_token("upgrade", "websocket"),
+            has_token("connection", "upgrade"),
+            find_header(&headers, "sec-websocket-version"),
+            key,
+        ) else {
+            stream
+                .write_all(
+                    b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                )
+                .await?;
+            stream.close().await?;
+            return Err(Error::WebSocket(
+                "not a WebSocket upgrade request".to_owned(),
+            ));
+        };
+
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(key)
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.flush().await?;
+
+        Ok(Self {
+            stream,
+            path: path.to_owned(),
+            protocol: None,
+            client: false,
+            max_message_len: 16 * 1024,
+            close_sent: false,
+        })
+    }
+
+    /// Open a WebSocket to `path` on `host` over `stream`, e.g. an [`AsyncTls`](crate::AsyncTls)
+    /// for `wss://`, offering the subprotocols `protocols`. If any are offered the server has to
+    /// pick one of them, see [`protocol`](Self::protocol).
+    pub async fn connect(
+        mut stream: T,
+        host: &str,
+        path: &str,
+        protocols: &[&str],
+    ) -> Result<Self> {
+        let key = base64(&random_bytes::<16>());
+
+        let mut request = format!(
+            "GET {path} HTTP/1.1\r\nHost: {host}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n"
+        );
+        if !protocols.is_empty() {
+            request.push_str(&format!(
+                "Sec-WebSocket-Protocol: {}\r\n",
+                protocols.join(", ")
+            ));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await?;
+
+        let head = read_head(&mut stream).await?;
+        let mut lines = head.lines();
+        let status = lines.next().unwrap_or_default();
+        let headers: Vec<_> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+            .collect();
+
+        if status.split_whitespace().nth(1) != Some("101") {
+            return Err(Error::WebSocket(format!("upgrade refused: {status}")));
+        }
+        if find_header(&headers, "sec-websocket-accept") != Some(accept_key(&key).as_str()) {
+            return Err(Error::WebSocket("invalid Sec-WebSocket-Accept".to_owned()));
+        }
+
+        let protocol = find_header(&headers, "sec-websocket-protocol").map(str::to_owned);
+        match &protocol {
+            None if protocols.is_empty() => (),
+            Some(protocol) if protocols.contains(&protocol.as_str()) => (),
+            _ => {
+                return Err(Error::WebSocket(format!(
+                    "the server picked the subprotocol {protocol:?} instead of one of {protocols:?}"
+                )))
+            }
+        }
+
+        Ok(Self {
+            stream,
+            path: path.to_owned(),
+            protocol,
+            client: true,
+            max_message_len: 16 * 1024,
+            close_sent: false,
+        })
+    }
+
+    /// The path the peer requested in the upgrade request, e.g. `/ws`, or the one connected to.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The subprotocol the server picked in [`connect`](Self::connect).
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// Reject messages longer than `len` bytes (16 KiB by default), which closes the connection.
+    pub fn max_message_len(&mut self, len: usize) {
+        self.max_message_len = len;
+    }
+
+    /// Receive the next message, reassembled from its fragments.
+    ///
+    /// After a [`Message::Close`] the connection is closing and should be dropped.
+    pub async fn recv(&mut self) -> Result<Message> {
+        let mut message: Option<(u8, Vec<u8>)> = None;
+
+        loop {
+            let frame = self.read_frame().await?;
+
+            match frame.opcode {
+                OP_PING => {
+                    if !self.close_sent {
+                        self.write_frame(OP_PONG, &frame.payload).await?;
+                    }
+                }
+                OP_PONG => return Ok(Message::Pong(frame.payload)),
+                OP_CLOSE => {
+                    let close = match frame.payload.as_slice() {
+                        [hi, lo, reason @ ..] => Some((
+                            u16::from_be_bytes([*hi, *lo]),
+                            String::from_utf8_lossy(reason).into_owned(),
+                        )),
+                        _ => None,
+                    };
+                    if !self.close_sent {
+                        // Echo the code as the RFC asks for
+                        self.close_sent = true;
+                        self.write_frame(OP_CLOSE, frame.payload.get(..2).unwrap_or_default())
+                            .await?;
+                    }
+
+                    return Ok(Message::Close(close));
+                }
+                OP_TEXT | OP_BINARY if message.is_none() => {
+                    message = Some((frame.opcode, frame.payload))
+                }
+                OP_CONTINUATION if message.is_some() => {
+                    let (_, data) = message.as_mut().unwrap();
+                    if data.len() + frame.payload.len() > self.max_message_len {
+                        return Err(self.fail(CLOSE_TOO_BIG, "message too long").await);
+                    }
+
+                    data.extend_from_slice(&frame.payload);
+                }
+                _ => return Err(self.fail(CLOSE_PROTOCOL_ERROR, "unexpected frame").await),
+            }
+
+            if !frame.fin || !matches!(frame.opcode, OP_TEXT | OP_BINARY | OP_CONTINUATION) {
+                continue;
+            }
+
+            return match message.take().unwrap() {
+                (OP_TEXT, data) => match String::from_utf8(data) {
+                    Ok(text) => Ok(Message::Text(text)),
+                    Err(_) => Err(self
+                        .fail(CLOSE_INVALID_DATA, "invalid UTF-8 in text message")
+                        .await),
+                },
+                (_, data) => Ok(Message::Binary(data)),
+            };
+        }
+    }
+
+    /// Send `message` as a single frame.
+    pub async fn send(&mut self, message: Message) -> Result<()> {
+        match message {
+            Message::Text(text) => self.write_frame(OP_TEXT, text.as_bytes()).await,
+            Message::Binary(data) => self.write_frame(OP_BINARY, &data).await,
+            Message::Ping(data) => self.write_frame(OP_PING, &data).await,
+            Message::Pong(data) => self.write_frame(OP_PONG, &data).await,
+            Message::Close(close) => {
+                let mut payload = Vec::new();
+                if let Some((code, reason)) = close {
+                    payload.extend_from_slice(&code.to_be_bytes());
+                    payload.extend_from_slice(reason.as_bytes());
+                }
+
+                self.close_sent = true;
+                self.write_frame(OP_CLOSE, &payload).await
+            }
+        }
+    }
+
+    /// Start the closing handshake with a normal closure (1000).
+    pub async fn close(&mut self) -> Result<()> {
+        self.send(Message::Close(Some((1000, String::new())))).await
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.stream
+    }
+
+    /// Read and write the payload of binary messages as a byte stream, without regard to message
+    /// boundaries. Text messages are read the same way.
+    pub fn into_stream(self) -> WebSocketStream<T> {
+        WebSocketStream {
+            ws: self,
+            read_buf: Vec::new(),
+            payload: Vec::new(),
+            payload_pos: 0,
+            write_buf: Vec::new(),
+            closed: false,
+        }
+    }
+
+    async fn read_frame(&mut self) -> Result<Frame> {
+        let mut head = [0; 2];
+        self.stream.read_exact(&mut head).await?;
+
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0f;
+        let masked = head[1] & 0x80 != 0;
+
+        let len = match head[1] & 0x7f {
+            126 => {
+                let mut len = [0; 2];
+                self.stream.read_exact(&mut len).await?;
+                u16::from_be_bytes(len) as u64
+            }
+            127 => {
+                let mut len = [0; 8];
+                self.stream.read_exact(&mut len).await?;
+                u64::from_be_bytes(len)
+            }
+            len => len as u64,
+        };
+
+        // No extensions are negotiated, only clients mask and control frames are small
+        if head[0] & 0x70 != 0
+            || masked == self.client
+            || (opcode >= OP_CLOSE && (!fin || len > 125))
+        {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "invalid frame").await);
+        }
+        if len > self.max_message_len as u64 {
+            return Err(self.fail(CLOSE_TOO_BIG, "message too long").await);
+        }
+
+        let mut mask = [0; 4];
+        if masked {
+            self.stream.read_exact(&mut mask).await?;
+        }
+
+        let mut payload = vec![0; len as usize];
+        self.stream.read_exact(&mut payload).await?;
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+
+        Ok(Frame {
+            fin,
+            opcode,
+            payload,
+        })
+    }
+
+    async fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<()> {
+        let mut frame = Vec::new();
+        encode_frame(&mut frame, opcode, payload, self.client);
+
+        self.stream.write_all(&frame).await?;
+        self.stream.flush().await?;
+
+        Ok(())
+    }
+
+    /// Close the connection with `code` because the peer misbehaved, returning the error to
+    /// report.
+    async fn fail(&mut self, code: u16, reason: &str) -> Error {
+        if !self.close_sent {
+            self.close_sent = true;
+
+            let mut payload = code.to_be_bytes().to_vec();
+            payload.extend_from_slice(reason.as_bytes());
+            // The connection is given up anyway
+            let _ = self.write_frame(OP_CLOSE, &payload).await;
+        }
+
+        Error::WebSocket(reason.to_owned())
+    }
+}
+
+/// A [`WebSocket`] as a byte stream, see [`WebSocket::into_stream`].
+///
+/// Pings are answered and a close frame from the peer reads as the end of the stream. Closing
+/// the stream starts the closing handshake.
+pub struct WebSocketStream<T> {
+    ws: WebSocket<T>,
+    /// Received bytes not parsed into a frame yet
+    read_buf: Vec<u8>,
+    /// Payload of the last data frame, returned from `payload_pos` on
+    payload: Vec<u8>,
+    payload_pos: usize,
+    /// Encoded frames not written to the transport yet
+    write_buf: Vec<u8>,
+    closed: bool,
+}
+
+impl<T> WebSocketStream<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn get_ref(&self) -> &T {
+        &self.ws.stream
+    }
+
+    /// Write out the frames in `write_buf`.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let written = ready!(Pin::new(&mut self.ws.stream).poll_write(cx, &self.write_buf))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.drain(..written);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Queue a control frame, written out along with the next data or flush.
+    fn queue(&mut self, opcode: u8, payload: &[u8]) {
+        encode_frame(&mut self.write_buf, opcode, payload, self.ws.client);
+    }
+
+    /// Act on the next complete frame in `read_buf`, `false` if there is none yet.
+    fn process_frame(&mut self) -> io::Result<bool> {
+        let Some((frame, consumed)) =
+            parse_frame(&self.read_buf, self.ws.client, self.ws.max_message_len)?
+        else {
+            return Ok(false);
+        };
+        self.read_buf.drain(..consumed);
+
+        match frame.opcode {
+            OP_TEXT | OP_BINARY | OP_CONTINUATION => {
+                self.payload = frame.payload;
+                self.payload_pos = 0;
+            }
+            OP_PING if !self.ws.close_sent => self.queue(OP_PONG, &frame.payload),
+            OP_PING | OP_PONG => (),
+            OP_CLOSE => {
+                if !self.ws.close_sent {
+                    self.ws.close_sent = true;
+                    self.queue(OP_CLOSE, frame.payload.get(..2).unwrap_or_default());
+                }
+                self.closed = true;
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid frame")),
+        }
+
+        Ok(true)
+    }
+}
+
+impl<T> AsyncRead for WebSocketStream<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.payload_pos < this.payload.len() {
+                let len = buf.len().min(this.payload.len() - this.payload_pos);
+                buf[..len].copy_from_slice(&this.payload[this.payload_pos..][..len]);
+                this.payload_pos += len;
+
+                return Poll::Ready(Ok(len));
+            }
+
+            // Pongs and the close echo go out as soon as possible, or with the next write
+            if !this.write_buf.is_empty() {
+                let _ = this.poll_drain(cx)?;
+            }
+            if this.closed {
+                return Poll::Ready(Ok(0));
+            }
+
+            if this.process_frame()? {
+                continue;
+            }
+            if this.read_buf.len() > this.ws.max_message_len + 14 {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "message too long",
+                )));
+            }
+
+            let mut chunk = [0; 512];
+            let read = ready!(Pin::new(&mut this.ws.stream).poll_read(cx, &mut chunk))?;
+            if read == 0 {
+                return Poll::Ready(Ok(0));
+            }
+            this.read_buf.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
+impl<T> AsyncWrite for WebSocketStream<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.ws.close_sent {
+            return Poll::Ready(Err(io::ErrorKind::NotConnected.into()));
+        }
+
+        ready!(this.poll_drain(cx))?;
+
+        let len = buf.len().min(MAX_STREAM_FRAME_LEN);
+        let client = this.ws.client;
+        encode_frame(&mut this.write_buf, OP_BINARY, &buf[..len], client);
+        // The frame is queued either way, written out by the next write or flush
+        let _ = this.poll_drain(cx)?;
+
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+
+        Pin::new(&mut this.ws.stream).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.ws.close_sent {
+            this.ws.close_sent = true;
+            this.queue(OP_CLOSE, &1000u16.to_be_bytes());
+        }
+        ready!(this.poll_drain(cx))?;
+
+        Pin::new(&mut this.ws.stream).poll_close(cx)
+    }
+}
+
+/// Append a frame to `out`, masked as clients send them or unmasked as servers do.
+fn encode_frame(out: &mut Vec<u8>, opcode: u8, payload: &[u8], masked: bool) {
+    let mask_bit = if masked { 0x80 } else { 0 };
+
+    out.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => out.push(mask_bit | len as u8),
+        len @ 126..=0xffff => {
+            out.push(mask_bit | 126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(mask_bit | 127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+
+    if masked {
+        let mask = random_bytes::<4>();
+        out.extend_from_slice(&mask);
+        out.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    } else {
+        out.extend_from_slice(payload);
+    }
+}
+
+/// The first frame in `data` and its encoded length, `None` if it is incomplete. Fails for
+/// frames longer than `max_len`.
+fn parse_frame(data: &[u8], client: bool, max_len: usize) -> io::Result<Option<(Frame, usize)>> {
+    let [b0, b1, rest @ ..] = data else {
+        return Ok(None);
+    };
+
+    let fin = b0 & 0x80 != 0;
+    let opcode = b0 & 0x0f;
+    let masked = b1 & 0x80 != 0;
+
+    let (len, rest) = match b1 & 0x7f {
+        126 => match rest {
+            [hi, lo, rest @ ..] => (u16::from_be_bytes([*hi, *lo]) as u64, rest),
+            _ => return Ok(None),
+        },
+        127 if rest.len() >= 8 => {
+            let (len, rest) = rest.split_at(8);
+            (u64::from_be_bytes(len.try_into().unwrap()), rest)
+        }
+        127 => return Ok(None),
+        len => (len as u64, rest),
+    };
+
+    if b0 & 0x70 != 0 || masked == client || (opcode >= OP_CLOSE && (!fin || len > 125)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid frame"));
+    }
+    // Also keeps the length from being truncated on 32 bit targets
+    if len > max_len as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message too long",
+        ));
+    }
+    let len = len as usize;
+
+    let (mask, rest) = match masked {
+        true if rest.len() >= 4 => {
+            let (mask, rest) = rest.split_at(4);
+            (mask.try_into().unwrap(), rest)
+        }
+        true => return Ok(None),
+        false => ([0; 4], rest),
+    };
+    let Some(payload) = rest.get(..len) else {
+        return Ok(None);
+    };
+
+    let payload = payload
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+    let consumed = data.len() - rest.len() + len;
+
+    Ok(Some((
+        Frame {
+            fin,
+            opcode,
+            payload,
+        },
+        consumed,
+    )))
+}
+
+/// Unpredictable enough for masking keys and handshake nonces, which protect intermediaries
+/// rather than secrets.
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    for chunk in bytes.chunks_mut(8) {
+        let random = RandomState::new().build_hasher().finish().to_le_bytes();
+        chunk.copy_from_slice(&random[..chunk.len()]);
+    }
+
+    bytes
+}
+
+/// The `Sec-WebSocket-Accept` value for the client's `key`.
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{key}{GUID}").as_bytes()))
+}
+
+async fn read_head<T>(stream: &mut T) -> Result<String>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut head = Vec::new();
+    let mut byte = [0; 1];
+
+    // Byte by byte, so that no frame after the head is consumed
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > MAX_HEAD_LEN {
+            return Err(Error::WebSocket("HTTP head too long".to_owned()));
+        }
+
+        stream.read_exact(&mut byte).await?;
+        head.push(byte[0]);
+    }
+
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::future;
+
+    use super::*;
+    use crate::mock::MockSocket;
+
+    /// The example handshake of RFC 6455.
+    const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+    const ACCEPT: &str = "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
+
+    /// A server accepted on one end of a pipe, with the other end as a scripted client.
+    fn accepted() -> (WebSocket<MockSocket>, MockSocket) {
+        let (mut client, server) = MockSocket::pair();
+
+        future::block_on(async {
+            let request = format!(
+                "GET /ws HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\n\
+                 Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: {KEY}\r\n\
+                 Sec-WebSocket-Version: 13\r\n\r\n"
+            );
+            client.write_all(request.as_bytes()).await.unwrap();
+            let server = WebSocket::accept(server).await.unwrap();
+
+            let response = read_head(&mut client).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 101 "), "{response}");
+            assert!(response.contains(&format!("\r\nSec-WebSocket-Accept: {ACCEPT}\r\n")));
+
+            (server, client)
+        })
+    }
+
+    /// Send a masked frame from the scripted client.
+    async fn send_frame(client: &mut MockSocket, fin: bool, opcode: u8, payload: &[u8]) {
+        let mut frame = Vec::new();
+        encode_frame(&mut frame, opcode, payload, true);
+        if !fin {
+            frame[0] &= 0x7f;
+        }
+        client.write_all(&frame).await.unwrap();
+    }
+
+    /// Receive a small frame from the server, which must not be masked.
+    async fn recv_frame(client: &mut MockSocket) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        client.read_exact(&mut head).await.unwrap();
+        assert_eq!(head[0] & 0x80, 0x80, "fragmented frame");
+        assert_eq!(head[1] & 0x80, 0, "masked frame");
+
+        let mut payload = vec![0; usize::from(head[1])];
+        client.read_exact(&mut payload).await.unwrap();
+        (head[0] & 0x0f, payload)
+    }
+
+    /// The close code the server sent.
+    async fn recv_close(client: &mut MockSocket) -> u16 {
+        let (opcode, payload) = recv_frame(client).await;
+        assert_eq!(opcode, OP_CLOSE);
+        u16::from_be_bytes([payload[0], payload[1]])
+    }
+
+    /// A client connected to a server over a pipe.
+    fn pair() -> (WebSocket<MockSocket>, WebSocket<MockSocket>) {
+        let (a, b) = MockSocket::pair();
+
+        let (client, server) = future::block_on(future::zip(
+            WebSocket::connect(a, "example.com", "/ws", &[]),
+            WebSocket::accept(b),
+        ));
+        (client.unwrap(), server.unwrap())
+    }
+
+    #[test]
+    fn frame_roundtrip() {
+        for masked in [false, true] {
+            for len in [0, 125, 126, 0xffff, 0x10000] {
+                let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+                let mut data = Vec::new();
+                encode_frame(&mut data, OP_BINARY, &payload, masked);
+
+                let (frame, consumed) = parse_frame(&data, !masked, len).unwrap().unwrap();
+                assert!(frame.fin);
+                assert_eq!(frame.opcode, OP_BINARY);
+                assert!(frame.payload == payload, "{len} bytes, masked: {masked}");
+                assert_eq!(consumed, data.len());
+
+                for partial in [0, 1, data.len() - 1] {
+                    assert!(parse_frame(&data[..partial], !masked, len)
+                        .unwrap()
+                        .is_none());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn frame_followed_by_more_data() {
+        let mut data = Vec::new();
+        encode_frame(&mut data, OP_TEXT, b"first", false);
+        encode_frame(&mut data, OP_TEXT, b"second", false);
+
+        let (frame, consumed) = parse_frame(&data, true, 1024).unwrap().unwrap();
+        assert_eq!(frame.payload, b"first");
+        let (frame, _) = parse_frame(&data[consumed..], true, 1024).unwrap().unwrap();
+        assert_eq!(frame.payload, b"second");
+    }
+
+    #[test]
+    fn invalid_frames() {
+        let mut masked = Vec::new();
+        encode_frame(&mut masked, OP_TEXT, b"hi", true);
+        let mut long_ping = Vec::new();
+        encode_frame(&mut long_ping, OP_PING, &[0; 126], false);
+
+        for data in [
+            // Masked frame to a client
+            &masked[..],
+            // Reserved bits
+            &[0xc1, 0x00][..],
+            // Fragmented control frame
+            &[OP_CLOSE, 0x00][..],
+            &long_ping[..],
+        ] {
+            let err = parse_frame(data, true, 1024).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{data:?}");
+        }
+    }
+
+    #[test]
+    fn frame_too_long() {
+        // Only the header of a frame of 2^32 + 3 bytes, which must not wrap around to 3 bytes
+        let mut data = vec![0x80 | OP_BINARY, 127];
+        data.extend_from_slice(&(u32::MAX as u64 + 4).to_be_bytes());
+        data.extend_from_slice(b"abc");
+
+        let err = parse_frame(&data, true, 16 * 1024).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(parse_frame(&data[..4], true, 16 * 1024).unwrap().is_none());
+    }
+
+    #[test]
+    fn accept_key_from_rfc() {
+        assert_eq!(accept_key(KEY), ACCEPT);
+    }
+
+    #[test]
+    fn server_messages() {
+        let (mut server, mut client) = accepted();
+        assert_eq!(server.path(), "/ws");
+
+        future::block_on(async {
+            send_frame(&mut client, true, OP_PING, b"ping").await;
+            send_frame(&mut client, false, OP_TEXT, b"hel").await;
+            send_frame(&mut client, true, OP_CONTINUATION, b"lo").await;
+            // The ping is answered on the way
+            assert_eq!(
+                server.recv().await.unwrap(),
+                Message::Text("hello".to_owned())
+            );
+            assert_eq!(recv_frame(&mut client).await, (OP_PONG, b"ping".to_vec()));
+
+            server.send(Message::Binary(vec![1, 2, 3])).await.unwrap();
+            assert_eq!(recv_frame(&mut client).await, (OP_BINARY, vec![1, 2, 3]));
+
+            send_frame(&mut client, true, OP_CLOSE, &1000u16.to_be_bytes()).await;
+            assert_eq!(
+                server.recv().await.unwrap(),
+                Message::Close(Some((1000, String::new())))
+            );
+            assert_eq!(recv_close(&mut client).await, 1000);
+        });
+    }
+
+    #[test]
+    fn unmasked_client_frame() {
+        let (mut server, mut client) = accepted();
+
+        future::block_on(async {
+            let mut frame = Vec::new();
+            encode_frame(&mut frame, OP_TEXT, b"hello", false);
+            client.write_all(&frame).await.unwrap();
+
+            assert!(matches!(server.recv().await, Err(Error::WebSocket(_))));
+            assert_eq!(recv_close(&mut client).await, CLOSE_PROTOCOL_ERROR);
+        });
+    }
+
+    #[test]
+    fn message_too_long() {
+        let (mut server, mut client) = accepted();
+        server.max_message_len(4);
+
+        future::block_on(async {
+            send_frame(&mut client, true, OP_BINARY, &[0; 5]).await;
+            assert!(matches!(server.recv().await, Err(Error::WebSocket(_))));
+            assert_eq!(recv_close(&mut client).await, CLOSE_TOO_BIG);
+        });
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let (mut server, mut client) = accepted();
+
+        future::block_on(async {
+            send_frame(&mut client, true, OP_TEXT, &[0xff, 0xfe]).await;
+            assert!(matches!(server.recv().await, Err(Error::WebSocket(_))));
+            assert_eq!(recv_close(&mut client).await, CLOSE_INVALID_DATA);
+        });
+    }
+
+    #[test]
+    fn not_an_upgrade() {
+        let (mut a, b) = MockSocket::pair();
+
+        future::block_on(async {
+            a.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+                .await
+                .unwrap();
+            assert!(WebSocket::accept(b).await.is_err());
+
+            let mut response = String::new();
+            a.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 400 "));
+        });
+    }
+
+    #[test]
+    fn client_messages() {
+        let (mut client, mut server) = pair();
+        assert_eq!(server.path(), "/ws");
+        assert_eq!(client.protocol(), None);
+
+        future::block_on(async {
+            client.send(Message::Ping(b"ping".to_vec())).await.unwrap();
+            client
+                .send(Message::Text("hello".to_owned()))
+                .await
+                .unwrap();
+            // The ping is answered on the way
+            assert_eq!(
+                server.recv().await.unwrap(),
+                Message::Text("hello".to_owned())
+            );
+            assert_eq!(
+                client.recv().await.unwrap(),
+                Message::Pong(b"ping".to_vec())
+            );
+
+            server.send(Message::Binary(vec![1, 2, 3])).await.unwrap();
+            assert_eq!(client.recv().await.unwrap(), Message::Binary(vec![1, 2, 3]));
+
+            client.close().await.unwrap();
+            let close = Message::Close(Some((1000, String::new())));
+            assert_eq!(server.recv().await.unwrap(), close);
+            assert_eq!(client.recv().await.unwrap(), close);
+        });
+    }
+
+    #[test]
+    fn subprotocol() {
+        let (a, mut b) = MockSocket::pair();
+
+        let (client, ()) = future::block_on(future::zip(
+            WebSocket::connect(a, "example.com", "/mqtt", &["mqtt"]),
+            async {
+                let head = read_head(&mut b).await.unwrap();
+                let key = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+                    .unwrap();
+                let response = format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                     Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\
+                     Sec-WebSocket-Protocol: mqtt\r\n\r\n",
+                    accept_key(key)
+                );
+                b.write_all(response.as_bytes()).await.unwrap();
+            },
+        ));
+        assert_eq!(client.unwrap().protocol(), Some("mqtt"));
+    }
+
+    #[test]
+    fn stream() {
+        let (client, mut server) = pair();
+        let mut stream = client.into_stream();
+
+        future::block_on(async {
+            stream.write_all(b"hello").await.unwrap();
+            stream.flush().await.unwrap();
+            assert_eq!(
+                server.recv().await.unwrap(),
+                Message::Binary(b"hello".to_vec())
+            );
+
+            server.send(Message::Ping(b"ping".to_vec())).await.unwrap();
+            server
+                .send(Message::Binary(b"world".to_vec()))
+                .await
+                .unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"world");
+            assert_eq!(
+                server.recv().await.unwrap(),
+                Message::Pong(b"ping".to_vec())
+            );
+
+            server.close().await.unwrap();
+            assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+            assert_eq!(
+                server.recv().await.unwrap(),
+                Message::Close(Some((1000, String::new())))
+            );
+        });
+    }
+}
@@ -0,0 +1,271 @@
+use embedded_svc::wifi::{AuthMethod, ClientConfiguration, Configuration};
+use esp_idf_hal::{modem::Modem, peripheral::Peripheral};
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
+    wifi::{BlockingWifi, EspWifi},
+};
+use esp_idf_sys::{self as sys, esp, EspError, ESP_ERR_INVALID_ARG};
+use log::*;
+
+use crate::error::{Error, Result};
+
+mod credentials;
+mod monitor;
+
+pub use self::{
+    credentials::WifiCredentials,
+    monitor::{LinkEvent, LinkMonitor, LinkMonitorHandle, LinkStats},
+};
+
+/// Modem power saving of the station while it is connected, see [`set_power_save`].
+///
+/// While saving power the modem sleeps between beacons of the access point and only wakes up to
+/// fetch frames the access point buffered meanwhile. Sending is not delayed, but incoming data,
+/// and with it the readiness [`AsyncTls`](crate::AsyncTls) waits for in `poll_readable`, arrives
+/// up to one DTIM interval late with `Min` (typically 100 to 300 ms) and up to the listen interval
+/// with `Max`. The TCP connection and the TLS session on it stay up, so any mode works as long as
+/// read timeouts leave room for that delay.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerSave {
+    /// The modem stays awake, lowest latency. Not allowed while Bluetooth is in use
+    None,
+    /// Wake up for every DTIM beacon, the ESP-IDF default
+    Min,
+    /// Wake up every listen interval of the station configuration (3 beacons by default)
+    Max,
+}
+
+impl PowerSave {
+    fn raw(self) -> sys::wifi_ps_type_t {
+        match self {
+            Self::None => sys::wifi_ps_type_t_WIFI_PS_NONE,
+            Self::Min => sys::wifi_ps_type_t_WIFI_PS_MIN_MODEM,
+            Self::Max => sys::wifi_ps_type_t_WIFI_PS_MAX_MODEM,
+        }
+    }
+}
+
+/// Trade latency for power, see [`PowerSave`]. Takes effect right away, also on established
+/// connections.
+pub fn set_power_save(mode: PowerSave) -> Result<()> {
+    esp!(unsafe { sys::esp_wifi_set_ps(mode.raw()) }).map_err(Error::Wifi)?;
+    info!("WiFi power save: {mode:?}");
+
+    Ok(())
+}
+
+pub fn power_save() -> Result<PowerSave> {
+    let mut raw = 0;
+    esp!(unsafe { sys::esp_wifi_get_ps(&mut raw) }).map_err(Error::Wifi)?;
+
+    Ok(match raw {
+        sys::wifi_ps_type_t_WIFI_PS_MIN_MODEM => PowerSave::Min,
+        sys::wifi_ps_type_t_WIFI_PS_MAX_MODEM => PowerSave::Max,
+        _ => PowerSave::None,
+    })
+}
+
+/// Keep the modem awake until the returned guard is dropped, e.g. for a download or a chatty
+/// exchange on a device that otherwise saves power.
+pub fn suspend_power_save() -> Result<PowerSaveGuard> {
+    let previous = power_save()?;
+    if previous != PowerSave::None {
+        set_power_save(PowerSave::None)?;
+    }
+
+    Ok(PowerSaveGuard { previous })
+}
+
+/// Restores the previous power save mode when dropped, see [`suspend_power_save`].
+pub struct PowerSaveGuard {
+    previous: PowerSave,
+}
+
+impl Drop for PowerSaveGuard {
+    fn drop(&mut self) {
+        if self.previous != PowerSave::None {
+            if let Err(e) = set_power_save(self.previous) {
+                warn!("failed to restore WiFi power save: {e}");
+            }
+        }
+    }
+}
+
+/// The access point the station is connected to, for reconnecting without a scan, see
+/// [`connect_with_hint`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ApHint {
+    pub bssid: [u8; 6],
+    pub channel: u8,
+}
+
+impl ApHint {
+    /// The access point the station is connected to now.
+    pub fn current() -> Result<Self> {
+        let mut info: sys::wifi_ap_record_t = Default::default();
+        esp!(unsafe { sys::esp_wifi_sta_get_ap_info(&mut info) }).map_err(Error::Wifi)?;
+
+        Ok(Self {
+            bssid: info.bssid,
+            channel: info.primary,
+        })
+    }
+}
+
+/// Connect to the access point `ssid` and wait for a DHCP lease.
+///
+/// An empty `pass` connects to an open network. The connection lasts as long as the returned
+/// driver is kept around.
+pub fn connect(
+    modem: impl Peripheral<P = Modem> + 'static,
+    sysloop: EspSystemEventLoop,
+    ssid: &str,
+    pass: &str,
+) -> Result<Box<EspWifi<'static>>> {
+    connect_with_hint(modem, sysloop, ssid, pass, None)
+}
+
+/// Same as [`connect`], but goes straight to the access point of `hint` instead of scanning for
+/// it first, which saves a second or two after waking up from deep sleep. Falls back to a scan
+/// if that access point can't be joined.
+pub fn connect_with_hint(
+    modem: impl Peripheral<P = Modem> + 'static,
+    sysloop: EspSystemEventLoop,
+    ssid: &str,
+    pass: &str,
+    hint: Option<ApHint>,
+) -> Result<Box<EspWifi<'static>>> {
+    let mut auth_method = AuthMethod::WPA2Personal;
+    if ssid.is_empty() {
+        error!("Missing WiFi name");
+        return Err(Error::Wifi(
+            EspError::from_infallible::<ESP_ERR_INVALID_ARG>(),
+        ));
+    }
+    if pass.is_empty() {
+        auth_method = AuthMethod::None;
+        info!("Wifi password is empty");
+    }
+    let mut esp_wifi = EspWifi::new(modem, sysloop.clone(), None).map_err(Error::Wifi)?;
+
+    let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop).map_err(Error::Wifi)?;
+
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))
+        .map_err(Error::Wifi)?;
+
+    info!("Starting wifi...");
+
+    wifi.start().map_err(Error::Wifi)?;
+
+    if let Some(hint) = hint {
+        info!(
+            "Connecting to {ssid} on channel {} without scanning...",
+            hint.channel
+        );
+
+        let joined = wifi
+            .set_configuration(&Configuration::Client(ClientConfiguration {
+                ssid: ssid.into(),
+                password: pass.into(),
+                channel: Some(hint.channel),
+                bssid: Some(hint.bssid),
+                auth_method,
+                ..Default::default()
+            }))
+            .and_then(|()| wifi.connect());
+        match joined {
+            Ok(()) => return finish_connect(wifi, esp_wifi),
+            Err(e) => {
+                warn!("Connecting to the known access point failed ({e}), scanning");
+                let _ = wifi.disconnect();
+            }
+        }
+    }
+
+    info!("Scanning...");
+
+    let ap_infos = wifi.scan().map_err(Error::Wifi)?;
+
+    let ours = ap_infos.into_iter().find(|a| a.ssid == ssid);
+
+    let channel = if let Some(ours) = ours {
+        info!(
+            "Found configured access point {} on channel {}",
+            ssid, ours.channel
+        );
+        Some(ours.channel)
+    } else {
+        info!(
+            "Configured access point {} not found during scanning, will go with unknown channel",
+            ssid
+        );
+        None
+    };
+
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
+        ssid: ssid.into(),
+        password: pass.into(),
+        channel,
+        auth_method,
+        ..Default::default()
+    }))
+    .map_err(Error::Wifi)?;
+
+    info!("Connecting wifi...");
+
+    wifi.connect().map_err(Error::Wifi)?;
+
+    finish_connect(wifi, esp_wifi)
+}
+
+/// Switch the station of `wifi`, started by [`connect`], over to the access point `ssid`
+/// without restarting the driver, e.g. after the password changed. Connections over the previous
+/// link break.
+pub fn reconnect(
+    wifi: &mut EspWifi<'static>,
+    sysloop: EspSystemEventLoop,
+    ssid: &str,
+    pass: &str,
+) -> Result<()> {
+    let auth_method = if pass.is_empty() {
+        AuthMethod::None
+    } else {
+        AuthMethod::WPA2Personal
+    };
+
+    let mut wifi = BlockingWifi::wrap(wifi, sysloop).map_err(Error::Wifi)?;
+
+    info!("Reconnecting wifi to {ssid}...");
+
+    if wifi.is_connected().map_err(Error::Wifi)? {
+        wifi.disconnect().map_err(Error::Wifi)?;
+    }
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
+        ssid: ssid.into(),
+        password: pass.into(),
+        auth_method,
+        ..Default::default()
+    }))
+    .map_err(Error::Wifi)?;
+    wifi.connect().map_err(Error::Wifi)?;
+    wifi.wait_netif_up().map_err(Error::Wifi)?;
+
+    info!("Reconnected wifi to {ssid}");
+
+    Ok(())
+}
+
+fn finish_connect(
+    wifi: BlockingWifi<&mut EspWifi<'static>>,
+    esp_wifi: EspWifi<'static>,
+) -> Result<Box<EspWifi<'static>>> {
+    info!("Waiting for DHCP lease...");
+
+    wifi.wait_netif_up().map_err(Error::Wifi)?;
+
+    let ip_info = wifi.wifi().sta_netif().get_ip_info().map_err(Error::Wifi)?;
+
+    info!("Wifi STA DHCP info: {:?}", ip_info);
+
+    Ok(Box::new(esp_wifi))
+}
@@ -0,0 +1,102 @@
+use core::fmt;
+
+use esp_idf_svc::nvs::{EspNvs, NvsPartitionId};
+
+use crate::error::{Error, Result};
+
+const NVS_KEY: &str = "wifi";
+
+/// Longest SSID 802.11 allows.
+const MAX_SSID_LEN: usize = 32;
+/// A WPA2 passphrase, or 64 hex digits of a raw key.
+const MAX_PASS_LEN: usize = 64;
+
+/// WiFi credentials kept in NVS, which take precedence over the ones built into the firmware so
+/// that a device can follow a changed access point password without physical access.
+///
+/// ```ignore
+/// let creds = WifiCredentials::load(&nvs)?
+///     .unwrap_or_else(|| WifiCredentials::new(config.wifi_ssid, config.wifi_pass));
+/// let wifi = wifi::connect(modem, sysloop, &creds.ssid, &creds.pass)?;
+/// ```
+#[derive(Clone, PartialEq, Eq)]
+pub struct WifiCredentials {
+    pub ssid: String,
+    /// Empty for an open network
+    pub pass: String,
+}
+
+impl fmt::Debug for WifiCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WifiCredentials")
+            .field("ssid", &self.ssid)
+            .finish_non_exhaustive()
+    }
+}
+
+impl WifiCredentials {
+    pub fn new(ssid: &str, pass: &str) -> Self {
+        Self {
+            ssid: ssid.to_owned(),
+            pass: pass.to_owned(),
+        }
+    }
+
+    /// The credentials stored with [`store`](Self::store), if any.
+    pub fn load<T: NvsPartitionId>(nvs: &EspNvs<T>) -> Result<Option<Self>> {
+        let mut buf = [0; MAX_SSID_LEN + 1 + MAX_PASS_LEN];
+        let Some(blob) = nvs.get_raw(NVS_KEY, &mut buf).map_err(Error::Nvs)? else {
+            return Ok(None);
+        };
+
+        let creds = blob
+            .iter()
+            .position(|&b| b == 0)
+            .map(|nul| (&blob[..nul], &blob[nul + 1..]))
+            .and_then(|(ssid, pass)| {
+                Some(Self {
+                    ssid: String::from_utf8(ssid.to_vec()).ok()?,
+                    pass: String::from_utf8(pass.to_vec()).ok()?,
+                })
+            });
+        if creds.is_none() {
+            log::warn!("ignoring the invalid WiFi credentials in NVS");
+        }
+
+        Ok(creds)
+    }
+
+    /// Write the credentials to `nvs` under the key `wifi`, replacing any stored before.
+    ///
+    /// Both go into a single entry, which NVS writes atomically, so a reset can't leave the new
+    /// password next to the old SSID.
+    pub fn store<T: NvsPartitionId>(&self, nvs: &mut EspNvs<T>) -> Result<()> {
+        if self.ssid.is_empty() || self.ssid.len() > MAX_SSID_LEN || self.ssid.contains('\0') {
+            return Err(Error::Credentials(format!(
+                "invalid SSID {:?}, expected 1 to 32 bytes",
+                self.ssid
+            )));
+        }
+        if self.pass.len() > MAX_PASS_LEN {
+            return Err(Error::Credentials(
+                "the WiFi password is longer than 64 bytes".to_owned(),
+            ));
+        }
+
+        let mut blob = Vec::with_capacity(self.ssid.len() + 1 + self.pass.len());
+        blob.extend_from_slice(self.ssid.as_bytes());
+        blob.push(0);
+        blob.extend_from_slice(self.pass.as_bytes());
+
+        nvs.set_raw(NVS_KEY, &blob).map_err(Error::Nvs)?;
+
+        Ok(())
+    }
+
+    /// Remove stored credentials, so that the ones of the firmware apply again.
+    pub fn clear<T: NvsPartitionId>(nvs: &mut EspNvs<T>) -> Result<()> {
+        nvs.remove(NVS_KEY).map_err(Error::Nvs)?;
+
+        Ok(())
+    }
+}
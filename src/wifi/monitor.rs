@@ -0,0 +1,200 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use esp_idf_sys as sys;
+
+use crate::{error::Result, runtime};
+
+/// Signal quality of the station's connection, sampled by a [`LinkMonitor`].
+#[derive(Clone, Copy, Debug)]
+pub struct LinkStats {
+    /// Signal strength of the access point in dBm
+    pub rssi: i8,
+    /// Exponential moving average of `rssi`, less jumpy for decisions
+    pub avg_rssi: f32,
+    /// Weakest signal seen since the monitor started
+    pub min_rssi: i8,
+    pub channel: u8,
+    pub sampled_at: Instant,
+}
+
+/// Changes of the link quality reported to [`LinkMonitor::on_event`].
+#[derive(Clone, Copy, Debug)]
+pub enum LinkEvent {
+    /// The average signal dropped below the threshold
+    Degraded(LinkStats),
+    /// The average signal is back above the threshold, with some margin
+    Recovered(LinkStats),
+    /// The station is not connected to an access point anymore
+    Lost,
+}
+
+type EventFn = dyn Fn(LinkEvent) + Send + Sync;
+
+/// Samples the signal strength periodically on a background thread, e.g. to tell whether TLS
+/// timeouts coincide with bad radio conditions.
+///
+/// ```ignore
+/// let monitor = LinkMonitor::new()
+///     .threshold(-75)
+///     .on_event(|event| log::info!("link: {event:?}"))
+///     .spawn()?;
+/// // Later
+/// if let Some(stats) = monitor.latest() { ... }
+/// ```
+pub struct LinkMonitor {
+    interval: Duration,
+    threshold: i8,
+    on_event: Option<Box<EventFn>>,
+}
+
+impl Default for LinkMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LinkMonitor {
+    /// Sample every 10 seconds and consider the link degraded below -80 dBm.
+    pub fn new() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            threshold: -80,
+            on_event: None,
+        }
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Report [`LinkEvent::Degraded`] once the average signal drops below `rssi` dBm.
+    pub fn threshold(mut self, rssi: i8) -> Self {
+        self.threshold = rssi;
+        self
+    }
+
+    /// Call `on_event` from the monitor thread when the link quality changes. Changes are logged
+    /// as warnings either way.
+    pub fn on_event<F>(mut self, on_event: F) -> Self
+    where
+        F: Fn(LinkEvent) + Send + Sync + 'static,
+    {
+        self.on_event = Some(Box::new(on_event));
+        self
+    }
+
+    /// Start sampling on a new thread, which stops once the returned handle and its clones are
+    /// dropped.
+    pub fn spawn(self) -> Result<LinkMonitorHandle> {
+        let shared = Arc::new(Shared {
+            latest: Mutex::new(None),
+            stopped: AtomicBool::new(false),
+        });
+
+        let handle = LinkMonitorHandle(Arc::new(Stop(shared.clone())));
+
+        runtime::spawn_with(runtime::current().link_monitor, || {
+            thread::Builder::new()
+                .name("link-monitor".into())
+                .stack_size(4 * 1024)
+                .spawn(move || self.run(&shared))
+        })?;
+
+        Ok(handle)
+    }
+
+    fn run(self, shared: &Shared) {
+        // Above the threshold by this much before the link counts as recovered, so that a signal
+        // hovering around the threshold does not flap
+        const HYSTERESIS: f32 = 5.0;
+
+        let mut degraded = false;
+        let mut connected = false;
+
+        while !shared.stopped.load(Ordering::Relaxed) {
+            let mut info: sys::wifi_ap_record_t = Default::default();
+            let event = if unsafe { sys::esp_wifi_sta_get_ap_info(&mut info) } == sys::ESP_OK {
+                let mut latest = shared.latest.lock().unwrap();
+                let stats = match *latest {
+                    Some(prev) if connected => LinkStats {
+                        rssi: info.rssi,
+                        avg_rssi: prev.avg_rssi * 0.75 + info.rssi as f32 * 0.25,
+                        min_rssi: prev.min_rssi.min(info.rssi),
+                        channel: info.primary,
+                        sampled_at: Instant::now(),
+                    },
+                    prev => LinkStats {
+                        rssi: info.rssi,
+                        avg_rssi: info.rssi as f32,
+                        min_rssi: prev.map_or(info.rssi, |prev| prev.min_rssi.min(info.rssi)),
+                        channel: info.primary,
+                        sampled_at: Instant::now(),
+                    },
+                };
+                *latest = Some(stats);
+                connected = true;
+
+                let threshold = self.threshold as f32;
+                if !degraded && stats.avg_rssi < threshold {
+                    degraded = true;
+                    Some(LinkEvent::Degraded(stats))
+                } else if degraded && stats.avg_rssi >= threshold + HYSTERESIS {
+                    degraded = false;
+                    Some(LinkEvent::Recovered(stats))
+                } else {
+                    None
+                }
+            } else if connected {
+                connected = false;
+                degraded = false;
+                Some(LinkEvent::Lost)
+            } else {
+                None
+            };
+
+            if let Some(event) = event {
+                log::warn!("WiFi link: {event:?}");
+                if let Some(on_event) = &self.on_event {
+                    on_event(event);
+                }
+            }
+
+            thread::sleep(self.interval);
+        }
+    }
+}
+
+struct Shared {
+    latest: Mutex<Option<LinkStats>>,
+    stopped: AtomicBool,
+}
+
+/// Stops the thread when the last handle is gone.
+struct Stop(Arc<Shared>);
+
+impl Drop for Stop {
+    fn drop(&mut self) {
+        self.0.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Access to a running [`LinkMonitor`].
+#[derive(Clone)]
+pub struct LinkMonitorHandle(Arc<Stop>);
+
+impl LinkMonitorHandle {
+    /// The most recent sample, `None` before the first one was taken. Stays at the last sample
+    /// while the station is disconnected.
+    pub fn latest(&self) -> Option<LinkStats> {
+        let Stop(shared) = &*self.0;
+        *shared.latest.lock().unwrap()
+    }
+}